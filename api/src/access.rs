@@ -40,6 +40,14 @@ impl ReadGuard {
     }
 }
 
+pub struct AdminGuard(app::auth::AdminGrant);
+
+impl AdminGuard {
+    pub fn grant(&self) -> &app::auth::AdminGrant {
+        &self.0
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("access denied")]
@@ -77,6 +85,15 @@ impl<'r> FromRequest<'r> for ReadGuard {
     }
 }
 
+#[async_trait]
+impl<'r> FromRequest<'r> for AdminGuard {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        guard_impl(req, app::auth::get_admin_grant, Self).await
+    }
+}
+
 impl<'a> OpenApiFromRequest<'a> for SpendGuard {
     fn from_request_input(
         _gen: &mut OpenApiGenerator,
@@ -107,6 +124,16 @@ impl<'a> OpenApiFromRequest<'a> for ReadGuard {
     }
 }
 
+impl<'a> OpenApiFromRequest<'a> for AdminGuard {
+    fn from_request_input(
+        _: &mut OpenApiGenerator,
+        _: String,
+        _: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(openapi_auth())
+    }
+}
+
 async fn guard_impl<
     'a,
     'b,
@@ -161,6 +188,12 @@ impl AnyGrant for app::auth::ReadGrant {
     }
 }
 
+impl AnyGrant for app::auth::AdminGrant {
+    fn user_id(&self) -> user::Id {
+        self.user_id
+    }
+}
+
 fn openapi_auth() -> RequestHeaderInput {
     let security_scheme = SecurityScheme {
         description: Some(format!(
@@ -52,3 +52,37 @@ pub fn concurrency_error<E: Serialize>(error: E) -> JsonError<E> {
         "a concurrency conflict could not be resolved, please contact support".to_owned(),
     )
 }
+
+pub fn not_found<E: Serialize>(error: E, description: String) -> JsonError<E> {
+    (
+        Status::NotFound,
+        Json(Error::new(Status::NotFound, description, error)),
+    )
+}
+
+pub fn conflict<E: Serialize>(error: E, description: String) -> JsonError<E> {
+    (
+        Status::Conflict,
+        Json(Error::new(Status::Conflict, description, error)),
+    )
+}
+
+/// Maps idempotency errors which can happen on any route accepting an idempotency key.
+pub fn idempotency_error<E: Serialize>(
+    e: app::idempotency::Error,
+    key_reused: E,
+    in_progress: E,
+) -> JsonError<E> {
+    match e {
+        app::idempotency::Error::KeyReused => bad_request(
+            key_reused,
+            "idempotency key has already been used for a different request".to_owned(),
+        ),
+        app::idempotency::Error::InProgress => conflict(
+            in_progress,
+            "a request with the same idempotency key is still in progress".to_owned(),
+        ),
+        // Invalid keys are rejected by the request guard
+        app::idempotency::Error::InvalidKey(_) => unreachable!(),
+    }
+}
@@ -0,0 +1,68 @@
+use okapi::openapi3::{Object, Parameter, ParameterValue};
+use rocket::{
+    async_trait,
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The optional idempotency key sent by the client.
+pub struct IdempotencyKey(Option<app::idempotency::Key>);
+
+impl IdempotencyKey {
+    pub fn key(&self) -> Option<&app::idempotency::Key> {
+        self.0.as_ref()
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = app::idempotency::Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one(IDEMPOTENCY_KEY_HEADER) {
+            Some(key) => match app::idempotency::Key::new(key.to_owned()) {
+                Ok(key) => Outcome::Success(Self(Some(key))),
+                Err(e) => Outcome::Failure((Status::BadRequest, e)),
+            },
+            None => Outcome::Success(Self(None)),
+        }
+    }
+}
+
+impl<'a> OpenApiFromRequest<'a> for IdempotencyKey {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _: String,
+        _: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: IDEMPOTENCY_KEY_HEADER.to_owned(),
+            location: "header".to_owned(),
+            description: Some(
+                "Unique key which makes it safe to retry the request. Retrying with the same key \
+                returns the result of the original request instead of repeating it. Keys can be \
+                up to 255 characters long."
+                    .to_owned(),
+            ),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: gen.json_schema::<String>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Object::default(),
+        }))
+    }
+}
@@ -3,9 +3,11 @@
 use app::{database::Database, ln::Lightning};
 use rocket::{Build, Rocket};
 use state::RocketState;
+use std::time::Duration;
 
 mod access;
 mod error;
+mod idempotency;
 mod rate_limit;
 mod routes;
 mod state;
@@ -19,6 +21,8 @@ pub fn register(
     lightning: Lightning,
     cash_limits: CashLimits,
     rate_limit: RateLimit,
+    allowlist_cooling_off: Duration,
+    max_sats_per_vbyte: u64,
 ) -> Rocket<Build> {
     routes::register(
         rocket,
@@ -27,6 +31,8 @@ pub fn register(
             lightning,
             cash_limits,
             rate_limit,
+            allowlist_cooling_off,
+            max_sats_per_vbyte,
         },
     )
 }
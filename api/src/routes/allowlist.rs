@@ -0,0 +1,287 @@
+use crate::{
+    access,
+    error::{self, JsonError, JsonResult},
+    state::RocketState,
+};
+use app::{allowlist, btc, user};
+use chrono::{DateTime, Utc};
+use rocket::{delete, get, post, serde::json::Json, State};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct AllowAddressRequest {
+    /// The BTC address to allow withdrawals to.
+    address: String,
+    /// A short description of the address, e.g. "cold storage".
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct PasswordRequest {
+    /// The password of your account.
+    password: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct AllowlistModel {
+    /// True if withdrawals are limited to the usable allowed addresses.
+    is_enforced: bool,
+    /// Time the allowlist was turned on.
+    enabled_at: Option<DateTime<Utc>>,
+    /// Time the allowlist stops being enforced, if it was turned off.
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+impl AllowlistModel {
+    fn from_entity(allowlist: &allowlist::Allowlist) -> Self {
+        Self {
+            is_enforced: allowlist.is_enforced(),
+            enabled_at: allowlist.enabled,
+            disabled_at: allowlist.disabled,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct AllowedAddressModel {
+    /// Unique identifier of the allowed address.
+    id: Uuid,
+    /// The BTC address withdrawals are allowed to.
+    address: String,
+    /// A short description of the address.
+    label: Option<String>,
+    /// Time the address was added.
+    created_at: DateTime<Utc>,
+    /// End of the cooling-off period. The address can't be used before this time.
+    usable_after: DateTime<Utc>,
+    /// True if withdrawals to the address are allowed right now, i.e. the address was confirmed,
+    /// the cooling-off period is over, and the address wasn't removed.
+    is_usable: bool,
+    /// Time the address was confirmed with your password.
+    confirmed_at: Option<DateTime<Utc>>,
+    /// Time the address was removed from the allowlist.
+    removed_at: Option<DateTime<Utc>>,
+}
+
+impl AllowedAddressModel {
+    fn from_entity(allowed: &allowlist::AllowedAddress) -> Self {
+        Self {
+            id: allowed.id.0,
+            address: allowed.address.to_string(),
+            label: allowed.label.clone(),
+            created_at: allowed.created,
+            usable_after: allowed.usable_after,
+            is_usable: allowed.is_usable(),
+            confirmed_at: allowed.confirmed,
+            removed_at: allowed.removed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct AllowlistResponse {
+    allowlist: AllowlistModel,
+    addresses: Vec<AllowedAddressModel>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct AllowlistStatusResponse {
+    allowlist: AllowlistModel,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct AllowedAddressResponse {
+    address: AllowedAddressModel,
+}
+
+/// Error during allowlist management.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(super) enum Error {
+    /// The allowed address does not exist.
+    NotFound,
+    /// The address is not a valid BTC address.
+    InvalidAddress,
+    /// The address is already on the allowlist.
+    AlreadyAllowed,
+    /// You have reached the maximum number of allowed addresses.
+    TooManyAddresses,
+    /// The address was removed from the allowlist, add it again instead.
+    Removed,
+    /// The password is wrong, or your account doesn't have one. Accounts created by an operator
+    /// don't have a password, ask the operator instead.
+    InvalidCredentials,
+}
+
+/// Show whether withdrawals are limited to allowed addresses, and list the allowed addresses,
+/// including removed ones, most recent first.
+#[openapi(tag = "Withdrawal allowlist")]
+#[get("/withdrawals/allowlist")]
+pub(super) async fn get(
+    state: &State<RocketState>,
+    guard: access::ReadGuard,
+) -> Json<AllowlistResponse> {
+    let allowlist = allowlist::get(guard.grant(), &state.db).await;
+    let addresses = allowlist::list(guard.grant(), &state.db).await;
+    Json(AllowlistResponse {
+        allowlist: AllowlistModel::from_entity(&allowlist),
+        addresses: addresses
+            .iter()
+            .map(AllowedAddressModel::from_entity)
+            .collect(),
+    })
+}
+
+/// Limit withdrawals to the usable allowed addresses, starting right away. Withdrawals to any
+/// other address are rejected with `ADDRESS_NOT_ALLOWED`, so that a leaked token can't be used to
+/// withdraw your balance.
+#[openapi(tag = "Withdrawal allowlist")]
+#[post("/withdrawals/allowlist/enable")]
+pub(super) async fn enable(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+) -> Json<AllowlistStatusResponse> {
+    let allowlist = allowlist::enable(guard.grant(), &state.db).await;
+    Json(AllowlistStatusResponse {
+        allowlist: AllowlistModel::from_entity(&allowlist),
+    })
+}
+
+/// Stop limiting withdrawals to the allowed addresses. This takes your password, and withdrawals
+/// are still limited to the allowed addresses until the cooling-off period is over.
+#[openapi(tag = "Withdrawal allowlist")]
+#[post("/withdrawals/allowlist/disable", data = "<req>")]
+pub(super) async fn disable(
+    state: &State<RocketState>,
+    req: Json<PasswordRequest>,
+    guard: access::AdminGuard,
+) -> JsonResult<AllowlistStatusResponse, Error> {
+    let req = req.into_inner();
+    allowlist::disable(
+        guard.grant(),
+        &state.db,
+        user::Password(req.password),
+        state.allowlist_cooling_off,
+    )
+    .await
+    .map(|allowlist| {
+        Json(AllowlistStatusResponse {
+            allowlist: AllowlistModel::from_entity(&allowlist),
+        })
+    })
+    .map_err(map_error)
+}
+
+/// Add an address to the allowlist. The address can be used once the cooling-off period is over
+/// and you confirmed it with your password, see `POST /withdrawals/allowlist/addresses/{id}/confirm`.
+#[openapi(tag = "Withdrawal allowlist")]
+#[post("/withdrawals/allowlist/addresses", data = "<req>")]
+pub(super) async fn post_address(
+    state: &State<RocketState>,
+    req: Json<AllowAddressRequest>,
+    guard: access::AdminGuard,
+) -> JsonResult<AllowedAddressResponse, Error> {
+    let req = req.into_inner();
+    let address = btc::Address::from_str(&req.address).map_err(|_| {
+        error::bad_request(
+            Error::InvalidAddress,
+            format!("{} is not a BTC address", req.address),
+        )
+    })?;
+    allowlist::add(
+        guard.grant(),
+        &state.db,
+        &address,
+        req.label,
+        state.allowlist_cooling_off,
+    )
+    .await
+    .map(|allowed| {
+        Json(AllowedAddressResponse {
+            address: AllowedAddressModel::from_entity(&allowed),
+        })
+    })
+    .map_err(map_error)
+}
+
+/// Confirm an allowed address with your password. A token isn't enough, so that a leaked token
+/// can't be used to allow an address. Confirming an already confirmed address has no effect.
+#[openapi(tag = "Withdrawal allowlist")]
+#[post(
+    "/withdrawals/allowlist/addresses/<address_id>/confirm",
+    data = "<req>"
+)]
+pub(super) async fn confirm_address(
+    state: &State<RocketState>,
+    req: Json<PasswordRequest>,
+    guard: access::AdminGuard,
+    address_id: String,
+) -> JsonResult<AllowedAddressResponse, Error> {
+    let address_id = parse_id(&address_id)?;
+    let req = req.into_inner();
+    allowlist::confirm(
+        guard.grant(),
+        &state.db,
+        address_id,
+        user::Password(req.password),
+    )
+    .await
+    .map(|allowed| {
+        Json(AllowedAddressResponse {
+            address: AllowedAddressModel::from_entity(&allowed),
+        })
+    })
+    .map_err(map_error)
+}
+
+/// Remove an address from the allowlist. Unlike adding an address, this takes effect right away.
+/// Removing an already removed address has no effect.
+#[openapi(tag = "Withdrawal allowlist")]
+#[delete("/withdrawals/allowlist/addresses/<address_id>")]
+pub(super) async fn delete_address(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+    address_id: String,
+) -> JsonResult<AllowedAddressResponse, Error> {
+    let address_id = parse_id(&address_id)?;
+    allowlist::remove(guard.grant(), &state.db, address_id)
+        .await
+        .map(|allowed| {
+            Json(AllowedAddressResponse {
+                address: AllowedAddressModel::from_entity(&allowed),
+            })
+        })
+        .map_err(map_error)
+}
+
+fn parse_id(address_id: &str) -> Result<allowlist::Id, JsonError<Error>> {
+    allowlist::Id::from_str(address_id)
+        .map_err(|_| error::not_found(Error::NotFound, "allowed address not found".to_owned()))
+}
+
+fn map_error(e: allowlist::Error) -> JsonError<Error> {
+    match e {
+        allowlist::Error::NotFound => {
+            error::not_found(Error::NotFound, "allowed address not found".to_owned())
+        }
+        allowlist::Error::AlreadyAllowed => error::bad_request(
+            Error::AlreadyAllowed,
+            "address is already allowed".to_owned(),
+        ),
+        allowlist::Error::TooManyAddresses => error::bad_request(
+            Error::TooManyAddresses,
+            "too many allowed addresses, remove one first".to_owned(),
+        ),
+        allowlist::Error::Removed => error::bad_request(
+            Error::Removed,
+            "address was removed from the allowlist".to_owned(),
+        ),
+        allowlist::Error::User(_) => {
+            error::bad_request(Error::InvalidCredentials, "invalid password".to_owned())
+        }
+    }
+}
@@ -1,6 +1,6 @@
-use super::{Range, RangeError};
+use super::{next_cursor, parse_amount_range, parse_flag, parse_period, ListError, Range};
 use crate::{access, error::JsonResult, state::RocketState};
-use app::btc;
+use app::{btc, cash_limits, deposit, Cursor};
 use chrono::{DateTime, Utc};
 use rocket::{get, post, serde::json::Json, State};
 use rocket_okapi::openapi;
@@ -25,6 +25,8 @@ pub(super) struct AddressResponse {
 #[derive(Debug, Serialize, JsonSchema)]
 pub(super) struct AddressesResponse {
     deposit_addresses: Vec<AddressModel>,
+    /// Pass it as `cursor` to get the next page. Empty on the last page.
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -37,14 +39,68 @@ pub(super) struct DepositModel {
     txid: String,
     /// Amount of satoshis deposited.
     amount_sats: i64,
-    /// True if the related BTC transaction was confirmed.
+    /// Our service fee in millisatoshis, taken out of the deposited amount. Set once the deposit
+    /// is confirmed.
+    service_fee_msats: Option<i64>,
+    /// True if the BTC transaction has the required confirmations and the amount was added to your
+    /// balance.
     is_confirmed: bool,
+    /// Deposit status.
+    status: DepositStatus,
+    /// The deposit whose BTC transaction replaced the transaction of this one, if it was
+    /// replaced.
+    replaced_by: Option<Uuid>,
+    /// Why the deposit is held, if it is.
+    held_reason: Option<HeldReason>,
+    /// Number of blocks confirming the BTC transaction so far, zero while it's unconfirmed.
+    confirmations: u32,
+    /// Number of confirmations needed before the amount is added to your balance. Larger
+    /// deposits may need more confirmations.
+    required_confirmations: u32,
     /// Deposit creation time.
     created_at: DateTime<Utc>,
     /// Deposit confirmation time, if the deposit was confirmed.
     confirmed_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum DepositStatus {
+    /// The BTC transaction doesn't have the required confirmations yet.
+    Pending,
+    /// The amount was added to your balance.
+    Confirmed,
+    /// The sender replaced the BTC transaction with another one, e.g. to bump the fee. This
+    /// deposit won't be confirmed, see `replaced_by` for the deposit of the new transaction.
+    Replaced,
+    /// The BTC transaction has the required confirmations, but the amount is outside the deposit
+    /// limits, so it wasn't added to your balance. See `held_reason`.
+    Held,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum HeldReason {
+    /// The amount is below the minimum deposit, e.g. dust which can't be spent economically.
+    /// Contact support to have it added to your balance.
+    AmountTooLow,
+    /// The amount is above the maximum deposit. Contact support to have it added to your balance.
+    AmountTooHigh,
+    /// Your deposits of the last 24 hours exceed the daily limit. The amount is added to your
+    /// balance once the limit allows it.
+    DailyLimitExceeded,
+}
+
+impl HeldReason {
+    fn from_entity(reason: cash_limits::Error) -> Self {
+        match reason {
+            cash_limits::Error::AmountTooLow => HeldReason::AmountTooLow,
+            cash_limits::Error::AmountTooHigh => HeldReason::AmountTooHigh,
+            cash_limits::Error::DailyLimitExceeded => HeldReason::DailyLimitExceeded,
+        }
+    }
+}
+
 impl DepositModel {
     fn from_entity(deposit: &app::deposit::Deposit) -> Self {
         Self {
@@ -52,7 +108,21 @@ impl DepositModel {
             address: deposit.tx_out.address.to_string(),
             txid: deposit.tx_out.tx.id.to_string(),
             amount_sats: deposit.tx_out.amount.0,
+            service_fee_msats: deposit.service_fee.map(|fee| fee.0),
             is_confirmed: deposit.is_confirmed(),
+            status: if deposit.is_confirmed() {
+                DepositStatus::Confirmed
+            } else if deposit.is_replaced() {
+                DepositStatus::Replaced
+            } else if deposit.is_held() {
+                DepositStatus::Held
+            } else {
+                DepositStatus::Pending
+            },
+            replaced_by: deposit.replaced_by.map(|id| id.0),
+            held_reason: deposit.held.map(HeldReason::from_entity),
+            confirmations: deposit.confirmations,
+            required_confirmations: deposit.required_confirmations,
             created_at: deposit.created,
             confirmed_at: deposit.confirmed,
         }
@@ -67,6 +137,8 @@ pub(super) struct DepositResponse {
 #[derive(Debug, Serialize, JsonSchema)]
 pub(super) struct DepositsResponse {
     deposits: Vec<DepositModel>,
+    /// Pass it as `cursor` to get the next page. Empty on the last page.
+    next_cursor: Option<String>,
 }
 
 /// Create a new deposit address. You can use your BTC wallet to pay to this address and
@@ -91,17 +163,27 @@ pub(super) async fn post_address(
     })
 }
 
-/// List deposit addresses.
+/// List deposit addresses, most recent first. Filter them by creation time with `from`
+/// (inclusive) and `to` (exclusive), both RFC 3339 times. Get the next page with the
+/// `next_cursor` of the response.
 #[openapi(tag = "Deposit Addresses")]
-#[get("/deposits/addresses?<range..>")]
+#[get("/deposits/addresses?<from>&<to>&<cursor>&<range..>")]
 pub(super) async fn list_addresses(
     state: &State<RocketState>,
     guard: access::ReadGuard,
     range: Range,
-) -> JsonResult<AddressesResponse, RangeError> {
-    let addresses =
-        app::deposit::get_addresses(guard.grant(), &state.db, range.query_range()?).await;
+    cursor: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+) -> JsonResult<AddressesResponse, ListError> {
+    let page = range.page(cursor, |address| btc::Address::from_str(address).ok())?;
+    let period = parse_period(from, to)?;
+    let addresses = app::deposit::get_addresses(guard.grant(), &state.db, period, &page).await;
     Ok(Json(AddressesResponse {
+        next_cursor: next_cursor(&page, &addresses, |address| Cursor {
+            created: address.created,
+            id: address.address.clone(),
+        }),
         deposit_addresses: addresses
             .into_iter()
             .map(|address| AddressModel {
@@ -136,20 +218,40 @@ pub(super) async fn get_address(
     }
 }
 
-/// List deposits.
+/// List deposits, most recent first. Filter them with `is_confirmed`, by detection time with
+/// `from` (inclusive) and `to` (exclusive), both RFC 3339 times, and by amount with
+/// `min_amount_sats` and `max_amount_sats`. Get the next page with the `next_cursor` of the
+/// response.
 #[openapi(tag = "Deposits")]
-#[get("/deposits?<range..>")]
+#[get(
+    "/deposits?<is_confirmed>&<from>&<to>&<min_amount_sats>&<max_amount_sats>&<cursor>&<range..>"
+)]
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn list_deposits(
     state: &State<RocketState>,
     guard: access::ReadGuard,
     range: Range,
-) -> JsonResult<DepositsResponse, RangeError> {
-    let deposits = app::deposit::list(guard.grant(), &state.db, range.query_range()?)
-        .await
-        .iter()
-        .map(DepositModel::from_entity)
-        .collect();
-    Ok(Json(DepositsResponse { deposits }))
+    cursor: Option<String>,
+    is_confirmed: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    min_amount_sats: Option<String>,
+    max_amount_sats: Option<String>,
+) -> JsonResult<DepositsResponse, ListError> {
+    let page = range.page(cursor, |id| Uuid::from_str(id).ok().map(deposit::Id))?;
+    let filter = deposit::Filter {
+        confirmed: parse_flag("is_confirmed", is_confirmed)?,
+        period: parse_period(from, to)?,
+        amount: parse_amount_range(min_amount_sats, max_amount_sats, btc::Sats)?,
+    };
+    let deposits = app::deposit::list(guard.grant(), &state.db, &filter, &page).await;
+    Ok(Json(DepositsResponse {
+        next_cursor: next_cursor(&page, &deposits, |deposit| Cursor {
+            created: deposit.created,
+            id: deposit.id.0,
+        }),
+        deposits: deposits.iter().map(DepositModel::from_entity).collect(),
+    }))
 }
 
 /// Get deposit details.
@@ -0,0 +1,143 @@
+use crate::{access, state::RocketState};
+use app::database::Database;
+use okapi::openapi3::{Object, Parameter, ParameterValue};
+use rocket::{
+    async_trait,
+    futures::stream::{self, BoxStream, StreamExt},
+    get,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    tokio::{select, time::sleep},
+    Request, Shutdown, State,
+};
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    openapi,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use std::{collections::VecDeque, time::Duration};
+
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+/// How often the database is checked for new events.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Keeps proxies from closing the connection when there are no events for a while.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const BATCH_SIZE: i64 = 100;
+
+/// The sequence number of the last event seen by the client. Browsers send it automatically when
+/// they reconnect to the stream.
+pub(super) struct LastEventId(Option<i64>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one(LAST_EVENT_ID_HEADER) {
+            Some(id) => match id.parse::<i64>() {
+                Ok(id) if id >= 0 => Outcome::Success(Self(Some(id))),
+                _ => Outcome::Failure((Status::BadRequest, ())),
+            },
+            None => Outcome::Success(Self(None)),
+        }
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for LastEventId {
+    fn from_request_input(
+        gen: &mut OpenApiGenerator,
+        _: String,
+        _: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name: LAST_EVENT_ID_HEADER.to_owned(),
+            location: "header".to_owned(),
+            description: Some(
+                "ID of the last event you received. The stream resumes with the events after it."
+                    .to_owned(),
+            ),
+            required: false,
+            deprecated: false,
+            allow_empty_value: false,
+            value: ParameterValue::Schema {
+                style: None,
+                explode: None,
+                allow_reserved: false,
+                schema: gen.json_schema::<i64>(),
+                example: None,
+                examples: None,
+            },
+            extensions: Object::default(),
+        }))
+    }
+}
+
+/// Stream account events as Server-Sent Events: settled invoices, payment outcomes, detected,
+/// confirmed, reversed and replaced deposits, and sent and confirmed withdrawals. The event name
+/// is the event type, e.g. `invoice.settled`, and the data is the same JSON body that is sent to
+/// webhooks.
+///
+/// Each event ID is a sequence number. When reconnecting, send the ID of the last event you
+/// received in the `Last-Event-ID` header to get the events you missed. Without the header, only
+/// new events are streamed.
+#[openapi(tag = "Events")]
+#[get("/events")]
+pub(super) async fn get(
+    state: &State<RocketState>,
+    guard: access::ReadGuard,
+    last_event_id: LastEventId,
+    shutdown: Shutdown,
+) -> EventStream<BoxStream<'static, Event>> {
+    let db = state.db.clone();
+    let last_sequence = match last_event_id.0 {
+        Some(sequence) => sequence,
+        None => app::event::last_sequence(guard.grant(), &db).await,
+    };
+    let stream_state = StreamState {
+        db,
+        guard,
+        last_sequence,
+        pending: VecDeque::new(),
+        shutdown,
+    };
+    EventStream::from(stream::unfold(stream_state, next_event).boxed())
+        .heartbeat(HEARTBEAT_INTERVAL)
+}
+
+struct StreamState {
+    db: Database,
+    guard: access::ReadGuard,
+    last_sequence: i64,
+    /// Events fetched from the database but not sent yet.
+    pending: VecDeque<app::event::Event>,
+    shutdown: Shutdown,
+}
+
+/// Waits for the next event, polling the database until there is one. Ends the stream when the
+/// server shuts down.
+async fn next_event(mut state: StreamState) -> Option<(Event, StreamState)> {
+    loop {
+        if let Some(event) = state.pending.pop_front() {
+            state.last_sequence = event.sequence;
+            let sse = Event::data(event.payload)
+                .event(event.kind.as_str())
+                .id(event.sequence.to_string());
+            return Some((sse, state));
+        }
+        let events = app::event::list_after(
+            state.guard.grant(),
+            &state.db,
+            state.last_sequence,
+            BATCH_SIZE,
+        )
+        .await;
+        if events.is_empty() {
+            select! {
+                _ = sleep(POLL_INTERVAL) => {},
+                _ = &mut state.shutdown => return None,
+            }
+        }
+        state.pending.extend(events);
+    }
+}
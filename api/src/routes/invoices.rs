@@ -1,10 +1,11 @@
-use super::{Range, RangeError};
+use super::{next_cursor, parse_amount_range, parse_flag, parse_period, ListError, Range};
 use crate::{
     access,
     error::{self, JsonResult},
+    idempotency::IdempotencyKey,
     state::RocketState,
 };
-use app::{btc, cash_limits, invoice, seconds::Seconds};
+use app::{btc, cash_limits, invoice, seconds::Seconds, Cursor};
 use chrono::{DateTime, Utc};
 use rocket::{get, post, serde::json::Json, State};
 use rocket_okapi::openapi;
@@ -31,6 +32,8 @@ pub(super) struct InvoiceResponse {
 #[derive(Debug, Serialize, JsonSchema)]
 pub(super) struct InvoicesResponse {
     invoices: Vec<InvoiceModel>,
+    /// Pass it as `cursor` to get the next page. Empty on the last page.
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -47,6 +50,8 @@ struct InvoiceModel {
     settled_at: Option<DateTime<Utc>>,
     /// The amount that was paid. Should match amount_msats.
     amount_paid_msats: Option<i64>,
+    /// Our service fee in millisatoshis, taken out of the paid amount, if the invoice was paid.
+    service_fee_msats: Option<i64>,
     /// Invoice expiry time.
     expires_at: DateTime<Utc>,
     /// True if the invoice has been paid.
@@ -70,6 +75,10 @@ pub(super) enum Error {
     InvalidExpiry,
     /// Memo was too long or contained invalid characters.
     InvalidMemo,
+    /// The idempotency key has already been used for a different request.
+    IdempotencyKeyReused,
+    /// A request with the same idempotency key is still being processed.
+    RequestInProgress,
 }
 
 impl InvoiceModel {
@@ -87,6 +96,10 @@ impl InvoiceModel {
                 .settlement
                 .as_ref()
                 .map(|settlement| settlement.amount.0),
+            service_fee_msats: invoice
+                .settlement
+                .as_ref()
+                .map(|settlement| settlement.service_fee.0),
             expires_at: invoice.expiration,
             is_settled: invoice.is_settled(),
             is_expired: invoice.is_expired(),
@@ -95,13 +108,15 @@ impl InvoiceModel {
 }
 
 /// Create a new invoice. When this invoice is paid on the Lightning Network, the invoice amount
-/// will be added to your balance.
+/// will be added to your balance. Retrying with the same `Idempotency-Key` returns the original
+/// invoice.
 #[openapi(tag = "Invoices")]
 #[post("/invoices", data = "<req>")]
 pub(super) async fn post(
     state: &State<RocketState>,
     req: Json<InvoiceRequest>,
     guard: access::ReceiveGuard,
+    idempotency_key: IdempotencyKey,
 ) -> JsonResult<InvoiceResponse, Error> {
     let amount = btc::MilliSats(req.amount_msats.try_into().unwrap());
     let memo = req.memo.clone();
@@ -114,6 +129,7 @@ pub(super) async fn post(
         memo,
         expiry.unwrap_or_else(Seconds::one_hour),
         &state.cash_limits.invoice_limits,
+        idempotency_key.key(),
     )
     .await
     .map(|invoice| {
@@ -144,23 +160,47 @@ pub(super) async fn post(
         invoice::Error::InvalidMemo(message) => {
             error::bad_request(Error::InvalidMemo, message.to_owned())
         }
+        invoice::Error::Idempotency(e) => {
+            error::idempotency_error(e, Error::IdempotencyKeyReused, Error::RequestInProgress)
+        }
     })
 }
 
-/// List invoices.
+/// List invoices, most recent first. Filter them with `is_settled` and `is_expired`, by creation
+/// time with `from` (inclusive) and `to` (exclusive), both RFC 3339 times, and by amount with
+/// `min_amount_msats` and `max_amount_msats`. Get the next page with the `next_cursor` of the
+/// response.
 #[openapi(tag = "Invoices")]
-#[get("/invoices?<range..>")]
+#[get(
+    "/invoices?<is_settled>&<is_expired>&<from>&<to>&<min_amount_msats>&<max_amount_msats>&<cursor>&<range..>"
+)]
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn list(
     state: &State<RocketState>,
     guard: access::ReadGuard,
     range: Range,
-) -> JsonResult<InvoicesResponse, RangeError> {
+    cursor: Option<String>,
+    is_settled: Option<String>,
+    is_expired: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    min_amount_msats: Option<String>,
+    max_amount_msats: Option<String>,
+) -> JsonResult<InvoicesResponse, ListError> {
+    let page = range.page(cursor, |id| Uuid::from_str(id).ok().map(invoice::Id))?;
+    let filter = invoice::Filter {
+        settled: parse_flag("is_settled", is_settled)?,
+        expired: parse_flag("is_expired", is_expired)?,
+        period: parse_period(from, to)?,
+        amount: parse_amount_range(min_amount_msats, max_amount_msats, btc::MilliSats)?,
+    };
+    let invoices = app::invoice::list(guard.grant(), &state.db, &filter, &page).await;
     Ok(Json(InvoicesResponse {
-        invoices: app::invoice::list(guard.grant(), &state.db, range.query_range()?)
-            .await
-            .iter()
-            .map(InvoiceModel::from_entity)
-            .collect(),
+        next_cursor: next_cursor(&page, &invoices, |invoice| Cursor {
+            created: invoice.created,
+            id: invoice.id.0,
+        }),
+        invoices: invoices.iter().map(InvoiceModel::from_entity).collect(),
     }))
 }
 
@@ -4,19 +4,28 @@ use crate::{
     error::{self, JsonError},
     state::RocketState,
 };
-use app::QueryRange;
-use rocket::{Build, FromForm, Rocket};
+use app::{AmountRange, Cursor, Page, Period, QueryRange};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rocket::{serde::json::Json, Build, FromForm, Rocket};
 use rocket_okapi::{
     openapi_get_routes,
     swagger_ui::{make_swagger_ui, DefaultModelRendering, SwaggerUIConfig},
 };
 use schemars::JsonSchema;
 use serde::Serialize;
+use std::fmt::Display;
 
+mod allowlist;
 mod deposits;
+mod events;
 mod invoices;
 mod payments;
+mod pricing;
+mod session;
+mod tokens;
+mod transactions;
 mod user;
+mod webhooks;
 mod withdrawals;
 
 const MIN_LIMIT: i64 = 1;
@@ -82,6 +91,151 @@ impl Range {
     }
 }
 
+/// Error while listing items with a cursor and filters.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+// The variants are the error codes clients see, named like the codes of `RangeError`
+#[allow(clippy::enum_variant_names)]
+pub enum ListError {
+    /// Invalid limit.
+    InvalidLimit,
+    /// Invalid offset, or an offset together with a cursor.
+    InvalidOffset,
+    /// The cursor wasn't returned by this endpoint.
+    InvalidCursor,
+    /// Invalid status filter.
+    InvalidStatus,
+    /// Invalid `from` or `to` time.
+    InvalidTime,
+    /// Invalid minimum or maximum amount.
+    InvalidAmount,
+}
+
+impl Range {
+    /// Selects the page starting after the cursor, which must have been returned as the
+    /// `next_cursor` of the previous page. Cursor ids are parsed with `parse_id`. The offset can't
+    /// be used together with a cursor, since the cursor already says where the page starts.
+    fn page<Id>(
+        self,
+        cursor: Option<String>,
+        parse_id: impl Fn(&str) -> Option<Id>,
+    ) -> Result<Page<Id>, JsonError<ListError>> {
+        if cursor.is_some() && self.offset.is_some() {
+            return Err(error::bad_request(
+                ListError::InvalidOffset,
+                "offset can't be used with a cursor".to_owned(),
+            ));
+        }
+        let range = self.query_range().map_err(|(_, Json(e))| {
+            let error = match e.error.status {
+                RangeError::InvalidLimit => ListError::InvalidLimit,
+                RangeError::InvalidOffset => ListError::InvalidOffset,
+            };
+            error::bad_request(error, e.error.description)
+        })?;
+        let after = cursor
+            .map(|cursor| {
+                decode_cursor(&cursor, parse_id).ok_or_else(|| {
+                    error::bad_request(ListError::InvalidCursor, "invalid cursor".to_owned())
+                })
+            })
+            .transpose()?;
+        Ok(Page { range, after })
+    }
+}
+
+/// Returns the cursor of the next page, or None if this was the last page.
+fn next_cursor<T, Id, CursorId: Display>(
+    page: &Page<Id>,
+    items: &[T],
+    cursor: impl Fn(&T) -> Cursor<CursorId>,
+) -> Option<String> {
+    match items.last() {
+        Some(last) if items.len() as i64 == page.range.limit => Some(encode_cursor(&cursor(last))),
+        _ => None,
+    }
+}
+
+/// Cursors are opaque to clients: the creation time and id of the item are hex encoded, so that
+/// clients don't depend on what's inside.
+fn encode_cursor<Id: Display>(cursor: &Cursor<Id>) -> String {
+    hex::encode(format!(
+        "{} {}",
+        cursor.created.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        cursor.id
+    ))
+}
+
+fn decode_cursor<Id>(cursor: &str, parse_id: impl Fn(&str) -> Option<Id>) -> Option<Cursor<Id>> {
+    let cursor = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let (created, id) = cursor.split_once(' ')?;
+    Some(Cursor {
+        created: DateTime::parse_from_rfc3339(created)
+            .ok()?
+            .with_timezone(&Utc),
+        id: parse_id(id)?,
+    })
+}
+
+/// Parses the `from` and `to` query parameters, both RFC 3339 times.
+fn parse_period(from: Option<String>, to: Option<String>) -> Result<Period, JsonError<ListError>> {
+    let parse_time = |time: Option<String>| {
+        time.map(|time| {
+            DateTime::parse_from_rfc3339(&time)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| {
+                    error::bad_request(
+                        ListError::InvalidTime,
+                        format!("{} is not an RFC 3339 time", time),
+                    )
+                })
+        })
+        .transpose()
+    };
+    Ok(Period {
+        from: parse_time(from)?,
+        to: parse_time(to)?,
+    })
+}
+
+/// Parses the minimum and maximum amount query parameters.
+fn parse_amount_range<T>(
+    min: Option<String>,
+    max: Option<String>,
+    amount: fn(i64) -> T,
+) -> Result<AmountRange<T>, JsonError<ListError>> {
+    let parse_amount = |value: Option<String>| {
+        value
+            .map(|value| {
+                value.parse().map(amount).map_err(|_| {
+                    error::bad_request(
+                        ListError::InvalidAmount,
+                        format!("{} is not an amount", value),
+                    )
+                })
+            })
+            .transpose()
+    };
+    Ok(AmountRange {
+        min: parse_amount(min)?,
+        max: parse_amount(max)?,
+    })
+}
+
+/// Parses a boolean status filter, e.g. `is_confirmed`.
+fn parse_flag(name: &str, value: Option<String>) -> Result<Option<bool>, JsonError<ListError>> {
+    value
+        .map(|value| match value.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(error::bad_request(
+                ListError::InvalidStatus,
+                format!("{} must be true or false", name),
+            )),
+        })
+        .transpose()
+}
+
 const VERSION: &str = "/v0";
 
 pub fn register(rocket: Rocket<Build>, state: RocketState) -> Rocket<Build> {
@@ -89,6 +243,8 @@ pub fn register(rocket: Rocket<Build>, state: RocketState) -> Rocket<Build> {
     let rocket = rocket.mount(
         VERSION,
         openapi_get_routes![
+            session::register,
+            session::login,
             user::get,
             deposits::post_address,
             deposits::list_addresses,
@@ -101,9 +257,32 @@ pub fn register(rocket: Rocket<Build>, state: RocketState) -> Rocket<Build> {
             payments::post,
             payments::list,
             payments::get,
+            pricing::get,
             withdrawals::post,
+            withdrawals::quote,
+            withdrawals::bump,
             withdrawals::list,
             withdrawals::get,
+            withdrawals::delete,
+            allowlist::get,
+            allowlist::enable,
+            allowlist::disable,
+            allowlist::post_address,
+            allowlist::confirm_address,
+            allowlist::delete_address,
+            tokens::post,
+            tokens::list,
+            tokens::get,
+            tokens::patch,
+            tokens::delete,
+            webhooks::post,
+            webhooks::list,
+            webhooks::get,
+            webhooks::delete,
+            webhooks::list_deliveries,
+            events::get,
+            transactions::list,
+            transactions::export,
         ],
     );
     mount_swagger(rocket)
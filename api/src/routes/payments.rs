@@ -1,10 +1,11 @@
-use super::{Range, RangeError};
+use super::{next_cursor, parse_amount_range, parse_period, ListError, Range};
 use crate::{
     access,
-    error::{self, JsonResult},
+    error::{self, JsonError, JsonResult},
+    idempotency::IdempotencyKey,
     state::RocketState,
 };
-use app::{btc, cash_limits, ln, payment};
+use app::{btc, cash_limits, ln, payment, Cursor};
 use chrono::{DateTime, Utc};
 use rocket::{get, post, serde::json::Json, State};
 use rocket_okapi::openapi;
@@ -19,6 +20,10 @@ pub(super) struct PaymentRequest {
     invoice: String,
     // TODO Remove this when we remove amountless invoices
     amount_msats: Option<u64>,
+    /// If true, the payment is queued and returned right away with the `NEW` status, instead of
+    /// waiting for the payment to complete. Poll the payment to find out the outcome.
+    #[serde(default, rename = "async")]
+    is_async: bool,
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -27,8 +32,11 @@ struct PaymentModel {
     id: Uuid,
     /// Amount paid in millisatoshis.
     amount_msats: i64,
-    /// Fee paid in millisatoshis.
+    /// Routing fee paid in millisatoshis.
     fee_msats: Option<i64>,
+    /// Our service fee in millisatoshis, paid on top of the amount and the routing fee. Set once
+    /// the funds are reserved.
+    service_fee_msats: Option<i64>,
     /// The payment invoice aka payment request.
     invoice: String,
     /// Payment creation time.
@@ -37,6 +45,9 @@ struct PaymentModel {
     status: PaymentStatus,
     /// Failure reason, in case the payment failed.
     failure_reason: Option<String>,
+    /// True if the invoice was created by another coupler.network user. Such payments are settled
+    /// instantly and without a routing fee.
+    is_internal: bool,
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -44,6 +55,8 @@ struct PaymentModel {
 enum PaymentStatus {
     /// Newly created payment, waiting to be sent.
     New,
+    /// The fee has been determined and the funds reserved, the payment is being sent.
+    Pending,
     /// The payment failed.
     Failed,
     /// The payment was sent successfully.
@@ -56,10 +69,12 @@ impl PaymentModel {
             id: payment.id.0,
             amount_msats: payment.amount.0,
             fee_msats: payment.fee.map(|fee| fee.0),
+            service_fee_msats: payment.service_fee.map(|fee| fee.0),
             invoice: payment.invoice.0.clone(),
             created_at: payment.created,
             status: match payment.status {
-                app::payment::Status::New | app::payment::Status::Ready => PaymentStatus::New,
+                app::payment::Status::New => PaymentStatus::New,
+                app::payment::Status::Ready => PaymentStatus::Pending,
                 app::payment::Status::Failed { .. } => PaymentStatus::Failed,
                 app::payment::Status::Succeeded { .. } => PaymentStatus::Succeeded,
             },
@@ -67,6 +82,7 @@ impl PaymentModel {
                 app::payment::Status::Failed { ref reason, .. } => Some(reason.to_owned()),
                 _ => None,
             },
+            is_internal: payment.internal,
         }
     }
 }
@@ -79,6 +95,8 @@ pub(super) struct PaymentResponse {
 #[derive(Debug, Serialize, JsonSchema)]
 pub(super) struct PaymentsResponse {
     payments: Vec<PaymentModel>,
+    /// Pass it as `cursor` to get the next page. Empty on the last page.
+    next_cursor: Option<String>,
 }
 
 /// Error during payment.
@@ -114,107 +132,174 @@ pub(super) enum Error {
     InsufficientLiquidity,
     /// Insufficient user balance to complete the payment.
     InsufficientBalance,
+    /// The idempotency key has already been used for a different request.
+    IdempotencyKeyReused,
+    /// A request with the same idempotency key is still being processed.
+    RequestInProgress,
 }
 
-/// Pay a Lightning invoice (aka payment request) with your coupler.network balance.
+/// Pay a Lightning invoice (aka payment request) with your coupler.network balance. By default,
+/// the request waits until the payment completes, which can take a while. Set `async` to return
+/// right away instead. Retrying with the same `Idempotency-Key` returns the original payment in its
+/// current state instead of paying again. Invoices created by other coupler.network users are paid
+/// instantly and without a routing fee. Our service fee is charged on top, see `GET /pricing`.
 #[openapi(tag = "Payments")]
 #[post("/payments", data = "<req>")]
 pub(super) async fn post(
     state: &State<RocketState>,
     req: Json<PaymentRequest>,
     guard: access::SpendGuard,
+    idempotency_key: IdempotencyKey,
 ) -> JsonResult<PaymentResponse, Error> {
-    app::payment::send(
-        guard.grant(),
-        &state.db,
-        state.lightning.create_node().await,
-        ln::RawInvoice(req.invoice.clone()),
-        req.amount_msats
-            .map(|amount| btc::MilliSats(amount.try_into().unwrap())),
-        &state.cash_limits.payment_limits,
-    )
-    .await
-    .map(|payment| {
-        Json(PaymentResponse {
-            payment: PaymentModel::from_entity(&payment),
+    let invoice = ln::RawInvoice(req.invoice.clone());
+    let amount = req
+        .amount_msats
+        .map(|amount| btc::MilliSats(amount.try_into().unwrap()));
+    let result = if req.is_async {
+        app::payment::queue(
+            guard.grant(),
+            &state.db,
+            invoice,
+            amount,
+            &state.cash_limits.payment_limits,
+            idempotency_key.key(),
+        )
+        .await
+    } else {
+        app::payment::send(
+            guard.grant(),
+            &state.db,
+            state.lightning.create_node().await,
+            invoice,
+            amount,
+            &state.cash_limits.payment_limits,
+            idempotency_key.key(),
+        )
+        .await
+    };
+    result
+        .map(|payment| {
+            Json(PaymentResponse {
+                payment: PaymentModel::from_entity(&payment),
+            })
         })
-    })
-    .map_err(|e| match e {
-        payment::Error::LimitsViolated(cash_limits::Error::AmountTooLow) => {
-            error::bad_request(Error::AmountTooLow, "payment amount too low".to_owned())
-        }
-        payment::Error::LimitsViolated(cash_limits::Error::AmountTooHigh) => {
-            error::bad_request(Error::AmountTooHigh, "payment amount too high".to_owned())
-        }
-        payment::Error::LimitsViolated(cash_limits::Error::DailyLimitExceeded) => {
-            error::bad_request(
-                Error::DailyLimitExceeded,
-                "daily payment total exceeded".to_owned(),
-            )
-        }
-        payment::Error::InvalidInvoice(inner) => error::bad_request(Error::InvalidInvoice, inner.0),
-        payment::Error::AmountSpecifiedTwice => error::bad_request(
-            Error::AmountSpecifiedTwice,
-            "payment amount already specified in invoice".to_owned(),
-        ),
-        payment::Error::AmountNotSpecified => {
-            error::bad_request(Error::AmountNotSpecified, "amount not specified".to_owned())
-        }
-        // TODO Log this
-        payment::Error::ConcurrencyConflict(_) => error::concurrency_error(Error::Unknown),
-        payment::Error::InsufficientBalance(_) => error::bad_request(
-            Error::InsufficientBalance,
-            "insufficient balance".to_owned(),
-        ),
-        payment::Error::PaymentError(inner) => match inner {
-            ln::PaymentError::Unknown => error::bad_request(
-                Error::Unknown,
-                "payment failed for unknown reason".to_owned(),
-            ),
-            ln::PaymentError::InvoiceExpired => {
-                error::bad_request(Error::InvoiceExpired, "invoice has expired".to_owned())
+        .map_err(|e| match e {
+            payment::Error::LimitsViolated(cash_limits::Error::AmountTooLow) => {
+                error::bad_request(Error::AmountTooLow, "payment amount too low".to_owned())
             }
-            ln::PaymentError::InvoiceAlreadyPaid => error::bad_request(
-                Error::InvoiceAlreadyPaid,
-                "invoice has already been paid".to_owned(),
-            ),
-            ln::PaymentError::TimedOut => {
-                error::bad_request(Error::TimedOut, "payment has failed out".to_owned())
+            payment::Error::LimitsViolated(cash_limits::Error::AmountTooHigh) => {
+                error::bad_request(Error::AmountTooHigh, "payment amount too high".to_owned())
+            }
+            payment::Error::LimitsViolated(cash_limits::Error::DailyLimitExceeded) => {
+                error::bad_request(
+                    Error::DailyLimitExceeded,
+                    "daily payment total exceeded".to_owned(),
+                )
             }
-            ln::PaymentError::NoRouteFound => {
-                error::bad_request(Error::NoRoute, "failed to route the payment".to_owned())
+            payment::Error::InvalidInvoice(inner) => {
+                error::bad_request(Error::InvalidInvoice, inner.0)
             }
-            ln::PaymentError::InvalidPaymentDetails(_) => error::bad_request(
-                Error::InvalidPaymentDetails,
-                "invalid payment details".to_owned(),
+            payment::Error::AmountSpecifiedTwice => error::bad_request(
+                Error::AmountSpecifiedTwice,
+                "payment amount already specified in invoice".to_owned(),
             ),
+            payment::Error::AmountNotSpecified => {
+                error::bad_request(Error::AmountNotSpecified, "amount not specified".to_owned())
+            }
             // TODO Log this
-            ln::PaymentError::InsufficientLiquidity => error::bad_request(
-                Error::InsufficientLiquidity,
-                "the liquidity on our Lightning nodes is running out, please notify support"
-                    .to_owned(),
+            payment::Error::ConcurrencyConflict(_) | payment::Error::NotQueued => {
+                error::concurrency_error(Error::Unknown)
+            }
+            payment::Error::InsufficientBalance(_) => error::bad_request(
+                Error::InsufficientBalance,
+                "insufficient balance".to_owned(),
             ),
-        },
-    })
+            payment::Error::Idempotency(e) => {
+                error::idempotency_error(e, Error::IdempotencyKeyReused, Error::RequestInProgress)
+            }
+            payment::Error::PaymentError(inner) => match inner {
+                ln::PaymentError::Unknown => error::bad_request(
+                    Error::Unknown,
+                    "payment failed for unknown reason".to_owned(),
+                ),
+                ln::PaymentError::InvoiceExpired => {
+                    error::bad_request(Error::InvoiceExpired, "invoice has expired".to_owned())
+                }
+                ln::PaymentError::InvoiceAlreadyPaid => error::bad_request(
+                    Error::InvoiceAlreadyPaid,
+                    "invoice has already been paid".to_owned(),
+                ),
+                ln::PaymentError::TimedOut => {
+                    error::bad_request(Error::TimedOut, "payment has failed out".to_owned())
+                }
+                ln::PaymentError::NoRouteFound => {
+                    error::bad_request(Error::NoRoute, "failed to route the payment".to_owned())
+                }
+                ln::PaymentError::InvalidPaymentDetails => error::bad_request(
+                    Error::InvalidPaymentDetails,
+                    "invalid payment details".to_owned(),
+                ),
+                // TODO Log this
+                ln::PaymentError::InsufficientLiquidity => error::bad_request(
+                    Error::InsufficientLiquidity,
+                    "the liquidity on our Lightning nodes is running out, please notify support"
+                        .to_owned(),
+                ),
+            },
+        })
 }
 
-/// List all payments made from your account.
+/// List payments made from your account, most recent first. Filter them by `status`, by creation
+/// time with `from` (inclusive) and `to` (exclusive), both RFC 3339 times, and by amount with
+/// `min_amount_msats` and `max_amount_msats`. Get the next page with the `next_cursor` of the
+/// response.
 #[openapi(tag = "Payments")]
-#[get("/payments?<range..>")]
+#[get("/payments?<status>&<from>&<to>&<min_amount_msats>&<max_amount_msats>&<cursor>&<range..>")]
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn list(
     state: &State<RocketState>,
     guard: access::ReadGuard,
     range: Range,
-) -> JsonResult<PaymentsResponse, RangeError> {
+    cursor: Option<String>,
+    status: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    min_amount_msats: Option<String>,
+    max_amount_msats: Option<String>,
+) -> JsonResult<PaymentsResponse, ListError> {
+    let page = range.page(cursor, |id| Uuid::from_str(id).ok().map(payment::Id))?;
+    let filter = payment::Filter {
+        status: parse_status(status)?,
+        period: parse_period(from, to)?,
+        amount: parse_amount_range(min_amount_msats, max_amount_msats, btc::MilliSats)?,
+    };
+    let payments = app::payment::list(guard.grant(), &state.db, &filter, &page).await;
     Ok(Json(PaymentsResponse {
-        payments: app::payment::list(guard.grant(), &state.db, range.query_range()?)
-            .await
-            .iter()
-            .map(PaymentModel::from_entity)
-            .collect(),
+        next_cursor: next_cursor(&page, &payments, |payment| Cursor {
+            created: payment.created,
+            id: payment.id.0,
+        }),
+        payments: payments.iter().map(PaymentModel::from_entity).collect(),
     }))
 }
 
+fn parse_status(
+    status: Option<String>,
+) -> Result<Option<payment::StatusKind>, JsonError<ListError>> {
+    status
+        .map(|status| match status.as_str() {
+            "NEW" => Ok(payment::StatusKind::New),
+            "PENDING" => Ok(payment::StatusKind::Ready),
+            "FAILED" => Ok(payment::StatusKind::Failed),
+            "SUCCEEDED" => Ok(payment::StatusKind::Succeeded),
+            _ => Err(error::bad_request(
+                ListError::InvalidStatus,
+                "status must be NEW, PENDING, FAILED or SUCCEEDED".to_owned(),
+            )),
+        })
+        .transpose()
+}
+
 /// Get payment details.
 #[openapi(tag = "Payments")]
 #[get("/payments/<payment_id>")]
@@ -0,0 +1,75 @@
+//! Routes for querying the service fees.
+
+use crate::{access, state::RocketState};
+use app::pricing;
+use rocket::{get, serde::json::Json, State};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum OperationModel {
+    /// Paying an invoice. The fee is debited on top of the amount and the routing fee.
+    Payment,
+    /// Receiving the payment of an invoice. The fee is taken out of the paid amount.
+    Invoice,
+    /// Withdrawing funds on-chain. The fee is debited on top of the amount and the transaction
+    /// fee.
+    Withdrawal,
+    /// Depositing funds on-chain. The fee is taken out of the deposited amount.
+    Deposit,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct FeeScheduleModel {
+    operation: OperationModel,
+    /// Flat fee in millisatoshis.
+    flat_fee_msats: i64,
+    /// Proportional fee in parts per million of the amount, e.g. 1000 for 0.1%. It's rounded up to
+    /// the next millisatoshi.
+    rate_ppm: i64,
+}
+
+impl FeeScheduleModel {
+    fn from_entity(schedule: &pricing::FeeSchedule) -> Self {
+        Self {
+            operation: match schedule.operation {
+                pricing::Operation::Payment => OperationModel::Payment,
+                pricing::Operation::Invoice => OperationModel::Invoice,
+                pricing::Operation::Withdrawal => OperationModel::Withdrawal,
+                pricing::Operation::Deposit => OperationModel::Deposit,
+            },
+            flat_fee_msats: schedule.flat.0,
+            rate_ppm: schedule.rate_ppm,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct PricingResponse {
+    /// The plan you are on.
+    plan: String,
+    /// The service fee of each operation. The fee is the flat fee plus the proportional fee.
+    fee_schedules: Vec<FeeScheduleModel>,
+}
+
+/// Get the service fees you are charged for payments, invoices, withdrawals and deposits. The
+/// service fee is shown separately from network fees on each of them, and included in the fee of
+/// their transactions.
+#[openapi(tag = "Pricing")]
+#[get("/pricing")]
+pub(super) async fn get(
+    state: &State<RocketState>,
+    guard: access::ReadGuard,
+) -> Json<PricingResponse> {
+    let plan = pricing::get_plan(guard.grant(), &state.db).await;
+    Json(PricingResponse {
+        plan: plan.name,
+        fee_schedules: plan
+            .schedules
+            .iter()
+            .map(FeeScheduleModel::from_entity)
+            .collect(),
+    })
+}
@@ -0,0 +1,121 @@
+//! Routes for signing up and logging in with an email and a password.
+
+use crate::{
+    error::{self, JsonResult},
+    state::RocketState,
+};
+use app::{auth, user};
+use chrono::{DateTime, Utc};
+use rocket::{post, serde::json::Json, State};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct CredentialsRequest {
+    /// Email address. Emails are case-insensitive.
+    email: String,
+    /// Password, at least 8 characters long.
+    password: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct SessionModel {
+    /// Identifier of the session token. Revoke the token to log out.
+    token_id: Uuid,
+    /// The token to put in the X-Auth-Token header. It has all permissions, so use it to create
+    /// tokens with limited permissions for your applications.
+    token: String,
+    /// Session creation time.
+    created_at: DateTime<Utc>,
+    /// The token can't be used after this time, log in again to get a new one.
+    expires_at: DateTime<Utc>,
+}
+
+impl SessionModel {
+    fn from_entity(token: &auth::Token, secret: &auth::TokenSecret) -> Self {
+        Self {
+            token_id: token.id.0,
+            token: secret.as_str().to_owned(),
+            created_at: token.created,
+            expires_at: token.expires.unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct SessionResponse {
+    session: SessionModel,
+}
+
+/// Error during registration.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(super) enum RegisterError {
+    /// A user with this email already exists.
+    UserAlreadyExists,
+    /// The email address is not valid.
+    InvalidEmail,
+    /// The password is too short or too long.
+    InvalidPassword,
+}
+
+/// Error during login.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(super) enum LoginError {
+    /// Wrong email or password.
+    InvalidCredentials,
+}
+
+/// Sign up for a coupler.network account. You are logged in right away.
+#[openapi(tag = "Session")]
+#[post("/register", data = "<req>")]
+pub(super) async fn register(
+    state: &State<RocketState>,
+    req: Json<CredentialsRequest>,
+) -> JsonResult<SessionResponse, RegisterError> {
+    let req = req.into_inner();
+    let user = user::register(&state.db, &req.email, user::Password(req.password))
+        .await
+        .map_err(|e| match e {
+            user::Error::UserAlreadyExists => error::bad_request(
+                RegisterError::UserAlreadyExists,
+                "user already exists".to_owned(),
+            ),
+            user::Error::InvalidPassword(reason) => {
+                error::bad_request(RegisterError::InvalidPassword, reason.to_owned())
+            }
+            user::Error::InvalidEmail | user::Error::InvalidCredentials => error::bad_request(
+                RegisterError::InvalidEmail,
+                "invalid email address".to_owned(),
+            ),
+        })?;
+    let (token, secret) = auth::create_session(&state.db, &user).await;
+    Ok(Json(SessionResponse {
+        session: SessionModel::from_entity(&token, &secret),
+    }))
+}
+
+/// Log in with your email and password to get a session token.
+#[openapi(tag = "Session")]
+#[post("/login", data = "<req>")]
+pub(super) async fn login(
+    state: &State<RocketState>,
+    req: Json<CredentialsRequest>,
+) -> JsonResult<SessionResponse, LoginError> {
+    let req = req.into_inner();
+    let user = user::authenticate(&state.db, &req.email, user::Password(req.password))
+        .await
+        .map_err(|_| {
+            error::bad_request(
+                LoginError::InvalidCredentials,
+                "invalid email or password".to_owned(),
+            )
+        })?;
+    let (token, secret) = auth::create_session(&state.db, &user).await;
+    Ok(Json(SessionResponse {
+        session: SessionModel::from_entity(&token, &secret),
+    }))
+}
@@ -0,0 +1,227 @@
+use crate::{
+    access,
+    error::{self, JsonError, JsonResult},
+    state::RocketState,
+};
+use app::auth;
+use chrono::{DateTime, Utc};
+use rocket::{delete, get, patch, post, serde::json::Json, State};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct CreateTokenRequest {
+    /// Token name, unique among your tokens.
+    name: String,
+    /// Allow the token to spend funds.
+    #[serde(default)]
+    can_spend: bool,
+    /// Allow the token to receive funds.
+    #[serde(default)]
+    can_receive: bool,
+    /// Allow the token to read data.
+    #[serde(default)]
+    can_read: bool,
+    /// Allow the token to manage your tokens.
+    #[serde(default)]
+    can_admin: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct UpdateTokenRequest {
+    /// New token name, unique among your tokens.
+    name: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct TokenModel {
+    /// Unique token identifier.
+    id: Uuid,
+    /// Token name.
+    name: String,
+    /// True if the token can spend funds.
+    can_spend: bool,
+    /// True if the token can receive funds.
+    can_receive: bool,
+    /// True if the token can read data.
+    can_read: bool,
+    /// True if the token can manage tokens.
+    can_admin: bool,
+    /// Token creation time.
+    created_at: DateTime<Utc>,
+    /// True if the token was revoked.
+    is_disabled: bool,
+    /// Token revocation time, if the token was revoked.
+    disabled_at: Option<DateTime<Utc>>,
+    /// Expiration time of session tokens. Other tokens don't expire.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl TokenModel {
+    fn from_entity(token: &auth::Token) -> Self {
+        Self {
+            id: token.id.0,
+            name: token.name.clone(),
+            can_spend: token.permissions.can_spend,
+            can_receive: token.permissions.can_receive,
+            can_read: token.permissions.can_read,
+            can_admin: token.permissions.can_admin,
+            created_at: token.created,
+            is_disabled: !token.is_enabled(),
+            disabled_at: token.disabled,
+            expires_at: token.expires,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct CreatedTokenResponse {
+    token: TokenModel,
+    /// The token to put in the X-Auth-Token header. It is shown only once and can't be recovered
+    /// later.
+    secret: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct TokenResponse {
+    token: TokenModel,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct TokensResponse {
+    tokens: Vec<TokenModel>,
+}
+
+/// Error during token management.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(super) enum Error {
+    /// The token does not exist.
+    NotFound,
+    /// You already have a token with this name.
+    NameTaken,
+    /// The token name is empty or too long.
+    InvalidName,
+}
+
+/// Create a new token. The response contains the token secret, which is never shown again.
+#[openapi(tag = "Tokens")]
+#[post("/tokens", data = "<req>")]
+pub(super) async fn post(
+    state: &State<RocketState>,
+    req: Json<CreateTokenRequest>,
+    guard: access::AdminGuard,
+) -> JsonResult<CreatedTokenResponse, Error> {
+    let req = req.into_inner();
+    let permissions = auth::Permissions {
+        can_spend: req.can_spend,
+        can_receive: req.can_receive,
+        can_read: req.can_read,
+        can_admin: req.can_admin,
+    };
+    auth::create(guard.grant(), &state.db, req.name, permissions)
+        .await
+        .map(|(token, secret)| {
+            Json(CreatedTokenResponse {
+                token: TokenModel::from_entity(&token),
+                secret: secret.as_str().to_owned(),
+            })
+        })
+        .map_err(map_error)
+}
+
+/// List tokens, including revoked ones.
+#[openapi(tag = "Tokens")]
+#[get("/tokens")]
+pub(super) async fn list(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+) -> Json<TokensResponse> {
+    let tokens = auth::list(guard.grant(), &state.db)
+        .await
+        .iter()
+        .map(TokenModel::from_entity)
+        .collect();
+    Json(TokensResponse { tokens })
+}
+
+/// Get token details.
+#[openapi(tag = "Tokens")]
+#[get("/tokens/<token_id>")]
+pub(super) async fn get(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+    token_id: String,
+) -> Option<Json<TokenResponse>> {
+    match Uuid::from_str(&token_id) {
+        Ok(token_id) => auth::get(guard.grant(), &state.db, auth::TokenId(token_id))
+            .await
+            .map(|token| {
+                Json(TokenResponse {
+                    token: TokenModel::from_entity(&token),
+                })
+            }),
+        Err(_) => None,
+    }
+}
+
+/// Rename a token.
+#[openapi(tag = "Tokens")]
+#[patch("/tokens/<token_id>", data = "<req>")]
+pub(super) async fn patch(
+    state: &State<RocketState>,
+    req: Json<UpdateTokenRequest>,
+    guard: access::AdminGuard,
+    token_id: String,
+) -> JsonResult<TokenResponse, Error> {
+    let token_id = parse_id(&token_id)?;
+    auth::rename(guard.grant(), &state.db, token_id, req.into_inner().name)
+        .await
+        .map(|token| {
+            Json(TokenResponse {
+                token: TokenModel::from_entity(&token),
+            })
+        })
+        .map_err(map_error)
+}
+
+/// Revoke a token. Revoked tokens can't be used anymore, and they can't be re-enabled. Revoking
+/// an already revoked token has no effect.
+#[openapi(tag = "Tokens")]
+#[delete("/tokens/<token_id>")]
+pub(super) async fn delete(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+    token_id: String,
+) -> JsonResult<TokenResponse, Error> {
+    let token_id = parse_id(&token_id)?;
+    auth::revoke(guard.grant(), &state.db, token_id)
+        .await
+        .map(|token| {
+            Json(TokenResponse {
+                token: TokenModel::from_entity(&token),
+            })
+        })
+        .map_err(map_error)
+}
+
+fn parse_id(token_id: &str) -> Result<auth::TokenId, JsonError<Error>> {
+    Uuid::from_str(token_id)
+        .map(auth::TokenId)
+        .map_err(|_| error::not_found(Error::NotFound, "token not found".to_owned()))
+}
+
+fn map_error(e: auth::Error) -> JsonError<Error> {
+    match e {
+        auth::Error::NotFound => error::not_found(Error::NotFound, "token not found".to_owned()),
+        auth::Error::NameTaken => {
+            error::bad_request(Error::NameTaken, "token name already taken".to_owned())
+        }
+        auth::Error::InvalidName(reason) => {
+            error::bad_request(Error::InvalidName, reason.to_owned())
+        }
+    }
+}
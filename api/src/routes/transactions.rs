@@ -0,0 +1,340 @@
+use super::{Range, RangeError};
+use crate::{
+    access,
+    error::{self, JsonError, JsonResult},
+    state::RocketState,
+};
+use app::{database::Database, ledger, Period};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rocket::{
+    futures::stream::{self, BoxStream, StreamExt},
+    get,
+    http::ContentType,
+    response::stream::TextStream,
+    serde::json::{self, Json},
+    State,
+};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// How many transactions are loaded from the database at a time while exporting.
+const EXPORT_BATCH_SIZE: i64 = 500;
+const CSV_HEADER: &str = "id,created_at,type,entity_type,entity_id,amount_msats,amount_sats,fee_msats,fee_sats,balance_msats,balance_sats\n";
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum TransactionType {
+    /// Funds you had before transactions were recorded.
+    Opening,
+    /// An invoice paid over Lightning.
+    Invoice,
+    /// An invoice paid by another coupler.network user.
+    InternalInvoice,
+    /// A confirmed deposit.
+    Deposit,
+    /// A deposit taken back because its transaction was removed from the blockchain by a
+    /// reorganization. If the transaction gets back into the blockchain, the deposit is credited
+    /// again.
+    DepositReversal,
+    /// A Lightning payment.
+    Payment,
+    /// A payment of an invoice created by another coupler.network user.
+    InternalPayment,
+    /// An on-chain withdrawal, or an extra fee paid to confirm it faster.
+    Withdrawal,
+    /// Funds returned to you because a payment or withdrawal failed or you cancelled a withdrawal,
+    /// or the part of a withdrawal fee you didn't have to pay because the withdrawal was sent
+    /// together with others.
+    Refund,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum EntityType {
+    Invoice,
+    Payment,
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct TransactionModel {
+    /// Unique transaction identifier.
+    id: Uuid,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    /// Type of the entity the transaction belongs to, e.g. the payment of a `PAYMENT` or a
+    /// `REFUND` transaction. Empty for opening balances.
+    entity_type: Option<EntityType>,
+    /// Identifier of the entity, e.g. the payment id. Use it to get the entity from its endpoint.
+    entity_id: Option<Uuid>,
+    /// Amount in millisatoshis, positive if it was credited to your balance and negative if it was
+    /// debited. Doesn't include the fee.
+    amount_msats: i64,
+    /// Fee debited from your balance in millisatoshis, including our service fee.
+    fee_msats: i64,
+    /// Your balance after the transaction in millisatoshis.
+    balance_msats: i64,
+    /// Transaction time.
+    created_at: DateTime<Utc>,
+}
+
+impl TransactionModel {
+    fn from_entity(transaction: &ledger::Transaction) -> Self {
+        let (entity_type, entity_id) = match transaction.entity {
+            Some(ledger::Entity::Invoice(id)) => (Some(EntityType::Invoice), Some(id.0)),
+            Some(ledger::Entity::Payment(id)) => (Some(EntityType::Payment), Some(id.0)),
+            Some(ledger::Entity::Deposit(id)) => (Some(EntityType::Deposit), Some(id.0)),
+            Some(ledger::Entity::Withdrawal(id)) => (Some(EntityType::Withdrawal), Some(id.0)),
+            None => (None, None),
+        };
+        Self {
+            id: transaction.id.0,
+            transaction_type: match transaction.kind {
+                ledger::EntryKind::Opening => TransactionType::Opening,
+                ledger::EntryKind::Invoice => TransactionType::Invoice,
+                ledger::EntryKind::InternalInvoice => TransactionType::InternalInvoice,
+                ledger::EntryKind::Deposit => TransactionType::Deposit,
+                ledger::EntryKind::DepositReversal => TransactionType::DepositReversal,
+                ledger::EntryKind::Payment => TransactionType::Payment,
+                ledger::EntryKind::InternalPayment => TransactionType::InternalPayment,
+                ledger::EntryKind::Withdrawal => TransactionType::Withdrawal,
+                ledger::EntryKind::Refund => TransactionType::Refund,
+                ledger::EntryKind::PaymentFee
+                | ledger::EntryKind::WithdrawalFee
+                | ledger::EntryKind::ServiceFee => {
+                    unreachable!("fees are part of the payment or withdrawal transaction")
+                }
+            },
+            entity_type,
+            entity_id,
+            amount_msats: transaction.amount.0,
+            fee_msats: transaction.fee.0,
+            balance_msats: transaction.balance.0,
+            created_at: transaction.created,
+        }
+    }
+}
+
+/// A transaction in an export, with the amounts in both millisatoshis and satoshis. Satoshi amounts
+/// are rounded towards zero.
+#[derive(Debug, Serialize)]
+struct ExportRecord {
+    #[serde(flatten)]
+    transaction: TransactionModel,
+    amount_sats: i64,
+    fee_sats: i64,
+    balance_sats: i64,
+}
+
+impl ExportRecord {
+    fn from_entity(transaction: &ledger::Transaction) -> Self {
+        Self {
+            transaction: TransactionModel::from_entity(transaction),
+            amount_sats: transaction.amount.sats_floor().0,
+            fee_sats: transaction.fee.sats_floor().0,
+            balance_sats: transaction.balance.sats_floor().0,
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        let transaction = &self.transaction;
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{}\n",
+            transaction.id,
+            transaction
+                .created_at
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            csv_value(&transaction.transaction_type),
+            transaction
+                .entity_type
+                .as_ref()
+                .map(csv_value)
+                .unwrap_or_default(),
+            transaction
+                .entity_id
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            transaction.amount_msats,
+            self.amount_sats,
+            transaction.fee_msats,
+            self.fee_sats,
+            transaction.balance_msats,
+            self.balance_sats,
+        )
+    }
+
+    fn to_ndjson(&self) -> String {
+        format!("{}\n", json::to_string(self).unwrap())
+    }
+}
+
+/// The serialized name of a unit enum variant, e.g. `INTERNAL_PAYMENT`.
+fn csv_value<T: Serialize>(value: &T) -> String {
+    json::to_string(value).unwrap().trim_matches('"').to_owned()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct TransactionsResponse {
+    transactions: Vec<TransactionModel>,
+}
+
+/// Error while listing transactions.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+// The variants are the error codes clients see, named like the codes of `RangeError`
+#[allow(clippy::enum_variant_names)]
+pub(super) enum Error {
+    /// Invalid limit.
+    InvalidLimit,
+    /// Invalid offset.
+    InvalidOffset,
+    /// Invalid `from` or `to` time.
+    InvalidTime,
+    /// Invalid export format.
+    InvalidFormat,
+}
+
+/// List every change to your balance, most recent first: settled invoices, confirmed deposits,
+/// payments, withdrawals, and refunds of failed payments and withdrawals. Each transaction shows
+/// your balance after it, like an account statement. Limit the list to a period with `from`
+/// (inclusive) and `to` (exclusive), both RFC 3339 times, e.g. `2022-01-31T00:00:00Z`.
+#[openapi(tag = "Transactions")]
+#[get("/transactions?<from>&<to>&<range..>")]
+pub(super) async fn list(
+    state: &State<RocketState>,
+    guard: access::ReadGuard,
+    range: Range,
+    from: Option<String>,
+    to: Option<String>,
+) -> JsonResult<TransactionsResponse, Error> {
+    let range = range.query_range().map_err(map_range_error)?;
+    let period = Period {
+        from: parse_time(from)?,
+        to: parse_time(to)?,
+    };
+    Ok(Json(TransactionsResponse {
+        transactions: ledger::list_transactions(guard.grant(), &state.db, period, range)
+            .await
+            .iter()
+            .map(TransactionModel::from_entity)
+            .collect(),
+    }))
+}
+
+/// Export every change to your balance in a period for accounting, oldest first. Unlike
+/// `GET /transactions`, the export isn't paginated: all the transactions are streamed in one
+/// response. Each transaction has the same fields as in `GET /transactions`, with the amount, fee
+/// and balance also in satoshis, rounded towards zero.
+///
+/// Set `format` to `csv` (the default) for comma-separated values with a header line, or to
+/// `ndjson` for one JSON object per line. Limit the export to a period with `from` (inclusive)
+/// and `to` (exclusive), both RFC 3339 times, e.g. `2022-01-31T00:00:00Z`.
+#[openapi(tag = "Transactions")]
+#[get("/transactions/export?<format>&<from>&<to>")]
+pub(super) async fn export(
+    state: &State<RocketState>,
+    guard: access::ReadGuard,
+    format: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<(ContentType, TextStream<BoxStream<'static, String>>), JsonError<Error>> {
+    let format = match format.as_deref() {
+        None | Some("csv") => ExportFormat::Csv,
+        Some("ndjson") => ExportFormat::Ndjson,
+        Some(format) => {
+            return Err(error::bad_request(
+                Error::InvalidFormat,
+                format!("unknown format {}, use csv or ndjson", format),
+            ))
+        }
+    };
+    let period = Period {
+        from: parse_time(from)?,
+        to: parse_time(to)?,
+    };
+    let export_state = ExportState {
+        db: state.db.clone(),
+        guard,
+        period,
+        last: None,
+        pending: VecDeque::new(),
+        is_done: false,
+    };
+    let records = stream::unfold(export_state, next_record);
+    Ok(match format {
+        ExportFormat::Csv => (
+            ContentType::CSV,
+            TextStream::from(
+                stream::once(async { CSV_HEADER.to_owned() })
+                    .chain(records.map(|record| record.to_csv()))
+                    .boxed(),
+            ),
+        ),
+        ExportFormat::Ndjson => (
+            ContentType::new("application", "x-ndjson"),
+            TextStream::from(records.map(|record| record.to_ndjson()).boxed()),
+        ),
+    })
+}
+
+struct ExportState {
+    db: Database,
+    guard: access::ReadGuard,
+    period: Period,
+    /// The last transaction loaded from the database, the next batch starts after it.
+    last: Option<ledger::Transaction>,
+    /// Transactions loaded from the database but not sent yet.
+    pending: VecDeque<ledger::Transaction>,
+    /// True once the last batch has been loaded.
+    is_done: bool,
+}
+
+async fn next_record(mut state: ExportState) -> Option<(ExportRecord, ExportState)> {
+    if state.pending.is_empty() && !state.is_done {
+        let transactions = ledger::list_transactions_after(
+            state.guard.grant(),
+            &state.db,
+            state.period,
+            state.last.as_ref(),
+            EXPORT_BATCH_SIZE,
+        )
+        .await;
+        state.is_done = transactions.len() < EXPORT_BATCH_SIZE as usize;
+        state.last = transactions.last().cloned();
+        state.pending.extend(transactions);
+    }
+    let transaction = state.pending.pop_front()?;
+    Some((ExportRecord::from_entity(&transaction), state))
+}
+
+fn parse_time(time: Option<String>) -> Result<Option<DateTime<Utc>>, JsonError<Error>> {
+    time.map(|time| {
+        DateTime::parse_from_rfc3339(&time)
+            .map(|time| time.with_timezone(&Utc))
+            .map_err(|_| {
+                error::bad_request(
+                    Error::InvalidTime,
+                    format!("{} is not an RFC 3339 time", time),
+                )
+            })
+    })
+    .transpose()
+}
+
+fn map_range_error((_, Json(e)): JsonError<RangeError>) -> JsonError<Error> {
+    let error = match e.error.status {
+        RangeError::InvalidLimit => Error::InvalidLimit,
+        RangeError::InvalidOffset => Error::InvalidOffset,
+    };
+    error::bad_request(error, e.error.description)
+}
@@ -0,0 +1,263 @@
+use super::{Range, RangeError};
+use crate::{
+    access,
+    error::{self, JsonError, JsonResult},
+    state::RocketState,
+};
+use app::webhook;
+use chrono::{DateTime, Utc};
+use rocket::{delete, get, post, serde::json::Json, State};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct CreateWebhookRequest {
+    /// URL the events are POSTed to. Must use http or https.
+    url: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct WebhookModel {
+    /// Unique webhook identifier.
+    id: Uuid,
+    /// URL the events are POSTed to.
+    url: String,
+    /// Webhook creation time.
+    created_at: DateTime<Utc>,
+    /// True if the webhook was disabled.
+    is_disabled: bool,
+    /// Time the webhook was disabled, if it was disabled.
+    disabled_at: Option<DateTime<Utc>>,
+}
+
+impl WebhookModel {
+    fn from_entity(webhook: &webhook::Webhook) -> Self {
+        Self {
+            id: webhook.id.0,
+            url: webhook.url.to_string(),
+            created_at: webhook.created,
+            is_disabled: !webhook.is_enabled(),
+            disabled_at: webhook.disabled,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum DeliveryStatus {
+    /// The event hasn't been delivered yet, and it will be retried.
+    Pending,
+    /// The webhook responded with a 2xx status.
+    Delivered,
+    /// All attempts failed, or the webhook was disabled. The event won't be sent again.
+    Failed,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct DeliveryModel {
+    /// Unique delivery identifier.
+    id: Uuid,
+    /// Identifier of the delivered event, sent in the X-Webhook-Event-Id header. An event is
+    /// delivered to each of your webhooks with the same identifier.
+    event_id: Uuid,
+    /// Event type, e.g. `invoice.settled` or `deposit.confirmed`.
+    event_type: String,
+    status: DeliveryStatus,
+    /// Number of delivery attempts so far.
+    attempts: i32,
+    /// Time of the last attempt.
+    last_attempt_at: Option<DateTime<Utc>>,
+    /// Time of the next attempt, if the event hasn't been delivered yet.
+    next_attempt_at: Option<DateTime<Utc>>,
+    /// HTTP status of the last response, if your webhook responded.
+    response_status: Option<i32>,
+    /// Reason the last attempt failed.
+    error: Option<String>,
+    /// Time the event was delivered.
+    delivered_at: Option<DateTime<Utc>>,
+    /// Time the event happened.
+    created_at: DateTime<Utc>,
+}
+
+impl DeliveryModel {
+    fn from_entity(delivery: &webhook::Delivery) -> Self {
+        Self {
+            id: delivery.id.0,
+            event_id: delivery.event_id.0,
+            event_type: delivery.event_kind.as_str().to_owned(),
+            status: match delivery.status() {
+                webhook::DeliveryStatus::Pending => DeliveryStatus::Pending,
+                webhook::DeliveryStatus::Delivered => DeliveryStatus::Delivered,
+                webhook::DeliveryStatus::Failed => DeliveryStatus::Failed,
+            },
+            attempts: delivery.attempts,
+            last_attempt_at: delivery.last_attempt,
+            next_attempt_at: delivery.next_attempt,
+            response_status: delivery.last_response_status,
+            error: delivery.last_error.clone(),
+            delivered_at: delivery.delivered,
+            created_at: delivery.created,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct CreatedWebhookResponse {
+    webhook: WebhookModel,
+    /// Key used to sign the events. The X-Webhook-Signature header contains `sha256=` followed by
+    /// the hex encoded HMAC-SHA256 of the X-Webhook-Timestamp header, a dot, and the request body.
+    /// It is shown only once and can't be recovered later.
+    secret: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct WebhookResponse {
+    webhook: WebhookModel,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct WebhooksResponse {
+    webhooks: Vec<WebhookModel>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct DeliveriesResponse {
+    deliveries: Vec<DeliveryModel>,
+}
+
+/// Error during webhook management.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(super) enum Error {
+    /// The webhook does not exist.
+    NotFound,
+    /// The URL is invalid.
+    InvalidUrl,
+    /// You have reached the maximum number of enabled webhooks.
+    TooManyWebhooks,
+}
+
+/// Register a webhook. Account events, e.g. a settled invoice or a confirmed deposit, are POSTed
+/// to the webhook URL. See `GET /events` for the list of events. Failed deliveries are retried
+/// with an exponential backoff. The response contains the secret used to sign the events, which
+/// is never shown again.
+#[openapi(tag = "Webhooks")]
+#[post("/webhooks", data = "<req>")]
+pub(super) async fn post(
+    state: &State<RocketState>,
+    req: Json<CreateWebhookRequest>,
+    guard: access::AdminGuard,
+) -> JsonResult<CreatedWebhookResponse, Error> {
+    webhook::create(guard.grant(), &state.db, &req.url)
+        .await
+        .map(|webhook| {
+            Json(CreatedWebhookResponse {
+                webhook: WebhookModel::from_entity(&webhook),
+                secret: webhook.secret.as_str().to_owned(),
+            })
+        })
+        .map_err(map_error)
+}
+
+/// List webhooks, including disabled ones.
+#[openapi(tag = "Webhooks")]
+#[get("/webhooks")]
+pub(super) async fn list(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+) -> Json<WebhooksResponse> {
+    let webhooks = webhook::list(guard.grant(), &state.db)
+        .await
+        .iter()
+        .map(WebhookModel::from_entity)
+        .collect();
+    Json(WebhooksResponse { webhooks })
+}
+
+/// Get webhook details.
+#[openapi(tag = "Webhooks")]
+#[get("/webhooks/<webhook_id>")]
+pub(super) async fn get(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+    webhook_id: String,
+) -> Option<Json<WebhookResponse>> {
+    match webhook::Id::from_str(&webhook_id) {
+        Ok(webhook_id) => webhook::get(guard.grant(), &state.db, webhook_id)
+            .await
+            .map(|webhook| {
+                Json(WebhookResponse {
+                    webhook: WebhookModel::from_entity(&webhook),
+                })
+            }),
+        Err(_) => None,
+    }
+}
+
+/// Disable a webhook. No more events are sent to it, including events whose delivery is still
+/// pending. Disabling an already disabled webhook has no effect.
+#[openapi(tag = "Webhooks")]
+#[delete("/webhooks/<webhook_id>")]
+pub(super) async fn delete(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+    webhook_id: String,
+) -> JsonResult<WebhookResponse, Error> {
+    let webhook_id = parse_id(&webhook_id)?;
+    webhook::disable(guard.grant(), &state.db, webhook_id)
+        .await
+        .map(|webhook| {
+            Json(WebhookResponse {
+                webhook: WebhookModel::from_entity(&webhook),
+            })
+        })
+        .map_err(map_error)
+}
+
+/// List the deliveries to a webhook, most recent first. Use this to find out why events didn't
+/// arrive.
+#[openapi(tag = "Webhooks")]
+#[get("/webhooks/<webhook_id>/deliveries?<range..>")]
+pub(super) async fn list_deliveries(
+    state: &State<RocketState>,
+    guard: access::AdminGuard,
+    webhook_id: String,
+    range: Range,
+) -> Option<JsonResult<DeliveriesResponse, RangeError>> {
+    let webhook_id = webhook::Id::from_str(&webhook_id).ok()?;
+    let range = match range.query_range() {
+        Ok(range) => range,
+        Err(e) => return Some(Err(e)),
+    };
+    webhook::list_deliveries(guard.grant(), &state.db, webhook_id, range)
+        .await
+        .ok()
+        .map(|deliveries| {
+            Ok(Json(DeliveriesResponse {
+                deliveries: deliveries.iter().map(DeliveryModel::from_entity).collect(),
+            }))
+        })
+}
+
+fn parse_id(webhook_id: &str) -> Result<webhook::Id, JsonError<Error>> {
+    webhook::Id::from_str(webhook_id)
+        .map_err(|_| error::not_found(Error::NotFound, "webhook not found".to_owned()))
+}
+
+fn map_error(e: webhook::Error) -> JsonError<Error> {
+    match e {
+        webhook::Error::NotFound => {
+            error::not_found(Error::NotFound, "webhook not found".to_owned())
+        }
+        webhook::Error::InvalidUrl(reason) => {
+            error::bad_request(Error::InvalidUrl, reason.to_owned())
+        }
+        webhook::Error::TooManyWebhooks => error::bad_request(
+            Error::TooManyWebhooks,
+            "too many webhooks, disable one first".to_owned(),
+        ),
+    }
+}
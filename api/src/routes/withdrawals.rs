@@ -1,10 +1,11 @@
-use super::{Range, RangeError};
-use crate::error::JsonResult;
+use super::{next_cursor, parse_amount_range, parse_flag, parse_period, ListError, Range};
+use crate::error::{JsonError, JsonResult};
+use crate::idempotency::IdempotencyKey;
 use crate::state::RocketState;
 use crate::{access, error};
-use app::{btc, withdrawal};
+use app::{btc, cash_limits, ln, withdrawal, Cursor};
 use chrono::{DateTime, Utc};
-use rocket::{get, post, serde::json::Json, State};
+use rocket::{delete, get, post, serde::json::Json, State};
 use rocket_okapi::openapi;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -18,6 +19,45 @@ pub(super) struct WithdrawalRequest {
     address: String,
     /// The balance you wish to withdraw, in satoshis.
     amount_sats: i64,
+    /// How fast the transaction should confirm. Faster withdrawals pay higher fees, see
+    /// `GET /withdrawals/quote`. Defaults to `FAST`. Leave it empty when setting `sats_per_vbyte`.
+    priority: Option<PriorityModel>,
+    /// Fee rate to pay, in satoshis per virtual byte, instead of a `priority`. Must be at least 1,
+    /// and at most the maximum we allow.
+    sats_per_vbyte: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(super) struct BumpFeeRequest {
+    /// The new priority. Defaults to `FAST`. Leave it empty when setting `sats_per_vbyte`.
+    priority: Option<PriorityModel>,
+    /// The new fee rate, in satoshis per virtual byte, instead of a `priority`. Must be at most the
+    /// maximum we allow.
+    sats_per_vbyte: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum PriorityModel {
+    /// Confirms in the next block.
+    Fast,
+    /// Confirms within about an hour.
+    Normal,
+    /// Confirms within about a day.
+    Economy,
+    /// Pays the fee rate set with `sats_per_vbyte`.
+    SatsPerVbyte,
+}
+
+impl PriorityModel {
+    fn from_entity(priority: withdrawal::Priority) -> Self {
+        match priority {
+            withdrawal::Priority::Fast => PriorityModel::Fast,
+            withdrawal::Priority::Normal => PriorityModel::Normal,
+            withdrawal::Priority::Economy => PriorityModel::Economy,
+            withdrawal::Priority::SatsPerVbyte(_) => PriorityModel::SatsPerVbyte,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -26,10 +66,19 @@ struct WithdrawalModel {
     id: Uuid,
     /// The BTC address that funds were sent to.
     address: String,
-    /// Fees paid as part of this withdrawal.
+    /// Fees paid as part of this withdrawal. Until the withdrawal is sent, this is the estimated
+    /// fee reserved from your balance. Withdrawals may be sent together in one transaction, then
+    /// each pays its share of the transaction fee, and the rest of the reserved fee is refunded.
     fee_sats: i64,
+    /// Our service fee in millisatoshis, reserved from your balance together with the amount and
+    /// the fee.
+    service_fee_msats: i64,
     /// Amount of funds withdrawn, in satoshis.
     amount_sats: i64,
+    /// How fast the transaction should confirm.
+    priority: PriorityModel,
+    /// The fee rate set when creating the withdrawal, if it was set instead of a priority.
+    sats_per_vbyte: Option<u64>,
     /// Withdrawal creation time.
     created_at: DateTime<Utc>,
     /// BTC transaction ID for this withdrawal, if the transaction has been broadcast.
@@ -38,6 +87,10 @@ struct WithdrawalModel {
     confirmed_at: Option<DateTime<Utc>>,
     /// True if the related BTC transaction has been confirmed.
     is_confirmed: bool,
+    /// Cancellation time, if you cancelled the withdrawal.
+    cancelled_at: Option<DateTime<Utc>>,
+    /// True if you cancelled the withdrawal. The funds were returned to your balance.
+    is_cancelled: bool,
 }
 
 impl WithdrawalModel {
@@ -46,14 +99,23 @@ impl WithdrawalModel {
             id: withdrawal.id.0,
             address: withdrawal.address.to_string(),
             fee_sats: withdrawal.fee.0,
+            service_fee_msats: withdrawal.service_fee.0,
             amount_sats: withdrawal.amount.0,
+            priority: PriorityModel::from_entity(withdrawal.priority),
+            sats_per_vbyte: match withdrawal.priority {
+                withdrawal::Priority::SatsPerVbyte(rate) => Some(rate),
+                _ => None,
+            },
             created_at: withdrawal.created,
             txid: withdrawal
                 .tx_out
                 .as_ref()
+                .filter(|_| withdrawal.is_sent())
                 .map(|tx_out| tx_out.tx.id.to_string()),
             confirmed_at: withdrawal.confirmed,
             is_confirmed: withdrawal.is_confirmed(),
+            cancelled_at: withdrawal.cancelled,
+            is_cancelled: withdrawal.is_cancelled(),
         }
     }
 }
@@ -63,9 +125,26 @@ pub(super) struct WithdrawalResponse {
     withdrawal: WithdrawalModel,
 }
 
+#[derive(Debug, Serialize, JsonSchema)]
+struct QuoteModel {
+    priority: PriorityModel,
+    /// Estimated fee of the withdrawal, in satoshis.
+    fee_sats: i64,
+    /// Estimated fee rate, in satoshis per virtual byte.
+    sats_per_vbyte: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub(super) struct QuoteResponse {
+    /// Fee estimates for each priority, fastest first.
+    quotes: Vec<QuoteModel>,
+}
+
 #[derive(Debug, Serialize, JsonSchema)]
 pub(super) struct WithdrawalsResponse {
     withdrawals: Vec<WithdrawalModel>,
+    /// Pass it as `cursor` to get the next page. Empty on the last page.
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, JsonSchema)]
@@ -78,55 +157,202 @@ pub(super) enum Error {
     InsufficientBalance,
     /// Amount must be positive.
     AmountNotPositive,
+    /// Amount too low.
+    AmountTooLow,
+    /// Amount too high.
+    AmountTooHigh,
+    /// Daily amount exceeded.
+    DailyLimitExceeded,
+    /// The fee rate must be at least 1 sat/vbyte and at most the maximum we allow, and can't be set
+    /// together with a priority other than `SATS_PER_VBYTE`.
+    InvalidFeeRate,
+    /// The withdrawal does not exist.
+    NotFound,
+    /// The withdrawal isn't waiting for confirmation, so its fee can't be bumped.
+    NotPending,
+    /// The transaction already pays at least the requested fee rate.
+    FeeTooLow,
+    /// The fee of the withdrawal's transaction can't be bumped any further.
+    CannotBump,
+    /// The withdrawal was sent in one transaction together with withdrawals of other users, so you
+    /// can't bump its fee.
+    SharedTransaction,
+    /// The withdrawal transaction has already been signed and may be broadcast at any moment, so
+    /// the withdrawal can't be cancelled.
+    AlreadySigned,
+    /// The withdrawal allowlist is turned on and the address isn't a usable allowed address. See
+    /// `GET /withdrawals/allowlist`.
+    AddressNotAllowed,
+    /// The idempotency key has already been used for a different request.
+    IdempotencyKeyReused,
+    /// A request with the same idempotency key is still being processed.
+    RequestInProgress,
+}
+
+/// Error while quoting withdrawal fees.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(super) enum QuoteError {
+    /// The address is not a valid BTC address.
+    InvalidAddress,
+    /// Amount must be positive.
+    AmountNotPositive,
 }
 
-/// Withdraw your balance from coupler.network into a BTC address.
+/// Withdraw your balance from coupler.network into a BTC address. Choose how fast the transaction
+/// confirms with `priority`, or set the fee rate yourself with `sats_per_vbyte`. Retrying with the
+/// same `Idempotency-Key` returns the original withdrawal instead of withdrawing again.
 #[openapi(tag = "Withdrawals")]
 #[post("/withdrawals", data = "<req>")]
 pub(super) async fn post(
     state: &State<RocketState>,
     req: Json<WithdrawalRequest>,
     guard: access::SpendGuard,
+    idempotency_key: IdempotencyKey,
 ) -> JsonResult<WithdrawalResponse, Error> {
-    match app::withdrawal::start(
+    let priority = parse_priority(req.priority, req.sats_per_vbyte)?;
+    app::withdrawal::start(
         guard.grant(),
         &state.db,
         state.lightning.create_node().await,
         &btc::Address::from_str(&req.address).unwrap(),
         btc::Sats(req.amount_sats),
+        priority,
+        state.max_sats_per_vbyte,
+        &state.cash_limits.withdrawal_limits,
+        idempotency_key.key(),
+    )
+    .await
+    .map(|withdrawal| {
+        Json(WithdrawalResponse {
+            withdrawal: WithdrawalModel::from_entity(&withdrawal),
+        })
+    })
+    .map_err(map_error)
+}
+
+/// Raise the fee of a withdrawal which was sent but hasn't confirmed yet, so that it confirms
+/// faster. Choose the new `priority`, or set the fee rate with `sats_per_vbyte`. The extra fee is
+/// debited from your balance. If the withdrawal was sent in one transaction together with your other
+/// withdrawals, you pay for bumping the whole transaction, while a transaction which pays other
+/// users too can't be bumped. The transaction may be replaced by one with a different `txid`.
+#[openapi(tag = "Withdrawals")]
+#[post("/withdrawals/<withdrawal_id>/bump", data = "<req>")]
+pub(super) async fn bump(
+    state: &State<RocketState>,
+    req: Json<BumpFeeRequest>,
+    guard: access::SpendGuard,
+    withdrawal_id: String,
+) -> JsonResult<WithdrawalResponse, Error> {
+    let withdrawal_id = Uuid::from_str(&withdrawal_id)
+        .map_err(|_| error::not_found(Error::NotFound, "withdrawal not found".to_owned()))?;
+    let priority = parse_priority(req.priority, req.sats_per_vbyte)?;
+    app::withdrawal::bump_fee(
+        guard.grant(),
+        &state.db,
+        state.lightning.create_node().await,
+        withdrawal::Id(withdrawal_id),
+        priority,
+        state.max_sats_per_vbyte,
     )
     .await
-    {
-        Ok(withdrawal) => Ok(Json(WithdrawalResponse {
+    .map(|withdrawal| {
+        Json(WithdrawalResponse {
             withdrawal: WithdrawalModel::from_entity(&withdrawal),
+        })
+    })
+    .map_err(map_error)
+}
+
+/// Cancel a withdrawal which hasn't been sent yet, e.g. because of a mistyped address. The
+/// reserved amount and fee are returned to your balance. Withdrawals can be cancelled only until
+/// their transaction is signed, which happens shortly before it's sent. Cancelling an already
+/// cancelled withdrawal has no effect.
+#[openapi(tag = "Withdrawals")]
+#[delete("/withdrawals/<withdrawal_id>")]
+pub(super) async fn delete(
+    state: &State<RocketState>,
+    guard: access::SpendGuard,
+    withdrawal_id: String,
+) -> JsonResult<WithdrawalResponse, Error> {
+    let withdrawal_id = Uuid::from_str(&withdrawal_id)
+        .map_err(|_| error::not_found(Error::NotFound, "withdrawal not found".to_owned()))?;
+    app::withdrawal::cancel(guard.grant(), &state.db, withdrawal::Id(withdrawal_id))
+        .await
+        .map(|withdrawal| {
+            Json(WithdrawalResponse {
+                withdrawal: WithdrawalModel::from_entity(&withdrawal),
+            })
+        })
+        .map_err(map_error)
+}
+
+/// Estimate the fee of withdrawing `amount_sats` to `address` with each priority. The fee is
+/// estimated again when the withdrawal is created, so it may differ from the quote.
+#[openapi(tag = "Withdrawals")]
+#[get("/withdrawals/quote?<address>&<amount_sats>")]
+pub(super) async fn quote(
+    state: &State<RocketState>,
+    _guard: access::ReadGuard,
+    address: String,
+    amount_sats: i64,
+) -> JsonResult<QuoteResponse, QuoteError> {
+    let address = btc::Address::from_str(&address).map_err(|_| {
+        error::bad_request(
+            QuoteError::InvalidAddress,
+            format!("{} is not a valid address", address),
+        )
+    })?;
+    let mut node = state.lightning.create_node().await;
+    match withdrawal::quote(&mut node, &address, btc::Sats(amount_sats)).await {
+        Ok(quotes) => Ok(Json(QuoteResponse {
+            quotes: quotes
+                .iter()
+                .map(|quote| QuoteModel {
+                    priority: PriorityModel::from_entity(quote.priority),
+                    fee_sats: quote.fee.0,
+                    sats_per_vbyte: quote.sats_per_vbyte,
+                })
+                .collect(),
         })),
-        Err(e) => match e {
-            withdrawal::Error::InsufficientBalance(_) => Err(error::bad_request(
-                Error::InsufficientBalance,
-                "insufficient balance".to_owned(),
-            )),
-            withdrawal::Error::AmountNotPositive => Err(error::bad_request(
-                Error::AmountNotPositive,
-                "amount must be positive".to_owned(),
-            )),
-            withdrawal::Error::ConcurrencyConflict(_) => {
-                Err(error::concurrency_error(Error::Unknown))
-            }
-        },
+        Err(_) => Err(error::bad_request(
+            QuoteError::AmountNotPositive,
+            "amount must be positive".to_owned(),
+        )),
     }
 }
 
-/// List withdrawals.
+/// List withdrawals, most recent first. Filter them with `is_confirmed`, by creation time with
+/// `from` (inclusive) and `to` (exclusive), both RFC 3339 times, and by amount with
+/// `min_amount_sats` and `max_amount_sats`. Get the next page with the `next_cursor` of the
+/// response.
 #[openapi(tag = "Withdrawals")]
-#[get("/withdrawals?<range..>")]
+#[get("/withdrawals?<is_confirmed>&<from>&<to>&<min_amount_sats>&<max_amount_sats>&<cursor>&<range..>")]
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn list(
     state: &State<RocketState>,
     guard: access::ReadGuard,
     range: Range,
-) -> JsonResult<WithdrawalsResponse, RangeError> {
+    cursor: Option<String>,
+    is_confirmed: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    min_amount_sats: Option<String>,
+    max_amount_sats: Option<String>,
+) -> JsonResult<WithdrawalsResponse, ListError> {
+    let page = range.page(cursor, |id| Uuid::from_str(id).ok().map(withdrawal::Id))?;
+    let filter = withdrawal::Filter {
+        confirmed: parse_flag("is_confirmed", is_confirmed)?,
+        period: parse_period(from, to)?,
+        amount: parse_amount_range(min_amount_sats, max_amount_sats, btc::Sats)?,
+    };
+    let withdrawals = app::withdrawal::list(guard.grant(), &state.db, &filter, &page).await;
     Ok(Json(WithdrawalsResponse {
-        withdrawals: app::withdrawal::list(guard.grant(), &state.db, range.query_range()?)
-            .await
+        next_cursor: next_cursor(&page, &withdrawals, |withdrawal| Cursor {
+            created: withdrawal.created,
+            id: withdrawal.id.0,
+        }),
+        withdrawals: withdrawals
             .iter()
             .map(WithdrawalModel::from_entity)
             .collect(),
@@ -154,3 +380,85 @@ pub(super) async fn get(
         Err(_) => None,
     }
 }
+
+fn parse_priority(
+    priority: Option<PriorityModel>,
+    sats_per_vbyte: Option<u64>,
+) -> Result<withdrawal::Priority, JsonError<Error>> {
+    match (priority, sats_per_vbyte) {
+        (None | Some(PriorityModel::Fast), None) => Ok(withdrawal::Priority::Fast),
+        (Some(PriorityModel::Normal), None) => Ok(withdrawal::Priority::Normal),
+        (Some(PriorityModel::Economy), None) => Ok(withdrawal::Priority::Economy),
+        (None | Some(PriorityModel::SatsPerVbyte), Some(rate)) => {
+            Ok(withdrawal::Priority::SatsPerVbyte(rate))
+        }
+        _ => Err(error::bad_request(
+            Error::InvalidFeeRate,
+            "set either a priority or sats_per_vbyte".to_owned(),
+        )),
+    }
+}
+
+fn map_error(e: withdrawal::Error) -> JsonError<Error> {
+    match e {
+        withdrawal::Error::LimitsViolated(cash_limits::Error::AmountTooLow) => {
+            error::bad_request(Error::AmountTooLow, "withdrawal amount too low".to_owned())
+        }
+        withdrawal::Error::LimitsViolated(cash_limits::Error::AmountTooHigh) => error::bad_request(
+            Error::AmountTooHigh,
+            "withdrawal amount too high".to_owned(),
+        ),
+        withdrawal::Error::LimitsViolated(cash_limits::Error::DailyLimitExceeded) => {
+            error::bad_request(
+                Error::DailyLimitExceeded,
+                "daily withdrawal total exceeded".to_owned(),
+            )
+        }
+        withdrawal::Error::InsufficientBalance(_) => error::bad_request(
+            Error::InsufficientBalance,
+            "insufficient balance".to_owned(),
+        ),
+        withdrawal::Error::AmountNotPositive => error::bad_request(
+            Error::AmountNotPositive,
+            "amount must be positive".to_owned(),
+        ),
+        withdrawal::Error::InvalidFeeRate => error::bad_request(
+            Error::InvalidFeeRate,
+            "fee rate must be at least 1 sat/vbyte".to_owned(),
+        ),
+        withdrawal::Error::FeeRateTooHigh(max) => error::bad_request(
+            Error::InvalidFeeRate,
+            format!("fee rate can be at most {} sat/vbyte", max),
+        ),
+        withdrawal::Error::NotFound => {
+            error::not_found(Error::NotFound, "withdrawal not found".to_owned())
+        }
+        withdrawal::Error::NotPending => error::bad_request(
+            Error::NotPending,
+            "withdrawal is not waiting for confirmation".to_owned(),
+        ),
+        withdrawal::Error::FeeBump(ln::FeeBumpError::FeeTooLow) => error::bad_request(
+            Error::FeeTooLow,
+            "the transaction already pays at least the fee rate".to_owned(),
+        ),
+        withdrawal::Error::FeeBump(
+            e @ (ln::FeeBumpError::NoChangeOutput | ln::FeeBumpError::AboveMaxFee),
+        ) => error::bad_request(Error::CannotBump, e.to_string()),
+        withdrawal::Error::AlreadySigned => error::bad_request(
+            Error::AlreadySigned,
+            "withdrawal has already been signed".to_owned(),
+        ),
+        withdrawal::Error::SharedTransaction => error::bad_request(
+            Error::SharedTransaction,
+            "withdrawal has been sent together with withdrawals of other users".to_owned(),
+        ),
+        withdrawal::Error::AddressNotAllowed(_) => error::bad_request(
+            Error::AddressNotAllowed,
+            "address is not a usable allowed address".to_owned(),
+        ),
+        withdrawal::Error::ConcurrencyConflict(_) => error::concurrency_error(Error::Unknown),
+        withdrawal::Error::Idempotency(e) => {
+            error::idempotency_error(e, Error::IdempotencyKeyReused, Error::RequestInProgress)
+        }
+    }
+}
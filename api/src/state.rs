@@ -1,10 +1,12 @@
 use app::{database::Database, ln::Lightning};
+use std::time::Duration;
 
 use crate::rate_limit::RateLimit;
 
 pub struct CashLimits {
     pub payment_limits: app::CashLimits,
     pub invoice_limits: app::CashLimits,
+    pub withdrawal_limits: app::CashLimits,
 }
 
 pub struct RocketState {
@@ -12,4 +14,9 @@ pub struct RocketState {
     pub lightning: Lightning,
     pub cash_limits: CashLimits,
     pub rate_limit: RateLimit,
+    /// How long newly allowed withdrawal addresses wait before they can be used, and how long a
+    /// turned off allowlist is still enforced.
+    pub allowlist_cooling_off: Duration,
+    /// The highest fee rate users can set for their withdrawals, in sat/vbyte.
+    pub max_sats_per_vbyte: u64,
 }
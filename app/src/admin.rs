@@ -0,0 +1,133 @@
+//! Operations for the operators of our service, used by the `laas` CLI. Unlike everything else in
+//! this crate, these operations don't require a grant, so they must never be exposed through the
+//! API.
+
+use crate::{
+    allowlist, auth, balance, btc, database::Database, deposit, ledger, pricing, user, withdrawal,
+};
+
+pub use crate::balance::{Reservation, ReservationId, ReservationStatus};
+
+pub async fn create_user(db: &Database, email: user::Email) -> Result<user::User, user::Error> {
+    user::create(db, email).await
+}
+
+pub async fn get_user(db: &Database, email: &user::Email) -> Option<user::User> {
+    user::get_by_email(db, email).await
+}
+
+pub async fn list_users(db: &Database) -> Vec<user::User> {
+    user::list(db).await
+}
+
+/// Creates a new token for the user. The returned secret is the only copy of the token.
+pub async fn create_token(
+    db: &Database,
+    user_id: user::Id,
+    name: String,
+    permissions: auth::Permissions,
+) -> Result<(auth::Token, auth::TokenSecret), auth::Error> {
+    auth::create_for_user(db, user_id, name, permissions).await
+}
+
+pub async fn list_tokens(db: &Database, user_id: user::Id) -> Vec<auth::Token> {
+    auth::list_for_user(db, user_id).await
+}
+
+/// Disables the token of any user.
+pub async fn disable_token(db: &Database, id: auth::TokenId) -> Result<auth::Token, auth::Error> {
+    auth::disable(db, id).await
+}
+
+pub async fn list_pending_reservations(
+    db: &Database,
+    user_id: Option<user::Id>,
+) -> Vec<Reservation> {
+    balance::list_pending_reservations(db, user_id).await
+}
+
+/// Verifies that the user balances match the ledger.
+pub async fn check_ledger(db: &Database) -> ledger::Check {
+    ledger::check(db).await
+}
+
+/// Sums the service fees we earned less the on-chain fees we paid for users, i.e. the balance of
+/// [`ledger::Account::Revenue`].
+pub async fn get_revenue(db: &Database) -> btc::MilliSats {
+    ledger::revenue(db).await
+}
+
+/// Lists the default plan and every other plan in use, with the fee schedules which apply to the
+/// users on each plan.
+pub async fn list_plans(db: &Database) -> Vec<pricing::Plan> {
+    pricing::list_plans(db).await
+}
+
+/// Sets the fee schedule of an operation on a plan. It applies to the fees charged from now on.
+pub async fn set_fee_schedule(
+    db: &Database,
+    plan: &str,
+    operation: pricing::Operation,
+    flat: btc::MilliSats,
+    rate_ppm: i64,
+) -> Result<pricing::FeeSchedule, pricing::Error> {
+    pricing::set_schedule(db, plan, operation, flat, rate_ppm).await
+}
+
+/// Removes the fee schedule of an operation from a plan, so that the schedule of the default plan
+/// applies instead.
+pub async fn remove_fee_schedule(db: &Database, plan: &str, operation: pricing::Operation) {
+    pricing::remove_schedule(db, plan, operation).await
+}
+
+/// Moves the user to another plan.
+pub async fn set_user_plan(
+    db: &Database,
+    user_id: user::Id,
+    plan: &str,
+) -> Result<(), pricing::Error> {
+    pricing::set_user_plan(db, user_id, plan).await
+}
+
+/// Lists the deposits flagged after chain reorganizations, which need to be sorted out by hand.
+pub async fn list_flagged_deposits(db: &Database) -> Vec<deposit::Deposit> {
+    deposit::list_flagged(db).await
+}
+
+/// Lists the deposits held because they're outside the deposit limits, oldest first.
+pub async fn list_held_deposits(db: &Database) -> Vec<deposit::Deposit> {
+    deposit::list_held(db).await
+}
+
+/// Credits a held deposit to the user regardless of the deposit limits, e.g. once it's clear that
+/// a deposit above the maximum is legitimate. Returns None if there's no held deposit with the id.
+pub async fn release_deposit(db: &Database, id: deposit::Id) -> Option<deposit::Deposit> {
+    deposit::release(db, id).await
+}
+
+/// Lists the withdrawals flagged after chain reorganizations, which need to be sorted out by hand.
+pub async fn list_flagged_withdrawals(db: &Database) -> Vec<withdrawal::Withdrawal> {
+    withdrawal::list_flagged(db).await
+}
+
+pub async fn list_allowed_addresses(
+    db: &Database,
+    user_id: user::Id,
+) -> Vec<allowlist::AllowedAddress> {
+    allowlist::list_for_user(db, user_id).await
+}
+
+/// Confirms an allowed withdrawal address of any user. Do this only after making sure through
+/// another channel, e.g. a call, that the user added the address, since it's what keeps a leaked
+/// token from withdrawing to it.
+pub async fn confirm_allowed_address(
+    db: &Database,
+    id: allowlist::Id,
+) -> Result<allowlist::AllowedAddress, allowlist::Error> {
+    allowlist::confirm_for_user(db, id).await
+}
+
+/// Turns the withdrawal allowlist of the user off right away, e.g. for a user who lost the password.
+pub async fn disable_allowlist(db: &Database, user_id: user::Id) -> allowlist::Allowlist {
+    allowlist::disable_for_user(db, user_id).await
+}
@@ -0,0 +1,153 @@
+//! The withdrawal allowlist limits the addresses the user's funds can be withdrawn to, so that a
+//! leaked spend token can't be used to drain the balance into any address.
+//!
+//! An [`AllowedAddress`] becomes usable only after a cooling-off period, and only once the user
+//! confirmed it through a channel a token alone doesn't give access to, i.e. with the account
+//! password. For the same reason, turning the [`Allowlist`] off takes the password, and takes
+//! effect only after the cooling-off period.
+
+use crate::{btc, user};
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("allowed address not found")]
+    NotFound,
+    #[error("address is already allowed")]
+    AlreadyAllowed,
+    #[error("too many allowed addresses")]
+    TooManyAddresses,
+    #[error("allowed address was removed")]
+    Removed,
+    #[error("{0}")]
+    User(#[from] user::Error),
+}
+
+/// The destination of a withdrawal isn't a usable address of the user's allowlist.
+#[derive(Debug, Error)]
+#[error("address is not allowed")]
+pub struct NotAllowed;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Id(pub Uuid);
+
+impl FromStr for Id {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::from_str(s).map(Self)
+    }
+}
+
+/// Whether withdrawals of the user are limited to the allowed addresses.
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    pub user_id: user::Id,
+    /// When the allowlist was turned on. None if it has never been turned on.
+    pub enabled: Option<DateTime<Utc>>,
+    /// When the allowlist stops being enforced after it was turned off.
+    pub disabled: Option<DateTime<Utc>>,
+}
+
+impl Allowlist {
+    pub(crate) fn new(user_id: user::Id) -> Self {
+        Self {
+            user_id,
+            enabled: None,
+            disabled: None,
+        }
+    }
+
+    /// True if withdrawals are limited to the allowed addresses right now. A turned off allowlist
+    /// is still enforced until its cooling-off period is over.
+    pub fn is_enforced(&self) -> bool {
+        self.enabled.is_some() && self.disabled.is_none_or(|disabled| disabled > Utc::now())
+    }
+
+    /// Turns the allowlist on right away, or keeps it on if it was being turned off.
+    pub(crate) fn enable(&mut self) {
+        if !self.is_enforced() {
+            self.enabled = Some(Utc::now());
+        }
+        self.disabled = None;
+    }
+
+    /// Turns the allowlist off once the cooling-off period is over. Turning off an allowlist which
+    /// is already being turned off doesn't postpone it.
+    pub(crate) fn disable(&mut self, cooling_off: Duration) {
+        if self.enabled.is_some() && self.disabled.is_none() {
+            self.disabled = Some(Utc::now() + cooling_off);
+        }
+    }
+}
+
+/// An address the user's funds may be withdrawn to while the allowlist is enforced.
+#[derive(Debug, Clone)]
+pub struct AllowedAddress {
+    pub id: Id,
+    pub user_id: user::Id,
+    pub address: btc::Address,
+    /// A short description of the address chosen by the user, e.g. "cold storage".
+    pub label: Option<String>,
+    pub created: DateTime<Utc>,
+    /// End of the cooling-off period. The address can't be used before this time, even if it was
+    /// confirmed.
+    pub usable_after: DateTime<Utc>,
+    pub confirmed: Option<DateTime<Utc>>,
+    pub removed: Option<DateTime<Utc>>,
+}
+
+const MAX_LABEL_CHARS: usize = 100;
+
+impl AllowedAddress {
+    pub(crate) fn create(
+        user_id: user::Id,
+        address: btc::Address,
+        label: Option<String>,
+        cooling_off: Duration,
+    ) -> Self {
+        let created = Utc::now();
+        Self {
+            id: Id(Uuid::new_v4()),
+            user_id,
+            address,
+            label: label.map(|label| label.chars().take(MAX_LABEL_CHARS).collect()),
+            created,
+            usable_after: created + cooling_off,
+            confirmed: None,
+            removed: None,
+        }
+    }
+
+    /// True if withdrawals to the address are allowed right now.
+    pub fn is_usable(&self) -> bool {
+        self.confirmed.is_some() && self.removed.is_none() && self.usable_after <= Utc::now()
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.removed.is_some()
+    }
+
+    /// Marks the address as confirmed by the user. Confirming an already confirmed address does
+    /// nothing.
+    pub(crate) fn confirm(&mut self) -> Result<(), Error> {
+        if self.is_removed() {
+            return Err(Error::Removed);
+        }
+        if self.confirmed.is_none() {
+            self.confirmed = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    /// Removes the address from the allowlist right away. Removing an already removed address does
+    /// nothing.
+    pub(crate) fn remove(&mut self) {
+        if self.removed.is_none() {
+            self.removed = Some(Utc::now());
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use crate::{
+    auth, btc,
+    database::{self, Database},
+    user,
+};
+use std::time::Duration;
+
+mod entities;
+
+pub use entities::{AllowedAddress, Allowlist, Error, Id, NotAllowed};
+
+const MAX_ADDRESSES_PER_USER: usize = 100;
+
+pub async fn get(grant: &auth::ReadGrant, db: &Database) -> Allowlist {
+    queries::get(db, grant.user_id).await
+}
+
+/// Lists the allowed addresses of the user, including removed ones, most recent first.
+pub async fn list(grant: &auth::ReadGrant, db: &Database) -> Vec<AllowedAddress> {
+    queries::list(db, grant.user_id).await
+}
+
+/// Adds the address to the allowlist. It can be used once the cooling-off period is over and the
+/// user confirmed it with [`confirm`].
+pub async fn add(
+    grant: &auth::AdminGrant,
+    db: &Database,
+    address: &btc::Address,
+    label: Option<String>,
+    cooling_off: Duration,
+) -> Result<AllowedAddress, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    // Lock the user's addresses, so that concurrent requests can't add the same address twice
+    let addresses = queries::lock_active_for_user(&mut data_tx, grant.user_id).await;
+    if addresses.iter().any(|allowed| &allowed.address == address) {
+        return Err(Error::AlreadyAllowed);
+    }
+    if addresses.len() >= MAX_ADDRESSES_PER_USER {
+        return Err(Error::TooManyAddresses);
+    }
+    let allowed = AllowedAddress::create(
+        grant.user_id,
+        address.clone(),
+        label,
+        chrono::Duration::from_std(cooling_off).unwrap(),
+    );
+    queries::upsert(&mut data_tx, &allowed).await;
+    data_tx.commit().await.unwrap();
+    Ok(allowed)
+}
+
+/// Confirms the address with the user's password, which a leaked token doesn't give away. Users
+/// created by an operator don't have a password, their addresses are confirmed by the operator.
+pub async fn confirm(
+    grant: &auth::AdminGrant,
+    db: &Database,
+    id: Id,
+    password: user::Password,
+) -> Result<AllowedAddress, Error> {
+    user::verify_password(db, grant.user_id, password).await?;
+    let mut data_tx = db.begin().await.unwrap();
+    let mut allowed = queries::lock(&mut data_tx, id, Some(grant.user_id))
+        .await
+        .ok_or(Error::NotFound)?;
+    allowed.confirm()?;
+    queries::upsert(&mut data_tx, &allowed).await;
+    data_tx.commit().await.unwrap();
+    Ok(allowed)
+}
+
+/// Removes the address from the allowlist. Unlike adding an address, this takes effect right away.
+pub async fn remove(
+    grant: &auth::AdminGrant,
+    db: &Database,
+    id: Id,
+) -> Result<AllowedAddress, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut allowed = queries::lock(&mut data_tx, id, Some(grant.user_id))
+        .await
+        .ok_or(Error::NotFound)?;
+    allowed.remove();
+    queries::upsert(&mut data_tx, &allowed).await;
+    data_tx.commit().await.unwrap();
+    Ok(allowed)
+}
+
+/// Limits the user's withdrawals to the usable allowed addresses, starting right away.
+pub async fn enable(grant: &auth::AdminGrant, db: &Database) -> Allowlist {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut allowlist = queries::lock_allowlist(&mut data_tx, grant.user_id).await;
+    allowlist.enable();
+    queries::upsert_allowlist(&mut data_tx, &allowlist).await;
+    data_tx.commit().await.unwrap();
+    allowlist
+}
+
+/// Turns the allowlist off with the user's password. Withdrawals are still limited to the allowed
+/// addresses until the cooling-off period is over.
+pub async fn disable(
+    grant: &auth::AdminGrant,
+    db: &Database,
+    password: user::Password,
+    cooling_off: Duration,
+) -> Result<Allowlist, Error> {
+    user::verify_password(db, grant.user_id, password).await?;
+    let mut data_tx = db.begin().await.unwrap();
+    let mut allowlist = queries::lock_allowlist(&mut data_tx, grant.user_id).await;
+    allowlist.disable(chrono::Duration::from_std(cooling_off).unwrap());
+    queries::upsert_allowlist(&mut data_tx, &allowlist).await;
+    data_tx.commit().await.unwrap();
+    Ok(allowlist)
+}
+
+/// Checks that the user may withdraw to the address, i.e. that the allowlist isn't enforced or
+/// that the address is usable. Call this in the transaction which starts the withdrawal.
+pub(crate) async fn check(
+    data_tx: &mut database::Transaction,
+    user_id: user::Id,
+    address: &btc::Address,
+) -> Result<(), NotAllowed> {
+    if !queries::get_in_tx(data_tx, user_id).await.is_enforced() {
+        return Ok(());
+    }
+    let is_usable = queries::list_active_for_user(data_tx, user_id)
+        .await
+        .iter()
+        .any(|allowed| &allowed.address == address && allowed.is_usable());
+    if is_usable {
+        Ok(())
+    } else {
+        Err(NotAllowed)
+    }
+}
+
+pub(crate) async fn list_for_user(db: &Database, user_id: user::Id) -> Vec<AllowedAddress> {
+    queries::list(db, user_id).await
+}
+
+/// Confirms the address on behalf of a user, once the operator made sure the user asked for it.
+pub(crate) async fn confirm_for_user(db: &Database, id: Id) -> Result<AllowedAddress, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut allowed = queries::lock(&mut data_tx, id, None)
+        .await
+        .ok_or(Error::NotFound)?;
+    allowed.confirm()?;
+    queries::upsert(&mut data_tx, &allowed).await;
+    data_tx.commit().await.unwrap();
+    Ok(allowed)
+}
+
+/// Turns the allowlist of the user off right away, without a cooling-off period.
+pub(crate) async fn disable_for_user(db: &Database, user_id: user::Id) -> Allowlist {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut allowlist = queries::lock_allowlist(&mut data_tx, user_id).await;
+    allowlist.disable(chrono::Duration::zero());
+    queries::upsert_allowlist(&mut data_tx, &allowlist).await;
+    data_tx.commit().await.unwrap();
+    allowlist
+}
+
+mod queries {
+    use super::{AllowedAddress, Allowlist, Id};
+    use crate::{
+        btc,
+        database::{self, Database},
+        user,
+    };
+    use chrono::{DateTime, Utc};
+    use const_format::formatcp;
+    use std::str::FromStr;
+    use uuid::Uuid;
+
+    const COLUMNS: &str = "id, user_id, address, label, created, usable_after, confirmed, removed";
+
+    pub(super) async fn upsert(data_tx: &mut database::Transaction, allowed: &AllowedAddress) {
+        sqlx::query(formatcp!(
+            r#"INSERT INTO allowed_addresses ({})
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (id) DO UPDATE SET
+                user_id = $2, address = $3, label = $4, created = $5, usable_after = $6, confirmed = $7, removed = $8"#,
+            COLUMNS
+        ))
+        .bind(allowed.id.0)
+        .bind(allowed.user_id.0)
+        .bind(allowed.address.to_string())
+        .bind(allowed.label.clone())
+        .bind(allowed.created)
+        .bind(allowed.usable_after)
+        .bind(allowed.confirmed)
+        .bind(allowed.removed)
+        .execute(data_tx)
+        .await
+        .unwrap();
+    }
+
+    /// Locks the address. Without a user id, the address of any user is locked.
+    pub(super) async fn lock(
+        data_tx: &mut database::Transaction,
+        id: Id,
+        user_id: Option<user::Id>,
+    ) -> Option<AllowedAddress> {
+        sqlx::query_as::<_, AllowedAddressRow>(formatcp!(
+            r#"SELECT {} FROM allowed_addresses
+                WHERE id = $1 AND ($2::UUID IS NULL OR user_id = $2) FOR UPDATE"#,
+            COLUMNS
+        ))
+        .bind(id.0)
+        .bind(user_id.map(|user_id| user_id.0))
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn list(db: &Database, user_id: user::Id) -> Vec<AllowedAddress> {
+        sqlx::query_as::<_, AllowedAddressRow>(formatcp!(
+            "SELECT {} FROM allowed_addresses WHERE user_id = $1 ORDER BY created DESC",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn list_active_for_user(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> Vec<AllowedAddress> {
+        sqlx::query_as::<_, AllowedAddressRow>(formatcp!(
+            "SELECT {} FROM allowed_addresses WHERE user_id = $1 AND removed IS NULL",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn lock_active_for_user(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> Vec<AllowedAddress> {
+        sqlx::query_as::<_, AllowedAddressRow>(formatcp!(
+            "SELECT {} FROM allowed_addresses WHERE user_id = $1 AND removed IS NULL FOR UPDATE",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn get(db: &Database, user_id: user::Id) -> Allowlist {
+        sqlx::query_as::<_, AllowlistRow>(
+            "SELECT user_id, enabled, disabled FROM allowlists WHERE user_id = $1",
+        )
+        .bind(user_id.0)
+        .fetch_optional(db)
+        .await
+        .unwrap()
+        .map_or_else(|| Allowlist::new(user_id), |row| row.into_entity())
+    }
+
+    pub(super) async fn get_in_tx(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> Allowlist {
+        sqlx::query_as::<_, AllowlistRow>(
+            "SELECT user_id, enabled, disabled FROM allowlists WHERE user_id = $1",
+        )
+        .bind(user_id.0)
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map_or_else(|| Allowlist::new(user_id), |row| row.into_entity())
+    }
+
+    /// Locks the allowlist of the user. If the user never turned the allowlist on, there is nothing
+    /// to lock yet, and a new allowlist is returned.
+    pub(super) async fn lock_allowlist(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> Allowlist {
+        sqlx::query_as::<_, AllowlistRow>(
+            "SELECT user_id, enabled, disabled FROM allowlists WHERE user_id = $1 FOR UPDATE",
+        )
+        .bind(user_id.0)
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map_or_else(|| Allowlist::new(user_id), |row| row.into_entity())
+    }
+
+    pub(super) async fn upsert_allowlist(
+        data_tx: &mut database::Transaction,
+        allowlist: &Allowlist,
+    ) {
+        sqlx::query(
+            r#"INSERT INTO allowlists (user_id, enabled, disabled) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id) DO UPDATE SET enabled = $2, disabled = $3"#,
+        )
+        .bind(allowlist.user_id.0)
+        .bind(allowlist.enabled)
+        .bind(allowlist.disabled)
+        .execute(data_tx)
+        .await
+        .unwrap();
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct AllowedAddressRow {
+        id: Uuid,
+        user_id: Uuid,
+        address: String,
+        label: Option<String>,
+        created: DateTime<Utc>,
+        usable_after: DateTime<Utc>,
+        confirmed: Option<DateTime<Utc>>,
+        removed: Option<DateTime<Utc>>,
+    }
+
+    impl AllowedAddressRow {
+        fn into_entity(self) -> AllowedAddress {
+            AllowedAddress {
+                id: Id(self.id),
+                user_id: user::Id(self.user_id),
+                address: btc::Address::from_str(&self.address).unwrap(),
+                label: self.label,
+                created: self.created,
+                usable_after: self.usable_after,
+                confirmed: self.confirmed,
+                removed: self.removed,
+            }
+        }
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct AllowlistRow {
+        user_id: Uuid,
+        enabled: Option<DateTime<Utc>>,
+        disabled: Option<DateTime<Utc>>,
+    }
+
+    impl AllowlistRow {
+        fn into_entity(self) -> Allowlist {
+            Allowlist {
+                user_id: user::Id(self.user_id),
+                enabled: self.enabled,
+                disabled: self.disabled,
+            }
+        }
+    }
+}
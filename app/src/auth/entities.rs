@@ -1,10 +1,13 @@
 //! Handles user authentication, authorization, and tokens. Authentication is proven by possession
-//! of a token; authorization is proven by possession of a grant. There are two different grants:
-//! spend and receive, and they're encoded as two separate types in the type system.
+//! of a token; authorization is proven by possession of a grant. There are four different grants:
+//! spend, receive, read and admin, and they're encoded as separate types in the type system.
 
 use crate::{hex::Hex, user};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use const_format::formatcp;
+use rand::Rng;
 use sha2::Digest;
+use std::str::FromStr;
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -12,9 +15,27 @@ use uuid::Uuid;
 #[error("access denied")]
 pub struct AccessDenied;
 
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("token not found")]
+    NotFound,
+    #[error("token name already taken")]
+    NameTaken,
+    #[error("invalid name: {0}")]
+    InvalidName(&'static str),
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct TokenId(pub Uuid);
 
+impl FromStr for TokenId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::from_str(s).map(Self)
+    }
+}
+
 /// This grant represents a compile-time proof that the token is authorized to spend funds.
 #[derive(Debug)]
 pub struct SpendGrant {
@@ -36,11 +57,39 @@ pub struct ReadGrant {
     pub user_id: user::Id,
 }
 
+/// This grant represents a compile-time proof that the token is authorized to manage the tokens of
+/// its user.
+#[derive(Debug)]
+pub struct AdminGrant {
+    pub token_id: TokenId,
+    pub user_id: user::Id,
+}
+
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct Permissions {
+pub struct Permissions {
     pub can_spend: bool,
     pub can_receive: bool,
     pub can_read: bool,
+    pub can_admin: bool,
+}
+
+/// The plaintext token, as given to the user. Only the [`TokenHash`] is stored, so the secret is
+/// known only at the moment the token is created.
+pub struct TokenSecret(String);
+
+impl TokenSecret {
+    const NUM_BYTES: usize = 32;
+
+    fn generate() -> Self {
+        let bytes: Vec<u8> = (0..Self::NUM_BYTES)
+            .map(|_| rand::thread_rng().gen())
+            .collect();
+        Self(Hex::encode(&bytes).as_str().to_owned())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 /// A hash of the token.
@@ -68,15 +117,78 @@ impl TokenHash {
 /// different or same permissions.
 #[derive(Debug)]
 pub struct Token {
-    pub(crate) id: TokenId,
-    pub(crate) user_id: user::Id,
-    pub(crate) permissions: Permissions,
-    pub(crate) disabled: Option<DateTime<Utc>>,
+    pub id: TokenId,
+    pub user_id: user::Id,
+    pub name: String,
+    pub permissions: Permissions,
+    pub created: DateTime<Utc>,
+    pub disabled: Option<DateTime<Utc>>,
+    /// Session tokens expire, other tokens are valid until they're disabled.
+    pub expires: Option<DateTime<Utc>>,
 }
 
+const MAX_NAME_CHARS: usize = 100;
+const SESSION_DAYS: i64 = 30;
+
 impl Token {
+    /// Creates a new token with a randomly generated secret. The secret should be handed to the
+    /// user right away, since it can't be recovered later.
+    pub(crate) fn create(
+        user_id: user::Id,
+        name: String,
+        permissions: Permissions,
+    ) -> Result<(Self, TokenSecret), Error> {
+        validate_name(&name)?;
+        let token = Self {
+            id: TokenId(Uuid::new_v4()),
+            user_id,
+            name,
+            permissions,
+            created: Utc::now(),
+            disabled: None,
+            expires: None,
+        };
+        Ok((token, TokenSecret::generate()))
+    }
+
+    /// Creates a token with all permissions for a user who logged in with their password. The
+    /// token expires after a while.
+    pub(crate) fn create_session(user_id: user::Id) -> (Self, TokenSecret) {
+        let id = TokenId(Uuid::new_v4());
+        let created = Utc::now();
+        let token = Self {
+            id,
+            user_id,
+            name: format!("session-{}", id.0),
+            permissions: Permissions {
+                can_spend: true,
+                can_receive: true,
+                can_read: true,
+                can_admin: true,
+            },
+            created,
+            disabled: None,
+            expires: Some(created + Duration::days(SESSION_DAYS)),
+        };
+        (token, TokenSecret::generate())
+    }
+
+    pub(crate) fn rename(&mut self, name: String) -> Result<(), Error> {
+        validate_name(&name)?;
+        self.name = name;
+        Ok(())
+    }
+
+    /// Disables the token. Disabled tokens don't grant any permissions.
+    pub(crate) fn disable(&mut self) {
+        if !self.is_enabled() {
+            panic!("token {:?} has already been disabled", self.id);
+        }
+        self.disabled = Some(Utc::now());
+    }
+
     pub(crate) fn spend_grant(&self) -> Result<SpendGrant, AccessDenied> {
-        if self.is_enabled() && self.permissions.can_spend {
+        if self.is_usable() && self.permissions.can_spend {
             Ok(SpendGrant {
                 token_id: self.id,
                 user_id: self.user_id,
@@ -87,7 +199,7 @@ impl Token {
     }
 
     pub(crate) fn receive_grant(&self) -> Result<ReceiveGrant, AccessDenied> {
-        if self.is_enabled() && self.permissions.can_receive {
+        if self.is_usable() && self.permissions.can_receive {
             Ok(ReceiveGrant {
                 token_id: self.id,
                 user_id: self.user_id,
@@ -98,7 +210,7 @@ impl Token {
     }
 
     pub(crate) fn read_grant(&self) -> Result<ReadGrant, AccessDenied> {
-        if self.is_enabled() && self.permissions.can_read {
+        if self.is_usable() && self.permissions.can_read {
             Ok(ReadGrant {
                 token_id: self.id,
                 user_id: self.user_id,
@@ -108,7 +220,39 @@ impl Token {
         }
     }
 
-    fn is_enabled(&self) -> bool {
+    pub(crate) fn admin_grant(&self) -> Result<AdminGrant, AccessDenied> {
+        if self.is_usable() && self.permissions.can_admin {
+            Ok(AdminGrant {
+                token_id: self.id,
+                user_id: self.user_id,
+            })
+        } else {
+            Err(AccessDenied)
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
         self.disabled.is_none()
     }
+
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires, Some(expires) if expires <= Utc::now())
+    }
+
+    fn is_usable(&self) -> bool {
+        self.is_enabled() && !self.is_expired()
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        Err(Error::InvalidName("name can't be empty"))
+    } else if name.chars().count() > MAX_NAME_CHARS {
+        Err(Error::InvalidName(formatcp!(
+            "name can be up to {} characters long",
+            MAX_NAME_CHARS
+        )))
+    } else {
+        Ok(())
+    }
 }
@@ -1,8 +1,11 @@
-use crate::database::Database;
+use crate::{database::Database, user};
 
 mod entities;
 
-pub use entities::{AccessDenied, ReadGrant, ReceiveGrant, SpendGrant, TokenHash, TokenId};
+pub use entities::{
+    AccessDenied, AdminGrant, Error, Permissions, ReadGrant, ReceiveGrant, SpendGrant, Token,
+    TokenHash, TokenId, TokenSecret,
+};
 
 pub async fn get_spend_grant(db: &Database, token: &str) -> Result<SpendGrant, AccessDenied> {
     queries::get_token(db, token)
@@ -25,19 +28,123 @@ pub async fn get_read_grant(db: &Database, token: &str) -> Result<ReadGrant, Acc
         .read_grant()
 }
 
+pub async fn get_admin_grant(db: &Database, token: &str) -> Result<AdminGrant, AccessDenied> {
+    queries::get_token(db, token)
+        .await
+        .ok_or(AccessDenied)?
+        .admin_grant()
+}
+
+/// Creates a new token for the user. The returned secret is the only copy of the token.
+pub async fn create(
+    grant: &AdminGrant,
+    db: &Database,
+    name: String,
+    permissions: Permissions,
+) -> Result<(Token, TokenSecret), Error> {
+    create_for_user(db, grant.user_id, name, permissions).await
+}
+
+pub async fn get(grant: &AdminGrant, db: &Database, id: TokenId) -> Option<Token> {
+    queries::get(db, id, grant.user_id).await
+}
+
+pub async fn list(grant: &AdminGrant, db: &Database) -> Vec<Token> {
+    list_for_user(db, grant.user_id).await
+}
+
+pub async fn rename(
+    grant: &AdminGrant,
+    db: &Database,
+    id: TokenId,
+    name: String,
+) -> Result<Token, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut token = queries::lock(&mut data_tx, id, Some(grant.user_id))
+        .await
+        .ok_or(Error::NotFound)?;
+    token.rename(name)?;
+    queries::update(&mut data_tx, &token).await?;
+    data_tx.commit().await.unwrap();
+    Ok(token)
+}
+
+/// Disables the token. Revoking an already disabled token does nothing.
+pub async fn revoke(grant: &AdminGrant, db: &Database, id: TokenId) -> Result<Token, Error> {
+    revoke_impl(db, id, Some(grant.user_id)).await
+}
+
+/// Logs in the user, who must have been authenticated with [`user::authenticate`] or just
+/// registered. The returned secret is the only copy of the session token.
+pub async fn create_session(db: &Database, user: &user::User) -> (Token, TokenSecret) {
+    let (token, secret) = Token::create_session(user.id);
+    let mut data_tx = db.begin().await.unwrap();
+    queries::insert(&mut data_tx, &token, &TokenHash::generate(secret.as_str()))
+        .await
+        .unwrap();
+    data_tx.commit().await.unwrap();
+    (token, secret)
+}
+
+pub(crate) async fn create_for_user(
+    db: &Database,
+    user_id: user::Id,
+    name: String,
+    permissions: Permissions,
+) -> Result<(Token, TokenSecret), Error> {
+    let (token, secret) = Token::create(user_id, name, permissions)?;
+    let mut data_tx = db.begin().await.unwrap();
+    queries::insert(&mut data_tx, &token, &TokenHash::generate(secret.as_str())).await?;
+    data_tx.commit().await.unwrap();
+    Ok((token, secret))
+}
+
+pub(crate) async fn list_for_user(db: &Database, user_id: user::Id) -> Vec<Token> {
+    queries::list(db, user_id).await
+}
+
+/// Disables any user's token.
+pub(crate) async fn disable(db: &Database, id: TokenId) -> Result<Token, Error> {
+    revoke_impl(db, id, None).await
+}
+
+async fn revoke_impl(
+    db: &Database,
+    id: TokenId,
+    user_id: Option<user::Id>,
+) -> Result<Token, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut token = queries::lock(&mut data_tx, id, user_id)
+        .await
+        .ok_or(Error::NotFound)?;
+    if token.is_enabled() {
+        token.disable();
+        queries::update(&mut data_tx, &token).await?;
+    }
+    data_tx.commit().await.unwrap();
+    Ok(token)
+}
+
 mod queries {
     use super::entities::{Permissions, Token};
-    use super::{TokenHash, TokenId};
-    use crate::{database::Database, user};
+    use super::{Error, TokenHash, TokenId};
+    use crate::{
+        database::{self, Database},
+        user,
+    };
     use chrono::{DateTime, Utc};
+    use const_format::formatcp;
     use uuid::Uuid;
 
+    const COLUMNS: &str =
+        "id, user_id, name, can_spend, can_receive, can_read, can_admin, created, disabled, expires";
+
     pub(super) async fn get_token(db: &Database, token: &str) -> Option<Token> {
         let token_hash = TokenHash::generate(token);
-        sqlx::query_as::<_, TokenRow>(
-            r#"SELECT id, user_id, can_spend, can_receive, can_read, disabled FROM auth_tokens
-                WHERE token_hash = $1"#,
-        )
+        sqlx::query_as::<_, TokenRow>(formatcp!(
+            "SELECT {} FROM auth_tokens WHERE token_hash = $1",
+            COLUMNS
+        ))
         .bind(token_hash.as_str())
         .fetch_optional(db)
         .await
@@ -45,14 +152,115 @@ mod queries {
         .map(|row| row.into_entity())
     }
 
+    pub(super) async fn get(db: &Database, id: TokenId, user_id: user::Id) -> Option<Token> {
+        sqlx::query_as::<_, TokenRow>(formatcp!(
+            "SELECT {} FROM auth_tokens WHERE id = $1 AND user_id = $2",
+            COLUMNS
+        ))
+        .bind(id.0)
+        .bind(user_id.0)
+        .fetch_optional(db)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    /// Loads and locks the token. If the user is given, only that user's token is loaded.
+    pub(super) async fn lock(
+        data_tx: &mut database::Transaction,
+        id: TokenId,
+        user_id: Option<user::Id>,
+    ) -> Option<Token> {
+        sqlx::query_as::<_, TokenRow>(formatcp!(
+            "SELECT {} FROM auth_tokens WHERE id = $1 AND ($2::UUID IS NULL OR user_id = $2) FOR UPDATE",
+            COLUMNS
+        ))
+        .bind(id.0)
+        .bind(user_id.map(|user_id| user_id.0))
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn list(db: &Database, user_id: user::Id) -> Vec<Token> {
+        sqlx::query_as::<_, TokenRow>(formatcp!(
+            "SELECT {} FROM auth_tokens WHERE user_id = $1 ORDER BY created DESC",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn insert(
+        data_tx: &mut database::Transaction,
+        token: &Token,
+        token_hash: &TokenHash,
+    ) -> Result<(), Error> {
+        let result = sqlx::query(
+            r#"INSERT INTO auth_tokens (id, user_id, name, token_hash, can_spend, can_receive, can_read, can_admin, created, disabled, expires)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"#,
+        )
+        .bind(token.id.0)
+        .bind(token.user_id.0)
+        .bind(&token.name)
+        .bind(token_hash.as_str())
+        .bind(token.permissions.can_spend)
+        .bind(token.permissions.can_receive)
+        .bind(token.permissions.can_read)
+        .bind(token.permissions.can_admin)
+        .bind(token.created)
+        .bind(token.disabled)
+        .bind(token.expires)
+        .execute(data_tx)
+        .await;
+        check_name_taken(result)
+    }
+
+    pub(super) async fn update(
+        data_tx: &mut database::Transaction,
+        token: &Token,
+    ) -> Result<(), Error> {
+        let result = sqlx::query("UPDATE auth_tokens SET name = $2, disabled = $3 WHERE id = $1")
+            .bind(token.id.0)
+            .bind(&token.name)
+            .bind(token.disabled)
+            .execute(data_tx)
+            .await;
+        check_name_taken(result)
+    }
+
+    fn check_name_taken<T>(result: Result<T, sqlx::Error>) -> Result<(), Error> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.to_string().to_lowercase().contains(
+                    "duplicate key value violates unique constraint \"auth_token_user_id_name\"",
+                ) =>
+            {
+                Err(Error::NameTaken)
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
     #[derive(Debug, sqlx::FromRow)]
     struct TokenRow {
         id: Uuid,
         user_id: Uuid,
+        name: String,
         can_spend: bool,
         can_receive: bool,
         can_read: bool,
+        can_admin: bool,
+        created: DateTime<Utc>,
         disabled: Option<DateTime<Utc>>,
+        expires: Option<DateTime<Utc>>,
     }
 
     impl TokenRow {
@@ -60,12 +268,16 @@ mod queries {
             Token {
                 id: TokenId(self.id),
                 user_id: user::Id(self.user_id),
+                name: self.name,
                 permissions: Permissions {
                     can_spend: self.can_spend,
                     can_receive: self.can_receive,
                     can_read: self.can_read,
+                    can_admin: self.can_admin,
                 },
+                created: self.created,
                 disabled: self.disabled,
+                expires: self.expires,
             }
         }
     }
@@ -10,11 +10,16 @@
 //! - commit the reservation to the database before moving on
 //! - execute the actual irrevocable operation, as described above
 //! - if something fails in an unrecoverable way, call [`Reservation::refund`], which returns the
-//! funds to the user and marks the reservation as refunded
+//!   funds to the user and marks the reservation as refunded
 //! - if all goes correctly, call [`Reservation::debit`], which marks the reservation as final and
-//! completed; the funds cannot be returned to the user after this.
+//!   completed; the funds cannot be returned to the user after this.
+//!
+//! Every change is also recorded as a journal entry in the ledger, see [`crate::ledger`]. The
+//! entries are saved by [`super::update`] and [`super::upsert_reservation`] together with the
+//! changes they explain.
 
 use crate::btc;
+use crate::ledger::{Account, EntryKind, JournalEntry, Line};
 use crate::user;
 use chrono::DateTime;
 use chrono::Utc;
@@ -36,6 +41,8 @@ pub struct Balance {
     user_id: user::Id,
     original_amount: btc::MilliSats,
     amount: btc::MilliSats,
+    /// Journal entries explaining the updates, not saved yet.
+    journal: Vec<JournalEntry>,
 }
 
 impl Balance {
@@ -44,6 +51,7 @@ impl Balance {
             user_id,
             original_amount: amount,
             amount,
+            journal: Vec::new(),
         }
     }
 
@@ -63,22 +71,154 @@ impl Balance {
         self.original_amount != self.amount
     }
 
-    pub fn credit(&mut self, amount: btc::MilliSats) {
-        self.amount += amount
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
     }
 
-    /// Debits the user balance and creates a reservation. See [`Reservation`].
-    pub fn reserve(&mut self, amount: btc::MilliSats) -> Result<Reservation, InsufficientBalance> {
-        if amount > self.amount {
+    /// Credits the user balance. The kind and reference explain where the funds come from, e.g.
+    /// [`EntryKind::Invoice`] and the invoice id.
+    pub fn credit(&mut self, amount: btc::MilliSats, kind: EntryKind, reference: Uuid) {
+        self.credit_with_fee(amount, btc::MilliSats(0), kind, reference);
+    }
+
+    /// Credits the user balance and debits our service fee from it in the same journal entry, so
+    /// the fee shows up as part of the credit. The service fee goes to [`Account::Revenue`], and
+    /// must not be higher than the amount.
+    pub fn credit_with_fee(
+        &mut self,
+        amount: btc::MilliSats,
+        service_fee: btc::MilliSats,
+        kind: EntryKind,
+        reference: Uuid,
+    ) {
+        if service_fee > amount {
+            panic!(
+                "service fee {:?} is higher than the credited amount {:?}",
+                service_fee, amount
+            );
+        }
+        self.amount += amount - service_fee;
+        let mut lines = vec![
+            Line {
+                account: kind.counter_account(self.user_id),
+                kind,
+                amount: btc::MilliSats(0) - amount,
+            },
+            Line {
+                account: Account::User(self.user_id),
+                kind,
+                amount,
+            },
+        ];
+        if service_fee != btc::MilliSats(0) {
+            lines.push(Line {
+                account: Account::User(self.user_id),
+                kind: EntryKind::ServiceFee,
+                amount: btc::MilliSats(0) - service_fee,
+            });
+            lines.push(Line {
+                account: Account::Revenue,
+                kind: EntryKind::ServiceFee,
+                amount: service_fee,
+            });
+        }
+        self.journal.push(JournalEntry::new(reference, lines));
+    }
+
+    /// Debits the user balance without a reservation. This is only meant for taking back funds
+    /// which were credited by mistake, e.g. a deposit whose transaction was removed from the
+    /// chain. Spending funds goes through [`Balance::reserve`]. The service fee charged on the
+    /// credit is taken back from [`Account::Revenue`] instead, so only the amount the user got is
+    /// debited.
+    pub fn debit(
+        &mut self,
+        amount: btc::MilliSats,
+        service_fee: btc::MilliSats,
+        kind: EntryKind,
+        reference: Uuid,
+    ) -> Result<(), InsufficientBalance> {
+        let net = amount - service_fee;
+        if net > self.amount {
             return Err(InsufficientBalance);
         }
-        self.amount -= amount;
+        self.amount -= net;
+        self.journal.push(JournalEntry::new(
+            reference,
+            vec![
+                Line {
+                    account: Account::User(self.user_id),
+                    kind,
+                    amount: btc::MilliSats(0) - net,
+                },
+                Line {
+                    account: Account::Revenue,
+                    kind,
+                    amount: btc::MilliSats(0) - service_fee,
+                },
+                Line {
+                    account: kind.counter_account(self.user_id),
+                    kind,
+                    amount,
+                },
+            ],
+        ));
+        Ok(())
+    }
+
+    /// Debits the amount, the fee and our service fee from the user balance and creates a
+    /// reservation. See [`Reservation`]. The kind and reference explain what the funds are for,
+    /// e.g. [`EntryKind::Payment`] and the payment id. Both fees are recorded in the ledger
+    /// separately.
+    pub fn reserve(
+        &mut self,
+        amount: btc::MilliSats,
+        fee: btc::MilliSats,
+        service_fee: btc::MilliSats,
+        kind: EntryKind,
+        reference: Uuid,
+    ) -> Result<Reservation, InsufficientBalance> {
+        let total = amount + fee + service_fee;
+        if total > self.amount {
+            return Err(InsufficientBalance);
+        }
+        self.amount -= total;
+        let mut lines = vec![
+            Line {
+                account: Account::User(self.user_id),
+                kind,
+                amount: btc::MilliSats(0) - amount,
+            },
+            Line {
+                account: Account::Reserved(self.user_id),
+                kind,
+                amount: total,
+            },
+        ];
+        if fee != btc::MilliSats(0) {
+            lines.push(Line {
+                account: Account::User(self.user_id),
+                kind: kind.fee_kind(),
+                amount: btc::MilliSats(0) - fee,
+            });
+        }
+        if service_fee != btc::MilliSats(0) {
+            lines.push(Line {
+                account: Account::User(self.user_id),
+                kind: EntryKind::ServiceFee,
+                amount: btc::MilliSats(0) - service_fee,
+            });
+        }
+        self.journal.push(JournalEntry::new(reference, lines));
         Ok(Reservation {
             id: ReservationId(Uuid::new_v4()),
             user_id: self.user_id,
-            amount,
+            amount: total,
+            service_fee,
+            kind,
+            reference,
             status: ReservationStatus::Pending,
             created: Utc::now(),
+            journal: Vec::new(),
         })
     }
 }
@@ -93,9 +233,18 @@ impl Balance {
 pub struct Reservation {
     pub id: ReservationId,
     pub user_id: user::Id,
+    /// The reserved amount, including the fee and the service fee.
     pub amount: btc::MilliSats,
+    /// Our service fee, part of the amount. It goes to [`Account::Revenue`] once the reservation
+    /// is debited, and back to the user if it's refunded.
+    pub service_fee: btc::MilliSats,
+    /// What the funds are reserved for, see [`Balance::reserve`].
+    pub kind: EntryKind,
+    pub reference: Uuid,
     pub status: ReservationStatus,
     pub created: DateTime<Utc>,
+    /// Journal entries of the status changes, not saved yet.
+    pub(crate) journal: Vec<JournalEntry>,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -119,6 +268,96 @@ impl Reservation {
             );
         }
         self.status = ReservationStatus::Debited;
+        let spent = self.amount - self.service_fee;
+        let mut lines = vec![
+            Line {
+                account: Account::Reserved(self.user_id),
+                kind: self.kind,
+                amount: btc::MilliSats(0) - spent,
+            },
+            Line {
+                account: self.kind.spending_account(),
+                kind: self.kind,
+                amount: spent,
+            },
+        ];
+        if self.service_fee != btc::MilliSats(0) {
+            lines.push(Line {
+                account: Account::Reserved(self.user_id),
+                kind: EntryKind::ServiceFee,
+                amount: btc::MilliSats(0) - self.service_fee,
+            });
+            lines.push(Line {
+                account: Account::Revenue,
+                kind: EntryKind::ServiceFee,
+                amount: self.service_fee,
+            });
+        }
+        self.journal.push(JournalEntry::new(self.reference, lines));
+    }
+
+    /// Credits part of the reserved funds back to the user, e.g. when the fee turns out to be lower
+    /// than reserved. The reservation stays pending with the rest of the funds. The service fee
+    /// can't be released.
+    pub fn release(&mut self, amount: btc::MilliSats, balance: &mut Balance) {
+        if self.status != ReservationStatus::Pending {
+            panic!(
+                "trying to release funds of a {:?} reservation {:?}",
+                self.status, self.id
+            );
+        }
+        if amount > self.amount - self.service_fee {
+            panic!(
+                "trying to release {:?} of reservation {:?} with {:?} and service fee {:?}",
+                amount, self.id, self.amount, self.service_fee
+            );
+        }
+        if self.user_id != balance.user_id() {
+            panic!(
+                "balance user id {:?} does not match reservation {:?} user id {:?}",
+                balance.user_id(),
+                self.id,
+                self.user_id
+            );
+        }
+        self.amount -= amount;
+        balance.credit(amount, EntryKind::Refund, self.reference);
+    }
+
+    /// Debits an extra fee from the user balance and adds it to the reservation, e.g. when the fee
+    /// of a withdrawal is raised after it was sent.
+    pub fn add_fee(
+        &mut self,
+        fee: btc::MilliSats,
+        balance: &mut Balance,
+    ) -> Result<(), InsufficientBalance> {
+        if self.status != ReservationStatus::Pending {
+            panic!(
+                "trying to add a fee to a {:?} reservation {:?}",
+                self.status, self.id
+            );
+        }
+        if self.user_id != balance.user_id() {
+            panic!(
+                "balance user id {:?} does not match reservation {:?} user id {:?}",
+                balance.user_id(),
+                self.id,
+                self.user_id
+            );
+        }
+        if fee > balance.amount {
+            return Err(InsufficientBalance);
+        }
+        balance.amount -= fee;
+        balance.journal.push(JournalEntry::transfer(
+            self.reference,
+            self.kind.fee_kind(),
+            Account::User(self.user_id),
+            Account::Reserved(self.user_id),
+            fee,
+        ));
+        self.amount += fee;
+        Ok(())
     }
 
     /// Credits the funds back to the user, and marks the reservation as finally refunded.
@@ -129,7 +368,15 @@ impl Reservation {
                 self.status, self.id
             );
         }
+        if self.user_id != balance.user_id() {
+            panic!(
+                "balance user id {:?} does not match reservation {:?} user id {:?}",
+                balance.user_id(),
+                self.id,
+                self.user_id
+            );
+        }
         self.status = ReservationStatus::Refunded;
-        balance.credit(self.amount);
+        balance.credit(self.amount, EntryKind::Refund, self.reference);
     }
 }
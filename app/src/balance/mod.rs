@@ -1,6 +1,7 @@
 use crate::btc;
 use crate::concurrency;
 use crate::database;
+use crate::ledger::{self, EntryKind};
 use crate::user;
 use chrono::DateTime;
 use chrono::Utc;
@@ -19,6 +20,21 @@ pub async fn get(data_tx: &mut database::Transaction, user_id: user::Id) -> Bala
         .into_entity()
 }
 
+/// Gets the balance and locks it until the end of the transaction, so that saving it can't
+/// conflict. Use it instead of [`get`] when the transaction can't be retried, e.g. because it
+/// records something that already happened outside of the database.
+pub async fn lock(data_tx: &mut database::Transaction, user_id: user::Id) -> Balance {
+    sqlx::query_as::<_, BalanceRow>(
+        "SELECT id AS user_id, balance_msats FROM users WHERE id = $1 FOR UPDATE",
+    )
+    .bind(user_id.0)
+    .fetch_one(data_tx)
+    .await
+    .unwrap()
+    .into_entity()
+}
+
+/// Saves the balance along with its journal entries.
 pub async fn update(
     data_tx: &mut database::Transaction,
     balance: &Balance,
@@ -30,46 +46,86 @@ pub async fn update(
         .bind(balance.amount().0)
         .bind(balance.user_id().0)
         .bind(balance.original_amount().0)
-        .fetch_optional(data_tx)
+        .fetch_optional(&mut *data_tx)
         .await
         .unwrap()
         .ok_or(concurrency::ConflictError)?;
     }
+    ledger::record(data_tx, balance.journal()).await;
     Ok(())
 }
 
-pub async fn upsert_reservation(data_tx: &mut database::Transaction, reservation: &Reservation) {
-    sqlx::query(
-        r#"INSERT INTO balance_reservations (id, user_id, amount_msats, status, created)
-            VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO UPDATE SET
-            user_id = $2, amount_msats = $3, status = $4, created = $5 WHERE balance_reservations.status = 0
+/// Saves the reservation along with the journal entries of its status changes. A reservation which
+/// has been debited or refunded can't change anymore, so saving it again without any new journal
+/// entries has no effect, while changing it returns a conflict.
+pub async fn upsert_reservation(
+    data_tx: &mut database::Transaction,
+    reservation: &Reservation,
+) -> Result<(), concurrency::ConflictError> {
+    let updated = sqlx::query(
+        r#"INSERT INTO balance_reservations (id, user_id, amount_msats, service_fee_msats, kind, reference_id, status, created)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (id) DO UPDATE SET
+            user_id = $2, amount_msats = $3, service_fee_msats = $4, kind = $5, reference_id = $6, status = $7, created = $8 WHERE balance_reservations.status = 0
             RETURNING id"#,
     )
     .bind(reservation.id.0)
     .bind(reservation.user_id.0)
     .bind(reservation.amount.0)
+    .bind(reservation.service_fee.0)
+    .bind(reservation.kind.as_str())
+    .bind(reservation.reference)
     .bind(match reservation.status {
         ReservationStatus::Pending => 0,
         ReservationStatus::Debited => 1,
         ReservationStatus::Refunded => 2,
     })
     .bind(reservation.created)
-    .fetch_one(data_tx)
+    .fetch_optional(&mut *data_tx)
     .await
     .unwrap();
+    if updated.is_none() {
+        let saved = get_reservation(data_tx, reservation.id).await;
+        if saved.status == reservation.status && reservation.journal.is_empty() {
+            return Ok(());
+        }
+        return Err(concurrency::ConflictError);
+    }
+    ledger::record(data_tx, &reservation.journal).await;
+    Ok(())
 }
 
-pub async fn get_reservation(db: &database::Database, id: ReservationId) -> Reservation {
+pub async fn get_reservation(
+    data_tx: &mut database::Transaction,
+    id: ReservationId,
+) -> Reservation {
     sqlx::query_as::<_, ReservationRow>(
-        "SELECT id, user_id, amount_msats, status, created FROM balance_reservations WHERE id = $1",
+        "SELECT id, user_id, amount_msats, service_fee_msats, kind, reference_id, status, created FROM balance_reservations WHERE id = $1",
     )
     .bind(id.0)
-    .fetch_one(db)
+    .fetch_one(data_tx)
     .await
     .unwrap()
     .into_entity()
 }
 
+/// Lists pending reservations, optionally only for one user, oldest first.
+pub(crate) async fn list_pending_reservations(
+    db: &database::Database,
+    user_id: Option<user::Id>,
+) -> Vec<Reservation> {
+    sqlx::query_as::<_, ReservationRow>(
+        r#"SELECT id, user_id, amount_msats, service_fee_msats, kind, reference_id, status, created FROM balance_reservations
+            WHERE status = 0 AND ($1::UUID IS NULL OR user_id = $1) ORDER BY created"#,
+    )
+    .bind(user_id.map(|user_id| user_id.0))
+    .fetch_all(db)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|row| row.into_entity())
+    .collect()
+}
+
 #[derive(sqlx::FromRow, Debug)]
 struct BalanceRow {
     user_id: Uuid,
@@ -87,6 +143,9 @@ struct ReservationRow {
     id: Uuid,
     user_id: Uuid,
     amount_msats: i64,
+    service_fee_msats: i64,
+    kind: String,
+    reference_id: Uuid,
     status: i32,
     created: DateTime<Utc>,
 }
@@ -97,6 +156,9 @@ impl ReservationRow {
             id: ReservationId(self.id),
             user_id: user::Id(self.user_id),
             amount: btc::MilliSats(self.amount_msats),
+            service_fee: btc::MilliSats(self.service_fee_msats),
+            kind: EntryKind::parse(&self.kind),
+            reference: self.reference_id,
             status: match self.status {
                 0 => ReservationStatus::Pending,
                 1 => ReservationStatus::Debited,
@@ -104,6 +166,7 @@ impl ReservationRow {
                 _ => unreachable!("unknown status number"),
             },
             created: self.created,
+            journal: Vec::new(),
         }
     }
 }
@@ -6,21 +6,30 @@ use std::ops::{Add, AddAssign, Sub, SubAssign};
 compile_error!("mainnet and testnet cannot be enabled at the same time");
 
 #[cfg(feature = "mainnet")]
-const NETWORK: bitcoin::Network = bitcoin::Network::Bitcoin;
+pub(crate) const NETWORK: bitcoin::Network = bitcoin::Network::Bitcoin;
 
 #[cfg(feature = "testnet")]
-const NETWORK: bitcoin::Network = bitcoin::Network::Testnet;
+pub(crate) const NETWORK: bitcoin::Network = bitcoin::Network::Testnet;
 
 #[cfg(all(not(feature = "mainnet"), not(feature = "testnet")))]
-const NETWORK: bitcoin::Network = bitcoin::Network::Regtest;
+pub(crate) const NETWORK: bitcoin::Network = bitcoin::Network::Regtest;
 
 pub use bitcoin::Address;
+pub use bitcoin::BlockHash;
+pub use bitcoin::OutPoint;
 pub use bitcoin::Txid as TxId;
 
 #[derive(Debug, Clone)]
 pub struct Tx {
     pub id: TxId,
     pub block_height: Option<u32>,
+    /// Hash of the block the transaction is in. Only known for transactions coming from the node,
+    /// transactions loaded from the database don't have it.
+    pub block_hash: Option<BlockHash>,
+    /// The outputs spent by the transaction. Two transactions spending the same output conflict,
+    /// e.g. when one replaced the other with a higher fee. Only known for transactions coming from
+    /// the node, like the block hash.
+    pub inputs: Vec<OutPoint>,
 }
 
 impl Tx {
@@ -37,6 +46,10 @@ pub struct TxOut {
     pub amount: Sats,
 }
 
+/// A signed transaction in the raw wire format, ready to be broadcast.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawTx(pub Vec<u8>);
+
 #[derive(Debug, Clone, Copy, Default, PartialOrd, Ord, PartialEq, Eq)]
 pub struct MilliSats(pub i64);
 
@@ -4,7 +4,7 @@ use crate::btc;
 
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
 pub enum Error {
     #[error("amount too low")]
     AmountTooLow,
@@ -14,7 +14,27 @@ pub enum Error {
     DailyLimitExceeded,
 }
 
-#[derive(Debug)]
+impl Error {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Error::AmountTooLow => "amount_too_low",
+            Error::AmountTooHigh => "amount_too_high",
+            Error::DailyLimitExceeded => "daily_limit_exceeded",
+        }
+    }
+
+    /// Parses an error stored with [`Error::as_str`].
+    pub(crate) fn parse(error: &str) -> Self {
+        match error {
+            "amount_too_low" => Error::AmountTooLow,
+            "amount_too_high" => Error::AmountTooHigh,
+            "daily_limit_exceeded" => Error::DailyLimitExceeded,
+            _ => panic!("unknown limits error {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct CashLimits {
     pub min: btc::MilliSats,
     pub max: btc::MilliSats,
@@ -3,13 +3,29 @@ use crate::ln::Lightning;
 use crate::worker;
 use crate::{btc, ln};
 use async_trait::async_trait;
+use std::collections::HashMap;
 use std::time::Duration;
 
+/// How many blocks below the chain tip are checked for reorganizations. Deeper reorganizations
+/// aren't detected.
+const REORG_DEPTH: u32 = 100;
+
 #[async_trait]
 pub trait TxListener: Send {
+    /// Names the listener in the database. Each listener keeps track of the blocks it has gone
+    /// through, so that every listener notices a reorganization which replaced them.
+    const NAME: &'static str;
+
     /// Processes a tx_out. NOTE: This method may be called multiple times for the same tx_out, and
     /// it should be prepared to handle that.
     async fn process(&mut self, tx_out: &btc::TxOut);
+
+    /// Processes a tx_out which was in a block removed by a chain reorganization, and which isn't
+    /// in the new chain. The tx_out is passed unconfirmed: its transaction is either back in the
+    /// mempool or gone for good, e.g. if it was double spent. The listener must save the tx_out as
+    /// unconfirmed, otherwise it's passed again. NOTE: Every listener gets every reorged tx_out,
+    /// so it should ignore the ones it doesn't know about.
+    async fn reorg(&mut self, tx_out: &btc::TxOut);
 }
 
 /// Starts a tx listener.
@@ -21,13 +37,32 @@ pub async fn listen(
 ) {
     worker::start(Worker {
         chain_tip: queries::get_chain_tip(start_height, db).await,
+        db: db.clone(),
         node: lightning.create_node().await,
         listener,
     });
 }
 
+/// Runs the listener over all tx outs from the start height up to the chain tip, once.
+pub async fn scan(
+    start_height: u32,
+    db: &Database,
+    lightning: &Lightning,
+    listener: impl TxListener + 'static,
+) {
+    let mut worker = Worker {
+        chain_tip: start_height,
+        db: db.clone(),
+        node: lightning.create_node().await,
+        listener,
+    };
+    worker::Worker::run(&mut worker).await;
+}
+
 struct Worker<L> {
+    /// The next block to go through.
     chain_tip: u32,
+    db: Database,
     node: ln::Node,
     listener: L,
 }
@@ -35,6 +70,11 @@ struct Worker<L> {
 #[async_trait]
 impl<L: TxListener + 'static> worker::Worker for Worker<L> {
     async fn run(&mut self) {
+        // Saved before going through the blocks, so that every tx out processed below is at or
+        // under the saved height.
+        let height = self.node.get_block_height().await;
+        queries::save_block_height(&self.db, height).await;
+        self.check_reorg(height).await;
         loop {
             let tx_outs = self
                 .node
@@ -52,6 +92,7 @@ impl<L: TxListener + 'static> worker::Worker for Worker<L> {
             for tx_out in tx_outs.iter() {
                 self.listener.process(tx_out).await;
             }
+            queries::save_blocks(&self.db, L::NAME, &tx_outs).await;
             let new_chain_tip = tx_outs
                 .into_iter()
                 .flat_map(|tx_out| tx_out.tx.block_height)
@@ -68,8 +109,92 @@ impl<L: TxListener + 'static> worker::Worker for Worker<L> {
     }
 }
 
+impl<L: TxListener + 'static> Worker<L> {
+    /// Compares the recent blocks and tx_outs we know about with the chain of the node. If a
+    /// reorganization replaced any of them, the tx_outs which aren't confirmed anymore are passed
+    /// to [`TxListener::reorg`], and the chain is scanned again from the fork point.
+    async fn check_reorg(&mut self, height: u32) {
+        let start_height = height.saturating_sub(REORG_DEPTH);
+        let known_blocks = queries::list_blocks(&self.db, L::NAME, start_height).await;
+        let known_tx_outs = queries::list_confirmed_tx_outs(&self.db, start_height).await;
+        if known_blocks.is_empty() && known_tx_outs.is_empty() {
+            return;
+        }
+        let tx_outs = self
+            .node
+            .get_tx_outs(ln::TransactionsQuery {
+                start_height,
+                num_blocks: height - start_height + 1,
+            })
+            .await;
+        let block_hashes: HashMap<u32, btc::BlockHash> = tx_outs
+            .iter()
+            .flat_map(|tx_out| Some((tx_out.tx.block_height?, tx_out.tx.block_hash?)))
+            .collect();
+        let replaced_block = known_blocks
+            .iter()
+            .find(|(height, hash)| block_hashes.get(height) != Some(hash))
+            .map(|(height, _)| *height);
+        let mut moved_tx_outs = Vec::new();
+        let mut reorged_tx_outs = Vec::new();
+        for known in known_tx_outs {
+            let current = tx_outs
+                .iter()
+                .find(|tx_out| tx_out.tx.id == known.tx.id && tx_out.v_out == known.v_out);
+            match current {
+                Some(current) if current.tx.block_height == known.tx.block_height => {}
+                Some(current) if current.tx.is_confirmed() => {
+                    moved_tx_outs.push((known, current.clone()))
+                }
+                _ => reorged_tx_outs.push(known),
+            }
+        }
+        let fork_height = moved_tx_outs
+            .iter()
+            .map(|(known, _)| known)
+            .chain(reorged_tx_outs.iter())
+            .flat_map(|tx_out| tx_out.tx.block_height)
+            .chain(replaced_block)
+            .min();
+        let fork_height = match fork_height {
+            Some(fork_height) => fork_height,
+            None => return,
+        };
+        log::error!(
+            "chain reorganization from block {}, {} tx outs moved to other blocks, {} tx outs are not confirmed anymore: {:?}",
+            fork_height,
+            moved_tx_outs.len(),
+            reorged_tx_outs.len(),
+            reorged_tx_outs
+        );
+        for mut tx_out in reorged_tx_outs {
+            tx_out.tx.block_height = None;
+            self.listener.reorg(&tx_out).await;
+        }
+        for (_, tx_out) in moved_tx_outs {
+            self.listener.process(&tx_out).await;
+        }
+        queries::delete_blocks(&self.db, L::NAME, fork_height).await;
+        self.chain_tip = self.chain_tip.min(fork_height);
+    }
+}
+
 mod queries {
+    use crate::btc;
     use crate::database::{self, Database};
+    use std::str::FromStr;
+
+    /// Saves the height of the most recent block, which is used to count the confirmations of
+    /// transactions.
+    pub(super) async fn save_block_height(db: &Database, height: u32) {
+        sqlx::query(
+            "INSERT INTO chain_tip (height) VALUES ($1) ON CONFLICT (id) DO UPDATE SET height = $1",
+        )
+        .bind(i32::try_from(height).unwrap())
+        .execute(db)
+        .await
+        .unwrap();
+    }
 
     pub(super) async fn get_chain_tip(start_height: u32, db: &Database) -> u32 {
         sqlx::query_as::<_, database::MaxRow<i32>>("SELECT MAX(block_height) AS max FROM tx_outs")
@@ -81,4 +206,105 @@ mod queries {
             .try_into()
             .unwrap()
     }
+
+    /// Saves the hashes of the blocks the tx_outs are in, to detect when they're replaced.
+    pub(super) async fn save_blocks(db: &Database, listener: &str, tx_outs: &[btc::TxOut]) {
+        for tx_out in tx_outs {
+            if let (Some(height), Some(hash)) = (tx_out.tx.block_height, tx_out.tx.block_hash) {
+                sqlx::query(
+                    r#"INSERT INTO blocks (listener, height, hash) VALUES ($1, $2, $3)
+                        ON CONFLICT (listener, height) DO UPDATE SET hash = $3"#,
+                )
+                .bind(listener)
+                .bind(i32::try_from(height).unwrap())
+                .bind(hash.to_string())
+                .execute(db)
+                .await
+                .unwrap();
+            }
+        }
+    }
+
+    pub(super) async fn list_blocks(
+        db: &Database,
+        listener: &str,
+        start_height: u32,
+    ) -> Vec<(u32, btc::BlockHash)> {
+        sqlx::query_as::<_, BlockRow>(
+            "SELECT height, hash FROM blocks WHERE listener = $1 AND height >= $2 ORDER BY height",
+        )
+        .bind(listener)
+        .bind(i32::try_from(start_height).unwrap())
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| {
+            (
+                row.height.try_into().unwrap(),
+                btc::BlockHash::from_str(&row.hash).unwrap(),
+            )
+        })
+        .collect()
+    }
+
+    /// Deletes the blocks from the given height on, after they were replaced by a reorganization.
+    pub(super) async fn delete_blocks(db: &Database, listener: &str, start_height: u32) {
+        sqlx::query("DELETE FROM blocks WHERE listener = $1 AND height >= $2")
+            .bind(listener)
+            .bind(i32::try_from(start_height).unwrap())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    /// Lists all the tx_outs confirmed from the given height on. The table doesn't say which
+    /// listener saved a tx_out, so every listener gets all of them.
+    pub(super) async fn list_confirmed_tx_outs(
+        db: &Database,
+        start_height: u32,
+    ) -> Vec<btc::TxOut> {
+        sqlx::query_as::<_, TxOutRow>(
+            r#"SELECT tx_id, v_out, block_height, address, amount_sats FROM tx_outs
+                WHERE block_height >= $1 ORDER BY block_height"#,
+        )
+        .bind(i32::try_from(start_height).unwrap())
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct BlockRow {
+        height: i32,
+        hash: String,
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct TxOutRow {
+        tx_id: String,
+        v_out: i32,
+        block_height: Option<i32>,
+        address: String,
+        amount_sats: i64,
+    }
+
+    impl TxOutRow {
+        fn into_entity(self) -> btc::TxOut {
+            btc::TxOut {
+                tx: btc::Tx {
+                    id: btc::TxId::from_str(&self.tx_id).unwrap(),
+                    block_height: self.block_height.map(|x| x.try_into().unwrap()),
+                    block_hash: None,
+                    inputs: Vec::new(),
+                },
+                address: btc::Address::from_str(&self.address).unwrap(),
+                v_out: self.v_out.into(),
+                amount: btc::Sats(self.amount_sats),
+            }
+        }
+    }
 }
@@ -0,0 +1,11 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 1,
+        sql: vec![
+            // Users created by an operator don't have a password, they only use tokens
+            r#"ALTER TABLE users ALTER COLUMN password DROP NOT NULL"#,
+        ],
+    }
+}
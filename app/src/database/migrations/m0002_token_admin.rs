@@ -0,0 +1,13 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 2,
+        sql: vec![
+            r#"ALTER TABLE auth_tokens ADD COLUMN can_admin BOOLEAN NOT NULL DEFAULT FALSE"#,
+            // Token names only need to be unique per user, they're chosen by the users themselves
+            r#"ALTER TABLE auth_tokens DROP CONSTRAINT auth_tokens_name_key"#,
+            r#"CREATE UNIQUE INDEX auth_token_user_id_name ON auth_tokens (user_id, name)"#,
+        ],
+    }
+}
@@ -0,0 +1,8 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 3,
+        sql: vec![r#"ALTER TABLE auth_tokens ADD COLUMN expires TIMESTAMP WITH TIME ZONE"#],
+    }
+}
@@ -0,0 +1,12 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 4,
+        sql: vec![
+            // Ready payments used to be saved with the same status as new ones, tell them apart by
+            // the balance reservation which only ready payments have
+            r#"UPDATE payments SET status = 1 WHERE status = 0 AND reservation_id IS NOT NULL"#,
+        ],
+    }
+}
@@ -0,0 +1,19 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 5,
+        sql: vec![
+            r#"
+            CREATE TABLE idempotency_keys (
+                user_id UUID NOT NULL REFERENCES users,
+                key TEXT NOT NULL,
+                operation TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                entity_id UUID,
+                created TIMESTAMP WITH TIME ZONE NOT NULL,
+                PRIMARY KEY (user_id, key)
+            )"#,
+        ],
+    }
+}
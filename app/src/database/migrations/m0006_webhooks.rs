@@ -0,0 +1,42 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 6,
+        sql: vec![
+            r#"
+            CREATE TABLE webhooks (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users,
+                url TEXT NOT NULL,
+                secret TEXT NOT NULL,
+                created TIMESTAMP WITH TIME ZONE NOT NULL,
+                disabled TIMESTAMP WITH TIME ZONE
+            )"#,
+            r#"CREATE INDEX webhook_user_id ON webhooks (user_id)"#,
+            r#"
+            CREATE TABLE webhook_events (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users,
+                kind TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                created TIMESTAMP WITH TIME ZONE NOT NULL
+            )"#,
+            r#"
+            CREATE TABLE webhook_deliveries (
+                id UUID PRIMARY KEY,
+                webhook_id UUID NOT NULL REFERENCES webhooks,
+                event_id UUID NOT NULL REFERENCES webhook_events,
+                attempts INTEGER NOT NULL,
+                next_attempt TIMESTAMP WITH TIME ZONE,
+                last_attempt TIMESTAMP WITH TIME ZONE,
+                last_response_status INTEGER,
+                last_error TEXT,
+                delivered TIMESTAMP WITH TIME ZONE,
+                created TIMESTAMP WITH TIME ZONE NOT NULL
+            )"#,
+            r#"CREATE INDEX webhook_delivery_next_attempt ON webhook_deliveries (next_attempt) WHERE next_attempt IS NOT NULL"#,
+            r#"CREATE INDEX webhook_delivery_webhook_id_created ON webhook_deliveries (webhook_id, created)"#,
+        ],
+    }
+}
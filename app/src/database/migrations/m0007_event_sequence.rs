@@ -0,0 +1,26 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 7,
+        sql: vec![
+            r#"ALTER TABLE webhook_events RENAME TO events"#,
+            r#"ALTER TABLE events ADD COLUMN sequence BIGINT"#,
+            r#"
+            UPDATE events SET sequence = numbered.sequence FROM (
+                SELECT id, ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY created) AS sequence
+                FROM events
+            ) AS numbered WHERE events.id = numbered.id"#,
+            r#"ALTER TABLE events ALTER COLUMN sequence SET NOT NULL"#,
+            r#"CREATE UNIQUE INDEX event_user_id_sequence ON events (user_id, sequence)"#,
+            r#"
+            CREATE TABLE event_sequences (
+                user_id UUID PRIMARY KEY REFERENCES users,
+                last_sequence BIGINT NOT NULL
+            )"#,
+            r#"
+            INSERT INTO event_sequences (user_id, last_sequence)
+                SELECT user_id, MAX(sequence) FROM events GROUP BY user_id"#,
+        ],
+    }
+}
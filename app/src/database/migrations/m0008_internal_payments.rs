@@ -0,0 +1,8 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 8,
+        sql: vec![r#"ALTER TABLE payments ADD COLUMN internal BOOLEAN NOT NULL DEFAULT FALSE"#],
+    }
+}
@@ -0,0 +1,48 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 9,
+        sql: vec![
+            r#"
+            CREATE TABLE ledger_entries (
+                id UUID PRIMARY KEY,
+                journal_id UUID NOT NULL,
+                reference_id UUID NOT NULL,
+                account TEXT NOT NULL,
+                user_id UUID REFERENCES users,
+                kind TEXT NOT NULL,
+                amount_msats BIGINT NOT NULL,
+                created TIMESTAMP WITH TIME ZONE NOT NULL
+            )"#,
+            r#"CREATE INDEX ledger_entry_user_id ON ledger_entries (user_id, created)"#,
+            r#"CREATE INDEX ledger_entry_journal_id ON ledger_entries (journal_id)"#,
+            r#"ALTER TABLE balance_reservations ADD COLUMN kind TEXT"#,
+            r#"ALTER TABLE balance_reservations ADD COLUMN reference_id UUID"#,
+            r#"
+            UPDATE balance_reservations SET
+                kind = CASE WHEN payments.internal THEN 'internal_payment' ELSE 'payment' END,
+                reference_id = payments.id
+            FROM payments WHERE payments.reservation_id = balance_reservations.id"#,
+            r#"
+            UPDATE balance_reservations SET kind = 'withdrawal', reference_id = withdrawals.id
+            FROM withdrawals WHERE withdrawals.reservation_id = balance_reservations.id"#,
+            r#"ALTER TABLE balance_reservations ALTER COLUMN kind SET NOT NULL"#,
+            r#"ALTER TABLE balance_reservations ALTER COLUMN reference_id SET NOT NULL"#,
+            // The funds users have before the ledger is introduced are moved in from the opening
+            // account, with one journal entry per user. The user id doubles as the journal id.
+            r#"
+            INSERT INTO ledger_entries (id, journal_id, reference_id, account, user_id, kind, amount_msats, created)
+                SELECT md5(users.id::TEXT || 'user')::UUID, users.id, users.id, 'user', users.id, 'opening', users.balance_msats, NOW()
+                FROM users WHERE users.balance_msats <> 0"#,
+            r#"
+            INSERT INTO ledger_entries (id, journal_id, reference_id, account, user_id, kind, amount_msats, created)
+                SELECT md5(user_id::TEXT || 'reserved')::UUID, user_id, user_id, 'reserved', user_id, 'opening', SUM(amount_msats), NOW()
+                FROM balance_reservations WHERE status = 0 GROUP BY user_id HAVING SUM(amount_msats) <> 0"#,
+            r#"
+            INSERT INTO ledger_entries (id, journal_id, reference_id, account, user_id, kind, amount_msats, created)
+                SELECT md5(journal_id::TEXT || 'opening')::UUID, journal_id, journal_id, 'opening', NULL, 'opening', -SUM(amount_msats), NOW()
+                FROM ledger_entries GROUP BY journal_id"#,
+        ],
+    }
+}
@@ -0,0 +1,10 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 10,
+        sql: vec![
+            r#"CREATE INDEX balance_reservation_reference_id ON balance_reservations (reference_id)"#,
+        ],
+    }
+}
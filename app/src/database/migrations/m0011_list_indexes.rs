@@ -0,0 +1,14 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 11,
+        sql: vec![
+            r#"CREATE INDEX payment_user_id_created ON payments (user_id, created, id)"#,
+            r#"CREATE INDEX invoice_user_id_created ON invoices (user_id, created, id)"#,
+            r#"CREATE INDEX deposit_user_id_created ON deposits (user_id, created, id)"#,
+            r#"CREATE INDEX deposit_address_user_id_created ON deposit_addresses (user_id, created, address)"#,
+            r#"CREATE INDEX withdrawal_user_id_created ON withdrawals (user_id, created, id)"#,
+        ],
+    }
+}
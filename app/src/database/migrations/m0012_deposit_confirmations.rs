@@ -0,0 +1,16 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 12,
+        sql: vec![
+            r#"CREATE TABLE chain_tip (
+                id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+                height INTEGER NOT NULL
+            )"#,
+            // Deposits used to be confirmed at the first confirmation
+            r#"ALTER TABLE deposits ADD COLUMN required_confirmations INTEGER NOT NULL DEFAULT 1"#,
+            r#"ALTER TABLE deposits ALTER COLUMN required_confirmations DROP DEFAULT"#,
+        ],
+    }
+}
@@ -0,0 +1,15 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 13,
+        sql: vec![
+            r#"CREATE TABLE blocks (
+                height INTEGER PRIMARY KEY,
+                hash TEXT NOT NULL
+            )"#,
+            r#"ALTER TABLE deposits ADD COLUMN flagged TIMESTAMPTZ"#,
+            r#"ALTER TABLE withdrawals ADD COLUMN flagged TIMESTAMPTZ"#,
+        ],
+    }
+}
@@ -0,0 +1,17 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 14,
+        sql: vec![
+            r#"CREATE TABLE tx_inputs (
+                tx_id TEXT NOT NULL,
+                spent_tx_id TEXT NOT NULL,
+                spent_v_out BIGINT NOT NULL,
+                PRIMARY KEY (tx_id, spent_tx_id, spent_v_out)
+            )"#,
+            r#"CREATE INDEX tx_input_spent_tx_id_v_out ON tx_inputs (spent_tx_id, spent_v_out)"#,
+            r#"ALTER TABLE deposits ADD COLUMN replaced_by UUID REFERENCES deposits"#,
+        ],
+    }
+}
@@ -0,0 +1,11 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 15,
+        sql: vec![
+            r#"ALTER TABLE withdrawals ADD COLUMN raw_tx BYTEA, ADD COLUMN sent TIMESTAMPTZ"#,
+            r#"UPDATE withdrawals SET sent = created WHERE tx_id IS NOT NULL"#,
+        ],
+    }
+}
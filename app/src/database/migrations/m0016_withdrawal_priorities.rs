@@ -0,0 +1,10 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 16,
+        sql: vec![
+            r#"ALTER TABLE withdrawals ADD COLUMN priority TEXT NOT NULL DEFAULT 'fast', ADD COLUMN sats_per_vbyte BIGINT"#,
+        ],
+    }
+}
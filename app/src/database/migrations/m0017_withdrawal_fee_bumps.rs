@@ -0,0 +1,10 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 17,
+        sql: vec![
+            r#"ALTER TABLE withdrawals ADD COLUMN tx_fee_sats BIGINT, ADD COLUMN bumped TIMESTAMPTZ"#,
+        ],
+    }
+}
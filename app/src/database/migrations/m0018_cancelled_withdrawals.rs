@@ -0,0 +1,8 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 18,
+        sql: vec![r#"ALTER TABLE withdrawals ADD COLUMN cancelled TIMESTAMPTZ"#],
+    }
+}
@@ -0,0 +1,25 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 19,
+        sql: vec![
+            r#"CREATE TABLE allowlists (
+                user_id UUID PRIMARY KEY REFERENCES users,
+                enabled TIMESTAMPTZ,
+                disabled TIMESTAMPTZ
+            )"#,
+            r#"CREATE TABLE allowed_addresses (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL REFERENCES users,
+                address TEXT NOT NULL,
+                label TEXT,
+                created TIMESTAMPTZ NOT NULL,
+                usable_after TIMESTAMPTZ NOT NULL,
+                confirmed TIMESTAMPTZ,
+                removed TIMESTAMPTZ
+            )"#,
+            r#"CREATE INDEX allowed_addresses_user_id ON allowed_addresses (user_id)"#,
+        ],
+    }
+}
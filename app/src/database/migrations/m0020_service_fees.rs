@@ -0,0 +1,22 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 20,
+        sql: vec![
+            r#"CREATE TABLE fee_schedules (
+                plan TEXT NOT NULL,
+                operation TEXT NOT NULL,
+                flat_msats BIGINT NOT NULL,
+                rate_ppm BIGINT NOT NULL,
+                PRIMARY KEY (plan, operation)
+            )"#,
+            r#"ALTER TABLE users ADD COLUMN plan TEXT NOT NULL DEFAULT 'default'"#,
+            r#"ALTER TABLE balance_reservations ADD COLUMN service_fee_msats BIGINT NOT NULL DEFAULT 0"#,
+            r#"ALTER TABLE payments ADD COLUMN service_fee_msats BIGINT"#,
+            r#"ALTER TABLE invoices ADD COLUMN service_fee_msats BIGINT"#,
+            r#"ALTER TABLE withdrawals ADD COLUMN service_fee_msats BIGINT NOT NULL DEFAULT 0"#,
+            r#"ALTER TABLE deposits ADD COLUMN service_fee_msats BIGINT"#,
+        ],
+    }
+}
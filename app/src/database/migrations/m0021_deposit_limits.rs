@@ -0,0 +1,8 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 21,
+        sql: vec![r#"ALTER TABLE deposits ADD COLUMN held_reason TEXT"#],
+    }
+}
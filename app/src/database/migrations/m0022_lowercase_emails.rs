@@ -0,0 +1,13 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 22,
+        sql: vec![
+            // Fails if two users only differ in the case of their emails, they have to be merged by
+            // hand first
+            r#"UPDATE users SET email = LOWER(email) WHERE email <> LOWER(email)"#,
+            r#"CREATE UNIQUE INDEX user_email_lower ON users (LOWER(email))"#,
+        ],
+    }
+}
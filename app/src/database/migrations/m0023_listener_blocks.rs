@@ -0,0 +1,16 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 23,
+        sql: vec![
+            // Each listener keeps its own blocks, starting with the blocks saved so far
+            r#"ALTER TABLE blocks ADD COLUMN listener TEXT NOT NULL DEFAULT 'deposit'"#,
+            r#"ALTER TABLE blocks DROP CONSTRAINT blocks_pkey"#,
+            r#"INSERT INTO blocks (listener, height, hash)
+                SELECT 'withdrawal', height, hash FROM blocks"#,
+            r#"ALTER TABLE blocks ALTER COLUMN listener DROP DEFAULT"#,
+            r#"ALTER TABLE blocks ADD PRIMARY KEY (listener, height)"#,
+        ],
+    }
+}
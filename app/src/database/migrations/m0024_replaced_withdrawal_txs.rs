@@ -0,0 +1,18 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 24,
+        sql: vec![
+            r#"
+            CREATE TABLE replaced_withdrawal_tx_outs (
+                tx_id TEXT NOT NULL,
+                v_out INT NOT NULL,
+                withdrawal_id UUID NOT NULL REFERENCES withdrawals,
+                PRIMARY KEY (tx_id, v_out),
+                FOREIGN KEY (tx_id, v_out) REFERENCES tx_outs (tx_id, v_out)
+            )
+            "#,
+        ],
+    }
+}
@@ -0,0 +1,30 @@
+use super::{Migration, SimpleSqlMigration};
+
+pub fn migration() -> impl Migration {
+    SimpleSqlMigration {
+        serial_number: 25,
+        sql: vec![
+            r#"
+            ALTER TABLE replaced_withdrawal_tx_outs
+                ADD COLUMN raw_tx BYTEA,
+                ADD COLUMN fee_sats BIGINT,
+                ADD COLUMN tx_fee_sats BIGINT
+            "#,
+            // Transactions replaced before their fees were kept are taken to have paid the fees
+            // the withdrawal pays now
+            r#"
+            UPDATE replaced_withdrawal_tx_outs SET
+                raw_tx = withdrawals.raw_tx,
+                fee_sats = withdrawals.fee_sats,
+                tx_fee_sats = withdrawals.tx_fee_sats
+            FROM withdrawals WHERE withdrawals.id = replaced_withdrawal_tx_outs.withdrawal_id
+            "#,
+            r#"
+            ALTER TABLE replaced_withdrawal_tx_outs
+                ALTER COLUMN raw_tx SET NOT NULL,
+                ALTER COLUMN fee_sats SET NOT NULL,
+                ALTER COLUMN tx_fee_sats SET NOT NULL
+            "#,
+        ],
+    }
+}
@@ -7,6 +7,31 @@ use sqlx::Transaction;
 use std::borrow::BorrowMut;
 
 mod m0000_init;
+mod m0001_optional_password;
+mod m0002_token_admin;
+mod m0003_sessions;
+mod m0004_ready_payments;
+mod m0005_idempotency_keys;
+mod m0006_webhooks;
+mod m0007_event_sequence;
+mod m0008_internal_payments;
+mod m0009_ledger;
+mod m0010_reservation_reference;
+mod m0011_list_indexes;
+mod m0012_deposit_confirmations;
+mod m0013_chain_reorgs;
+mod m0014_deposit_replacements;
+mod m0015_signed_withdrawals;
+mod m0016_withdrawal_priorities;
+mod m0017_withdrawal_fee_bumps;
+mod m0018_cancelled_withdrawals;
+mod m0019_withdrawal_allowlists;
+mod m0020_service_fees;
+mod m0021_deposit_limits;
+mod m0022_lowercase_emails;
+mod m0023_listener_blocks;
+mod m0024_replaced_withdrawal_txs;
+mod m0025_replaced_withdrawal_fees;
 
 #[async_trait]
 pub trait Migration {
@@ -38,6 +63,31 @@ impl Migration for SimpleSqlMigration {
 pub async fn run_migrations(db: &Database) {
     prepare_migrations_table(db).await;
     run_migration(m0000_init::migration(), db).await;
+    run_migration(m0001_optional_password::migration(), db).await;
+    run_migration(m0002_token_admin::migration(), db).await;
+    run_migration(m0003_sessions::migration(), db).await;
+    run_migration(m0004_ready_payments::migration(), db).await;
+    run_migration(m0005_idempotency_keys::migration(), db).await;
+    run_migration(m0006_webhooks::migration(), db).await;
+    run_migration(m0007_event_sequence::migration(), db).await;
+    run_migration(m0008_internal_payments::migration(), db).await;
+    run_migration(m0009_ledger::migration(), db).await;
+    run_migration(m0010_reservation_reference::migration(), db).await;
+    run_migration(m0011_list_indexes::migration(), db).await;
+    run_migration(m0012_deposit_confirmations::migration(), db).await;
+    run_migration(m0013_chain_reorgs::migration(), db).await;
+    run_migration(m0014_deposit_replacements::migration(), db).await;
+    run_migration(m0015_signed_withdrawals::migration(), db).await;
+    run_migration(m0016_withdrawal_priorities::migration(), db).await;
+    run_migration(m0017_withdrawal_fee_bumps::migration(), db).await;
+    run_migration(m0018_cancelled_withdrawals::migration(), db).await;
+    run_migration(m0019_withdrawal_allowlists::migration(), db).await;
+    run_migration(m0020_service_fees::migration(), db).await;
+    run_migration(m0021_deposit_limits::migration(), db).await;
+    run_migration(m0022_lowercase_emails::migration(), db).await;
+    run_migration(m0023_listener_blocks::migration(), db).await;
+    run_migration(m0024_replaced_withdrawal_txs::migration(), db).await;
+    run_migration(m0025_replaced_withdrawal_fees::migration(), db).await;
 }
 
 async fn prepare_migrations_table(db: &Database) {
@@ -1,5 +1,5 @@
 use super::{Database, Transaction};
-use crate::auth;
+use crate::{auth, balance, btc, ledger::EntryKind, user};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
@@ -19,15 +19,20 @@ async fn seed_test_user(data_tx: &mut Transaction, index: u128) {
     if row.is_some() {
         return;
     }
+    let password_hash = user::hash(user::Password(format!("test-{}", index))).await;
     sqlx::query("INSERT INTO users (id, email, password, balance_msats, created) VALUES ($1, $2, $3, $4, $5)")
         .bind(Uuid::from_u128(index))
         .bind(format!("test-{}@user.net", index))
-        .bind(format!("test-{}", index))
-        .bind(2_000_000_000)
+        .bind(password_hash.as_str().to_owned())
+        .bind(0)
         .bind(Utc::now())
         .execute(&mut *data_tx)
         .await
         .unwrap();
+    let user_id = user::Id(Uuid::from_u128(index));
+    let mut balance = balance::get(data_tx, user_id).await;
+    balance.credit(btc::MilliSats(2_000_000_000), EntryKind::Opening, user_id.0);
+    balance::update(data_tx, &balance).await.unwrap();
     sqlx::query(
         r#"INSERT INTO auth_tokens (id, user_id, name, token_hash, can_spend, can_receive, can_read, created, disabled)
             VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)"#
@@ -77,8 +82,8 @@ async fn seed_test_user(data_tx: &mut Transaction, index: u128) {
     .await
     .unwrap();
     sqlx::query(
-        r#"INSERT INTO auth_tokens (id, user_id, name, token_hash, can_spend, can_receive, can_read, created, disabled)
-            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)"#
+        r#"INSERT INTO auth_tokens (id, user_id, name, token_hash, can_spend, can_receive, can_read, can_admin, created, disabled)
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)"#
     )
     .bind(Uuid::from_u128(index * 100 + 4))
     .bind(Uuid::from_u128(index))
@@ -87,6 +92,7 @@ async fn seed_test_user(data_tx: &mut Transaction, index: u128) {
     .bind(true)
     .bind(true)
     .bind(true)
+    .bind(true)
     .bind(Utc::now())
     .bind(Option::<DateTime<Utc>>::None)
     .execute(&mut *data_tx)
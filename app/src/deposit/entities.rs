@@ -1,19 +1,36 @@
 //! Handles the logic of users depositing funds into our service.
 //! The funds deposit flow goes as follows:
 //! - the user generates an [`Address`], which contains an onchain address for him to send
-//! BTC to
+//!   BTC to
 //! - the user sends any amount of BTC to the address in an onchain transaction, which causes a
-//! [`Deposit`] to be created
-//! - when the transaction is confirmed, the [`Deposit::confirm`] method is called, updating the
-//! user balance and completing the deposit flow.
+//!   [`Deposit`] to be created
+//! - when the transaction has the number of confirmations required by the
+//!   [`ConfirmationPolicy`], the [`Deposit::confirm`] method is called, updating the user balance
+//!   and completing the deposit flow.
+//!
+//! While the transaction is unconfirmed, the sender may replace it with a new one spending the
+//! same inputs, e.g. to bump the fee with RBF. If the new transaction also pays to a deposit
+//! address, it starts a new deposit, and [`Deposit::replace`] marks the old one as replaced by it.
+//! Replaced deposits aren't credited, unless the old transaction gets confirmed after all, in
+//! which case it's the new deposit that gets replaced. Fee bumps with CPFP don't change the
+//! deposit transaction, so they need no special handling.
+//!
+//! Deposits outside the deposit [`CashLimits`], e.g. dust too small to ever be spent
+//! economically, aren't credited even with the required confirmations. [`Deposit::hold`] holds
+//! them instead. Deposits held because of the daily limit are credited once the limit allows it,
+//! the others stay held until an operator releases them.
 
 use crate::auth;
-use crate::balance::Balance;
+use crate::balance::{Balance, InsufficientBalance};
 use crate::btc;
+use crate::cash_limits::{self, CashLimits};
+use crate::ledger::EntryKind;
 use crate::ln;
+use crate::pricing::FeeSchedule;
 use crate::user;
 use chrono::DateTime;
 use chrono::Utc;
+use std::str::FromStr;
 use uuid::Uuid;
 
 /// Represents a BTC address for the user to deposit funds into. This is the primary way for users
@@ -39,20 +56,74 @@ impl Address {
 
     /// Starts a new deposit of funds. This method is called whenever the user sends a new
     /// transaction to this deposit address.
-    pub(crate) async fn start_deposit(&self, tx_out: &btc::TxOut) -> Deposit {
+    pub(crate) async fn start_deposit(
+        &self,
+        tx_out: &btc::TxOut,
+        policy: &ConfirmationPolicy,
+    ) -> Deposit {
         Deposit {
             id: Id(Uuid::new_v4()),
             user_id: self.user_id,
             tx_out: tx_out.clone(),
             created: Utc::now(),
             confirmed: None,
+            service_fee: None,
+            confirmations: 0,
+            required_confirmations: policy.required_confirmations(tx_out.amount),
+            flagged: None,
+            replaced_by: None,
+            held: None,
         }
     }
 }
 
+/// Decides how many confirmations a deposit transaction needs before the deposit is credited to
+/// the user. A transaction buried deeper in the chain is less likely to be reversed by a reorg,
+/// so larger deposits should wait for more confirmations.
+#[derive(Debug, Clone)]
+pub enum ConfirmationPolicy {
+    /// All deposits need the same number of confirmations.
+    Fixed(u32),
+    /// Deposits smaller than the amount of a tier need the confirmations of the smallest such
+    /// tier, deposits which aren't smaller than any tier need `above`.
+    Tiered {
+        tiers: Vec<ConfirmationTier>,
+        above: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConfirmationTier {
+    pub below: btc::Sats,
+    pub confirmations: u32,
+}
+
+impl ConfirmationPolicy {
+    pub fn required_confirmations(&self, amount: btc::Sats) -> u32 {
+        match self {
+            ConfirmationPolicy::Fixed(confirmations) => *confirmations,
+            ConfirmationPolicy::Tiered { tiers, above } => tiers
+                .iter()
+                .filter(|tier| amount < tier.below)
+                .min_by_key(|tier| tier.below)
+                .map_or(*above, |tier| tier.confirmations),
+        }
+        // A deposit can't be credited before its transaction is in a block
+        .max(1)
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Id(pub Uuid);
 
+impl FromStr for Id {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::from_str(s).map(Self)
+    }
+}
+
 /// Corresponds to a particular BTC transaction that was deposited into an [`Address`].
 #[derive(Debug)]
 pub struct Deposit {
@@ -60,7 +131,24 @@ pub struct Deposit {
     pub user_id: user::Id,
     pub tx_out: btc::TxOut,
     pub created: DateTime<Utc>,
+    /// Set once the deposit has been credited to the user.
     pub confirmed: Option<DateTime<Utc>>,
+    /// Our service fee, taken out of the deposited amount. Set once the deposit has been credited.
+    pub service_fee: Option<btc::MilliSats>,
+    /// Number of blocks confirming the transaction, as of the last time we went through the
+    /// chain. Zero while the transaction isn't in a block.
+    pub confirmations: u32,
+    pub required_confirmations: u32,
+    /// Set if a chain reorganization removed the transaction after the deposit was credited, and
+    /// the deposit couldn't be reversed because the user had already spent the funds. Flagged
+    /// deposits have to be sorted out by an operator.
+    pub flagged: Option<DateTime<Utc>>,
+    /// Set if the transaction was replaced by the transaction of another deposit, e.g. after a fee
+    /// bump. See [`Deposit::replace`].
+    pub replaced_by: Option<Id>,
+    /// The limit the deposit violates, if it has the required confirmations but wasn't credited
+    /// because of the deposit limits. See [`Deposit::hold`].
+    pub held: Option<cash_limits::Error>,
 }
 
 impl Deposit {
@@ -68,18 +156,61 @@ impl Deposit {
         self.confirmed.is_some()
     }
 
-    /// Confirms the deposit, finally updating the user balance. This method is called when the
-    /// deposit transaction gets confirmed on the BTC network.
-    pub(crate) fn confirm(&mut self, tx_out: &btc::TxOut, balance: &mut Balance) {
+    pub fn is_replaced(&self) -> bool {
+        self.replaced_by.is_some()
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.is_some()
+    }
+
+    /// True if the transaction has enough confirmations for the deposit to be credited.
+    pub fn has_required_confirmations(&self) -> bool {
+        self.confirmations >= self.required_confirmations
+    }
+
+    /// Checks the deposited amount against the limits. `daily_total` is the amount of the user's
+    /// deposits credited in the last 24 hours.
+    pub(crate) fn check_limits(
+        &self,
+        limits: &CashLimits,
+        daily_total: btc::MilliSats,
+    ) -> Result<(), cash_limits::Error> {
+        limits.check(cash_limits::Amounts {
+            amount: self.tx_out.amount.msats(),
+            daily_total,
+        })
+    }
+
+    /// Holds the deposit instead of crediting it, because it violates the deposit limits. Returns
+    /// false if it was already held for the same reason.
+    pub(crate) fn hold(&mut self, reason: cash_limits::Error) -> bool {
+        if self.is_confirmed() {
+            panic!("deposit {:?} has already been confirmed", self.id)
+        }
+        let is_new = self.held != Some(reason);
+        self.held = Some(reason);
+        is_new
+    }
+
+    /// Confirms the deposit, finally updating the user balance with the deposited amount minus our
+    /// service fee according to the schedule. This method is called when the deposit transaction
+    /// gets confirmed on the BTC network.
+    pub(crate) fn confirm(
+        &mut self,
+        tx_out: &btc::TxOut,
+        balance: &mut Balance,
+        schedule: &FeeSchedule,
+    ) {
         if self.is_confirmed() {
             panic!("deposit {:?} has already been confirmed", self.id)
         }
-        // TODO What about fee bumps? RBF and CPFP
-        // v0l: RBF usage is very high, there should be a way to detect if the same inputs are used in
-        // any other of the deposits (on confirm here) or they simply will never be confirmed.
-        // v0l: For UX purposes it would be necessary to detect that the deposit was abandoned due to
-        // double spends (RBF)
-        // TODO Maybe just don't track unconfirmed deposits, and that solves the above?
+        if !self.has_required_confirmations() {
+            panic!(
+                "deposit {:?} has {} confirmations, {} are required",
+                self.id, self.confirmations, self.required_confirmations
+            )
+        }
         if tx_out.tx.id != self.tx_out.tx.id {
             panic!(
                 "deposit {:?} with tx id {:?} is not confirmed by {:?}",
@@ -95,7 +226,59 @@ impl Deposit {
             )
         }
         self.tx_out = tx_out.clone();
+        let amount = tx_out.amount.msats();
+        let service_fee = schedule.credit_fee(amount);
         self.confirmed = Some(Utc::now());
-        balance.credit(tx_out.amount.msats());
+        self.service_fee = Some(service_fee);
+        self.held = None;
+        balance.credit_with_fee(amount, service_fee, EntryKind::Deposit, self.id.0);
+    }
+
+    /// Marks the deposit as replaced by another deposit whose transaction spends the same inputs.
+    /// Only pending deposits can be replaced, a confirmed transaction can't be replaced anymore.
+    pub(crate) fn replace(&mut self, replacement: &Deposit) {
+        if self.is_confirmed() {
+            panic!(
+                "deposit {:?} has already been confirmed, it can't be replaced by {:?}",
+                self.id, replacement.id
+            )
+        }
+        if self.id == replacement.id {
+            panic!("deposit {:?} can't replace itself", self.id)
+        }
+        self.replaced_by = Some(replacement.id);
+    }
+
+    /// Takes the credited funds back from the user after a chain reorganization removed the
+    /// deposit transaction from the chain, and gives up our service fee. The deposit is pending
+    /// again, so it's credited again if the transaction gets back into the chain. Fails if the user
+    /// doesn't have the funds anymore.
+    pub(crate) fn reverse(&mut self, balance: &mut Balance) -> Result<(), InsufficientBalance> {
+        if !self.is_confirmed() {
+            panic!("deposit {:?} hasn't been confirmed", self.id)
+        }
+        if self.user_id != balance.user_id() {
+            panic!(
+                "deposit {:?} user id {:?} does not match {:?}",
+                self.id,
+                self.user_id,
+                balance.user_id()
+            )
+        }
+        balance.debit(
+            self.tx_out.amount.msats(),
+            self.service_fee.unwrap_or_default(),
+            EntryKind::DepositReversal,
+            self.id.0,
+        )?;
+        self.confirmed = None;
+        self.service_fee = None;
+        Ok(())
+    }
+
+    /// Flags a credited deposit whose transaction was removed from the chain, see
+    /// [`Deposit::flagged`].
+    pub(crate) fn flag(&mut self) {
+        self.flagged = Some(Utc::now());
     }
 }
@@ -1,16 +1,21 @@
 use crate::auth;
 use crate::balance;
 use crate::btc;
+use crate::cash_limits::CashLimits;
 use crate::chain;
 use crate::concurrency;
 use crate::database::{self, Database};
+use crate::event::{self, Event};
 use crate::ln;
-use crate::QueryRange;
+use crate::pricing;
+use crate::worker;
+use crate::{swallow_panic, AmountRange, Page, Period};
 use async_trait::async_trait;
+use std::time::Duration;
 
 mod entities;
 
-pub use entities::{Address, Deposit, Id};
+pub use entities::{Address, ConfirmationPolicy, ConfirmationTier, Deposit, Id};
 
 pub async fn create_address(
     grant: &auth::ReceiveGrant,
@@ -32,47 +37,215 @@ pub async fn get_address(
     queries::get_address_for_user(db, address, grant.user_id).await
 }
 
+/// Lists the addresses created in the period, most recent first.
 pub async fn get_addresses(
     grant: &auth::ReadGrant,
     db: &Database,
-    range: QueryRange,
+    period: Period,
+    page: &Page<btc::Address>,
 ) -> Vec<Address> {
-    queries::get_addresses_for_user(db, range, grant.user_id).await
+    queries::get_addresses_for_user(db, period, page, grant.user_id).await
 }
 
 pub async fn get(grant: &auth::ReadGrant, db: &Database, id: Id) -> Option<Deposit> {
     queries::get_for_user(db, id, grant.user_id).await
 }
 
-pub async fn list(grant: &auth::ReadGrant, db: &Database, range: QueryRange) -> Vec<Deposit> {
-    queries::list_for_user(db, grant.user_id, range).await
+/// Selects the deposits to list. The default filter selects all deposits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Filter {
+    pub confirmed: Option<bool>,
+    pub period: Period,
+    pub amount: AmountRange<btc::Sats>,
 }
 
-pub async fn start_worker(start_height: u32, db: &Database, lightning: &ln::Lightning) {
-    chain::listen(start_height, db, lightning, Listener { db: db.clone() }).await;
+/// Lists the deposits matching the filter, most recent first.
+pub async fn list(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    filter: &Filter,
+    page: &Page<Id>,
+) -> Vec<Deposit> {
+    queries::list_for_user(db, grant.user_id, filter, page).await
+}
+
+/// Lists the deposits flagged after chain reorganizations, oldest first. See [`Deposit::flagged`].
+pub(crate) async fn list_flagged(db: &Database) -> Vec<Deposit> {
+    queries::list_flagged(db).await
+}
+
+/// Lists the deposits held because of the deposit limits, oldest first. See [`Deposit::held`].
+pub(crate) async fn list_held(db: &Database) -> Vec<Deposit> {
+    queries::list_held(db).await
+}
+
+/// Credits a held deposit regardless of the deposit limits. Returns None if there's no held
+/// deposit with the id.
+pub(crate) async fn release(db: &Database, id: Id) -> Option<Deposit> {
+    concurrency::retry_loop(|| async {
+        let mut data_tx = db.begin().await.unwrap();
+        let deposit = match queries::get_by_id(&mut data_tx, id).await {
+            Some(deposit) if deposit.is_held() && !deposit.is_confirmed() => deposit,
+            _ => return Ok(None),
+        };
+        log::info!(
+            "releasing deposit {:?} held by {:?}",
+            deposit.id,
+            deposit.held
+        );
+        confirm(&mut data_tx, deposit, None).await?;
+        let deposit = queries::get_by_id(&mut data_tx, id).await;
+        data_tx.commit().await.unwrap();
+        Ok::<_, concurrency::ConflictError>(deposit)
+    })
+    .await
+    .unwrap()
+}
+
+pub async fn start_worker(
+    start_height: u32,
+    db: &Database,
+    lightning: &ln::Lightning,
+    policy: ConfirmationPolicy,
+    limits: CashLimits,
+) {
+    let listener = Listener {
+        db: db.clone(),
+        policy,
+        limits: limits.clone(),
+    };
+    chain::listen(start_height, db, lightning, listener).await;
+    worker::start(DepositConfirmer {
+        db: db.clone(),
+        limits,
+    });
+}
+
+/// Goes through the chain from the start height, starting and confirming any deposits that were
+/// missed.
+pub async fn rescan(
+    start_height: u32,
+    db: &Database,
+    lightning: &ln::Lightning,
+    policy: ConfirmationPolicy,
+    limits: CashLimits,
+) {
+    let listener = Listener {
+        db: db.clone(),
+        policy,
+        limits: limits.clone(),
+    };
+    chain::scan(start_height, db, lightning, listener).await;
+    confirm_pending(db, &limits).await;
+}
+
+/// Confirms the deposits whose transactions got enough confirmations since they were last
+/// processed by the tx listener, and the deposits held because of the daily limit, if the limit
+/// allows it now.
+pub async fn confirm_pending(db: &Database, limits: &CashLimits) {
+    for deposit in queries::list_confirmable(db).await {
+        swallow_panic(async {
+            concurrency::retry_loop(|| async {
+                let mut data_tx = db.begin().await.unwrap();
+                let deposit =
+                    queries::get(&mut data_tx, &deposit.tx_out.tx.id, deposit.tx_out.v_out)
+                        .await
+                        .unwrap();
+                confirm(&mut data_tx, deposit, Some(limits)).await?;
+                data_tx.commit().await.unwrap();
+                Ok::<_, concurrency::ConflictError>(())
+            })
+            .await
+            .unwrap();
+        })
+        .await;
+    }
+}
+
+/// Confirms the deposit if it's still pending and has the required confirmations. Holds it instead
+/// if it violates the limits. Without limits, e.g. when an operator releases a held deposit, it's
+/// credited whatever its amount.
+async fn confirm(
+    data_tx: &mut database::Transaction,
+    mut deposit: Deposit,
+    limits: Option<&CashLimits>,
+) -> Result<(), concurrency::ConflictError> {
+    if deposit.is_confirmed() || deposit.is_replaced() || !deposit.has_required_confirmations() {
+        log::info!(
+            "not confirming deposit {:?} with {} of {} confirmations, replaced by {:?}",
+            deposit.id,
+            deposit.confirmations,
+            deposit.required_confirmations,
+            deposit.replaced_by
+        );
+        return Ok(());
+    }
+    if let Some(limits) = limits {
+        let daily_total = queries::daily_total(data_tx, deposit.user_id).await;
+        if let Err(reason) = deposit.check_limits(limits, daily_total) {
+            if deposit.hold(reason) {
+                log::warn!("holding deposit {:?}: {}", deposit.id, reason);
+                queries::upsert(data_tx, &deposit).await?;
+                event::publish(data_tx, Event::deposit_held(&deposit)).await;
+            }
+            return Ok(());
+        }
+    }
+    log::info!("confirming deposit {:?}", deposit.id);
+    let mut balance = balance::get(data_tx, deposit.user_id).await;
+    let schedule =
+        pricing::get_schedule(data_tx, deposit.user_id, pricing::Operation::Deposit).await;
+    let tx_out = deposit.tx_out.clone();
+    deposit.confirm(&tx_out, &mut balance, &schedule);
+    queries::upsert(data_tx, &deposit).await?;
+    balance::update(data_tx, &balance).await?;
+    event::publish(data_tx, Event::deposit_confirmed(&deposit)).await;
+    Ok(())
+}
+
+struct DepositConfirmer {
+    db: Database,
+    limits: CashLimits,
+}
+
+#[async_trait]
+impl worker::Worker for DepositConfirmer {
+    async fn run(&mut self) {
+        confirm_pending(&self.db, &self.limits).await;
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_secs(10)
+    }
 }
 
 struct Listener {
     db: Database,
+    policy: ConfirmationPolicy,
+    limits: CashLimits,
 }
 
 #[async_trait]
 impl chain::TxListener for Listener {
+    const NAME: &'static str = "deposit";
+
     async fn process(&mut self, tx_out: &btc::TxOut) {
         concurrency::retry_loop(|| async {
             log::info!("processing transaction as deposit: {:?}", tx_out);
             let mut data_tx = self.db.begin().await.unwrap();
-            match get_or_start(&mut data_tx, tx_out).await? {
+            match get_or_start(&mut data_tx, tx_out, &self.policy).await? {
                 Some(mut deposit) => {
-                    if tx_out.tx.is_confirmed() && !deposit.is_confirmed() {
-                        log::info!("confirming deposit {:?}", deposit.id);
-                        let mut balance = balance::get(&mut data_tx, deposit.user_id).await;
-                        deposit.confirm(tx_out, &mut balance);
+                    if deposit.tx_out.tx.block_height != tx_out.tx.block_height {
+                        deposit.tx_out = tx_out.clone();
                         queries::upsert(&mut data_tx, &deposit).await?;
-                        balance::update(&mut data_tx, &balance).await?;
-                    } else {
-                        log::info!("not confirming deposit {:?}", deposit.id);
                     }
+                    queries::insert_inputs(&mut data_tx, &tx_out.tx).await;
+                    replace_conflicting(&mut data_tx, &mut deposit, tx_out).await?;
+                    // The confirmations are counted by the database, reload to get them
+                    let deposit = queries::get(&mut data_tx, &tx_out.tx.id, tx_out.v_out)
+                        .await
+                        .unwrap();
+                    confirm(&mut data_tx, deposit, Some(&self.limits)).await?;
                     data_tx.commit().await.unwrap();
                 }
                 None => log::info!("txout {:?} not related to a deposit", tx_out),
@@ -82,27 +255,102 @@ impl chain::TxListener for Listener {
         .await
         .unwrap();
     }
+
+    async fn reorg(&mut self, tx_out: &btc::TxOut) {
+        concurrency::retry_loop(|| async {
+            let mut data_tx = self.db.begin().await.unwrap();
+            let mut deposit = match queries::get(&mut data_tx, &tx_out.tx.id, tx_out.v_out).await {
+                Some(deposit) => deposit,
+                None => return Ok(()),
+            };
+            deposit.tx_out = tx_out.clone();
+            if deposit.is_confirmed() {
+                let mut balance = balance::get(&mut data_tx, deposit.user_id).await;
+                match deposit.reverse(&mut balance) {
+                    Ok(()) => {
+                        log::warn!(
+                            "reversed deposit {:?}, its transaction is not in the chain anymore",
+                            deposit.id
+                        );
+                        balance::update(&mut data_tx, &balance).await?;
+                        event::publish(&mut data_tx, Event::deposit_reversed(&deposit)).await;
+                    }
+                    Err(balance::InsufficientBalance) => {
+                        log::error!(
+                            "deposit {:?} of user {:?} can't be reversed, the funds were spent, flagging it",
+                            deposit.id,
+                            deposit.user_id
+                        );
+                        deposit.flag();
+                    }
+                }
+            }
+            queries::upsert(&mut data_tx, &deposit).await?;
+            data_tx.commit().await.unwrap();
+            Ok::<_, concurrency::ConflictError>(())
+        })
+        .await
+        .unwrap();
+    }
+}
+
+/// Marks the pending deposits whose transactions conflict with the deposit transaction as replaced
+/// by the deposit. A transaction is replaced by a more recent conflicting one, unless it's the one
+/// that gets confirmed.
+async fn replace_conflicting(
+    data_tx: &mut database::Transaction,
+    deposit: &mut Deposit,
+    tx_out: &btc::TxOut,
+) -> Result<(), concurrency::ConflictError> {
+    if tx_out.tx.is_confirmed() && deposit.is_replaced() {
+        log::warn!(
+            "deposit {:?} was replaced by {:?}, but its transaction got confirmed",
+            deposit.id,
+            deposit.replaced_by
+        );
+        deposit.replaced_by = None;
+        queries::upsert(data_tx, deposit).await?;
+    }
+    for mut conflicting in queries::list_conflicting(data_tx, &tx_out.tx.id).await {
+        let is_replaced = tx_out.tx.is_confirmed()
+            || (!deposit.is_replaced() && conflicting.created < deposit.created);
+        if !is_replaced || conflicting.replaced_by == Some(deposit.id) {
+            continue;
+        }
+        log::info!(
+            "deposit {:?} was replaced by {:?}",
+            conflicting.id,
+            deposit.id
+        );
+        conflicting.replace(deposit);
+        queries::upsert(data_tx, &conflicting).await?;
+        event::publish(data_tx, Event::deposit_replaced(&conflicting)).await;
+    }
+    Ok(())
 }
 
 async fn get_or_start(
     data_tx: &mut database::Transaction,
     tx_out: &btc::TxOut,
+    policy: &ConfirmationPolicy,
 ) -> Result<Option<Deposit>, concurrency::ConflictError> {
     match queries::get(data_tx, &tx_out.tx.id, tx_out.v_out).await {
         Some(deposit) => Ok(Some(deposit)),
-        None => Ok(start(data_tx, tx_out).await?),
+        None => Ok(start(data_tx, tx_out, policy).await?),
     }
 }
 
 async fn start(
     data_tx: &mut database::Transaction,
     tx_out: &btc::TxOut,
+    policy: &ConfirmationPolicy,
 ) -> Result<Option<Deposit>, concurrency::ConflictError> {
     match queries::get_address(data_tx, &tx_out.address).await {
         Some(deposit_address) => {
             log::info!("starting deposit for {:?}", deposit_address);
-            let deposit = deposit_address.start_deposit(tx_out).await;
+            let deposit = deposit_address.start_deposit(tx_out, policy).await;
             queries::upsert(data_tx, &deposit).await?;
+            event::publish(data_tx, Event::deposit_detected(&deposit)).await;
             Ok(Some(deposit))
         }
         None => Ok(None),
@@ -110,18 +358,41 @@ async fn start(
 }
 
 mod queries {
-    use super::{Address, Deposit, Id};
+    use super::{Address, Deposit, Filter, Id};
     use crate::auth;
     use crate::btc;
+    use crate::cash_limits;
     use crate::concurrency;
     use crate::database;
-    use crate::database::Database;
+    use crate::database::{Database, SumRow};
     use crate::user;
-    use crate::QueryRange;
-    use chrono::{DateTime, Utc};
+    use crate::{Page, Period};
+    use chrono::{DateTime, Duration, Utc};
+    use const_format::formatcp;
     use std::str::FromStr;
     use uuid::Uuid;
 
+    /// Selects deposits along with their tx outs. The confirmations are counted from the chain tip
+    /// saved by the tx listener.
+    const SELECT: &str = r#"SELECT
+                deposits.id,
+                deposits.user_id,
+                deposits.tx_id,
+                deposits.v_out,
+                deposits.created,
+                deposits.confirmed,
+                deposits.service_fee_msats,
+                deposits.required_confirmations,
+                deposits.flagged,
+                deposits.replaced_by,
+                deposits.held_reason,
+                GREATEST((SELECT height FROM chain_tip) - tx_outs.block_height + 1, 0) AS confirmations,
+                tx_outs.block_height,
+                tx_outs.address,
+                tx_outs.amount_sats
+            FROM deposits
+            JOIN tx_outs ON deposits.tx_id = tx_outs.tx_id AND deposits.v_out = tx_outs.v_out"#;
+
     pub(super) async fn insert_address(data_tx: &mut database::Transaction, address: &Address) {
         sqlx::query(
             "INSERT INTO deposit_addresses (user_id, token_id, address, created) VALUES ($1, $2, $3, $4)",
@@ -167,16 +438,24 @@ mod queries {
 
     pub(super) async fn get_addresses_for_user(
         db: &Database,
-        range: QueryRange,
+        period: Period,
+        page: &Page<btc::Address>,
         user_id: user::Id,
     ) -> Vec<Address> {
         sqlx::query_as::<_, DepositAddressRow>(
             r#"SELECT user_id, token_id, address, created FROM deposit_addresses
-                WHERE user_id = $1 ORDER BY created DESC LIMIT $2 OFFSET $3"#,
+                WHERE user_id = $1
+                AND ($2::TIMESTAMPTZ IS NULL OR created >= $2) AND ($3::TIMESTAMPTZ IS NULL OR created < $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR (created, address) < ($4, $5))
+                ORDER BY created DESC, address DESC LIMIT $6 OFFSET $7"#,
         )
         .bind(user_id.0)
-        .bind(range.limit)
-        .bind(range.offset)
+        .bind(period.from)
+        .bind(period.to)
+        .bind(page.after.as_ref().map(|cursor| cursor.created))
+        .bind(page.after.as_ref().map(|cursor| cursor.id.to_string()))
+        .bind(page.range.limit)
+        .bind(page.range.offset)
         .fetch_all(db)
         .await
         .unwrap()
@@ -203,9 +482,10 @@ mod queries {
         .await
         .unwrap();
         match sqlx::query(
-            r#"INSERT INTO deposits (id, user_id, tx_id, v_out, address, created, confirmed)
-                VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (id) DO UPDATE SET
-                user_id = $2, tx_id = $3, v_out = $4, address = $5, created = $6, confirmed = $7"#,
+            r#"INSERT INTO deposits (id, user_id, tx_id, v_out, address, created, confirmed, required_confirmations, flagged, replaced_by, service_fee_msats, held_reason)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) ON CONFLICT (id) DO UPDATE SET
+                user_id = $2, tx_id = $3, v_out = $4, address = $5, created = $6, confirmed = $7, required_confirmations = $8,
+                flagged = $9, replaced_by = $10, service_fee_msats = $11, held_reason = $12"#,
         )
         .bind(deposit.id.0)
         .bind(deposit.user_id.0)
@@ -214,6 +494,11 @@ mod queries {
         .bind(deposit.tx_out.address.to_string())
         .bind(deposit.created)
         .bind(deposit.confirmed)
+        .bind(i32::try_from(deposit.required_confirmations).unwrap())
+        .bind(deposit.flagged)
+        .bind(deposit.replaced_by.map(|id| id.0))
+        .bind(deposit.service_fee.map(|fee| fee.0))
+        .bind(deposit.held.map(|reason| reason.as_str()))
         .execute(&mut *data_tx)
         .await
         {
@@ -229,26 +514,55 @@ mod queries {
         }
     }
 
+    /// Saves the outputs spent by the transaction, to find the transactions conflicting with it.
+    pub(super) async fn insert_inputs(data_tx: &mut database::Transaction, tx: &btc::Tx) {
+        for input in &tx.inputs {
+            sqlx::query(
+                r#"INSERT INTO tx_inputs (tx_id, spent_tx_id, spent_v_out) VALUES ($1, $2, $3)
+                    ON CONFLICT DO NOTHING"#,
+            )
+            .bind(tx.id.to_string())
+            .bind(input.txid.to_string())
+            .bind(i64::from(input.vout))
+            .execute(&mut *data_tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    /// Lists the pending deposits whose transactions spend any of the outputs spent by the given
+    /// transaction.
+    pub(super) async fn list_conflicting(
+        data_tx: &mut database::Transaction,
+        tx_id: &btc::TxId,
+    ) -> Vec<Deposit> {
+        sqlx::query_as::<_, DepositRow>(formatcp!(
+            r#"{} WHERE deposits.confirmed IS NULL AND deposits.tx_id <> $1 AND deposits.tx_id IN (
+                SELECT conflicting.tx_id FROM tx_inputs
+                JOIN tx_inputs AS conflicting ON conflicting.spent_tx_id = tx_inputs.spent_tx_id
+                    AND conflicting.spent_v_out = tx_inputs.spent_v_out
+                WHERE tx_inputs.tx_id = $1
+            ) ORDER BY deposits.created"#,
+            SELECT
+        ))
+        .bind(tx_id.to_string())
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
     pub(super) async fn get(
         data_tx: &mut database::Transaction,
         tx_id: &btc::TxId,
         v_out: i64,
     ) -> Option<Deposit> {
-        sqlx::query_as::<_, DepositRow>(
-            r#"SELECT
-                deposits.id,
-                deposits.user_id,
-                deposits.tx_id,
-                deposits.v_out,
-                deposits.created,
-                deposits.confirmed,
-                tx_outs.block_height,
-                tx_outs.address,
-                tx_outs.amount_sats
-            FROM deposits
-            JOIN tx_outs ON deposits.tx_id = tx_outs.tx_id AND deposits.v_out = tx_outs.v_out
-            WHERE deposits.tx_id = $1 AND deposits.v_out = $2"#,
-        )
+        sqlx::query_as::<_, DepositRow>(formatcp!(
+            "{} WHERE deposits.tx_id = $1 AND deposits.v_out = $2",
+            SELECT
+        ))
         .bind(tx_id.to_string())
         .bind(v_out)
         .fetch_optional(data_tx)
@@ -257,53 +571,81 @@ mod queries {
         .map(|row| row.into_entity())
     }
 
+    pub(super) async fn get_by_id(data_tx: &mut database::Transaction, id: Id) -> Option<Deposit> {
+        sqlx::query_as::<_, DepositRow>(formatcp!("{} WHERE deposits.id = $1", SELECT))
+            .bind(id.0)
+            .fetch_optional(data_tx)
+            .await
+            .unwrap()
+            .map(|row| row.into_entity())
+    }
+
     pub(super) async fn get_for_user(db: &Database, id: Id, user_id: user::Id) -> Option<Deposit> {
-        sqlx::query_as::<_, DepositRow>(
-            r#"SELECT
-                deposits.id,
-                deposits.user_id,
-                deposits.tx_id,
-                deposits.v_out,
-                deposits.created,
-                deposits.confirmed,
-                tx_outs.block_height,
-                tx_outs.address,
-                tx_outs.amount_sats
-            FROM deposits
-            JOIN tx_outs ON deposits.tx_id = tx_outs.tx_id AND deposits.v_out = tx_outs.v_out
-            WHERE id = $1 AND user_id = $2"#,
-        )
-        .bind(id.0)
-        .bind(user_id.0)
-        .fetch_optional(db)
-        .await
-        .unwrap()
-        .map(|row| row.into_entity())
+        sqlx::query_as::<_, DepositRow>(formatcp!("{} WHERE id = $1 AND user_id = $2", SELECT))
+            .bind(id.0)
+            .bind(user_id.0)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .map(|row| row.into_entity())
     }
 
     pub(super) async fn list_for_user(
         db: &Database,
         user_id: user::Id,
-        range: QueryRange,
+        filter: &Filter,
+        page: &Page<Id>,
     ) -> Vec<Deposit> {
-        sqlx::query_as::<_, DepositRow>(
-            r#"SELECT
-                deposits.id,
-                deposits.user_id,
-                deposits.tx_id,
-                deposits.v_out,
-                deposits.created,
-                deposits.confirmed,
-                tx_outs.block_height,
-                tx_outs.address,
-                tx_outs.amount_sats
-            FROM deposits
-            JOIN tx_outs ON deposits.tx_id = tx_outs.tx_id AND deposits.v_out = tx_outs.v_out
-            WHERE user_id = $1 ORDER BY deposits.created DESC LIMIT $2 OFFSET $3"#,
-        )
+        sqlx::query_as::<_, DepositRow>(formatcp!(
+            r#"{} WHERE user_id = $1
+            AND ($2::BOOLEAN IS NULL OR (deposits.confirmed IS NOT NULL) = $2)
+            AND ($3::TIMESTAMPTZ IS NULL OR deposits.created >= $3) AND ($4::TIMESTAMPTZ IS NULL OR deposits.created < $4)
+            AND ($5::BIGINT IS NULL OR tx_outs.amount_sats >= $5) AND ($6::BIGINT IS NULL OR tx_outs.amount_sats <= $6)
+            AND ($7::TIMESTAMPTZ IS NULL OR (deposits.created, deposits.id) < ($7, $8))
+            ORDER BY deposits.created DESC, deposits.id DESC LIMIT $9 OFFSET $10"#,
+            SELECT
+        ))
         .bind(user_id.0)
-        .bind(range.limit)
-        .bind(range.offset)
+        .bind(filter.confirmed)
+        .bind(filter.period.from)
+        .bind(filter.period.to)
+        .bind(filter.amount.min.map(|amount| amount.0))
+        .bind(filter.amount.max.map(|amount| amount.0))
+        .bind(page.after.map(|cursor| cursor.created))
+        .bind(page.after.map(|cursor| cursor.id.0))
+        .bind(page.range.limit)
+        .bind(page.range.offset)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    /// Lists the pending deposits whose transactions have the required confirmations, except the
+    /// ones held for a reason other than the daily limit, which only an operator can release.
+    pub(super) async fn list_confirmable(db: &Database) -> Vec<Deposit> {
+        sqlx::query_as::<_, DepositRow>(formatcp!(
+            r#"{} WHERE deposits.confirmed IS NULL AND deposits.replaced_by IS NULL
+                AND (deposits.held_reason IS NULL OR deposits.held_reason = 'daily_limit_exceeded')
+                AND (SELECT height FROM chain_tip) - tx_outs.block_height + 1 >= deposits.required_confirmations
+                ORDER BY deposits.created"#,
+            SELECT
+        ))
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn list_flagged(db: &Database) -> Vec<Deposit> {
+        sqlx::query_as::<_, DepositRow>(formatcp!(
+            "{} WHERE deposits.flagged IS NOT NULL ORDER BY deposits.flagged",
+            SELECT
+        ))
         .fetch_all(db)
         .await
         .unwrap()
@@ -312,6 +654,40 @@ mod queries {
         .collect()
     }
 
+    pub(super) async fn list_held(db: &Database) -> Vec<Deposit> {
+        sqlx::query_as::<_, DepositRow>(formatcp!(
+            r#"{} WHERE deposits.held_reason IS NOT NULL AND deposits.confirmed IS NULL
+                ORDER BY deposits.created"#,
+            SELECT
+        ))
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    /// Sums the user's deposits credited in the last 24 hours.
+    pub(super) async fn daily_total(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> btc::MilliSats {
+        sqlx::query_as::<_, SumRow<Option<i64>>>(
+            r#"SELECT SUM(tx_outs.amount_sats)::BIGINT AS sum FROM deposits
+                JOIN tx_outs ON deposits.tx_id = tx_outs.tx_id AND deposits.v_out = tx_outs.v_out
+                WHERE deposits.user_id = $1 AND deposits.confirmed > $2"#,
+        )
+        .bind(user_id.0)
+        .bind(Utc::now() - Duration::days(1))
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .sum
+        .map(|sum| btc::Sats(sum).msats())
+        .unwrap_or_default()
+    }
+
     #[derive(sqlx::FromRow, Debug)]
     struct DepositAddressRow {
         user_id: Uuid,
@@ -339,6 +715,12 @@ mod queries {
         v_out: i32,
         created: DateTime<Utc>,
         confirmed: Option<DateTime<Utc>>,
+        service_fee_msats: Option<i64>,
+        required_confirmations: i32,
+        flagged: Option<DateTime<Utc>>,
+        replaced_by: Option<Uuid>,
+        held_reason: Option<String>,
+        confirmations: Option<i32>,
         block_height: Option<i32>,
         address: String,
         amount_sats: i64,
@@ -353,6 +735,8 @@ mod queries {
                     tx: btc::Tx {
                         id: btc::TxId::from_str(&self.tx_id).unwrap(),
                         block_height: self.block_height.map(|x| x.try_into().unwrap()),
+                        block_hash: None,
+                        inputs: Vec::new(),
                     },
                     address: btc::Address::from_str(&self.address).unwrap(),
                     v_out: self.v_out.try_into().unwrap(),
@@ -360,6 +744,12 @@ mod queries {
                 },
                 created: self.created,
                 confirmed: self.confirmed,
+                service_fee: self.service_fee_msats.map(btc::MilliSats),
+                confirmations: self.confirmations.unwrap_or(0).try_into().unwrap(),
+                required_confirmations: self.required_confirmations.try_into().unwrap(),
+                flagged: self.flagged,
+                replaced_by: self.replaced_by.map(Id),
+                held: self.held_reason.as_deref().map(cash_limits::Error::parse),
             }
         }
     }
@@ -0,0 +1,270 @@
+//! Events describe state changes users are notified about, e.g. a settled invoice or a confirmed
+//! deposit. Events are sent to the user's webhooks, and streamed to the user through the events
+//! endpoint.
+//!
+//! Each event of a user gets the next number in the user's sequence. Clients use the sequence
+//! number to resume the event stream where they left off.
+
+use crate::{
+    deposit::Deposit,
+    invoice::Invoice,
+    payment::{self, Payment},
+    user,
+    withdrawal::Withdrawal,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EventId(pub Uuid);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    InvoiceSettled,
+    PaymentSucceeded,
+    PaymentFailed,
+    DepositDetected,
+    DepositConfirmed,
+    DepositReversed,
+    DepositReplaced,
+    DepositHeld,
+    WithdrawalSent,
+    WithdrawalConfirmed,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::InvoiceSettled => "invoice.settled",
+            EventKind::PaymentSucceeded => "payment.succeeded",
+            EventKind::PaymentFailed => "payment.failed",
+            EventKind::DepositDetected => "deposit.detected",
+            EventKind::DepositConfirmed => "deposit.confirmed",
+            EventKind::DepositReversed => "deposit.reversed",
+            EventKind::DepositReplaced => "deposit.replaced",
+            EventKind::DepositHeld => "deposit.held",
+            EventKind::WithdrawalSent => "withdrawal.sent",
+            EventKind::WithdrawalConfirmed => "withdrawal.confirmed",
+        }
+    }
+
+    /// Parses a kind stored with [`EventKind::as_str`].
+    pub(crate) fn parse(kind: &str) -> Self {
+        match kind {
+            "invoice.settled" => EventKind::InvoiceSettled,
+            "payment.succeeded" => EventKind::PaymentSucceeded,
+            "payment.failed" => EventKind::PaymentFailed,
+            "deposit.detected" => EventKind::DepositDetected,
+            "deposit.confirmed" => EventKind::DepositConfirmed,
+            "deposit.reversed" => EventKind::DepositReversed,
+            "deposit.replaced" => EventKind::DepositReplaced,
+            "deposit.held" => EventKind::DepositHeld,
+            "withdrawal.sent" => EventKind::WithdrawalSent,
+            "withdrawal.confirmed" => EventKind::WithdrawalConfirmed,
+            _ => panic!("unknown event kind {}", kind),
+        }
+    }
+}
+
+/// A state change the user is notified about. The payload is the JSON body sent to the webhooks
+/// and to the event stream.
+#[derive(Debug)]
+pub struct Event {
+    pub id: EventId,
+    pub user_id: user::Id,
+    /// Position of the event among the events of the user, assigned when the event is published.
+    pub sequence: i64,
+    pub kind: EventKind,
+    pub payload: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct Payload<'a, T: Serialize> {
+    id: Uuid,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    created_at: DateTime<Utc>,
+    data: &'a T,
+}
+
+#[derive(Serialize)]
+struct InvoiceData {
+    id: Uuid,
+    amount_msats: i64,
+    amount_paid_msats: i64,
+    service_fee_msats: i64,
+    memo: Option<String>,
+    settled_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct PaymentData {
+    id: Uuid,
+    amount_msats: i64,
+    fee_msats: Option<i64>,
+    service_fee_msats: Option<i64>,
+    failure_reason: Option<String>,
+    finished_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct DepositData {
+    id: Uuid,
+    address: String,
+    amount_sats: i64,
+    service_fee_msats: Option<i64>,
+    tx_id: String,
+    v_out: i64,
+    required_confirmations: u32,
+    confirmed_at: Option<DateTime<Utc>>,
+    replaced_by: Option<Uuid>,
+    held_reason: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct WithdrawalData {
+    id: Uuid,
+    address: String,
+    amount_sats: i64,
+    fee_sats: i64,
+    service_fee_msats: i64,
+    tx_id: String,
+    confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl Event {
+    fn new<T: Serialize>(user_id: user::Id, kind: EventKind, data: &T) -> Self {
+        let id = EventId(Uuid::new_v4());
+        let created = Utc::now();
+        let payload = serde_json::to_string(&Payload {
+            id: id.0,
+            kind: kind.as_str(),
+            created_at: created,
+            data,
+        })
+        .unwrap();
+        Self {
+            id,
+            user_id,
+            sequence: 0,
+            kind,
+            payload,
+            created,
+        }
+    }
+
+    /// Call this after [`Invoice::settle`].
+    pub(crate) fn invoice_settled(invoice: &Invoice) -> Self {
+        let settlement = invoice.settlement.as_ref().unwrap();
+        Self::new(
+            invoice.user_id,
+            EventKind::InvoiceSettled,
+            &InvoiceData {
+                id: invoice.id.0,
+                amount_msats: invoice.amount.0,
+                amount_paid_msats: settlement.amount.0,
+                service_fee_msats: settlement.service_fee.0,
+                memo: invoice.memo.clone(),
+                settled_at: settlement.timestamp,
+            },
+        )
+    }
+
+    /// Returns None if the outcome of the payment isn't known yet.
+    pub(crate) fn payment_finished(payment: &Payment) -> Option<Self> {
+        let (kind, failure_reason, finished_at) = match payment.status {
+            payment::Status::Succeeded { timestamp } => {
+                (EventKind::PaymentSucceeded, None, timestamp)
+            }
+            payment::Status::Failed {
+                ref reason,
+                timestamp,
+            } => (EventKind::PaymentFailed, Some(reason.clone()), timestamp),
+            payment::Status::New | payment::Status::Ready => return None,
+        };
+        Some(Self::new(
+            payment.user_id,
+            kind,
+            &PaymentData {
+                id: payment.id.0,
+                amount_msats: payment.amount.0,
+                fee_msats: payment.fee.map(|fee| fee.0),
+                service_fee_msats: payment.service_fee.map(|fee| fee.0),
+                failure_reason,
+                finished_at,
+            },
+        ))
+    }
+
+    /// Call this after [`crate::deposit::Address::start_deposit`].
+    pub(crate) fn deposit_detected(deposit: &Deposit) -> Self {
+        Self::deposit(EventKind::DepositDetected, deposit)
+    }
+
+    /// Call this after [`Deposit::confirm`].
+    pub(crate) fn deposit_confirmed(deposit: &Deposit) -> Self {
+        Self::deposit(EventKind::DepositConfirmed, deposit)
+    }
+
+    /// Call this after [`Deposit::reverse`].
+    pub(crate) fn deposit_reversed(deposit: &Deposit) -> Self {
+        Self::deposit(EventKind::DepositReversed, deposit)
+    }
+
+    /// Call this after [`Deposit::replace`].
+    pub(crate) fn deposit_replaced(deposit: &Deposit) -> Self {
+        Self::deposit(EventKind::DepositReplaced, deposit)
+    }
+
+    /// Call this after [`Deposit::hold`].
+    pub(crate) fn deposit_held(deposit: &Deposit) -> Self {
+        Self::deposit(EventKind::DepositHeld, deposit)
+    }
+
+    fn deposit(kind: EventKind, deposit: &Deposit) -> Self {
+        Self::new(
+            deposit.user_id,
+            kind,
+            &DepositData {
+                id: deposit.id.0,
+                address: deposit.tx_out.address.to_string(),
+                amount_sats: deposit.tx_out.amount.0,
+                service_fee_msats: deposit.service_fee.map(|fee| fee.0),
+                tx_id: deposit.tx_out.tx.id.to_string(),
+                v_out: deposit.tx_out.v_out,
+                required_confirmations: deposit.required_confirmations,
+                confirmed_at: deposit.confirmed,
+                replaced_by: deposit.replaced_by.map(|id| id.0),
+                held_reason: deposit.held.map(|reason| reason.as_str()),
+            },
+        )
+    }
+
+    /// Call this after [`Withdrawal::send`].
+    pub(crate) fn withdrawal_sent(withdrawal: &Withdrawal) -> Self {
+        Self::withdrawal(EventKind::WithdrawalSent, withdrawal)
+    }
+
+    /// Call this after [`Withdrawal::confirm`].
+    pub(crate) fn withdrawal_confirmed(withdrawal: &Withdrawal) -> Self {
+        Self::withdrawal(EventKind::WithdrawalConfirmed, withdrawal)
+    }
+
+    fn withdrawal(kind: EventKind, withdrawal: &Withdrawal) -> Self {
+        Self::new(
+            withdrawal.user_id,
+            kind,
+            &WithdrawalData {
+                id: withdrawal.id.0,
+                address: withdrawal.address.to_string(),
+                amount_sats: withdrawal.amount.0,
+                fee_sats: withdrawal.fee.0,
+                service_fee_msats: withdrawal.service_fee.0,
+                tx_id: withdrawal.tx_out.as_ref().unwrap().tx.id.to_string(),
+                confirmed_at: withdrawal.confirmed,
+            },
+        )
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{
+    auth,
+    database::{self, Database},
+    webhook,
+};
+
+mod entities;
+
+pub use entities::{Event, EventId, EventKind};
+
+/// Assigns the next sequence number of the user to the event, saves it, and queues it for the
+/// user's webhooks. Call this in the transaction which saves the state change, so that the event is
+/// published if and only if the state change is committed.
+///
+/// The user's sequence stays locked until the transaction ends, so the events of a user are
+/// committed in the order of their sequence numbers. This guarantees that a client which has seen
+/// an event has also seen all the events before it.
+pub(crate) async fn publish(data_tx: &mut database::Transaction, mut event: Event) {
+    event.sequence = queries::next_sequence(data_tx, event.user_id).await;
+    queries::insert(data_tx, &event).await;
+    webhook::enqueue(data_tx, &event).await;
+}
+
+/// Lists the events with a sequence number greater than `after`, oldest first.
+pub async fn list_after(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    after: i64,
+    limit: i64,
+) -> Vec<Event> {
+    queries::list_after(db, grant.user_id, after, limit).await
+}
+
+/// Returns the sequence number of the user's latest event, or 0 if there are no events yet.
+pub async fn last_sequence(grant: &auth::ReadGrant, db: &Database) -> i64 {
+    queries::last_sequence(db, grant.user_id).await
+}
+
+mod queries {
+    use super::{Event, EventId, EventKind};
+    use crate::{
+        database::{self, Database, MaxRow},
+        user,
+    };
+    use chrono::{DateTime, Utc};
+    use const_format::formatcp;
+    use uuid::Uuid;
+
+    const COLUMNS: &str = "id, user_id, sequence, kind, payload, created";
+
+    pub(super) async fn next_sequence(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> i64 {
+        sqlx::query_as::<_, SequenceRow>(
+            r#"INSERT INTO event_sequences (user_id, last_sequence) VALUES ($1, 1)
+                ON CONFLICT (user_id) DO UPDATE SET last_sequence = event_sequences.last_sequence + 1
+                RETURNING last_sequence"#,
+        )
+        .bind(user_id.0)
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .last_sequence
+    }
+
+    pub(super) async fn insert(data_tx: &mut database::Transaction, event: &Event) {
+        sqlx::query(formatcp!(
+            "INSERT INTO events ({}) VALUES ($1, $2, $3, $4, $5, $6)",
+            COLUMNS
+        ))
+        .bind(event.id.0)
+        .bind(event.user_id.0)
+        .bind(event.sequence)
+        .bind(event.kind.as_str())
+        .bind(event.payload.clone())
+        .bind(event.created)
+        .execute(data_tx)
+        .await
+        .unwrap();
+    }
+
+    pub(super) async fn list_after(
+        db: &Database,
+        user_id: user::Id,
+        after: i64,
+        limit: i64,
+    ) -> Vec<Event> {
+        sqlx::query_as::<_, EventRow>(formatcp!(
+            "SELECT {} FROM events WHERE user_id = $1 AND sequence > $2 ORDER BY sequence LIMIT $3",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn last_sequence(db: &Database, user_id: user::Id) -> i64 {
+        sqlx::query_as::<_, MaxRow<i64>>(
+            "SELECT MAX(sequence) AS max FROM events WHERE user_id = $1",
+        )
+        .bind(user_id.0)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .max
+        .unwrap_or(0)
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct SequenceRow {
+        last_sequence: i64,
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct EventRow {
+        id: Uuid,
+        user_id: Uuid,
+        sequence: i64,
+        kind: String,
+        payload: String,
+        created: DateTime<Utc>,
+    }
+
+    impl EventRow {
+        fn into_entity(self) -> Event {
+            Event {
+                id: EventId(self.id),
+                user_id: user::Id(self.user_id),
+                sequence: self.sequence,
+                kind: EventKind::parse(&self.kind),
+                payload: self.payload,
+                created: self.created,
+            }
+        }
+    }
+}
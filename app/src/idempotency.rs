@@ -0,0 +1,303 @@
+//! Allows clients to safely retry requests which create payments, invoices and withdrawals. The
+//! client sends a unique key with the request, and the key is stored together with the ID of the
+//! created entity. If the request is retried with the same key, the original entity is returned
+//! instead of creating a new one.
+//!
+//! Requests are run with [`run`], which claims the key before doing any work. The entity ID is then
+//! recorded with [`record`] in the same transaction which saves the entity, so that a key never
+//! points to an entity which doesn't exist. If the request fails, the claim is dropped and the key
+//! can be used again, so a retry gets the error again instead of a failed entity. The claim is kept
+//! only if the request may still complete, e.g. a payment whose outcome is unknown.
+
+use crate::{
+    database::{self, Database},
+    hex::Hex,
+    user,
+};
+use chrono::{Duration, Utc};
+use const_format::formatcp;
+use sha2::Digest;
+use std::future::Future;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid idempotency key: {0}")]
+    InvalidKey(&'static str),
+    #[error("idempotency key has already been used for a different request")]
+    KeyReused,
+    #[error("a request with the same idempotency key is still in progress")]
+    InProgress,
+}
+
+/// An idempotency key chosen by the client.
+#[derive(Debug, Clone)]
+pub struct Key(String);
+
+const MAX_KEY_CHARS: usize = 255;
+
+/// Claims without a recorded entity are considered abandoned after this long, e.g. because our
+/// service was restarted in the middle of a request. This is longer than any request can take.
+const ABANDONED_AFTER_MINUTES: i64 = 10;
+
+impl Key {
+    pub fn new(key: String) -> Result<Self, Error> {
+        if key.is_empty() {
+            Err(Error::InvalidKey("key can't be empty"))
+        } else if key.chars().count() > MAX_KEY_CHARS {
+            Err(Error::InvalidKey(formatcp!(
+                "key can be up to {} characters long",
+                MAX_KEY_CHARS
+            )))
+        } else {
+            Ok(Self(key))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The kind of entity created by the request.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operation {
+    Payment,
+    Invoice,
+    Withdrawal,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Payment => "payment",
+            Operation::Invoice => "invoice",
+            Operation::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+/// Describes the request, so that retries with the same key but different parameters can be told
+/// apart from genuine retries.
+pub(crate) struct Fingerprint(Hex);
+
+impl Fingerprint {
+    pub(crate) fn new(operation: Operation, request: &str) -> Self {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(operation.as_str());
+        hasher.update(":");
+        hasher.update(request);
+        Self(Hex::encode(&hasher.finalize()))
+    }
+}
+
+/// The error of a request run with [`run`].
+pub(crate) trait RequestError: From<Error> {
+    /// True if the request may still complete despite the error, in which case the claim is kept,
+    /// so that a retry can't repeat the request.
+    fn is_pending(&self) -> bool {
+        false
+    }
+}
+
+/// Runs the request under the idempotency key, if there is one. If the key has already been used
+/// for the same request, `get` loads the entity created by the original request instead.
+pub(crate) async fn run<T, E, G, R>(
+    db: &Database,
+    user_id: user::Id,
+    key: Option<&Key>,
+    operation: Operation,
+    fingerprint: &Fingerprint,
+    get: impl FnOnce(Uuid) -> G,
+    request: impl FnOnce() -> R,
+) -> Result<T, E>
+where
+    E: RequestError,
+    G: Future<Output = Option<T>>,
+    R: Future<Output = Result<T, E>>,
+{
+    let key = match key {
+        Some(key) => key,
+        None => return request().await,
+    };
+    if let Claim::Replay(entity_id) = claim(db, user_id, key, operation, fingerprint).await? {
+        match get(entity_id).await {
+            Some(entity) => return Ok(entity),
+            // The entity is gone, so the key can be reused, unless a concurrent retry got it first
+            None if queries::forget(db, user_id, key, entity_id).await => {
+                log::warn!(
+                    "idempotency key of user {:?} points to missing entity {}",
+                    user_id,
+                    entity_id
+                );
+            }
+            None => return Err(Error::InProgress.into()),
+        }
+    }
+    let result = request().await;
+    if let Err(e) = &result {
+        if !e.is_pending() {
+            queries::delete(db, user_id, key).await;
+        }
+    }
+    result
+}
+
+enum Claim {
+    /// The key hasn't been used before, the request should be processed.
+    New,
+    /// The request has already been processed, creating the entity with this ID.
+    Replay(Uuid),
+}
+
+async fn claim(
+    db: &Database,
+    user_id: user::Id,
+    key: &Key,
+    operation: Operation,
+    fingerprint: &Fingerprint,
+) -> Result<Claim, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    if queries::insert(&mut data_tx, user_id, key, operation, fingerprint).await {
+        data_tx.commit().await.unwrap();
+        return Ok(Claim::New);
+    }
+    let existing = queries::lock(&mut data_tx, user_id, key).await;
+    if existing.operation != operation.as_str() || existing.fingerprint != fingerprint.0.as_str() {
+        return Err(Error::KeyReused);
+    }
+    match existing.entity_id {
+        Some(entity_id) => Ok(Claim::Replay(entity_id)),
+        None if existing.created < Utc::now() - Duration::minutes(ABANDONED_AFTER_MINUTES) => {
+            queries::renew(&mut data_tx, user_id, key).await;
+            data_tx.commit().await.unwrap();
+            Ok(Claim::New)
+        }
+        None => Err(Error::InProgress),
+    }
+}
+
+/// Records the entity created for the claimed key. Call this in the transaction which saves the
+/// entity.
+pub(crate) async fn record(
+    data_tx: &mut database::Transaction,
+    user_id: user::Id,
+    key: &Key,
+    entity_id: Uuid,
+) {
+    queries::set_entity_id(data_tx, user_id, key, entity_id).await;
+}
+
+mod queries {
+    use super::{Fingerprint, Key, Operation};
+    use crate::{
+        database::{self, Database},
+        user,
+    };
+    use chrono::{DateTime, Utc};
+    use uuid::Uuid;
+
+    /// Inserts the key, returning false if the user has already used it.
+    pub(super) async fn insert(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+        key: &Key,
+        operation: Operation,
+        fingerprint: &Fingerprint,
+    ) -> bool {
+        sqlx::query(
+            r#"INSERT INTO idempotency_keys (user_id, key, operation, fingerprint, entity_id, created)
+                VALUES ($1, $2, $3, $4, NULL, $5) ON CONFLICT (user_id, key) DO NOTHING RETURNING key"#,
+        )
+        .bind(user_id.0)
+        .bind(key.as_str())
+        .bind(operation.as_str())
+        .bind(fingerprint.0.as_str())
+        .bind(Utc::now())
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    pub(super) async fn lock(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+        key: &Key,
+    ) -> KeyRow {
+        sqlx::query_as::<_, KeyRow>(
+            r#"SELECT operation, fingerprint, entity_id, created FROM idempotency_keys
+                WHERE user_id = $1 AND key = $2 FOR UPDATE"#,
+        )
+        .bind(user_id.0)
+        .bind(key.as_str())
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+    }
+
+    pub(super) async fn renew(data_tx: &mut database::Transaction, user_id: user::Id, key: &Key) {
+        sqlx::query("UPDATE idempotency_keys SET created = $3 WHERE user_id = $1 AND key = $2")
+            .bind(user_id.0)
+            .bind(key.as_str())
+            .bind(Utc::now())
+            .execute(data_tx)
+            .await
+            .unwrap();
+    }
+
+    pub(super) async fn set_entity_id(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+        key: &Key,
+        entity_id: Uuid,
+    ) {
+        sqlx::query("UPDATE idempotency_keys SET entity_id = $3 WHERE user_id = $1 AND key = $2")
+            .bind(user_id.0)
+            .bind(key.as_str())
+            .bind(entity_id)
+            .execute(data_tx)
+            .await
+            .unwrap();
+    }
+
+    pub(super) async fn delete(db: &Database, user_id: user::Id, key: &Key) {
+        sqlx::query("DELETE FROM idempotency_keys WHERE user_id = $1 AND key = $2")
+            .bind(user_id.0)
+            .bind(key.as_str())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    /// Unrecords the entity and renews the claim, returning false if the key doesn't point to the
+    /// entity anymore.
+    pub(super) async fn forget(
+        db: &Database,
+        user_id: user::Id,
+        key: &Key,
+        entity_id: Uuid,
+    ) -> bool {
+        sqlx::query(
+            r#"UPDATE idempotency_keys SET entity_id = NULL, created = $4
+                WHERE user_id = $1 AND key = $2 AND entity_id = $3 RETURNING key"#,
+        )
+        .bind(user_id.0)
+        .bind(key.as_str())
+        .bind(entity_id)
+        .bind(Utc::now())
+        .fetch_optional(db)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    pub(super) struct KeyRow {
+        pub(super) operation: String,
+        pub(super) fingerprint: String,
+        pub(super) entity_id: Option<Uuid>,
+        pub(super) created: DateTime<Utc>,
+    }
+}
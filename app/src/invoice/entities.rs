@@ -1,9 +1,14 @@
 //! Handles the creation and settlement of users' Lightning invoices within the service.
 //!
 //! Create an invoice by calling [`Invoice::create`], and once it is eventually paid settle
-//! the invoice via [`Invoice::settle`], which will update the user balance.
+//! the invoice via [`Invoice::settle`], which will update the user balance. Invoices paid by other
+//! users of the service are settled via [`Invoice::settle_internally`] instead, without involving
+//! the Lightning node.
 
-use crate::{auth, balance::Balance, btc, cash_limits, ln, seconds::Seconds, user, CashLimits};
+use crate::{
+    auth, balance::Balance, btc, cash_limits, idempotency, ledger::EntryKind, ln,
+    pricing::FeeSchedule, seconds::Seconds, user, CashLimits,
+};
 use chrono::{DateTime, Utc};
 use const_format::formatcp;
 use thiserror::Error;
@@ -19,8 +24,12 @@ pub enum Error {
     InvalidExpiry(&'static str),
     #[error("invalid memo: {0}")]
     InvalidMemo(&'static str),
+    #[error("{0}")]
+    Idempotency(#[from] idempotency::Error),
 }
 
+impl idempotency::RequestError for Error {}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Id(pub Uuid);
 
@@ -39,12 +48,16 @@ pub struct Invoice {
 
 #[derive(Debug)]
 pub struct Settlement {
+    /// The received amount, including our service fee.
     pub amount: btc::MilliSats,
+    /// Our service fee, taken out of the received amount.
+    pub service_fee: btc::MilliSats,
     pub timestamp: DateTime<Utc>,
     /// Unique index on our Lightning node, which indicates the settlement order of this invoice.
     /// When our service gets restarted, this index allows us to continue the invoice update stream
-    /// from where we were before the restart.
-    pub settle_index: u64,
+    /// from where we were before the restart. None if the invoice was paid by another user of our
+    /// service, in which case the payment never reached the node.
+    pub settle_index: Option<u64>,
 }
 
 const MAX_MEMO_BYTES: usize = 639;
@@ -111,8 +124,47 @@ impl Invoice {
         Utc::now() >= self.expiration
     }
 
-    /// Settles the invoice. Credits the received funds to the user.
-    pub(crate) fn settle(&mut self, balance: &mut Balance, settled_invoice: &ln::SettledInvoice) {
+    /// Settles the invoice. Credits the received funds to the user, minus our service fee according
+    /// to the schedule.
+    pub(crate) fn settle(
+        &mut self,
+        balance: &mut Balance,
+        settled_invoice: &ln::SettledInvoice,
+        schedule: &FeeSchedule,
+    ) {
+        if settled_invoice.raw != self.raw {
+            panic!(
+                "payment request {:?} does not match {:?} for invoice {:?}",
+                settled_invoice.raw, self.raw, self.id
+            );
+        }
+        self.credit(
+            balance,
+            settled_invoice.amount,
+            Some(settled_invoice.settle_index),
+            schedule,
+        );
+    }
+
+    /// Settles the invoice paid by another user of our service. Credits the received funds to the
+    /// user, minus our service fee according to the schedule. The caller is responsible for
+    /// debiting the payer, see [`crate::payment::Payment::settle_internally`].
+    pub(crate) fn settle_internally(
+        &mut self,
+        balance: &mut Balance,
+        amount: btc::MilliSats,
+        schedule: &FeeSchedule,
+    ) {
+        self.credit(balance, amount, None, schedule);
+    }
+
+    fn credit(
+        &mut self,
+        balance: &mut Balance,
+        amount: btc::MilliSats,
+        settle_index: Option<u64>,
+        schedule: &FeeSchedule,
+    ) {
         if self.is_settled() {
             panic!("invoice {:?} has already been completed", self.id);
         }
@@ -124,17 +176,17 @@ impl Invoice {
                 self.id
             );
         }
-        if settled_invoice.raw != self.raw {
-            panic!(
-                "payment request {:?} does not match {:?} for invoice {:?}",
-                settled_invoice.raw, self.raw, self.id
-            );
-        }
+        let service_fee = schedule.credit_fee(amount);
         self.settlement = Some(Settlement {
-            amount: settled_invoice.amount,
+            amount,
+            service_fee,
             timestamp: Utc::now(),
-            settle_index: settled_invoice.settle_index,
+            settle_index,
         });
-        balance.credit(settled_invoice.amount);
+        let kind = match settle_index {
+            Some(_) => EntryKind::Invoice,
+            None => EntryKind::InternalInvoice,
+        };
+        balance.credit_with_fee(amount, service_fee, kind, self.id.0);
     }
 }
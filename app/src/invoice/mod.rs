@@ -1,9 +1,12 @@
 use crate::{
     auth, balance, btc, concurrency,
-    database::Database,
+    database::{self, Database},
+    event::{self, Event},
+    idempotency::{self, Fingerprint, Operation},
     ln::{self, Lightning},
+    pricing,
     seconds::Seconds,
-    swallow_panic, worker, CashLimits, QueryRange,
+    swallow_panic, worker, AmountRange, CashLimits, Page, Period,
 };
 use async_trait::async_trait;
 use futures::StreamExt;
@@ -14,6 +17,9 @@ mod entities;
 
 pub use entities::{Error, Id, Invoice, Settlement};
 
+/// Creates an invoice. If an invoice has already been created with the same idempotency key, that
+/// invoice is returned instead.
+#[allow(clippy::too_many_arguments)]
 pub async fn create(
     grant: &auth::ReceiveGrant,
     db: &Database,
@@ -22,37 +28,94 @@ pub async fn create(
     memo: Option<String>,
     expiry: Seconds,
     limits: &CashLimits,
+    idempotency_key: Option<&idempotency::Key>,
 ) -> Result<Invoice, Error> {
-    let daily_total = queries::daily_total(db, grant.user_id).await;
-    let invoice = Invoice::create(grant, node, amount, memo, expiry, limits, daily_total).await?;
+    let fingerprint = Fingerprint::new(
+        Operation::Invoice,
+        &format!("{:?} {:?} {:?}", amount, memo, expiry),
+    );
+    idempotency::run(
+        db,
+        grant.user_id,
+        idempotency_key,
+        Operation::Invoice,
+        &fingerprint,
+        |id| queries::get(db, Id(id), grant.user_id),
+        || async {
+            let daily_total = queries::daily_total(db, grant.user_id).await;
+            let invoice =
+                Invoice::create(grant, node, amount, memo, expiry, limits, daily_total).await?;
 
-    let mut data_tx = db.begin().await.unwrap();
-    queries::upsert(&mut data_tx, &invoice).await;
-    data_tx.commit().await.unwrap();
-    Ok(invoice)
+            let mut data_tx = db.begin().await.unwrap();
+            queries::upsert(&mut data_tx, &invoice).await;
+            if let Some(key) = idempotency_key {
+                idempotency::record(&mut data_tx, invoice.user_id, key, invoice.id.0).await;
+            }
+            data_tx.commit().await.unwrap();
+            Ok(invoice)
+        },
+    )
+    .await
 }
 
 pub async fn get(grant: &auth::ReadGrant, db: &Database, id: Id) -> Option<Invoice> {
     queries::get(db, id, grant.user_id).await
 }
 
-pub async fn list(grant: &auth::ReadGrant, db: &Database, range: QueryRange) -> Vec<Invoice> {
-    queries::list(db, grant.user_id, range).await
+/// Selects the invoices to list. The default filter selects all invoices.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Filter {
+    pub settled: Option<bool>,
+    pub expired: Option<bool>,
+    pub period: Period,
+    pub amount: AmountRange<btc::MilliSats>,
+}
+
+/// Lists the invoices matching the filter, most recent first.
+pub async fn list(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    filter: &Filter,
+    page: &Page<Id>,
+) -> Vec<Invoice> {
+    queries::list(db, grant.user_id, filter, page).await
+}
+
+/// Finds the invoice of one of our users with the given payment request, if there is one.
+pub(crate) async fn get_by_invoice(db: &Database, invoice: &ln::RawInvoice) -> Option<Invoice> {
+    queries::get_by_invoice(db, invoice).await
+}
+
+/// Locks the invoice with the given payment request until the transaction ends.
+pub(crate) async fn lock_by_invoice(
+    data_tx: &mut database::Transaction,
+    invoice: &ln::RawInvoice,
+) -> Invoice {
+    queries::lock_by_invoice(data_tx, invoice).await
+}
+
+/// Saves an invoice settled via [`Invoice::settle_internally`], publishing the settlement.
+pub(crate) async fn save_settled(data_tx: &mut database::Transaction, invoice: &Invoice) {
+    queries::upsert(data_tx, invoice).await;
+    event::publish(data_tx, Event::invoice_settled(invoice)).await;
 }
 
 pub async fn start_worker(db: Database, lightning: &Lightning) {
     let mut node = lightning.create_node().await;
-    {
-        let mut uncompleted_invoices = queries::get_unsettled(&db);
-        while let Some(invoice) = uncompleted_invoices.next().await {
-            if let ln::InvoiceStatus::Settled(settled_invoice) =
-                node.get_invoice_status(&invoice.raw).await
-            {
-                complete(&db, invoice, &settled_invoice).await;
-            }
+    reconcile(&db, &mut node).await;
+    worker::start(InvoiceListener { db, node });
+}
+
+/// Settles the invoices which were paid while the invoice listener wasn't running.
+pub async fn reconcile(db: &Database, node: &mut ln::Node) {
+    let mut uncompleted_invoices = queries::get_unsettled(db);
+    while let Some(invoice) = uncompleted_invoices.next().await {
+        if let ln::InvoiceStatus::Settled(settled_invoice) =
+            node.get_invoice_status(&invoice.raw).await
+        {
+            complete(db, invoice, &settled_invoice).await;
         }
     }
-    worker::start(InvoiceListener { db, node });
 }
 
 struct InvoiceListener {
@@ -90,12 +153,22 @@ async fn complete(db: &Database, invoice: Invoice, settled_invoice: &ln::Settled
     let invoice = Mutex::new(invoice);
     concurrency::retry_loop(|| async {
         let mut invoice = invoice.lock().await;
+        if invoice.is_settled() && invoice.settlement.as_ref().unwrap().settle_index.is_none() {
+            log::error!(
+                "invoice {:?} was paid by another user, but it was also paid over Lightning",
+                invoice.id
+            );
+        }
         if !invoice.is_settled() {
             let mut data_tx = db.begin().await.unwrap();
             let mut balance = balance::get(&mut data_tx, invoice.user_id).await;
-            invoice.settle(&mut balance, settled_invoice);
+            let schedule =
+                pricing::get_schedule(&mut data_tx, invoice.user_id, pricing::Operation::Invoice)
+                    .await;
+            invoice.settle(&mut balance, settled_invoice, &schedule);
             queries::upsert(&mut data_tx, &invoice).await;
             balance::update(&mut data_tx, &balance).await?;
+            event::publish(&mut data_tx, Event::invoice_settled(&invoice)).await;
             data_tx.commit().await.unwrap();
         }
         Ok::<_, concurrency::ConflictError>(())
@@ -105,24 +178,24 @@ async fn complete(db: &Database, invoice: Invoice, settled_invoice: &ln::Settled
 }
 
 mod queries {
-    use super::{Id, Invoice, Settlement};
+    use super::{Filter, Id, Invoice, Settlement};
     use crate::{
         auth, btc,
         database::{self, Database, SumRow},
-        ln, user, QueryRange,
+        ln, user, Page,
     };
     use chrono::{DateTime, Duration, Utc};
     use const_format::formatcp;
     use futures::{stream::BoxStream, StreamExt};
     use uuid::Uuid;
 
-    const COLUMNS: &str = "id, user_id, token_id, amount_msats, memo, invoice, created, expiration, settlement_amount, settlement_timestamp, settle_index";
+    const COLUMNS: &str = "id, user_id, token_id, amount_msats, memo, invoice, created, expiration, settlement_amount, settlement_timestamp, settle_index, service_fee_msats";
 
     pub(super) async fn upsert(data_tx: &mut database::Transaction, invoice: &Invoice) {
         sqlx::query(
             formatcp!(r#"INSERT INTO invoices ({})
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) ON CONFLICT (id) DO UPDATE SET
-                user_id = $2, token_id = $3, amount_msats = $4, memo = $5, invoice = $6, created = $7, expiration = $8, settlement_amount = $9, settlement_timestamp = $10, settle_index = $11"#,
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) ON CONFLICT (id) DO UPDATE SET
+                user_id = $2, token_id = $3, amount_msats = $4, memo = $5, invoice = $6, created = $7, expiration = $8, settlement_amount = $9, settlement_timestamp = $10, settle_index = $11, service_fee_msats = $12"#,
                 COLUMNS)
         )
         .bind(invoice.id.0)
@@ -135,7 +208,8 @@ mod queries {
         .bind(invoice.expiration)
         .bind(invoice.settlement.as_ref().map(|settlement| settlement.amount.0))
         .bind(invoice.settlement.as_ref().map(|settlement| settlement.timestamp))
-        .bind(invoice.settlement.as_ref().map(|settlement| i64::try_from(settlement.settle_index).unwrap()))
+        .bind(invoice.settlement.as_ref().and_then(|settlement| settlement.settle_index).map(|settle_index| i64::try_from(settle_index).unwrap()))
+        .bind(invoice.settlement.as_ref().map(|settlement| settlement.service_fee.0))
         .execute(&mut *data_tx)
         .await
         .unwrap();
@@ -153,6 +227,21 @@ mod queries {
         .map(|row| row.into_entity())
     }
 
+    pub(super) async fn lock_by_invoice(
+        data_tx: &mut database::Transaction,
+        invoice: &ln::RawInvoice,
+    ) -> Invoice {
+        sqlx::query_as::<_, InvoiceRow>(formatcp!(
+            "SELECT {} FROM invoices WHERE invoice = $1 FOR UPDATE",
+            COLUMNS
+        ))
+        .bind(invoice.0.clone())
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .into_entity()
+    }
+
     pub(super) async fn get(db: &Database, id: Id, user_id: user::Id) -> Option<Invoice> {
         sqlx::query_as::<_, InvoiceRow>(formatcp!(
             "SELECT {} FROM invoices WHERE id = $1 AND user_id = $2",
@@ -166,14 +255,33 @@ mod queries {
         .map(|row| row.into_entity())
     }
 
-    pub(super) async fn list(db: &Database, user_id: user::Id, range: QueryRange) -> Vec<Invoice> {
+    pub(super) async fn list(
+        db: &Database,
+        user_id: user::Id,
+        filter: &Filter,
+        page: &Page<Id>,
+    ) -> Vec<Invoice> {
         sqlx::query_as::<_, InvoiceRow>(formatcp!(
-            "SELECT {} FROM invoices WHERE user_id = $1 ORDER BY created DESC LIMIT $2 OFFSET $3",
+            r#"SELECT {} FROM invoices WHERE user_id = $1
+                AND ($2::BOOLEAN IS NULL OR (settlement_timestamp IS NOT NULL) = $2)
+                AND ($3::BOOLEAN IS NULL OR (expiration <= NOW()) = $3)
+                AND ($4::TIMESTAMPTZ IS NULL OR created >= $4) AND ($5::TIMESTAMPTZ IS NULL OR created < $5)
+                AND ($6::BIGINT IS NULL OR amount_msats >= $6) AND ($7::BIGINT IS NULL OR amount_msats <= $7)
+                AND ($8::TIMESTAMPTZ IS NULL OR (created, id) < ($8, $9))
+                ORDER BY created DESC, id DESC LIMIT $10 OFFSET $11"#,
             COLUMNS
         ))
         .bind(user_id.0)
-        .bind(range.limit)
-        .bind(range.offset)
+        .bind(filter.settled)
+        .bind(filter.expired)
+        .bind(filter.period.from)
+        .bind(filter.period.to)
+        .bind(filter.amount.min.map(|amount| amount.0))
+        .bind(filter.amount.max.map(|amount| amount.0))
+        .bind(page.after.map(|cursor| cursor.created))
+        .bind(page.after.map(|cursor| cursor.id.0))
+        .bind(page.range.limit)
+        .bind(page.range.offset)
         .fetch_all(db)
         .await
         .unwrap()
@@ -205,7 +313,7 @@ mod queries {
 
     pub(super) async fn daily_total(db: &Database, user_id: user::Id) -> btc::MilliSats {
         sqlx::query_as::<_, SumRow<Option<i64>>>(
-            "SELECT SUM(CAST(amount_msats AS INTEGER)) AS sum FROM invoices WHERE user_id = $1 AND created > $2",
+            "SELECT SUM(amount_msats)::BIGINT AS sum FROM invoices WHERE user_id = $1 AND created > $2",
         )
         .bind(user_id.0)
         .bind(Utc::now() - Duration::days(1))
@@ -230,6 +338,7 @@ mod queries {
         settlement_amount: Option<i64>,
         settlement_timestamp: Option<DateTime<Utc>>,
         settle_index: Option<i64>,
+        service_fee_msats: Option<i64>,
     }
 
     impl InvoiceRow {
@@ -243,15 +352,14 @@ mod queries {
                 raw: ln::RawInvoice(self.invoice),
                 created: self.created,
                 expiration: self.expiration,
-                settlement: match (
-                    self.settlement_amount,
-                    self.settlement_timestamp,
-                    self.settle_index,
-                ) {
-                    (Some(amount), Some(timestamp), Some(settle_index)) => Some(Settlement {
+                settlement: match (self.settlement_amount, self.settlement_timestamp) {
+                    (Some(amount), Some(timestamp)) => Some(Settlement {
                         amount: btc::MilliSats(amount),
+                        service_fee: btc::MilliSats(self.service_fee_msats.unwrap_or(0)),
                         timestamp,
-                        settle_index: settle_index.try_into().unwrap(),
+                        settle_index: self
+                            .settle_index
+                            .map(|settle_index| settle_index.try_into().unwrap()),
                     }),
                     _ => None,
                 },
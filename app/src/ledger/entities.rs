@@ -0,0 +1,290 @@
+//! The ledger is an append-only record of every change to user funds, kept with double-entry
+//! bookkeeping. Each change is a [`JournalEntry`] made of lines which move funds between accounts.
+//! The lines of a journal entry always add up to zero, so funds are never created or destroyed,
+//! only moved from one account to another.
+//!
+//! Every user has two accounts: [`Account::User`], which holds the available balance, and
+//! [`Account::Reserved`], which holds the funds reserved for payments and withdrawals that haven't
+//! completed yet. [`Account::Revenue`] collects the service fees we charge, less the on-chain fees
+//! we pay for users. The other accounts
+//! stand for the world outside of our service, e.g. [`Account::Lightning`] is where the funds of
+//! settled invoices come from and where the funds of payments go to.
+//!
+//! Journal entries are created by [`crate::balance::Balance`] and
+//! [`crate::balance::Reservation`], and saved together with the balance changes they explain. The
+//! on-chain fees we pay for users are recorded by the withdrawals which cause them.
+
+use crate::{btc, deposit, invoice, payment, user, withdrawal};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JournalId(pub Uuid);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Account {
+    /// The available balance of the user.
+    User(user::Id),
+    /// Funds of the user reserved for a payment or withdrawal which hasn't completed yet.
+    Reserved(user::Id),
+    /// Funds received and sent over Lightning.
+    Lightning,
+    /// Funds received and sent on-chain.
+    Onchain,
+    /// Funds moved between users of our service. The payer's side and the payee's side of a
+    /// payment cancel each other out, so this account always adds up to zero.
+    Internal,
+    /// Balances which existed before the ledger was introduced, or were seeded.
+    Opening,
+    /// Service fees earned by our service, see [`crate::pricing`], less the on-chain fees we pay
+    /// for users.
+    Revenue,
+}
+
+impl Account {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Account::User(_) => "user",
+            Account::Reserved(_) => "reserved",
+            Account::Lightning => "lightning",
+            Account::Onchain => "onchain",
+            Account::Internal => "internal",
+            Account::Opening => "opening",
+            Account::Revenue => "revenue",
+        }
+    }
+
+    pub(crate) fn user_id(&self) -> Option<user::Id> {
+        match self {
+            Account::User(user_id) | Account::Reserved(user_id) => Some(*user_id),
+            _ => None,
+        }
+    }
+}
+
+/// What a line of a journal entry is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Funds the user had before the ledger was introduced.
+    Opening,
+    /// An invoice paid over Lightning.
+    Invoice,
+    /// An invoice paid by another user of our service.
+    InternalInvoice,
+    /// A confirmed deposit.
+    Deposit,
+    /// A confirmed deposit taken back because a chain reorganization removed its transaction.
+    DepositReversal,
+    /// A Lightning payment, without the fee.
+    Payment,
+    /// The routing fee of a Lightning payment.
+    PaymentFee,
+    /// A payment of an invoice created by another user of our service.
+    InternalPayment,
+    /// An on-chain withdrawal, without the fee.
+    Withdrawal,
+    /// The on-chain fee of a withdrawal.
+    WithdrawalFee,
+    /// Reserved funds returned to the user because a payment or withdrawal failed.
+    Refund,
+    /// Our service fee for a payment, invoice, withdrawal or deposit.
+    ServiceFee,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Opening => "opening",
+            EntryKind::Invoice => "invoice",
+            EntryKind::InternalInvoice => "internal_invoice",
+            EntryKind::Deposit => "deposit",
+            EntryKind::DepositReversal => "deposit_reversal",
+            EntryKind::Payment => "payment",
+            EntryKind::PaymentFee => "payment_fee",
+            EntryKind::InternalPayment => "internal_payment",
+            EntryKind::Withdrawal => "withdrawal",
+            EntryKind::WithdrawalFee => "withdrawal_fee",
+            EntryKind::Refund => "refund",
+            EntryKind::ServiceFee => "service_fee",
+        }
+    }
+
+    /// Parses a kind stored with [`EntryKind::as_str`].
+    pub(crate) fn parse(kind: &str) -> Self {
+        match kind {
+            "opening" => EntryKind::Opening,
+            "invoice" => EntryKind::Invoice,
+            "internal_invoice" => EntryKind::InternalInvoice,
+            "deposit" => EntryKind::Deposit,
+            "deposit_reversal" => EntryKind::DepositReversal,
+            "payment" => EntryKind::Payment,
+            "payment_fee" => EntryKind::PaymentFee,
+            "internal_payment" => EntryKind::InternalPayment,
+            "withdrawal" => EntryKind::Withdrawal,
+            "withdrawal_fee" => EntryKind::WithdrawalFee,
+            "refund" => EntryKind::Refund,
+            "service_fee" => EntryKind::ServiceFee,
+            _ => panic!("unknown entry kind {}", kind),
+        }
+    }
+
+    /// The account on the other side of the user's account when funds of this kind are credited
+    /// to or debited from the user.
+    pub(crate) fn counter_account(&self, user_id: user::Id) -> Account {
+        match self {
+            EntryKind::Opening => Account::Opening,
+            EntryKind::Invoice => Account::Lightning,
+            EntryKind::InternalInvoice => Account::Internal,
+            EntryKind::Deposit | EntryKind::DepositReversal => Account::Onchain,
+            EntryKind::Payment
+            | EntryKind::PaymentFee
+            | EntryKind::InternalPayment
+            | EntryKind::Withdrawal
+            | EntryKind::WithdrawalFee
+            | EntryKind::Refund => Account::Reserved(user_id),
+            EntryKind::ServiceFee => Account::Revenue,
+        }
+    }
+
+    /// The account reserved funds of this kind go to once they're irrevocably spent.
+    pub(crate) fn spending_account(&self) -> Account {
+        match self {
+            EntryKind::Payment | EntryKind::PaymentFee => Account::Lightning,
+            EntryKind::InternalPayment => Account::Internal,
+            EntryKind::Withdrawal | EntryKind::WithdrawalFee => Account::Onchain,
+            EntryKind::ServiceFee => Account::Revenue,
+            _ => panic!("funds of kind {:?} are never reserved", self),
+        }
+    }
+
+    /// The kind of the fee line for a reservation of this kind.
+    pub(crate) fn fee_kind(&self) -> Self {
+        match self {
+            EntryKind::Payment => EntryKind::PaymentFee,
+            EntryKind::Withdrawal => EntryKind::WithdrawalFee,
+            _ => panic!("{:?} has no fee", self),
+        }
+    }
+}
+
+/// One change to user funds, e.g. a settled invoice or a reservation for a payment.
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub id: JournalId,
+    /// The invoice, deposit, payment or withdrawal which caused the change, or the user for
+    /// opening balances.
+    pub reference: Uuid,
+    pub created: DateTime<Utc>,
+    pub lines: Vec<Line>,
+}
+
+/// A line of a journal entry. A positive amount adds funds to the account, a negative amount
+/// takes funds away from it.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub account: Account,
+    pub kind: EntryKind,
+    pub amount: btc::MilliSats,
+}
+
+impl JournalEntry {
+    /// Creates a journal entry, skipping lines with a zero amount. Panics if the lines don't add up
+    /// to zero.
+    pub(crate) fn new(reference: Uuid, lines: Vec<Line>) -> Self {
+        let total = lines
+            .iter()
+            .fold(btc::MilliSats(0), |total, line| total + line.amount);
+        if total != btc::MilliSats(0) {
+            panic!(
+                "journal entry for {:?} does not balance: {:?}",
+                reference, lines
+            );
+        }
+        Self {
+            id: JournalId(Uuid::new_v4()),
+            reference,
+            created: Utc::now(),
+            lines: lines
+                .into_iter()
+                .filter(|line| line.amount != btc::MilliSats(0))
+                .collect(),
+        }
+    }
+
+    /// Moves the amount from one account to another.
+    pub(crate) fn transfer(
+        reference: Uuid,
+        kind: EntryKind,
+        from: Account,
+        to: Account,
+        amount: btc::MilliSats,
+    ) -> Self {
+        Self::new(
+            reference,
+            vec![
+                Line {
+                    account: from,
+                    kind,
+                    amount: btc::MilliSats(0) - amount,
+                },
+                Line {
+                    account: to,
+                    kind,
+                    amount,
+                },
+            ],
+        )
+    }
+}
+
+/// A movement of the user's available balance, i.e. the [`Account::User`] lines of a journal
+/// entry. Transactions make up the user's account statement.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub id: JournalId,
+    pub kind: EntryKind,
+    /// The invoice, payment, deposit or withdrawal the transaction belongs to. None for opening
+    /// balances.
+    pub entity: Option<Entity>,
+    /// Positive if funds were credited to the user, negative if they were debited. Doesn't include
+    /// the fee.
+    pub amount: btc::MilliSats,
+    /// The fee debited from the user, including our service fee, zero if there was none.
+    pub fee: btc::MilliSats,
+    /// The available balance of the user after the transaction.
+    pub balance: btc::MilliSats,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entity {
+    Invoice(invoice::Id),
+    Payment(payment::Id),
+    Deposit(deposit::Id),
+    Withdrawal(withdrawal::Id),
+}
+
+/// The cached balance of a user doesn't match the sum of the user's ledger entries.
+#[derive(Debug)]
+pub struct BalanceMismatch {
+    pub user_id: user::Id,
+    pub account: Account,
+    /// The balance stored for the user, i.e. `users.balance_msats` for [`Account::User`] and the
+    /// sum of pending reservations for [`Account::Reserved`].
+    pub cached: btc::MilliSats,
+    pub ledger: btc::MilliSats,
+}
+
+/// Result of [`crate::ledger::check`]. The ledger is consistent if both lists are empty.
+#[derive(Debug, Default)]
+pub struct Check {
+    pub balance_mismatches: Vec<BalanceMismatch>,
+    /// Journal entries whose lines don't add up to zero.
+    pub unbalanced_journals: Vec<JournalId>,
+}
+
+impl Check {
+    pub fn is_consistent(&self) -> bool {
+        self.balance_mismatches.is_empty() && self.unbalanced_journals.is_empty()
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    auth, btc,
+    database::{self, Database},
+    Period, QueryRange,
+};
+
+mod entities;
+
+pub use entities::{
+    Account, BalanceMismatch, Check, Entity, EntryKind, JournalEntry, JournalId, Line, Transaction,
+};
+
+/// Appends the journal entries to the ledger. Call this in the transaction which saves the balance
+/// changes the entries explain.
+pub(crate) async fn record(data_tx: &mut database::Transaction, journal: &[JournalEntry]) {
+    for entry in journal {
+        queries::insert(data_tx, entry).await;
+    }
+}
+
+/// Lists the movements of the user's available balance in the period, most recent first. Each
+/// transaction includes the balance after it, so the list reads like an account statement.
+pub async fn list_transactions(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    period: Period,
+    range: QueryRange,
+) -> Vec<Transaction> {
+    queries::list_transactions(db, grant.user_id, period, range).await
+}
+
+/// Lists the movements of the user's available balance in the period after the given transaction,
+/// oldest first. Use it to go through all the transactions in batches, passing the last transaction
+/// of each batch to get the next one.
+pub async fn list_transactions_after(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    period: Period,
+    after: Option<&Transaction>,
+    limit: i64,
+) -> Vec<Transaction> {
+    queries::list_transactions_after(db, grant.user_id, period, after, limit).await
+}
+
+/// Sums the lines of [`Account::Revenue`], i.e. the service fees earned so far, less the on-chain
+/// fees we paid for users.
+pub(crate) async fn revenue(db: &Database) -> btc::MilliSats {
+    queries::revenue(db).await
+}
+
+/// Verifies that the cached balances match the ledger: the balance of each user must equal the
+/// sum of the user's [`Account::User`] lines, the pending reservations of each user must equal the
+/// sum of the user's [`Account::Reserved`] lines, and every journal entry must add up to zero.
+pub(crate) async fn check(db: &Database) -> Check {
+    let mut balance_mismatches = queries::user_balance_mismatches(db).await;
+    balance_mismatches.extend(queries::reserved_balance_mismatches(db).await);
+    Check {
+        balance_mismatches,
+        unbalanced_journals: queries::unbalanced_journals(db).await,
+    }
+}
+
+mod queries {
+    use super::{
+        Account, BalanceMismatch, Entity, EntryKind, JournalEntry, JournalId, Transaction,
+    };
+    use crate::{
+        btc,
+        database::{self, Database, SumRow},
+        deposit, invoice, payment, user, withdrawal, Period, QueryRange,
+    };
+    use chrono::{DateTime, Utc};
+    use const_format::formatcp;
+    use uuid::Uuid;
+
+    /// The user's lines grouped by journal entry, with the fee lines, including service fees,
+    /// separated from the rest. A journal entry with only a fee, e.g. a raised withdrawal fee,
+    /// takes the kind the fee is for.
+    /// The balance is added up over all of the user's journal entries, so it must be selected
+    /// before filtering the transactions. Binds the user id to `$1`.
+    const STATEMENT: &str = r#"WITH journals AS (
+            SELECT journal_id, reference_id, MIN(created) AS created,
+                COALESCE(
+                    MIN(kind) FILTER (WHERE kind NOT IN ('payment_fee', 'withdrawal_fee', 'service_fee')),
+                    MIN('payment'::TEXT) FILTER (WHERE kind = 'payment_fee'),
+                    MIN('withdrawal'::TEXT) FILTER (WHERE kind = 'withdrawal_fee')
+                ) AS kind,
+                COALESCE(SUM(amount_msats) FILTER (WHERE kind NOT IN ('payment_fee', 'withdrawal_fee', 'service_fee')), 0)::BIGINT AS amount_msats,
+                COALESCE(-SUM(amount_msats) FILTER (WHERE kind IN ('payment_fee', 'withdrawal_fee', 'service_fee')), 0)::BIGINT AS fee_msats
+            FROM ledger_entries WHERE account = 'user' AND user_id = $1
+            GROUP BY journal_id, reference_id
+        ), statement AS (
+            SELECT *, SUM(amount_msats - fee_msats) OVER (ORDER BY created, journal_id)::BIGINT AS balance_msats
+            FROM journals
+        )
+        SELECT journal_id, reference_id, kind, amount_msats, fee_msats, balance_msats, created,
+            CASE WHEN kind = 'refund' THEN
+                (SELECT kind FROM balance_reservations WHERE balance_reservations.reference_id = statement.reference_id)
+            END AS refunded_kind
+        FROM statement
+        WHERE ($2::TIMESTAMPTZ IS NULL OR created >= $2) AND ($3::TIMESTAMPTZ IS NULL OR created < $3)"#;
+
+    pub(super) async fn insert(data_tx: &mut database::Transaction, entry: &JournalEntry) {
+        for line in &entry.lines {
+            sqlx::query(
+                r#"INSERT INTO ledger_entries (id, journal_id, reference_id, account, user_id, kind, amount_msats, created)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"#,
+            )
+            .bind(Uuid::new_v4())
+            .bind(entry.id.0)
+            .bind(entry.reference)
+            .bind(line.account.name())
+            .bind(line.account.user_id().map(|user_id| user_id.0))
+            .bind(line.kind.as_str())
+            .bind(line.amount.0)
+            .bind(entry.created)
+            .execute(&mut *data_tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    pub(super) async fn list_transactions(
+        db: &Database,
+        user_id: user::Id,
+        period: Period,
+        range: QueryRange,
+    ) -> Vec<Transaction> {
+        sqlx::query_as::<_, TransactionRow>(formatcp!(
+            "{} ORDER BY created DESC, journal_id DESC LIMIT $4 OFFSET $5",
+            STATEMENT
+        ))
+        .bind(user_id.0)
+        .bind(period.from)
+        .bind(period.to)
+        .bind(range.limit)
+        .bind(range.offset)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    /// Lists the transactions after the given one, oldest first.
+    pub(super) async fn list_transactions_after(
+        db: &Database,
+        user_id: user::Id,
+        period: Period,
+        after: Option<&Transaction>,
+        limit: i64,
+    ) -> Vec<Transaction> {
+        sqlx::query_as::<_, TransactionRow>(formatcp!(
+            r#"{} AND ($4::TIMESTAMPTZ IS NULL OR (created, journal_id) > ($4, $5))
+                ORDER BY created, journal_id LIMIT $6"#,
+            STATEMENT
+        ))
+        .bind(user_id.0)
+        .bind(period.from)
+        .bind(period.to)
+        .bind(after.map(|transaction| transaction.created))
+        .bind(after.map(|transaction| transaction.id.0))
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn user_balance_mismatches(db: &Database) -> Vec<BalanceMismatch> {
+        sqlx::query_as::<_, MismatchRow>(
+            r#"SELECT users.id AS user_id, users.balance_msats AS cached, COALESCE(ledger.amount_msats, 0) AS ledger
+                FROM users LEFT JOIN (
+                    SELECT user_id, SUM(amount_msats)::BIGINT AS amount_msats FROM ledger_entries
+                    WHERE account = 'user' GROUP BY user_id
+                ) AS ledger ON ledger.user_id = users.id
+                WHERE users.balance_msats <> COALESCE(ledger.amount_msats, 0)"#,
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity(Account::User))
+        .collect()
+    }
+
+    pub(super) async fn reserved_balance_mismatches(db: &Database) -> Vec<BalanceMismatch> {
+        sqlx::query_as::<_, MismatchRow>(
+            r#"SELECT users.id AS user_id, COALESCE(pending.amount_msats, 0) AS cached, COALESCE(ledger.amount_msats, 0) AS ledger
+                FROM users LEFT JOIN (
+                    SELECT user_id, SUM(amount_msats)::BIGINT AS amount_msats FROM balance_reservations
+                    WHERE status = 0 GROUP BY user_id
+                ) AS pending ON pending.user_id = users.id LEFT JOIN (
+                    SELECT user_id, SUM(amount_msats)::BIGINT AS amount_msats FROM ledger_entries
+                    WHERE account = 'reserved' GROUP BY user_id
+                ) AS ledger ON ledger.user_id = users.id
+                WHERE COALESCE(pending.amount_msats, 0) <> COALESCE(ledger.amount_msats, 0)"#,
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity(Account::Reserved))
+        .collect()
+    }
+
+    pub(super) async fn revenue(db: &Database) -> btc::MilliSats {
+        sqlx::query_as::<_, SumRow<Option<i64>>>(
+            "SELECT SUM(amount_msats)::BIGINT AS sum FROM ledger_entries WHERE account = 'revenue'",
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .sum
+        .map(btc::MilliSats)
+        .unwrap_or_default()
+    }
+
+    pub(super) async fn unbalanced_journals(db: &Database) -> Vec<JournalId> {
+        sqlx::query_as::<_, JournalRow>(
+            "SELECT journal_id FROM ledger_entries GROUP BY journal_id HAVING SUM(amount_msats) <> 0",
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| JournalId(row.journal_id))
+        .collect()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct TransactionRow {
+        journal_id: Uuid,
+        reference_id: Uuid,
+        kind: String,
+        amount_msats: i64,
+        fee_msats: i64,
+        balance_msats: i64,
+        created: DateTime<Utc>,
+        refunded_kind: Option<String>,
+    }
+
+    impl TransactionRow {
+        fn into_entity(self) -> Transaction {
+            let kind = EntryKind::parse(&self.kind);
+            let entity = match kind {
+                EntryKind::Opening => None,
+                EntryKind::Invoice | EntryKind::InternalInvoice => {
+                    Some(Entity::Invoice(invoice::Id(self.reference_id)))
+                }
+                EntryKind::Deposit | EntryKind::DepositReversal => {
+                    Some(Entity::Deposit(deposit::Id(self.reference_id)))
+                }
+                EntryKind::Payment | EntryKind::InternalPayment => {
+                    Some(Entity::Payment(payment::Id(self.reference_id)))
+                }
+                EntryKind::Withdrawal => {
+                    Some(Entity::Withdrawal(withdrawal::Id(self.reference_id)))
+                }
+                EntryKind::Refund => match self.refunded_kind.as_deref().map(EntryKind::parse) {
+                    Some(EntryKind::Withdrawal) => {
+                        Some(Entity::Withdrawal(withdrawal::Id(self.reference_id)))
+                    }
+                    _ => Some(Entity::Payment(payment::Id(self.reference_id))),
+                },
+                EntryKind::PaymentFee | EntryKind::WithdrawalFee | EntryKind::ServiceFee => {
+                    unreachable!("fee lines are never listed on their own")
+                }
+            };
+            Transaction {
+                id: JournalId(self.journal_id),
+                kind,
+                entity,
+                amount: btc::MilliSats(self.amount_msats),
+                fee: btc::MilliSats(self.fee_msats),
+                balance: btc::MilliSats(self.balance_msats),
+                created: self.created,
+            }
+        }
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct MismatchRow {
+        user_id: Uuid,
+        cached: i64,
+        ledger: i64,
+    }
+
+    impl MismatchRow {
+        fn into_entity(self, account: fn(user::Id) -> Account) -> BalanceMismatch {
+            BalanceMismatch {
+                user_id: user::Id(self.user_id),
+                account: account(user::Id(self.user_id)),
+                cached: btc::MilliSats(self.cached),
+                ledger: btc::MilliSats(self.ledger),
+            }
+        }
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct JournalRow {
+        journal_id: Uuid,
+    }
+}
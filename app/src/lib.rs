@@ -1,6 +1,9 @@
+use chrono::{DateTime, Utc};
 use futures::FutureExt;
 use std::{future::Future, panic::AssertUnwindSafe};
 
+pub mod admin;
+pub mod allowlist;
 pub mod auth;
 mod balance;
 pub mod btc;
@@ -9,12 +12,17 @@ mod chain;
 mod concurrency;
 pub mod database;
 pub mod deposit;
+pub mod event;
 mod hex;
+pub mod idempotency;
 pub mod invoice;
+pub mod ledger;
 pub mod ln;
 pub mod payment;
+pub mod pricing;
 pub mod seconds;
 pub mod user;
+pub mod webhook;
 pub mod withdrawal;
 mod worker;
 
@@ -26,6 +34,47 @@ pub struct QueryRange {
     pub offset: i64,
 }
 
+/// Limits a listing to the items created in the period. Both ends are optional, the start is
+/// inclusive and the end is exclusive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Period {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// The position of an item in a listing ordered by creation time, most recent first. The id
+/// orders items created at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor<Id> {
+    pub created: DateTime<Utc>,
+    pub id: Id,
+}
+
+/// Selects a page of a listing ordered by creation time, most recent first. If there is a cursor,
+/// the page starts with the item right after it, and the offset is counted from there.
+#[derive(Debug, Clone, Copy)]
+pub struct Page<Id> {
+    pub range: QueryRange,
+    pub after: Option<Cursor<Id>>,
+}
+
+/// Limits a listing to the items whose amount is within the range. Both ends are optional and
+/// inclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct AmountRange<T> {
+    pub min: Option<T>,
+    pub max: Option<T>,
+}
+
+impl<T> Default for AmountRange<T> {
+    fn default() -> Self {
+        Self {
+            min: None,
+            max: None,
+        }
+    }
+}
+
 async fn swallow_panic(f: impl Future<Output = ()>) {
     let _ = AssertUnwindSafe(f).catch_unwind().await;
 }
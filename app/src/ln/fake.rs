@@ -0,0 +1,657 @@
+//! An in-memory implementation of [`LightningBackend`]. It doesn't talk to any real node or
+//! blockchain, which allows the whole `app` crate to be exercised end to end without running
+//! bitcoind and LND.
+//!
+//! All nodes created from the same [`FakeNetwork`] share state, and the network exposes methods to
+//! script what happens outside of our service: mining blocks, receiving onchain funds, paying our
+//! invoices, and failing outgoing payments. Everything is deterministic, so the same sequence of
+//! calls always produces the same addresses, transaction IDs and invoices.
+
+use super::node::{
+    FeeBump, FeeBumpError, FeeEstimate, FeeRate, InvoiceStatus, LightningBackend, PaymentError,
+    PaymentStatus, SettledInvoice, SignedTx, TransactionsQuery,
+};
+use super::{ParsedInvoice, RawInvoice};
+use crate::btc;
+use crate::seconds::Seconds;
+use async_trait::async_trait;
+use bitcoin::blockdata::script;
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin_hashes::{sha256, Hash as _};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use lightning::ln::PaymentSecret;
+use lightning_invoice::{Currency, InvoiceBuilder};
+use secp256k1::{Secp256k1, SecretKey};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// The virtual size of every fake onchain transaction, used to convert between fees and fee rates.
+pub const FAKE_TX_VSIZE: i64 = 141;
+
+/// Shared state of all fake nodes, along with the controls for simulating the outside world.
+#[derive(Debug, Clone)]
+pub struct FakeNetwork {
+    state: Arc<Mutex<State>>,
+    settlements: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Height of the most recently mined block.
+    height: u32,
+    /// Hashes of the mined blocks, the hash of the block at height `n` is at index `n - 1`.
+    block_hashes: Vec<btc::BlockHash>,
+    /// Used to derive unique addresses, transaction IDs and payment hashes.
+    counter: u64,
+    tx_outs: Vec<btc::TxOut>,
+    /// Transactions signed by a node, kept until they're published.
+    signed_txs: Vec<SignedTx>,
+    invoices: Vec<FakeInvoice>,
+    settle_index: u64,
+    payments: Vec<FakePayment>,
+    /// Latest status of every payment attempt, by invoice.
+    payment_statuses: Vec<(RawInvoice, PaymentStatus)>,
+    payment_failures: VecDeque<PaymentError>,
+    probe_failures: VecDeque<PaymentError>,
+    routing_fee: btc::MilliSats,
+    onchain_fee: btc::Sats,
+}
+
+#[derive(Debug)]
+struct FakeInvoice {
+    raw: RawInvoice,
+    amount: btc::MilliSats,
+    settlement: Option<SettledInvoice>,
+}
+
+/// An outgoing payment which was successfully sent through a fake node.
+#[derive(Debug, Clone)]
+pub struct FakePayment {
+    pub invoice: RawInvoice,
+    pub amount: btc::MilliSats,
+    pub fee: btc::MilliSats,
+}
+
+impl Default for FakeNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeNetwork {
+    pub fn new() -> Self {
+        Self {
+            state: Default::default(),
+            settlements: Default::default(),
+        }
+    }
+
+    pub(super) fn create_node(&self) -> FakeNode {
+        FakeNode {
+            network: self.clone(),
+        }
+    }
+
+    /// Returns the height of the most recently mined block.
+    pub fn height(&self) -> u32 {
+        self.state.lock().unwrap().height
+    }
+
+    /// Mines the given number of blocks. All unconfirmed transactions are included in the first
+    /// mined block, except the ones conflicting with a more recent transaction, which disappear.
+    pub fn mine_blocks(&self, num_blocks: u32) {
+        let mut state = self.state.lock().unwrap();
+        let block_height = state.height + 1;
+        for _ in 0..num_blocks {
+            let block_hash = btc::BlockHash::from_hash(state.next_hash());
+            state.block_hashes.push(block_hash);
+        }
+        let block_hash = state.block_hashes.get(block_height as usize - 1).copied();
+        let mut spent: HashMap<btc::OutPoint, btc::TxId> = state
+            .tx_outs
+            .iter()
+            .filter(|tx_out| tx_out.tx.is_confirmed())
+            .flat_map(|tx_out| {
+                let tx = &tx_out.tx;
+                tx.inputs.iter().map(|input| (*input, tx.id))
+            })
+            .collect();
+        let mut conflicting = Vec::new();
+        // The most recent transactions replace the ones they conflict with
+        for tx_out in state.tx_outs.iter_mut().rev() {
+            let tx = &mut tx_out.tx;
+            if tx.is_confirmed() {
+                continue;
+            }
+            if tx
+                .inputs
+                .iter()
+                .any(|input| matches!(spent.get(input), Some(tx_id) if *tx_id != tx.id))
+            {
+                conflicting.push(tx.id);
+                continue;
+            }
+            spent.extend(tx.inputs.iter().map(|input| (*input, tx.id)));
+            tx.block_height = Some(block_height);
+            tx.block_hash = block_hash;
+        }
+        state
+            .tx_outs
+            .retain(|tx_out| !conflicting.contains(&tx_out.tx.id));
+        state.height += num_blocks;
+    }
+
+    /// Simulates a chain reorganization which replaces the last `depth` blocks with as many new
+    /// blocks. The transactions of the replaced blocks go back to the mempool and are included in
+    /// the next mined block, except the dropped ones, which disappear as if they were double spent.
+    pub fn reorg(&self, depth: u32, dropped: &[btc::TxId]) {
+        let mut state = self.state.lock().unwrap();
+        if depth > state.height {
+            panic!("can't replace {} blocks of {}", depth, state.height);
+        }
+        let fork_height = state.height - depth + 1;
+        for height in fork_height..=state.height {
+            let block_hash = btc::BlockHash::from_hash(state.next_hash());
+            state.block_hashes[height as usize - 1] = block_hash;
+        }
+        state
+            .tx_outs
+            .retain(|tx_out| !dropped.contains(&tx_out.tx.id));
+        for tx_out in state.tx_outs.iter_mut() {
+            if matches!(tx_out.tx.block_height, Some(height) if height >= fork_height) {
+                tx_out.tx.block_height = None;
+                tx_out.tx.block_hash = None;
+            }
+        }
+    }
+
+    /// Simulates someone outside of our service sending funds to the address. The transaction is
+    /// unconfirmed until the next block is mined.
+    pub fn receive_onchain(&self, address: &btc::Address, amount: btc::Sats) -> btc::TxOut {
+        self.state
+            .lock()
+            .unwrap()
+            .add_tx_out(address.clone(), amount)
+    }
+
+    /// Simulates someone outside of our service replacing an unconfirmed transaction with a new
+    /// one spending the same inputs, e.g. to bump the fee. The replacement pays the amount to the
+    /// address. Both transactions are listed until one of them is mined, then the other one
+    /// disappears.
+    pub fn replace_onchain(
+        &self,
+        tx_id: &btc::TxId,
+        address: &btc::Address,
+        amount: btc::Sats,
+    ) -> btc::TxOut {
+        let mut state = self.state.lock().unwrap();
+        let tx = state
+            .tx_outs
+            .iter()
+            .map(|tx_out| &tx_out.tx)
+            .find(|tx| tx.id == *tx_id)
+            .unwrap_or_else(|| panic!("transaction {:?} does not exist", tx_id));
+        if tx.is_confirmed() {
+            panic!("transaction {:?} is already confirmed", tx_id);
+        }
+        let inputs = tx.inputs.clone();
+        state.push_tx_out(address.clone(), amount, inputs)
+    }
+
+    /// Simulates someone outside of our service paying one of our invoices.
+    pub fn settle_invoice(&self, invoice: &RawInvoice) {
+        self.state.lock().unwrap().settle(invoice);
+        self.settlements.notify_waiters();
+    }
+
+    /// Makes the next call to [`LightningBackend::pay_invoice`] fail with the error. Failing with
+    /// [`PaymentError::Unknown`] leaves the payment in flight, see [`FakeNetwork::resolve_payment`].
+    pub fn fail_next_payment(&self, error: PaymentError) {
+        self.state.lock().unwrap().payment_failures.push_back(error);
+    }
+
+    /// Makes the next call to [`LightningBackend::probe_fee`] fail with the error.
+    pub fn fail_next_probe(&self, error: PaymentError) {
+        self.state.lock().unwrap().probe_failures.push_back(error);
+    }
+
+    /// Sets the routing fee for all subsequent Lightning payments.
+    pub fn set_routing_fee(&self, fee: btc::MilliSats) {
+        self.state.lock().unwrap().routing_fee = fee;
+    }
+
+    /// Sets the fee for all subsequent onchain transactions which should confirm in the next
+    /// block. Transactions which may take longer pay proportionally less, e.g. a transaction
+    /// targeting 6 blocks pays a sixth of the fee. Transactions with a fixed fee rate are
+    /// [`FAKE_TX_VSIZE`] virtual bytes large.
+    pub fn set_onchain_fee(&self, fee: btc::Sats) {
+        self.state.lock().unwrap().onchain_fee = fee;
+    }
+
+    /// Settles an outgoing payment which was left in flight by failing it with
+    /// [`PaymentError::Unknown`].
+    pub fn resolve_payment(&self, invoice: &RawInvoice, result: Result<(), PaymentError>) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(state.payment_status(invoice), PaymentStatus::InFlight) {
+            panic!("payment for invoice {:?} is not in flight", invoice);
+        }
+        let status = match result {
+            Ok(()) => {
+                let amount = btc::MilliSats(
+                    invoice
+                        .parse()
+                        .unwrap()
+                        .amount_milli_satoshis()
+                        .unwrap()
+                        .try_into()
+                        .unwrap(),
+                );
+                let fee = state.routing_fee;
+                state.payments.push(FakePayment {
+                    invoice: invoice.clone(),
+                    amount,
+                    fee,
+                });
+                PaymentStatus::Succeeded { fee }
+            }
+            Err(e) => PaymentStatus::Failed(e),
+        };
+        state.set_payment_status(invoice, status);
+    }
+
+    /// Returns all payments sent by our nodes so far.
+    pub fn payments(&self) -> Vec<FakePayment> {
+        self.state.lock().unwrap().payments.clone()
+    }
+
+    fn settled_after(&self, settle_index: u64) -> Option<SettledInvoice> {
+        self.state
+            .lock()
+            .unwrap()
+            .invoices
+            .iter()
+            .flat_map(|invoice| invoice.settlement.as_ref())
+            .find(|settlement| settlement.settle_index == settle_index + 1)
+            .cloned()
+    }
+}
+
+impl State {
+    fn next_hash(&mut self) -> sha256d::Hash {
+        self.counter += 1;
+        sha256d::Hash::hash(&self.counter.to_be_bytes())
+    }
+
+    fn add_tx_out(&mut self, address: btc::Address, amount: btc::Sats) -> btc::TxOut {
+        let input = btc::OutPoint::new(btc::TxId::from_hash(self.next_hash()), 0);
+        self.push_tx_out(address, amount, vec![input])
+    }
+
+    fn push_tx_out(
+        &mut self,
+        address: btc::Address,
+        amount: btc::Sats,
+        inputs: Vec<btc::OutPoint>,
+    ) -> btc::TxOut {
+        let tx_out = self.new_tx_out(address, amount, inputs);
+        self.tx_outs.push(tx_out.clone());
+        tx_out
+    }
+
+    fn new_tx_out(
+        &mut self,
+        address: btc::Address,
+        amount: btc::Sats,
+        inputs: Vec<btc::OutPoint>,
+    ) -> btc::TxOut {
+        btc::TxOut {
+            tx: btc::Tx {
+                id: btc::TxId::from_hash(self.next_hash()),
+                block_height: None,
+                block_hash: None,
+                inputs,
+            },
+            address,
+            v_out: 0,
+            amount,
+        }
+    }
+
+    fn estimate_fee(&self, fee_rate: FeeRate) -> FeeEstimate {
+        let fee = match fee_rate {
+            FeeRate::Target(blocks) => btc::Sats(self.onchain_fee.0 / i64::from(blocks.max(1))),
+            FeeRate::SatsPerVbyte(rate) => btc::Sats(i64::try_from(rate).unwrap() * FAKE_TX_VSIZE),
+        };
+        FeeEstimate {
+            fee,
+            sats_per_vbyte: (fee.0 / FAKE_TX_VSIZE).try_into().unwrap(),
+        }
+    }
+
+    fn payment_status(&self, invoice: &RawInvoice) -> PaymentStatus {
+        self.payment_statuses
+            .iter()
+            .find(|(raw, _)| raw == invoice)
+            .map(|(_, status)| status.clone())
+            .unwrap_or(PaymentStatus::NotFound)
+    }
+
+    fn set_payment_status(&mut self, invoice: &RawInvoice, status: PaymentStatus) {
+        self.payment_statuses.retain(|(raw, _)| raw != invoice);
+        self.payment_statuses.push((invoice.clone(), status));
+    }
+
+    fn settle(&mut self, raw: &RawInvoice) {
+        let settle_index = self.settle_index + 1;
+        let invoice = self
+            .invoices
+            .iter_mut()
+            .find(|invoice| invoice.raw == *raw)
+            .unwrap_or_else(|| panic!("invoice {:?} was not created by a fake node", raw));
+        if invoice.settlement.is_some() {
+            panic!("invoice {:?} has already been settled", raw);
+        }
+        invoice.settlement = Some(SettledInvoice {
+            amount: invoice.amount,
+            settle_index,
+            raw: raw.clone(),
+        });
+        self.settle_index = settle_index;
+    }
+}
+
+/// A node connected to a [`FakeNetwork`].
+pub struct FakeNode {
+    network: FakeNetwork,
+}
+
+impl FakeNode {
+    fn currency() -> Currency {
+        match btc::NETWORK {
+            bitcoin::Network::Bitcoin => Currency::Bitcoin,
+            bitcoin::Network::Testnet => Currency::BitcoinTestnet,
+            bitcoin::Network::Signet => Currency::Signet,
+            bitcoin::Network::Regtest => Currency::Regtest,
+        }
+    }
+
+    fn node_key() -> SecretKey {
+        SecretKey::from_slice(&[0x42; 32]).unwrap()
+    }
+}
+
+#[async_trait]
+impl LightningBackend for FakeNode {
+    async fn generate_address(&mut self) -> btc::Address {
+        let mut state = self.network.state.lock().unwrap();
+        let script = script::Builder::new()
+            .push_slice(&state.next_hash()[..])
+            .into_script();
+        btc::Address::p2wsh(&script, btc::NETWORK)
+    }
+
+    async fn get_block_height(&mut self) -> u32 {
+        self.network.height()
+    }
+
+    async fn get_tx_outs(&mut self, query: TransactionsQuery) -> Vec<btc::TxOut> {
+        let state = self.network.state.lock().unwrap();
+        let end_height = query.start_height + query.num_blocks - 1;
+        state
+            .tx_outs
+            .iter()
+            .filter(|tx_out| match tx_out.tx.block_height {
+                Some(block_height) => {
+                    block_height >= query.start_height
+                        && (block_height <= end_height || end_height >= state.height)
+                }
+                None => end_height >= state.height,
+            })
+            .cloned()
+            .collect()
+    }
+
+    async fn sign_onchain(
+        &mut self,
+        outputs: &[(btc::Address, btc::Sats)],
+        fee_rate: FeeRate,
+    ) -> SignedTx {
+        let mut state = self.network.state.lock().unwrap();
+        let input = btc::OutPoint::new(btc::TxId::from_hash(state.next_hash()), 0);
+        let tx = btc::Tx {
+            id: btc::TxId::from_hash(state.next_hash()),
+            block_height: None,
+            block_hash: None,
+            inputs: vec![input],
+        };
+        let signed_tx = SignedTx {
+            raw: btc::RawTx(tx.id.to_vec()),
+            tx_outs: outputs
+                .iter()
+                .zip(0..)
+                .map(|((address, amount), v_out)| btc::TxOut {
+                    tx: tx.clone(),
+                    address: address.clone(),
+                    v_out,
+                    amount: *amount,
+                })
+                .collect(),
+            fee: state.estimate_fee(fee_rate).fee,
+        };
+        state.signed_txs.push(signed_tx.clone());
+        signed_tx
+    }
+
+    async fn publish_tx(&mut self, tx: &btc::RawTx, _label: &str) {
+        let mut state = self.network.state.lock().unwrap();
+        let signed_tx = state
+            .signed_txs
+            .iter()
+            .find(|signed_tx| signed_tx.raw == *tx)
+            .cloned()
+            .unwrap_or_else(|| panic!("transaction {:?} was not signed by a fake node", tx));
+        let tx = signed_tx.tx_outs[0].tx.clone();
+        if !state
+            .tx_outs
+            .iter()
+            .any(|published| published.tx.id == tx.id)
+        {
+            // A replacement takes the place of the unconfirmed transaction spending the same inputs
+            state.tx_outs.retain(|published| {
+                published.tx.is_confirmed()
+                    || !published
+                        .tx
+                        .inputs
+                        .iter()
+                        .any(|input| tx.inputs.contains(input))
+            });
+            state.tx_outs.extend(signed_tx.tx_outs);
+        }
+    }
+
+    /// Always replaces the transaction, the fake node never uses CPFP.
+    async fn bump_fee(
+        &mut self,
+        tx: &SignedTx,
+        fee_rate: FeeRate,
+        max_extra_fee: Option<btc::Sats>,
+    ) -> Result<FeeBump, FeeBumpError> {
+        let mut state = self.network.state.lock().unwrap();
+        let fee = state.estimate_fee(fee_rate).fee;
+        if fee <= tx.fee {
+            return Err(FeeBumpError::FeeTooLow);
+        }
+        if max_extra_fee.is_some_and(|max| fee.0 - tx.fee.0 > max.0) {
+            return Err(FeeBumpError::AboveMaxFee);
+        }
+        let inputs = state
+            .signed_txs
+            .iter()
+            .find(|signed_tx| signed_tx.raw == tx.raw)
+            .map(|signed_tx| signed_tx.tx_outs[0].tx.inputs.clone())
+            .unwrap_or_else(|| panic!("transaction {:?} was not signed by a fake node", tx.raw));
+        let replacement = btc::Tx {
+            id: btc::TxId::from_hash(state.next_hash()),
+            block_height: None,
+            block_hash: None,
+            inputs,
+        };
+        let signed_tx = SignedTx {
+            raw: btc::RawTx(replacement.id.to_vec()),
+            tx_outs: tx
+                .tx_outs
+                .iter()
+                .map(|tx_out| btc::TxOut {
+                    tx: replacement.clone(),
+                    ..tx_out.clone()
+                })
+                .collect(),
+            fee,
+        };
+        state.signed_txs.push(signed_tx.clone());
+        Ok(FeeBump {
+            tx: signed_tx,
+            extra_fee: btc::Sats(fee.0 - tx.fee.0),
+        })
+    }
+
+    async fn estimate_fee(
+        &mut self,
+        _amount: btc::Sats,
+        _address: &btc::Address,
+        fee_rate: FeeRate,
+    ) -> FeeEstimate {
+        self.network.state.lock().unwrap().estimate_fee(fee_rate)
+    }
+
+    async fn pay_invoice(
+        &mut self,
+        invoice: &RawInvoice,
+        amount: Option<btc::MilliSats>,
+        fee_limit: btc::MilliSats,
+    ) -> Result<(), PaymentError> {
+        let parsed = invoice.parse().unwrap();
+        let paid_own_invoice = {
+            let mut state = self.network.state.lock().unwrap();
+            if let Some(error) = state.payment_failures.pop_front() {
+                let status = match error {
+                    PaymentError::Unknown => PaymentStatus::InFlight,
+                    ref e => PaymentStatus::Failed(e.clone()),
+                };
+                state.set_payment_status(invoice, status);
+                return Err(error);
+            }
+            if parsed.is_expired() {
+                return Err(PaymentError::InvoiceExpired);
+            }
+            if state.routing_fee > fee_limit {
+                return Err(PaymentError::NoRouteFound);
+            }
+            let own_invoice = state.invoices.iter().find(|own| own.raw == *invoice);
+            if matches!(own_invoice, Some(own) if own.settlement.is_some()) {
+                return Err(PaymentError::InvoiceAlreadyPaid);
+            }
+            let paid_own_invoice = own_invoice.is_some();
+            if paid_own_invoice {
+                state.settle(invoice);
+            }
+            let payment = FakePayment {
+                invoice: invoice.clone(),
+                amount: amount.unwrap_or_else(|| {
+                    btc::MilliSats(parsed.amount_milli_satoshis().unwrap().try_into().unwrap())
+                }),
+                fee: state.routing_fee,
+            };
+            let fee = payment.fee;
+            state.payments.push(payment);
+            state.set_payment_status(invoice, PaymentStatus::Succeeded { fee });
+            paid_own_invoice
+        };
+        if paid_own_invoice {
+            self.network.settlements.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn probe_fee(
+        &mut self,
+        _invoice: &ParsedInvoice,
+        _amount: Option<btc::MilliSats>,
+    ) -> Result<btc::MilliSats, PaymentError> {
+        let mut state = self.network.state.lock().unwrap();
+        match state.probe_failures.pop_front() {
+            Some(error) => Err(error),
+            None => Ok(state.routing_fee),
+        }
+    }
+
+    async fn create_invoice(
+        &mut self,
+        amount: btc::MilliSats,
+        memo: Option<String>,
+        expiry: Seconds,
+    ) -> RawInvoice {
+        let mut state = self.network.state.lock().unwrap();
+        let preimage = state.next_hash();
+        let invoice = InvoiceBuilder::new(Self::currency())
+            .description(memo.unwrap_or_default())
+            .amount_milli_satoshis(amount.0.try_into().unwrap())
+            .payment_hash(sha256::Hash::hash(&preimage[..]))
+            .payment_secret(PaymentSecret(preimage.into_inner()))
+            .current_timestamp()
+            .expiry_time(Duration::from_secs(expiry.0.try_into().unwrap()))
+            .min_final_cltv_expiry(144)
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &Self::node_key()))
+            .unwrap();
+        let raw = RawInvoice(invoice.to_string());
+        state.invoices.push(FakeInvoice {
+            raw: raw.clone(),
+            amount,
+            settlement: None,
+        });
+        raw
+    }
+
+    async fn get_invoice_status(&mut self, invoice: &RawInvoice) -> InvoiceStatus {
+        let state = self.network.state.lock().unwrap();
+        let invoice = state
+            .invoices
+            .iter()
+            .find(|own| own.raw == *invoice)
+            .unwrap_or_else(|| panic!("invoice {:?} was not created by a fake node", invoice));
+        match invoice.settlement {
+            Some(ref settlement) => InvoiceStatus::Settled(settlement.clone()),
+            None => InvoiceStatus::Pending,
+        }
+    }
+
+    async fn get_payment_status(&mut self, invoice: &RawInvoice) -> PaymentStatus {
+        self.network.state.lock().unwrap().payment_status(invoice)
+    }
+
+    async fn stream_settled_invoices<'a>(
+        &'a mut self,
+        settle_index: u64,
+    ) -> BoxStream<'a, SettledInvoice> {
+        futures::stream::unfold(
+            (self.network.clone(), settle_index),
+            |(network, settle_index)| async move {
+                loop {
+                    // The notification future has to be created before checking for settlements,
+                    // otherwise a settlement in between the two would be missed.
+                    let settlements = Arc::clone(&network.settlements);
+                    let notified = settlements.notified();
+                    if let Some(settlement) = network.settled_after(settle_index) {
+                        let settle_index = settlement.settle_index;
+                        return Some((settlement, (network, settle_index)));
+                    }
+                    notified.await;
+                }
+            },
+        )
+        .boxed()
+    }
+}
@@ -0,0 +1,779 @@
+use super::node::{
+    FeeBump, FeeBumpError, FeeEstimate, FeeRate, InvoiceStatus, LightningBackend, PaymentError,
+    PaymentStatus, SettledInvoice, SignedTx, TransactionsQuery,
+};
+use crate::btc;
+use crate::hex;
+use crate::seconds::Seconds;
+use async_trait::async_trait;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use proto::lnrpc;
+use proto::lnrpc::payment::PaymentStatus as LndPaymentStatus;
+use proto::routerrpc::{SendPaymentRequest, TrackPaymentRequest};
+use proto::walletrpc;
+use proto::walletrpc::fund_psbt_request::{Fees, Template};
+use rand::Rng;
+use rustls::internal::pemfile;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{io::BufReader, str::FromStr, sync::Arc};
+use tonic::Response;
+use tonic::Streaming;
+use tonic::{
+    metadata::MetadataValue,
+    transport::{Channel, ClientTlsConfig, Uri},
+};
+use url::Url;
+
+use self::proto::lnrpc::InvoiceSubscription;
+use self::proto::lnrpc::PaymentFailureReason;
+use self::proto::lnrpc::PaymentHash;
+
+use super::RawInvoice;
+
+type LightningClient = proto::lnrpc::lightning_client::LightningClient<Channel>;
+type RouterClient = proto::routerrpc::router_client::RouterClient<Channel>;
+type WalletKitClient = proto::walletrpc::wallet_kit_client::WalletKitClient<Channel>;
+
+/// Communicates with our Lightning node over LND's gRPC interface. This is the implementation of
+/// [`LightningBackend`] used in production.
+pub struct Lnd {
+    lightning: LightningClient,
+    router: RouterClient,
+    wallet: WalletKitClient,
+    macaroon: hex::Hex,
+}
+
+impl Lnd {
+    const DEFAULT_TIMEOUT_SECS: i32 = 20;
+
+    pub(super) async fn connect(endpoint: &Url, macaroon: hex::Hex, cert: Vec<u8>) -> Self {
+        let mut tls_config = rustls::ClientConfig::new();
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(LndCertVerifier::new(cert)));
+        tls_config.set_protocols(&["h2".into()]);
+        let channel = Channel::builder(Uri::try_from(endpoint.to_string()).unwrap())
+            .tls_config(ClientTlsConfig::new().rustls_client_config(tls_config))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        Lnd {
+            lightning: LightningClient::new(channel.clone()),
+            router: RouterClient::new(channel.clone()),
+            wallet: WalletKitClient::new(channel),
+            macaroon,
+        }
+    }
+
+    async fn get_tx_outs_start_end(
+        &mut self,
+        start_height: i32,
+        end_height: i32,
+    ) -> Vec<btc::TxOut> {
+        let resp = self
+            .lightning
+            .get_transactions(self.req(lnrpc::GetTransactionsRequest {
+                start_height,
+                end_height,
+                account: "default".to_owned(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        log::debug!(
+            "calling LND GetTransactions from {} to {}, got {} transactions",
+            start_height,
+            end_height,
+            resp.transactions.len()
+        );
+        resp.transactions
+            .into_iter()
+            .flat_map(|t| {
+                let tx = btc::Tx {
+                    id: btc::TxId::from_str(&t.tx_hash).unwrap(),
+                    block_height: if t.block_height == 0 {
+                        None
+                    } else {
+                        Some(t.block_height.try_into().unwrap())
+                    },
+                    block_hash: if t.block_hash.is_empty() {
+                        None
+                    } else {
+                        Some(btc::BlockHash::from_str(&t.block_hash).unwrap())
+                    },
+                    inputs: if t.raw_tx_hex.is_empty() {
+                        Vec::new()
+                    } else {
+                        let raw_tx = ::hex::decode(&t.raw_tx_hex).unwrap();
+                        bitcoin::consensus::deserialize::<bitcoin::Transaction>(&raw_tx)
+                            .unwrap()
+                            .input
+                            .into_iter()
+                            .map(|input| input.previous_output)
+                            .collect()
+                    },
+                };
+                t.output_details
+                    .into_iter()
+                    // Filtering out empty addresses here because currently
+                    // business logic does not need to deal with transactions
+                    // that don't pay to an address (https://en.bitcoin.it/wiki/Invoice_address).
+                    // If this need ever arises, adjustments will need to be made to the way
+                    // transactions are modelled.
+                    .filter(|output| !output.address.is_empty())
+                    .map(move |output| btc::TxOut {
+                        tx: tx.clone(),
+                        address: btc::Address::from_str(&output.address).unwrap(),
+                        v_out: output.output_index,
+                        amount: btc::Sats(output.amount),
+                    })
+            })
+            .collect()
+    }
+
+    const MAX_PROBE_RETRIES: i32 = 5;
+
+    /// The virtual size of the child transaction LND creates to bump a fee: one P2WPKH input
+    /// paying to one P2WPKH output.
+    const CPFP_CHILD_VSIZE: u64 = 110;
+
+    fn handle_payment_error(
+        resp: Result<Response<Streaming<lnrpc::Payment>>, tonic::Status>,
+    ) -> Result<Response<Streaming<lnrpc::Payment>>, PaymentError> {
+        resp.map_err(|e| {
+            let msg = e.message().to_lowercase();
+            if msg.contains("invoice is already paid") {
+                PaymentError::InvoiceAlreadyPaid
+            } else if msg.contains("invoice expired") {
+                PaymentError::InvoiceExpired
+            } else {
+                panic!("{:?}", e);
+            }
+        })
+    }
+
+    async fn handle_payment_status(payment: Option<lnrpc::Payment>) -> Result<(), PaymentError> {
+        match payment {
+            Some(payment) => match payment.status() {
+                LndPaymentStatus::Unknown => Err(PaymentError::Unknown),
+                LndPaymentStatus::Failed => match payment.failure_reason() {
+                    PaymentFailureReason::FailureReasonTimeout => Err(PaymentError::TimedOut),
+                    PaymentFailureReason::FailureReasonNoRoute => Err(PaymentError::NoRouteFound),
+                    PaymentFailureReason::FailureReasonIncorrectPaymentDetails => {
+                        Err(PaymentError::InvalidPaymentDetails)
+                    }
+                    PaymentFailureReason::FailureReasonInsufficientBalance => {
+                        log::error!("insufficient liquidity error");
+                        Err(PaymentError::InsufficientLiquidity)
+                    }
+                    PaymentFailureReason::FailureReasonNone => Err(PaymentError::Unknown),
+                    PaymentFailureReason::FailureReasonError => Err(PaymentError::Unknown),
+                },
+                LndPaymentStatus::InFlight => Err(PaymentError::Unknown),
+                LndPaymentStatus::Succeeded => Ok(()),
+            },
+            None => Err(PaymentError::Unknown),
+        }
+    }
+
+    fn get_highest_block(tx_outs: &[btc::TxOut]) -> Option<u32> {
+        tx_outs
+            .iter()
+            .flat_map(|tx_out| tx_out.tx.block_height)
+            .max()
+    }
+
+    fn req<T>(&self, msg: T) -> tonic::Request<T> {
+        self.req_timeout(
+            msg,
+            Duration::from_secs(Self::DEFAULT_TIMEOUT_SECS.try_into().unwrap()),
+        )
+    }
+
+    fn req_timeout<T>(&self, msg: T, timeout: Duration) -> tonic::Request<T> {
+        let mut req = tonic::Request::new(msg);
+        req.metadata_mut().insert(
+            "macaroon",
+            MetadataValue::from_str(self.macaroon.as_str()).unwrap(),
+        );
+        req.set_timeout(timeout);
+        req
+    }
+}
+
+#[async_trait]
+impl LightningBackend for Lnd {
+    async fn generate_address(&mut self) -> btc::Address {
+        let resp = self
+            .lightning
+            .new_address(self.req(lnrpc::NewAddressRequest {
+                r#type: lnrpc::AddressType::WitnessPubkeyHash.into(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        btc::Address::from_str(&resp.address).unwrap()
+    }
+
+    async fn get_block_height(&mut self) -> u32 {
+        self.lightning
+            .get_info(self.req(lnrpc::GetInfoRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .block_height
+    }
+
+    async fn get_tx_outs(&mut self, query: TransactionsQuery) -> Vec<btc::TxOut> {
+        // -1 because LND's end_height parameter is inclusive
+        let end_height = query.start_height + query.num_blocks - 1;
+        let confirmed_tx_outs = self
+            .get_tx_outs_start_end(
+                query.start_height.try_into().unwrap(),
+                end_height.try_into().unwrap(),
+            )
+            .await;
+        let highest_block = Self::get_highest_block(&confirmed_tx_outs).unwrap_or(0);
+        if highest_block < end_height {
+            self.get_tx_outs_start_end(query.start_height.try_into().unwrap(), -1)
+                .await
+        } else {
+            confirmed_tx_outs
+        }
+    }
+
+    async fn sign_onchain(
+        &mut self,
+        outputs: &[(btc::Address, btc::Sats)],
+        fee_rate: FeeRate,
+    ) -> SignedTx {
+        let funded = self
+            .wallet
+            .fund_psbt(
+                self.req(walletrpc::FundPsbtRequest {
+                    template: Some(Template::Raw(walletrpc::TxTemplate {
+                        inputs: Vec::new(),
+                        outputs: outputs
+                            .iter()
+                            .map(|(address, amount)| {
+                                (address.to_string(), amount.0.try_into().unwrap())
+                            })
+                            .collect(),
+                    })),
+                    fees: Some(match fee_rate {
+                        FeeRate::Target(blocks) => Fees::TargetConf(blocks),
+                        FeeRate::SatsPerVbyte(rate) => Fees::SatPerVbyte(rate),
+                    }),
+                    spend_unconfirmed: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        let psbt =
+            bitcoin::consensus::deserialize::<PartiallySignedTransaction>(&funded.funded_psbt)
+                .unwrap();
+        let input_amount: u64 = psbt
+            .inputs
+            .iter()
+            .zip(&psbt.global.unsigned_tx.input)
+            .map(
+                |(input, tx_in)| match (&input.witness_utxo, &input.non_witness_utxo) {
+                    (Some(utxo), _) => utxo.value,
+                    (None, Some(prev_tx)) => {
+                        prev_tx.output[tx_in.previous_output.vout as usize].value
+                    }
+                    (None, None) => panic!("funded input {:?} has no utxo", tx_in.previous_output),
+                },
+            )
+            .sum();
+        let finalized = self
+            .wallet
+            .finalize_psbt(self.req(walletrpc::FinalizePsbtRequest {
+                funded_psbt: funded.funded_psbt,
+                ..Default::default()
+            }))
+            .await;
+        let raw_tx = match finalized {
+            Ok(resp) => resp.into_inner().raw_final_tx,
+            Err(e) => {
+                // The funded inputs stay locked until they're released or the lease expires
+                for lease in funded.locked_utxos {
+                    self.wallet
+                        .release_output(self.req(walletrpc::ReleaseOutputRequest {
+                            id: lease.id,
+                            outpoint: lease.outpoint,
+                        }))
+                        .await
+                        .unwrap();
+                }
+                panic!("failed to finalize transaction: {}", e);
+            }
+        };
+        let tx = bitcoin::consensus::deserialize::<bitcoin::Transaction>(&raw_tx).unwrap();
+        let output_amount: u64 = tx.output.iter().map(|output| output.value).sum();
+        let btc_tx = btc::Tx {
+            id: tx.txid(),
+            block_height: None,
+            block_hash: None,
+            inputs: tx.input.iter().map(|input| input.previous_output).collect(),
+        };
+        let tx_outs = outputs
+            .iter()
+            .map(|(address, amount)| {
+                let v_out = tx
+                    .output
+                    .iter()
+                    .position(|output| output.script_pubkey == address.script_pubkey())
+                    .unwrap();
+                btc::TxOut {
+                    tx: btc_tx.clone(),
+                    address: address.clone(),
+                    v_out: v_out.try_into().unwrap(),
+                    amount: *amount,
+                }
+            })
+            .collect();
+        SignedTx {
+            raw: btc::RawTx(raw_tx),
+            tx_outs,
+            fee: btc::Sats((input_amount - output_amount).try_into().unwrap()),
+        }
+    }
+
+    async fn publish_tx(&mut self, tx: &btc::RawTx, label: &str) {
+        let resp = self
+            .wallet
+            .publish_transaction(self.req(walletrpc::Transaction {
+                tx_hex: tx.0.clone(),
+                label: label.to_owned(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        if !resp.publish_error.is_empty() {
+            panic!("failed to publish transaction: {}", resp.publish_error);
+        }
+    }
+
+    /// LND bumps fees by spending the change of the transaction in a child transaction, which is
+    /// then rebroadcast by its sweeper until it confirms. The child's fee rate is chosen so that
+    /// the transaction and the child together pay the fee rate. A transaction can only be bumped
+    /// once this way, since its change is spent afterwards.
+    async fn bump_fee(
+        &mut self,
+        tx: &SignedTx,
+        fee_rate: FeeRate,
+        max_extra_fee: Option<btc::Sats>,
+    ) -> Result<FeeBump, FeeBumpError> {
+        let sats_per_vbyte = match fee_rate {
+            FeeRate::Target(blocks) => {
+                let resp = self
+                    .wallet
+                    .estimate_fee(self.req(walletrpc::EstimateFeeRequest {
+                        conf_target: blocks.try_into().unwrap(),
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner();
+                // 1 kw is 250 vbytes
+                u64::try_from(resp.sat_per_kw).unwrap().div_ceil(250)
+            }
+            FeeRate::SatsPerVbyte(rate) => rate,
+        };
+        let parent = bitcoin::consensus::deserialize::<bitcoin::Transaction>(&tx.raw.0).unwrap();
+        let parent_vsize = u64::try_from(parent.get_weight()).unwrap().div_ceil(4);
+        let package_fee = sats_per_vbyte * (parent_vsize + Self::CPFP_CHILD_VSIZE);
+        let parent_fee = u64::try_from(tx.fee.0).unwrap();
+        if package_fee <= parent_fee {
+            return Err(FeeBumpError::FeeTooLow);
+        }
+        let child_rate = (package_fee - parent_fee).div_ceil(Self::CPFP_CHILD_VSIZE);
+        let extra_fee = btc::Sats((child_rate * Self::CPFP_CHILD_VSIZE).try_into().unwrap());
+        if max_extra_fee.is_some_and(|max| extra_fee > max) {
+            return Err(FeeBumpError::AboveMaxFee);
+        }
+        let tx_id = parent.txid().to_string();
+        let change = self
+            .wallet
+            .list_unspent(self.req(walletrpc::ListUnspentRequest {
+                unconfirmed_only: true,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .utxos
+            .into_iter()
+            .find(|utxo| {
+                utxo.outpoint
+                    .as_ref()
+                    .is_some_and(|outpoint| outpoint.txid_str == tx_id)
+            })
+            .ok_or(FeeBumpError::NoChangeOutput)?;
+        self.wallet
+            .bump_fee(self.req(walletrpc::BumpFeeRequest {
+                outpoint: change.outpoint,
+                sat_per_vbyte: child_rate,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        Ok(FeeBump {
+            tx: SignedTx {
+                fee: btc::Sats(tx.fee.0 + extra_fee.0),
+                ..tx.clone()
+            },
+            extra_fee,
+        })
+    }
+
+    async fn estimate_fee(
+        &mut self,
+        amount: btc::Sats,
+        address: &btc::Address,
+        fee_rate: FeeRate,
+    ) -> FeeEstimate {
+        let target_conf = match fee_rate {
+            FeeRate::Target(blocks) => blocks,
+            FeeRate::SatsPerVbyte(_) => 1,
+        };
+        let resp = self
+            .lightning
+            .estimate_fee(self.req(lnrpc::EstimateFeeRequest {
+                addr_to_amount: HashMap::from([(address.to_string(), amount.0)]),
+                target_conf: target_conf.try_into().unwrap(),
+                spend_unconfirmed: true,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        match fee_rate {
+            FeeRate::Target(_) => FeeEstimate {
+                fee: btc::Sats(resp.fee_sat),
+                sats_per_vbyte: resp.sat_per_vbyte,
+            },
+            // LND only estimates for a target, so the size of the transaction is derived from the
+            // estimate and priced at the fixed rate
+            FeeRate::SatsPerVbyte(rate) => {
+                let estimated_rate = resp.sat_per_vbyte.max(1);
+                let fee = (u64::try_from(resp.fee_sat).unwrap() * rate).div_ceil(estimated_rate);
+                FeeEstimate {
+                    fee: btc::Sats(fee.try_into().unwrap()),
+                    sats_per_vbyte: rate,
+                }
+            }
+        }
+    }
+
+    async fn pay_invoice(
+        &mut self,
+        invoice: &super::RawInvoice,
+        amount: Option<btc::MilliSats>,
+        fee_limit: btc::MilliSats,
+    ) -> Result<(), PaymentError> {
+        let amount = amount.unwrap_or_default();
+        let resp = self
+            .router
+            .send_payment_v2(self.req(SendPaymentRequest {
+                payment_request: invoice.0.clone(),
+                amt_msat: amount.0,
+                no_inflight_updates: true,
+                timeout_seconds: Self::DEFAULT_TIMEOUT_SECS,
+                fee_limit_msat: fee_limit.0,
+                allow_self_payment: true,
+                ..Default::default()
+            }))
+            .await;
+        let resp = Self::handle_payment_error(resp)?;
+        let payment = resp.into_inner().message().await.unwrap();
+        Self::handle_payment_status(payment).await
+    }
+
+    async fn probe_fee(
+        &mut self,
+        invoice: &super::ParsedInvoice,
+        amount: Option<btc::MilliSats>,
+    ) -> Result<btc::MilliSats, PaymentError> {
+        for _ in 0..Self::MAX_PROBE_RETRIES {
+            let resp = self
+                .router
+                .send_payment_v2(
+                    self.req(SendPaymentRequest {
+                        dest: invoice
+                            .payee_pub_key()
+                            .cloned()
+                            .unwrap_or_else(|| invoice.recover_payee_pub_key())
+                            .serialize()
+                            .into_iter()
+                            .collect(),
+                        amt_msat: amount.map(|amount| amount.0).unwrap_or_else(|| {
+                            invoice
+                                .amount_milli_satoshis()
+                                .unwrap_or_default()
+                                .try_into()
+                                .unwrap()
+                        }),
+                        // TODO Configurable fee limit
+                        fee_limit_msat: i64::MAX,
+                        // TODO Test that this works (private channels)
+                        route_hints: invoice
+                            .route_hints()
+                            .into_iter()
+                            .map(|hint| lnrpc::RouteHint {
+                                hop_hints: hint
+                                    .0
+                                    .into_iter()
+                                    .map(|hop| lnrpc::HopHint {
+                                        node_id: hop.src_node_id.to_string(),
+                                        chan_id: hop.short_channel_id,
+                                        fee_base_msat: hop.fees.base_msat,
+                                        fee_proportional_millionths: hop
+                                            .fees
+                                            .proportional_millionths,
+                                        cltv_expiry_delta: hop.cltv_expiry_delta.into(),
+                                    })
+                                    .collect(),
+                            })
+                            .collect(),
+                        no_inflight_updates: true,
+                        // TODO Test that this works
+                        final_cltv_delta: invoice.min_final_cltv_expiry().try_into().unwrap(),
+                        payment_hash: (0..32).map(|_| rand::thread_rng().gen()).collect(),
+                        timeout_seconds: 30,
+                        allow_self_payment: true,
+                        ..Default::default()
+                    }),
+                )
+                .await;
+            let resp = Self::handle_payment_error(resp)?;
+            let payment = resp.into_inner().message().await.unwrap();
+            // The probe pays a random payment hash, so the destination rejects the payment details
+            // once a route reaches it, and the fee of that route is the one we're after
+            if let Some(ref payment) = payment {
+                if payment.status() == LndPaymentStatus::Failed
+                    && payment.failure_reason()
+                        == PaymentFailureReason::FailureReasonIncorrectPaymentDetails
+                {
+                    return Ok(btc::MilliSats(
+                        payment
+                            .htlcs
+                            .iter()
+                            .map(|htlc| {
+                                htlc.route.as_ref().map_or(0, |route| route.total_fees_msat)
+                            })
+                            .sum(),
+                    ));
+                }
+            }
+            match Self::handle_payment_status(payment).await {
+                Err(PaymentError::NoRouteFound) => {
+                    // Delay and retry
+                    tokio::time::sleep(Duration::from_millis(500)).await
+                }
+                Err(e) => return Err(e),
+                Ok(()) => unreachable!("should never succeed with a random payment hash"),
+            }
+        }
+        Err(PaymentError::NoRouteFound)
+    }
+
+    async fn create_invoice(
+        &mut self,
+        amount: btc::MilliSats,
+        memo: Option<String>,
+        expiry: Seconds,
+    ) -> RawInvoice {
+        let resp = self
+            .lightning
+            .add_invoice(self.req(proto::lnrpc::Invoice {
+                memo: memo.unwrap_or_default(),
+                value_msat: amount.0,
+                private: true,
+                expiry: expiry.0,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        RawInvoice(resp.payment_request)
+    }
+
+    async fn get_invoice_status(&mut self, invoice: &RawInvoice) -> InvoiceStatus {
+        let invoice = self
+            .lightning
+            .lookup_invoice(
+                self.req(PaymentHash {
+                    r_hash: invoice
+                        .parse()
+                        .unwrap()
+                        .payment_hash()
+                        .iter()
+                        .copied()
+                        .collect(),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        if invoice.settle_date != 0 {
+            InvoiceStatus::Settled(SettledInvoice {
+                amount: btc::MilliSats(invoice.amt_paid_msat),
+                raw: RawInvoice(invoice.payment_request),
+                settle_index: invoice.settle_index,
+            })
+        } else {
+            InvoiceStatus::Pending
+        }
+    }
+
+    async fn get_payment_status(&mut self, invoice: &RawInvoice) -> PaymentStatus {
+        let resp = self
+            .router
+            .track_payment_v2(
+                self.req(TrackPaymentRequest {
+                    payment_hash: invoice
+                        .parse()
+                        .unwrap()
+                        .payment_hash()
+                        .iter()
+                        .copied()
+                        .collect(),
+                    // The first update is the current state of the payment, even if it's in flight
+                    no_inflight_updates: false,
+                }),
+            )
+            .await;
+        // LND reports unknown payments as an error, either right away or as the first message
+        let payment = match resp {
+            Ok(resp) => resp.into_inner().message().await,
+            Err(e) => Err(e),
+        };
+        match payment {
+            Ok(Some(payment)) => match payment.status() {
+                LndPaymentStatus::Unknown | LndPaymentStatus::InFlight => PaymentStatus::InFlight,
+                LndPaymentStatus::Succeeded => PaymentStatus::Succeeded {
+                    fee: btc::MilliSats(payment.fee_msat),
+                },
+                LndPaymentStatus::Failed => {
+                    match Self::handle_payment_status(Some(payment)).await {
+                        Err(e) => PaymentStatus::Failed(e),
+                        Ok(()) => unreachable!("failed payment can't succeed"),
+                    }
+                }
+            },
+            Ok(None) => PaymentStatus::NotFound,
+            Err(e) if e.code() == tonic::Code::NotFound => PaymentStatus::NotFound,
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    async fn stream_settled_invoices<'a>(
+        &'a mut self,
+        settle_index: u64,
+    ) -> BoxStream<'a, SettledInvoice> {
+        let one_month = Duration::from_secs(2_629_746);
+        let stream = self
+            .lightning
+            .subscribe_invoices(self.req_timeout(
+                InvoiceSubscription {
+                    settle_index,
+                    ..Default::default()
+                },
+                one_month,
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        futures::stream::unfold(stream, |mut stream| async move {
+            let resp = stream.message().await;
+            Some((resp, stream))
+        })
+        .filter_map(|update| async move {
+            match update.unwrap() {
+                Some(update) if update.settle_date != 0 => Some(SettledInvoice {
+                    amount: btc::MilliSats(update.amt_paid_msat),
+                    settle_index: update.settle_index,
+                    raw: RawInvoice(update.payment_request),
+                }),
+                _ => None,
+            }
+        })
+        .boxed()
+    }
+}
+
+pub(super) mod proto {
+    pub mod lnrpc {
+        #![allow(clippy::all, dead_code)]
+        tonic::include_proto!("lnrpc");
+    }
+
+    pub mod routerrpc {
+        #![allow(clippy::all)]
+        tonic::include_proto!("routerrpc");
+    }
+
+    pub mod signrpc {
+        #![allow(clippy::all)]
+        tonic::include_proto!("signrpc");
+    }
+
+    pub mod walletrpc {
+        #![allow(clippy::all)]
+        tonic::include_proto!("walletrpc");
+    }
+}
+
+struct LndCertVerifier {
+    cert: rustls::Certificate,
+}
+
+impl LndCertVerifier {
+    fn new(cert: Vec<u8>) -> Self {
+        let mut reader = BufReader::new(cert.as_slice());
+        let mut certs = pemfile::certs(&mut reader).unwrap();
+
+        if certs.len() != 1 {
+            panic!(
+                "tls.cert contains {} certificates, expected one",
+                certs.len()
+            )
+        } else {
+            Self {
+                cert: certs.swap_remove(0),
+            }
+        }
+    }
+}
+
+impl rustls::ServerCertVerifier for LndCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        match presented_certs {
+            [presented_cert] if *presented_cert == self.cert => {
+                Ok(rustls::ServerCertVerified::assertion())
+            }
+            [presented_cert] if *presented_cert != self.cert => Err(rustls::TLSError::General(
+                "server certificate doesn't match ours".to_owned(),
+            )),
+            _ => Err(rustls::TLSError::General(format!(
+                "server sent {} certificates, expected one",
+                presented_certs.len()
+            ))),
+        }
+    }
+}
@@ -1,15 +1,21 @@
 //! Contains code related to integrating with the Lightning network. The most important abstraction
-//! exposed by this module is [`Node`], which allows us to communicate with our Lightning node.
+//! exposed by this module is [`Node`], which allows us to communicate with our Lightning node. The
+//! node is either a real LND node, or a fake in-memory one (see [`fake`]).
 
 use crate::hex::Hex;
 use std::{fs, str::FromStr};
 use thiserror::Error;
 use url::Url;
 
+pub mod fake;
+mod lnd;
 mod node;
 
 pub(crate) use lightning_invoice::Invoice as ParsedInvoice;
-pub use node::{InvoiceStatus, Node, PaymentError, SettledInvoice, TransactionsQuery};
+pub use node::{
+    FeeBump, FeeBumpError, FeeEstimate, FeeRate, InvoiceStatus, LightningBackend, Node,
+    PaymentError, PaymentStatus, SettledInvoice, SignedTx, TransactionsQuery,
+};
 
 #[derive(Debug, Error)]
 #[error("{0}")]
@@ -29,16 +35,22 @@ pub struct Config {
     pub endpoint: Url,
     pub macaroon_path: String,
     pub cert_path: String,
-    pub first_block: u32,
 }
 
 /// Represents a gateway into the Lightning network.
 #[derive(Debug, Clone)]
 pub struct Lightning {
-    endpoint: Url,
-    cert: Vec<u8>,
-    macaroon: Hex,
-    first_block: u32,
+    backend: Backend,
+}
+
+#[derive(Debug, Clone)]
+enum Backend {
+    Lnd {
+        endpoint: Url,
+        cert: Vec<u8>,
+        macaroon: Hex,
+    },
+    Fake(fake::FakeNetwork),
 }
 
 impl Lightning {
@@ -46,21 +58,30 @@ impl Lightning {
         let macaroon = fs::read(config.macaroon_path).unwrap();
         let cert = fs::read(config.cert_path).unwrap();
         Self {
-            endpoint: config.endpoint,
-            cert,
-            macaroon: Hex::encode(&macaroon),
-            first_block: config.first_block,
+            backend: Backend::Lnd {
+                endpoint: config.endpoint,
+                cert,
+                macaroon: Hex::encode(&macaroon),
+            },
+        }
+    }
+
+    /// Creates a gateway whose nodes are all connected to the fake network.
+    pub fn fake(network: fake::FakeNetwork) -> Self {
+        Self {
+            backend: Backend::Fake(network),
         }
     }
 
     /// Opens a new connection to our node.
     pub async fn create_node(&self) -> Node {
-        Node::connect(
-            &self.endpoint,
-            self.macaroon.clone(),
-            self.cert.clone(),
-            self.first_block,
-        )
-        .await
+        match self.backend {
+            Backend::Lnd {
+                ref endpoint,
+                ref cert,
+                ref macaroon,
+            } => Box::new(lnd::Lnd::connect(endpoint, macaroon.clone(), cert.clone()).await),
+            Backend::Fake(ref network) => Box::new(network.create_node()),
+        }
     }
 }
@@ -1,6 +1,13 @@
-//! Handles the logic behind outgoing Lightning payments. Lightning payments require two steps:
-//! - reserving user funds via [`Payment::create`], and
+//! Handles the logic behind outgoing Lightning payments. Lightning payments require three steps:
+//! - creating the payment via [`Payment::create`],
+//! - determining the fee and reserving user funds via [`Payment::prepare`], and
 //! - sending the Lightning payment via [`Payment::send`].
+//!
+//! Payments are either sent right away, or queued in [`Status::New`] and sent later by a worker.
+//!
+//! Invoices created by users of our service are paid internally instead, via
+//! [`Payment::settle_internally`], which moves the funds between the two balances without going
+//! through the Lightning node.
 
 use crate::auth;
 use crate::balance;
@@ -9,7 +16,11 @@ use crate::btc;
 use crate::cash_limits;
 use crate::cash_limits::CashLimits;
 use crate::concurrency;
+use crate::idempotency;
+use crate::invoice::Invoice;
+use crate::ledger::EntryKind;
 use crate::ln;
+use crate::pricing::FeeSchedule;
 use crate::user;
 use chrono::DateTime;
 use chrono::Utc;
@@ -32,6 +43,18 @@ pub enum Error {
     ConcurrencyConflict(#[from] concurrency::ConflictError),
     #[error("{0:?}")]
     InsufficientBalance(#[from] balance::InsufficientBalance),
+    #[error("{0}")]
+    Idempotency(#[from] idempotency::Error),
+    #[error("payment is not queued anymore")]
+    NotQueued,
+}
+
+impl idempotency::RequestError for Error {
+    /// The payment may still succeed when its outcome is unknown, so a retry mustn't send it
+    /// again.
+    fn is_pending(&self) -> bool {
+        matches!(self, Error::PaymentError(ln::PaymentError::Unknown))
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -47,10 +70,16 @@ pub struct Payment {
     pub user_id: user::Id,
     pub amount: btc::MilliSats,
     pub invoice: ln::RawInvoice,
+    /// The routing fee reserved for the payment.
     pub fee: Option<btc::MilliSats>,
+    /// Our service fee, set once the funds are reserved.
+    pub service_fee: Option<btc::MilliSats>,
     pub reservation_id: Option<balance::ReservationId>,
     pub created: DateTime<Utc>,
     pub status: Status,
+    /// True if the invoice was created by a user of our service. Such payments are settled
+    /// internally, without a routing fee.
+    pub internal: bool,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -67,13 +96,15 @@ pub enum Status {
 }
 
 impl Payment {
-    /// Creates a new payment. This cannot cause a concurrency conflict.
+    /// Creates a new payment. This cannot cause a concurrency conflict. Set internal if the invoice
+    /// was created by a user of our service.
     pub(crate) fn create(
         grant: &auth::SpendGrant,
         invoice: ln::RawInvoice,
         amount: Option<btc::MilliSats>,
         limits: &CashLimits,
         daily_total: btc::MilliSats,
+        internal: bool,
     ) -> Result<Self, Error> {
         let amount = match (invoice.parse()?.amount_milli_satoshis(), amount) {
             (Some(_), Some(_)) => Err(Error::AmountSpecifiedTwice),
@@ -93,16 +124,83 @@ impl Payment {
             invoice,
             reservation_id: None,
             fee: None,
+            service_fee: None,
             created: Utc::now(),
             status: Status::New,
+            internal,
         })
     }
 
-    /// Determines the routing fee and reserves user funds.
+    /// Pays an invoice created by another user of our service by debiting the user balance, without
+    /// a routing fee. Our service fee is still charged according to the schedule. On success, the
+    /// payment is advanced straight into [`Status::Succeeded`] and the caller must settle the
+    /// invoice via [`Invoice::settle_internally`] in the same transaction.
+    /// The returned reservation is already debited, it only records the spent funds.
+    pub(crate) fn settle_internally(
+        &mut self,
+        balance: &mut Balance,
+        invoice: &Invoice,
+        schedule: &FeeSchedule,
+    ) -> Result<balance::Reservation, Error> {
+        if self.status != Status::New {
+            panic!("payment {:?} is not new", self.id);
+        }
+        if !self.internal {
+            panic!("payment {:?} is not internal", self.id);
+        }
+        if self.user_id != balance.user_id() {
+            panic!(
+                "user id {:?} does not match payment {:?} user id {:?}",
+                balance.user_id(),
+                self.id,
+                self.user_id
+            );
+        }
+        if invoice.raw != self.invoice {
+            panic!(
+                "invoice {:?} does not match {:?} for payment {:?}",
+                invoice.raw, self.invoice, self.id
+            );
+        }
+        if invoice.is_settled() {
+            let e = ln::PaymentError::InvoiceAlreadyPaid;
+            self.fail(&e);
+            return Err(Error::PaymentError(e));
+        }
+        if invoice.is_expired() {
+            let e = ln::PaymentError::InvoiceExpired;
+            self.fail(&e);
+            return Err(Error::PaymentError(e));
+        }
+        let service_fee = schedule.fee(self.amount);
+        match balance.reserve(
+            self.amount,
+            btc::MilliSats(0),
+            service_fee,
+            EntryKind::InternalPayment,
+            self.id.0,
+        ) {
+            Ok(mut reservation) => {
+                self.fee = Some(btc::MilliSats(0));
+                self.service_fee = Some(service_fee);
+                self.reservation_id = Some(reservation.id);
+                self.succeed(&mut reservation);
+                Ok(reservation)
+            }
+            Err(e) => {
+                self.fail_with_reason("INSUFFICIENT_BALANCE");
+                Err(Error::InsufficientBalance(e))
+            }
+        }
+    }
+
+    /// Determines the routing fee and our service fee according to the schedule, and reserves user
+    /// funds.
     pub(crate) async fn prepare(
         &mut self,
         node: &mut ln::Node,
         balance: &mut Balance,
+        schedule: &FeeSchedule,
     ) -> Result<balance::Reservation, Error> {
         if self.status != Status::New {
             panic!("payment {:?} is not new", self.id);
@@ -120,11 +218,21 @@ impl Payment {
             .await
         {
             Ok(fee) => {
-                let reservation = balance.reserve(self.amount + fee)?;
-                self.fee = Some(fee);
-                self.reservation_id = Some(reservation.id);
-                self.status = Status::Ready;
-                Ok(reservation)
+                let service_fee = schedule.fee(self.amount);
+                match balance.reserve(self.amount, fee, service_fee, EntryKind::Payment, self.id.0)
+                {
+                    Ok(reservation) => {
+                        self.fee = Some(fee);
+                        self.service_fee = Some(service_fee);
+                        self.reservation_id = Some(reservation.id);
+                        self.status = Status::Ready;
+                        Ok(reservation)
+                    }
+                    Err(e) => {
+                        self.fail_with_reason("INSUFFICIENT_BALANCE");
+                        Err(Error::InsufficientBalance(e))
+                    }
+                }
             }
             Err(e) => {
                 self.fail(&e);
@@ -137,35 +245,14 @@ impl Payment {
     /// [`PaymentStatus::Succeeded`]. If sending fails, the payment is advanced into
     /// [`PaymentStatus::Failed`] and the balance reservation is refunded to the user.
     /// Both success and failure are final, and this method can't be called again afterwards.
+    /// If the outcome is unknown, the payment stays ready, see [`Payment::reconcile`].
     pub(crate) async fn send(
         &mut self,
         node: &mut ln::Node,
         balance: &mut Balance,
         reservation: &mut balance::Reservation,
     ) -> Result<(), Error> {
-        if self.user_id != balance.user_id() {
-            panic!(
-                "balance user id {:?} does not match user id {:?} for payment {:?}",
-                balance.user_id(),
-                self.user_id,
-                self.id
-            );
-        }
-        if self.status != Status::Ready {
-            panic!("payment {:?} is not ready", self.id);
-        }
-        if reservation.status != balance::ReservationStatus::Pending {
-            panic!(
-                "reservation {:?} is not pending for payment {:?}",
-                reservation.id, self.id
-            );
-        }
-        if self.reservation_id != Some(reservation.id) {
-            panic!(
-                "reservation {:?} does not match {:?} for payment {:?}",
-                reservation.id, self.reservation_id, self.id
-            );
-        }
+        self.check_ready(balance, reservation);
         let fee = self
             .fee
             .expect("fee should be set for a payment in ready state");
@@ -183,15 +270,12 @@ impl Payment {
         };
         match node.pay_invoice(&self.invoice, amount, fee).await {
             Ok(()) => {
-                reservation.debit();
-                self.status = Status::Succeeded {
-                    timestamp: Utc::now(),
-                };
+                self.succeed(reservation);
                 Ok(())
             }
             Err(ln::PaymentError::Unknown) => {
                 log::error!(
-                    "payment outcome unknown for {:?}, this might require manual intervention",
+                    "payment outcome unknown for {:?}, leaving it for reconciliation",
                     self.id
                 );
                 Err(Error::PaymentError(ln::PaymentError::Unknown))
@@ -204,17 +288,87 @@ impl Payment {
         }
     }
 
+    /// Finalizes a ready payment whose outcome was unknown when it was sent, based on the status
+    /// reported by the node. Returns false if the payment is still in flight, in which case
+    /// nothing changes.
+    ///
+    /// If the node has no record of the payment, it was never sent, so it's failed and the
+    /// reservation is refunded. The caller must make sure that the payment isn't being sent at the
+    /// same time.
+    pub(crate) fn reconcile(
+        &mut self,
+        status: &ln::PaymentStatus,
+        balance: &mut Balance,
+        reservation: &mut balance::Reservation,
+    ) -> bool {
+        self.check_ready(balance, reservation);
+        match status {
+            ln::PaymentStatus::InFlight => false,
+            ln::PaymentStatus::Succeeded { .. } => {
+                self.succeed(reservation);
+                true
+            }
+            ln::PaymentStatus::Failed(e) => {
+                reservation.refund(balance);
+                self.fail(e);
+                true
+            }
+            ln::PaymentStatus::NotFound => {
+                reservation.refund(balance);
+                self.fail_with_reason("NOT_SENT");
+                true
+            }
+        }
+    }
+
+    fn check_ready(&self, balance: &Balance, reservation: &balance::Reservation) {
+        if self.user_id != balance.user_id() {
+            panic!(
+                "balance user id {:?} does not match user id {:?} for payment {:?}",
+                balance.user_id(),
+                self.user_id,
+                self.id
+            );
+        }
+        if self.status != Status::Ready {
+            panic!("payment {:?} is not ready", self.id);
+        }
+        if reservation.status != balance::ReservationStatus::Pending {
+            panic!(
+                "reservation {:?} is not pending for payment {:?}",
+                reservation.id, self.id
+            );
+        }
+        if self.reservation_id != Some(reservation.id) {
+            panic!(
+                "reservation {:?} does not match {:?} for payment {:?}",
+                reservation.id, self.reservation_id, self.id
+            );
+        }
+    }
+
+    fn succeed(&mut self, reservation: &mut balance::Reservation) {
+        reservation.debit();
+        self.status = Status::Succeeded {
+            timestamp: Utc::now(),
+        };
+    }
+
     fn fail(&mut self, e: &ln::PaymentError) {
+        self.fail_with_reason(match e {
+            ln::PaymentError::Unknown => "UNKNOWN",
+            ln::PaymentError::InvoiceExpired => "INVOICE_EXPIRED",
+            ln::PaymentError::InvoiceAlreadyPaid => "INVOICE_ALREADY_PAID",
+            ln::PaymentError::TimedOut => "TIMED_OUT",
+            ln::PaymentError::NoRouteFound => "NO_ROUTE_FOUND",
+            ln::PaymentError::InvalidPaymentDetails => "INVALID_PAYMENT_DETAILS",
+            ln::PaymentError::InsufficientLiquidity => "INSUFFICIENT_LIQUIDITY",
+        });
+    }
+
+    fn fail_with_reason(&mut self, reason: &str) {
         self.status = Status::Failed {
-            reason: match e {
-                ln::PaymentError::Unknown => "UNKNOWN".to_owned(),
-                ln::PaymentError::InvoiceExpired => "INVOICE_EXPIRED".to_owned(),
-                ln::PaymentError::InvoiceAlreadyPaid => "INVOICE_ALREADY_PAID".to_owned(),
-                ln::PaymentError::TimedOut => "TIMED_OUT".to_owned(),
-                ln::PaymentError::NoRouteFound => "NO_ROUTE_FOUND".to_owned(),
-                ln::PaymentError::InvalidPaymentDetails(_) => "INVALID_PAYMENT_DETAILS".to_owned(),
-                ln::PaymentError::InsufficientLiquidity => "INSUFFICIENT_LIQUIDITY".to_owned(),
-            },
+            reason: reason.to_owned(),
             timestamp: Utc::now(),
         };
     }
@@ -1,12 +1,25 @@
 use crate::{
-    auth, balance, btc, cash_limits::CashLimits, concurrency, database::Database, ln, QueryRange,
+    auth, balance, btc,
+    cash_limits::CashLimits,
+    concurrency,
+    database::{self, Database},
+    event::{self, Event},
+    idempotency::{self, Fingerprint, Operation},
+    invoice,
+    ln::{self, Lightning},
+    pricing, swallow_panic, worker, AmountRange, Page, Period,
 };
+use async_trait::async_trait;
+use chrono::Utc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 mod entities;
 
 pub use entities::{Error, Id, Payment, Status};
 
+/// Sends the payment, waiting until the outcome is known. If a payment has already been made with
+/// the same idempotency key, that payment is returned instead.
 pub async fn send(
     grant: &auth::SpendGrant,
     db: &Database,
@@ -14,83 +27,374 @@ pub async fn send(
     invoice: ln::RawInvoice,
     amount: Option<btc::MilliSats>,
     limits: &CashLimits,
+    idempotency_key: Option<&idempotency::Key>,
 ) -> Result<Payment, Error> {
-    let daily_total = queries::daily_total(db, grant.user_id).await;
-    let payment = Payment::create(grant, invoice, amount, limits, daily_total)?;
+    let fingerprint = Fingerprint::new(
+        Operation::Payment,
+        &format!("send {} {:?}", invoice.0, amount),
+    );
+    idempotency::run(
+        db,
+        grant.user_id,
+        idempotency_key,
+        Operation::Payment,
+        &fingerprint,
+        |id| queries::get(db, Id(id), grant.user_id),
+        || async {
+            let daily_total = queries::daily_total(db, grant.user_id).await;
+            let internal = invoice::get_by_invoice(db, &invoice).await.is_some();
+            let payment = Payment::create(grant, invoice, amount, limits, daily_total, internal)?;
+            if payment.internal {
+                return settle_internally(db, payment, idempotency_key, false).await;
+            }
+            let mut node = node;
+            let payment = prepare(db, &mut node, payment, idempotency_key, false).await?;
+            send_prepared(db, &mut node, payment).await
+        },
+    )
+    .await
+}
+
+/// Saves the payment without sending it, returning right away. The payment is sent later by the
+/// worker started with [`start_worker`].
+pub async fn queue(
+    grant: &auth::SpendGrant,
+    db: &Database,
+    invoice: ln::RawInvoice,
+    amount: Option<btc::MilliSats>,
+    limits: &CashLimits,
+    idempotency_key: Option<&idempotency::Key>,
+) -> Result<Payment, Error> {
+    let fingerprint = Fingerprint::new(
+        Operation::Payment,
+        &format!("queue {} {:?}", invoice.0, amount),
+    );
+    idempotency::run(
+        db,
+        grant.user_id,
+        idempotency_key,
+        Operation::Payment,
+        &fingerprint,
+        |id| queries::get(db, Id(id), grant.user_id),
+        || async {
+            let daily_total = queries::daily_total(db, grant.user_id).await;
+            let internal = invoice::get_by_invoice(db, &invoice).await.is_some();
+            let payment = Payment::create(grant, invoice, amount, limits, daily_total, internal)?;
+
+            let mut data_tx = db.begin().await.unwrap();
+            queries::upsert(&mut data_tx, &payment).await;
+            if let Some(key) = idempotency_key {
+                idempotency::record(&mut data_tx, payment.user_id, key, payment.id.0).await;
+            }
+            data_tx.commit().await.unwrap();
+            Ok(payment)
+        },
+    )
+    .await
+}
+
+pub async fn start_worker(db: &Database, lightning: &Lightning) {
+    worker::start(PaymentSender {
+        db: db.clone(),
+        node: lightning.create_node().await,
+    });
+    worker::start(PaymentReconciler {
+        db: db.clone(),
+        node: lightning.create_node().await,
+    });
+}
+
+/// Sends all queued payments. A payment picked up by another sender at the same time is skipped,
+/// so that it's never paid twice.
+pub async fn send_queued(db: &Database, node: &mut ln::Node) {
+    let queued_payments = queries::list_new(db).await;
+    for payment in queued_payments {
+        swallow_panic(async {
+            log::info!(
+                "sending queued payment {:?} with amount {:?}",
+                payment.id,
+                payment.amount
+            );
+            if payment.internal {
+                settle_internally(db, payment, None, true).await.ok();
+            } else if let Ok(payment) = prepare(db, node, payment, None, true).await {
+                send_prepared(db, node, payment).await.ok();
+            }
+        })
+        .await;
+    }
+}
+
+/// How long a payment has to be ready before its outcome is looked up on the node. This must be
+/// well over the time it takes to send a payment, otherwise a payment which is still being sent
+/// could be failed because the node doesn't know about it yet.
+const RECONCILE_AFTER_MINUTES: i64 = 10;
 
-    let mut data_tx = db.begin().await.unwrap();
-    queries::upsert(&mut data_tx, &payment).await;
-    data_tx.commit().await.unwrap();
+/// Finalizes ready payments whose outcome was unknown when they were sent, so that their balance
+/// reservations don't stay pending forever. Payments still in flight are left alone.
+pub async fn reconcile(db: &Database, node: &mut ln::Node) {
+    let ready_before = Utc::now() - chrono::Duration::minutes(RECONCILE_AFTER_MINUTES);
+    let stale_payments = queries::list_ready(db, ready_before).await;
+    for payment in stale_payments {
+        swallow_panic(async {
+            let status = node.get_payment_status(&payment.invoice).await;
+            if let ln::PaymentStatus::InFlight = status {
+                log::info!("payment {:?} is still in flight", payment.id);
+                return;
+            }
+            log::info!("reconciling payment {:?} with {:?}", payment.id, status);
+            concurrency::retry_loop(|| async {
+                let mut data_tx = db.begin().await.unwrap();
+                let mut payment = queries::lock(&mut data_tx, payment.id).await;
+                if payment.status != Status::Ready {
+                    return Ok(());
+                }
+                let mut balance = balance::get(&mut data_tx, payment.user_id).await;
+                let mut reservation =
+                    balance::get_reservation(&mut data_tx, payment.reservation_id.unwrap()).await;
+                payment.reconcile(&status, &mut balance, &mut reservation);
+                balance::upsert_reservation(&mut data_tx, &reservation).await?;
+                queries::upsert(&mut data_tx, &payment).await;
+                publish_outcome(&mut data_tx, &payment).await;
+                balance::update(&mut data_tx, &balance).await?;
+                data_tx.commit().await.unwrap();
+                Ok::<_, concurrency::ConflictError>(())
+            })
+            .await
+            .unwrap();
+        })
+        .await;
+    }
+}
 
+/// Pays an invoice of another user of our service. The payer's balance is debited and the payee's
+/// invoice settled in the same transaction, so the funds are never lost or credited twice. A
+/// `queued` payment is locked first, see [`claim_queued`].
+async fn settle_internally(
+    db: &Database,
+    payment: Payment,
+    idempotency_key: Option<&idempotency::Key>,
+    queued: bool,
+) -> Result<Payment, Error> {
+    let payment = Mutex::new(payment);
+    concurrency::retry_loop(|| async {
+        let mut data_tx = db.begin().await.unwrap();
+        let mut payment = payment.lock().await;
+        if queued {
+            claim_queued(&mut data_tx, &payment).await?;
+        }
+        let mut invoice = invoice::lock_by_invoice(&mut data_tx, &payment.invoice).await;
+        let mut balance = balance::get(&mut data_tx, payment.user_id).await;
+        let schedule =
+            pricing::get_schedule(&mut data_tx, payment.user_id, pricing::Operation::Payment).await;
+        let payee_schedule =
+            pricing::get_schedule(&mut data_tx, invoice.user_id, pricing::Operation::Invoice).await;
+
+        let result = payment.settle_internally(&mut balance, &invoice, &schedule);
+
+        let mut payee_balance = None;
+        if let Ok(ref reservation) = result {
+            balance::upsert_reservation(&mut data_tx, reservation).await?;
+            if invoice.user_id == payment.user_id {
+                invoice.settle_internally(&mut balance, payment.amount, &payee_schedule);
+            } else {
+                let mut other_balance = balance::get(&mut data_tx, invoice.user_id).await;
+                invoice.settle_internally(&mut other_balance, payment.amount, &payee_schedule);
+                payee_balance = Some(other_balance);
+            }
+            invoice::save_settled(&mut data_tx, &invoice).await;
+        }
+        queries::upsert(&mut data_tx, &payment).await;
+        publish_outcome(&mut data_tx, &payment).await;
+        if let Some(key) = idempotency_key {
+            idempotency::record(&mut data_tx, payment.user_id, key, payment.id.0).await;
+        }
+        // Update the balances in a fixed order, so that two users paying each other at the same
+        // time can't deadlock.
+        let mut balances: Vec<_> = std::iter::once(&balance).chain(&payee_balance).collect();
+        balances.sort_by_key(|balance| balance.user_id().0);
+        for balance in balances {
+            balance::update(&mut data_tx, balance).await?;
+        }
+        data_tx.commit().await.unwrap();
+        result.map(|_| ())
+    })
+    .await?;
+    Ok(payment.into_inner())
+}
+
+/// Determines the fees and reserves user funds, saving the payment as ready. A `queued` payment is
+/// locked first, see [`claim_queued`].
+async fn prepare(
+    db: &Database,
+    node: &mut ln::Node,
+    payment: Payment,
+    idempotency_key: Option<&idempotency::Key>,
+    queued: bool,
+) -> Result<Payment, Error> {
     let payment = Mutex::new(payment);
     let node = Mutex::new(node);
     concurrency::retry_loop(|| async {
         let mut data_tx = db.begin().await.unwrap();
-        let mut balance = balance::get(&mut data_tx, grant.user_id).await;
         let mut payment = payment.lock().await;
+        if queued {
+            claim_queued(&mut data_tx, &payment).await?;
+        }
+        let mut balance = balance::get(&mut data_tx, payment.user_id).await;
+        let schedule =
+            pricing::get_schedule(&mut data_tx, payment.user_id, pricing::Operation::Payment).await;
         let mut node = node.lock().await;
 
-        let result = payment.prepare(&mut node, &mut balance).await;
+        let result = payment.prepare(&mut node, &mut balance, &schedule).await;
 
         if let Ok(ref reservation) = result {
-            balance::upsert_reservation(&mut data_tx, reservation).await;
+            balance::upsert_reservation(&mut data_tx, reservation).await?;
         }
         queries::upsert(&mut data_tx, &payment).await;
+        publish_outcome(&mut data_tx, &payment).await;
+        if let Some(key) = idempotency_key {
+            idempotency::record(&mut data_tx, payment.user_id, key, payment.id.0).await;
+        }
         balance::update(&mut data_tx, &balance).await?;
         data_tx.commit().await.unwrap();
         result
     })
     .await?;
+    Ok(payment.into_inner())
+}
+
+/// Locks the queued payment until the transaction ends. Fails if another sender has locked it, or
+/// has already sent it, so that only one sender gets to send it.
+async fn claim_queued(data_tx: &mut database::Transaction, payment: &Payment) -> Result<(), Error> {
+    match queries::lock_queued(data_tx, payment.id).await {
+        Some(_) => Ok(()),
+        None => {
+            log::info!("payment {:?} was picked up by another sender", payment.id);
+            Err(Error::NotQueued)
+        }
+    }
+}
 
+/// Sends a ready payment, saving the outcome.
+async fn send_prepared(
+    db: &Database,
+    node: &mut ln::Node,
+    payment: Payment,
+) -> Result<Payment, Error> {
+    let payment = Mutex::new(payment);
+    let node = Mutex::new(node);
     concurrency::retry_loop(|| async {
         let mut data_tx = db.begin().await.unwrap();
-        let mut balance = balance::get(&mut data_tx, grant.user_id).await;
         let mut payment = payment.lock().await;
+        let mut balance = balance::get(&mut data_tx, payment.user_id).await;
         let mut node = node.lock().await;
-        let mut reservation = balance::get_reservation(db, payment.reservation_id.unwrap()).await;
+        let mut reservation =
+            balance::get_reservation(&mut data_tx, payment.reservation_id.unwrap()).await;
 
         let result = payment
             .send(&mut node, &mut balance, &mut reservation)
             .await;
 
-        balance::upsert_reservation(&mut data_tx, &reservation).await;
+        balance::upsert_reservation(&mut data_tx, &reservation).await?;
         queries::upsert(&mut data_tx, &payment).await;
+        publish_outcome(&mut data_tx, &payment).await;
         balance::update(&mut data_tx, &balance).await?;
         data_tx.commit().await.unwrap();
         result
     })
     .await?;
-
     Ok(payment.into_inner())
 }
 
+/// Publishes the outcome of the payment, if it's known.
+async fn publish_outcome(data_tx: &mut database::Transaction, payment: &Payment) {
+    if let Some(event) = Event::payment_finished(payment) {
+        event::publish(data_tx, event).await;
+    }
+}
+
+struct PaymentReconciler {
+    db: Database,
+    node: ln::Node,
+}
+
+#[async_trait]
+impl worker::Worker for PaymentReconciler {
+    async fn run(&mut self) {
+        reconcile(&self.db, &mut self.node).await;
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+struct PaymentSender {
+    db: Database,
+    node: ln::Node,
+}
+
+#[async_trait]
+impl worker::Worker for PaymentSender {
+    async fn run(&mut self) {
+        send_queued(&self.db, &mut self.node).await;
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
 pub async fn get(grant: &auth::ReadGrant, db: &Database, id: Id) -> Option<Payment> {
     queries::get(db, id, grant.user_id).await
 }
 
-pub async fn list(grant: &auth::ReadGrant, db: &Database, range: QueryRange) -> Vec<Payment> {
-    queries::list(db, grant.user_id, range).await
+/// Selects the payments to list. The default filter selects all payments.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Filter {
+    pub status: Option<StatusKind>,
+    pub period: Period,
+    pub amount: AmountRange<btc::MilliSats>,
+}
+
+/// A payment [`Status`] without its details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusKind {
+    New,
+    Ready,
+    Succeeded,
+    Failed,
+}
+
+/// Lists the payments matching the filter, most recent first.
+pub async fn list(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    filter: &Filter,
+    page: &Page<Id>,
+) -> Vec<Payment> {
+    queries::list(db, grant.user_id, filter, page).await
 }
 
 mod queries {
-    use super::{Id, Payment, Status};
+    use super::{Filter, Id, Payment, Status, StatusKind};
     use crate::{
         auth, balance, btc,
         database::{self, Database, SumRow},
-        ln, user, QueryRange,
+        ln, user, Page,
     };
     use chrono::{DateTime, Duration, Utc};
     use const_format::formatcp;
     use uuid::Uuid;
 
-    const COLUMNS: &str = "id, user_id, token_id, reservation_id, amount_msats, fee_msats, invoice, created, status, failure_reason, failure_timestamp, success_timestamp";
+    const COLUMNS: &str = "id, user_id, token_id, reservation_id, amount_msats, fee_msats, invoice, created, status, failure_reason, failure_timestamp, success_timestamp, internal, service_fee_msats";
 
     pub(super) async fn upsert(data_tx: &mut database::Transaction, payment: &Payment) {
         sqlx::query(
             formatcp!(
             r#"INSERT INTO payments ({})
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) ON CONFLICT (id) DO UPDATE SET
-                user_id = $2, token_id = $3, reservation_id = $4, amount_msats = $5, fee_msats = $6, invoice = $7, created = $8, status = $9, failure_reason = $10, failure_timestamp = $11, success_timestamp = $12"#,
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) ON CONFLICT (id) DO UPDATE SET
+                user_id = $2, token_id = $3, reservation_id = $4, amount_msats = $5, fee_msats = $6, invoice = $7, created = $8, status = $9, failure_reason = $10, failure_timestamp = $11, success_timestamp = $12, internal = $13, service_fee_msats = $14"#,
                 COLUMNS)
         )
         .bind(payment.id.0)
@@ -114,6 +418,8 @@ mod queries {
             Status::Succeeded{ timestamp } => Some(timestamp),
             _ => None
         })
+        .bind(payment.internal)
+        .bind(payment.service_fee.map(|fee| fee.0))
         .execute(&mut *data_tx)
         .await
         .unwrap();
@@ -132,14 +438,45 @@ mod queries {
         .map(|row| row.into_entity())
     }
 
-    pub(super) async fn list(db: &Database, user_id: user::Id, range: QueryRange) -> Vec<Payment> {
+    pub(super) async fn list(
+        db: &Database,
+        user_id: user::Id,
+        filter: &Filter,
+        page: &Page<Id>,
+    ) -> Vec<Payment> {
         sqlx::query_as::<_, PaymentRow>(formatcp!(
-            "SELECT {} FROM payments WHERE user_id = $1 ORDER BY created DESC LIMIT $2 OFFSET $3",
+            r#"SELECT {} FROM payments WHERE user_id = $1
+                AND ($2::INTEGER IS NULL OR status = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR created >= $3) AND ($4::TIMESTAMPTZ IS NULL OR created < $4)
+                AND ($5::BIGINT IS NULL OR amount_msats >= $5) AND ($6::BIGINT IS NULL OR amount_msats <= $6)
+                AND ($7::TIMESTAMPTZ IS NULL OR (created, id) < ($7, $8))
+                ORDER BY created DESC, id DESC LIMIT $9 OFFSET $10"#,
             COLUMNS
         ))
         .bind(user_id.0)
-        .bind(range.limit)
-        .bind(range.offset)
+        .bind(filter.status.map(status_kind_to_i32))
+        .bind(filter.period.from)
+        .bind(filter.period.to)
+        .bind(filter.amount.min.map(|amount| amount.0))
+        .bind(filter.amount.max.map(|amount| amount.0))
+        .bind(page.after.map(|cursor| cursor.created))
+        .bind(page.after.map(|cursor| cursor.id.0))
+        .bind(page.range.limit)
+        .bind(page.range.offset)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    /// Lists queued payments, oldest first.
+    pub(super) async fn list_new(db: &Database) -> Vec<Payment> {
+        sqlx::query_as::<_, PaymentRow>(formatcp!(
+            "SELECT {} FROM payments WHERE status = 0 ORDER BY created",
+            COLUMNS
+        ))
         .fetch_all(db)
         .await
         .unwrap()
@@ -148,9 +485,53 @@ mod queries {
         .collect()
     }
 
+    /// Lists payments which have been ready since before the given time, oldest first.
+    pub(super) async fn list_ready(db: &Database, ready_before: DateTime<Utc>) -> Vec<Payment> {
+        sqlx::query_as::<_, PaymentRow>(formatcp!(
+            r#"SELECT {} FROM payments WHERE status = 1 AND reservation_id IN
+                (SELECT id FROM balance_reservations WHERE created < $1) ORDER BY created"#,
+            COLUMNS
+        ))
+        .bind(ready_before)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn lock(data_tx: &mut database::Transaction, id: Id) -> Payment {
+        sqlx::query_as::<_, PaymentRow>(formatcp!(
+            "SELECT {} FROM payments WHERE id = $1 FOR UPDATE",
+            COLUMNS
+        ))
+        .bind(id.0)
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .into_entity()
+    }
+
+    /// Locks the payment if it's still queued, skipping it if another transaction has locked it.
+    pub(super) async fn lock_queued(
+        data_tx: &mut database::Transaction,
+        id: Id,
+    ) -> Option<Payment> {
+        sqlx::query_as::<_, PaymentRow>(formatcp!(
+            "SELECT {} FROM payments WHERE id = $1 AND status = 0 FOR UPDATE SKIP LOCKED",
+            COLUMNS
+        ))
+        .bind(id.0)
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
     pub(super) async fn daily_total(db: &Database, user_id: user::Id) -> btc::MilliSats {
         sqlx::query_as::<_, SumRow<Option<i64>>>(
-            "SELECT SUM(CAST(amount_msats AS INTEGER)) AS sum FROM payments WHERE user_id = $1 AND created > $2",
+            "SELECT SUM(amount_msats)::BIGINT AS sum FROM payments WHERE user_id = $1 AND created > $2",
         )
         .bind(user_id.0)
         .bind(Utc::now() - Duration::days(1))
@@ -176,6 +557,8 @@ mod queries {
         failure_reason: Option<String>,
         failure_timestamp: Option<DateTime<Utc>>,
         success_timestamp: Option<DateTime<Utc>>,
+        internal: bool,
+        service_fee_msats: Option<i64>,
     }
 
     impl PaymentRow {
@@ -187,10 +570,12 @@ mod queries {
                 user_id: user::Id(self.user_id),
                 amount: btc::MilliSats(self.amount_msats),
                 fee: self.fee_msats.map(btc::MilliSats),
+                service_fee: self.service_fee_msats.map(btc::MilliSats),
                 invoice: ln::RawInvoice(self.invoice),
                 reservation_id: self.reservation_id.map(balance::ReservationId),
                 created: self.created,
                 status,
+                internal: self.internal,
             }
         }
 
@@ -211,11 +596,20 @@ mod queries {
     }
 
     fn status_to_i32(status: &Status) -> i32 {
+        status_kind_to_i32(match status {
+            Status::New => StatusKind::New,
+            Status::Ready => StatusKind::Ready,
+            Status::Succeeded { .. } => StatusKind::Succeeded,
+            Status::Failed { .. } => StatusKind::Failed,
+        })
+    }
+
+    fn status_kind_to_i32(status: StatusKind) -> i32 {
         match status {
-            Status::New => 0,
-            Status::Ready => 0,
-            Status::Succeeded { .. } => 2,
-            Status::Failed { .. } => 3,
+            StatusKind::New => 0,
+            StatusKind::Ready => 1,
+            StatusKind::Succeeded => 2,
+            StatusKind::Failed => 3,
         }
     }
 }
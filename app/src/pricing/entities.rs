@@ -0,0 +1,138 @@
+//! Pricing decides the service fees we charge for payments, invoices, withdrawals and deposits.
+//!
+//! Each [`Operation`] has a [`FeeSchedule`] per plan, a flat fee plus a proportional fee. Users are
+//! on the [`DEFAULT_PLAN`] unless an operator moved them to another plan, and a plan without a
+//! schedule for an operation falls back to the schedule of the default plan. Operations without
+//! any schedule are free.
+//!
+//! The service fee is recorded separately from the amount and from the network fees, and it's
+//! credited to [`crate::ledger::Account::Revenue`].
+
+use crate::btc;
+use thiserror::Error;
+
+/// The plan every user is on unless an operator moved them to another plan.
+pub const DEFAULT_PLAN: &str = "default";
+
+const MAX_PLAN_CHARS: usize = 50;
+/// The highest proportional fee, i.e. 100%.
+const MAX_RATE_PPM: i64 = 1_000_000;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid plan: {0}")]
+    InvalidPlan(&'static str),
+    #[error("Invalid fee schedule: {0}")]
+    InvalidSchedule(&'static str),
+    #[error("user not found")]
+    UserNotFound,
+}
+
+/// An operation we charge a service fee for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Paying an invoice, over Lightning or internally.
+    Payment,
+    /// Receiving the payment of an invoice, over Lightning or internally.
+    Invoice,
+    /// Sending funds on-chain.
+    Withdrawal,
+    /// Receiving funds on-chain.
+    Deposit,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 4] = [
+        Operation::Payment,
+        Operation::Invoice,
+        Operation::Withdrawal,
+        Operation::Deposit,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Payment => "payment",
+            Operation::Invoice => "invoice",
+            Operation::Withdrawal => "withdrawal",
+            Operation::Deposit => "deposit",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|operation| operation.as_str() == s)
+    }
+}
+
+/// The service fee of an operation: a flat fee plus a proportional fee in parts per million of the
+/// amount, rounded up to the next millisatoshi.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub operation: Operation,
+    pub flat: btc::MilliSats,
+    pub rate_ppm: i64,
+}
+
+impl FeeSchedule {
+    pub(crate) fn new(
+        operation: Operation,
+        flat: btc::MilliSats,
+        rate_ppm: i64,
+    ) -> Result<Self, Error> {
+        if flat < btc::MilliSats(0) {
+            return Err(Error::InvalidSchedule("the flat fee can't be negative"));
+        }
+        if !(0..=MAX_RATE_PPM).contains(&rate_ppm) {
+            return Err(Error::InvalidSchedule(
+                "the rate must be between 0 and 1000000 ppm",
+            ));
+        }
+        Ok(Self {
+            operation,
+            flat,
+            rate_ppm,
+        })
+    }
+
+    /// The schedule of operations which are free.
+    pub(crate) fn free(operation: Operation) -> Self {
+        Self {
+            operation,
+            flat: btc::MilliSats(0),
+            rate_ppm: 0,
+        }
+    }
+
+    /// The service fee for the amount.
+    pub fn fee(&self, amount: btc::MilliSats) -> btc::MilliSats {
+        let amount = amount.0.max(0) as i128;
+        let proportional =
+            (amount * self.rate_ppm as i128 + MAX_RATE_PPM as i128 - 1) / MAX_RATE_PPM as i128;
+        self.flat + btc::MilliSats(proportional as i64)
+    }
+
+    /// The service fee for an amount we credit to the user. The fee is taken out of the amount, so
+    /// it's never higher than the amount.
+    pub fn credit_fee(&self, amount: btc::MilliSats) -> btc::MilliSats {
+        self.fee(amount).min(amount.max(btc::MilliSats(0)))
+    }
+}
+
+/// The fee schedules which apply to the users on a plan, one for each [`Operation`].
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub name: String,
+    pub schedules: Vec<FeeSchedule>,
+}
+
+pub(crate) fn validate_plan(plan: &str) -> Result<(), Error> {
+    if plan.is_empty() {
+        Err(Error::InvalidPlan("the plan name can't be empty"))
+    } else if plan.chars().count() > MAX_PLAN_CHARS {
+        Err(Error::InvalidPlan("the plan name is too long"))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    auth, btc,
+    database::{self, Database},
+    user,
+};
+
+mod entities;
+
+pub use entities::{Error, FeeSchedule, Operation, Plan, DEFAULT_PLAN};
+
+/// Gets the plan the user is on, with the fee schedules which apply to the user.
+pub async fn get_plan(grant: &auth::ReadGrant, db: &Database) -> Plan {
+    let mut data_tx = db.begin().await.unwrap();
+    let name = queries::get_user_plan(&mut data_tx, grant.user_id).await;
+    let plan = effective_plan(&mut data_tx, name).await;
+    data_tx.commit().await.unwrap();
+    plan
+}
+
+/// Gets the fee schedule of the operation for the user. Call this in the transaction which
+/// charges the fee, so that the fee matches the plan the user is on at the time.
+pub(crate) async fn get_schedule(
+    data_tx: &mut database::Transaction,
+    user_id: user::Id,
+    operation: Operation,
+) -> FeeSchedule {
+    let plan = queries::get_user_plan(data_tx, user_id).await;
+    queries::list_schedules(data_tx, &plan)
+        .await
+        .into_iter()
+        .find(|schedule| schedule.operation == operation)
+        .unwrap_or_else(|| FeeSchedule::free(operation))
+}
+
+/// Lists the default plan, and every plan with a fee schedule or a user on it, with the fee
+/// schedules which apply to the users on each plan.
+pub(crate) async fn list_plans(db: &Database) -> Vec<Plan> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut plans = Vec::new();
+    for name in queries::list_plan_names(&mut data_tx).await {
+        plans.push(effective_plan(&mut data_tx, name).await);
+    }
+    data_tx.commit().await.unwrap();
+    plans
+}
+
+/// Sets the fee schedule of the operation on the plan. The users on the plan are charged
+/// according to the new schedule from now on.
+pub(crate) async fn set_schedule(
+    db: &Database,
+    plan: &str,
+    operation: Operation,
+    flat: btc::MilliSats,
+    rate_ppm: i64,
+) -> Result<FeeSchedule, Error> {
+    entities::validate_plan(plan)?;
+    let schedule = FeeSchedule::new(operation, flat, rate_ppm)?;
+    queries::upsert_schedule(db, plan, &schedule).await;
+    Ok(schedule)
+}
+
+/// Removes the fee schedule of the operation from the plan, so that the users on the plan are
+/// charged according to the default plan, or not at all if it's the default plan.
+pub(crate) async fn remove_schedule(db: &Database, plan: &str, operation: Operation) {
+    queries::delete_schedule(db, plan, operation).await;
+}
+
+/// Moves the user to another plan.
+pub(crate) async fn set_user_plan(
+    db: &Database,
+    user_id: user::Id,
+    plan: &str,
+) -> Result<(), Error> {
+    entities::validate_plan(plan)?;
+    if queries::update_user_plan(db, user_id, plan).await {
+        Ok(())
+    } else {
+        Err(Error::UserNotFound)
+    }
+}
+
+/// The schedules of the plan for every operation, falling back to the default plan, or to free
+/// operations.
+async fn effective_plan(data_tx: &mut database::Transaction, name: String) -> Plan {
+    let found = queries::list_schedules(data_tx, &name).await;
+    let schedules = Operation::ALL
+        .iter()
+        .map(|&operation| {
+            found
+                .iter()
+                .find(|schedule| schedule.operation == operation)
+                .copied()
+                .unwrap_or_else(|| FeeSchedule::free(operation))
+        })
+        .collect();
+    Plan { name, schedules }
+}
+
+mod queries {
+    use super::{FeeSchedule, Operation, DEFAULT_PLAN};
+    use crate::{
+        btc,
+        database::{self, Database},
+        user,
+    };
+
+    pub(super) async fn get_user_plan(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> String {
+        sqlx::query_as::<_, PlanRow>("SELECT plan FROM users WHERE id = $1")
+            .bind(user_id.0)
+            .fetch_one(data_tx)
+            .await
+            .unwrap()
+            .plan
+    }
+
+    /// Lists the schedules of the plan, with the schedules of the default plan for the operations
+    /// the plan has no schedule for.
+    pub(super) async fn list_schedules(
+        data_tx: &mut database::Transaction,
+        plan: &str,
+    ) -> Vec<FeeSchedule> {
+        sqlx::query_as::<_, ScheduleRow>(
+            r#"SELECT DISTINCT ON (operation) operation, flat_msats, rate_ppm FROM fee_schedules
+                WHERE plan = $1 OR plan = $2 ORDER BY operation, plan = $2"#,
+        )
+        .bind(plan)
+        .bind(DEFAULT_PLAN)
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn list_plan_names(data_tx: &mut database::Transaction) -> Vec<String> {
+        sqlx::query_as::<_, PlanRow>(
+            r#"SELECT $1 AS plan UNION SELECT plan FROM fee_schedules UNION SELECT plan FROM users
+                ORDER BY plan"#,
+        )
+        .bind(DEFAULT_PLAN)
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.plan)
+        .collect()
+    }
+
+    pub(super) async fn upsert_schedule(db: &Database, plan: &str, schedule: &FeeSchedule) {
+        sqlx::query(
+            r#"INSERT INTO fee_schedules (plan, operation, flat_msats, rate_ppm) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (plan, operation) DO UPDATE SET flat_msats = $3, rate_ppm = $4"#,
+        )
+        .bind(plan)
+        .bind(schedule.operation.as_str())
+        .bind(schedule.flat.0)
+        .bind(schedule.rate_ppm)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    pub(super) async fn delete_schedule(db: &Database, plan: &str, operation: Operation) {
+        sqlx::query("DELETE FROM fee_schedules WHERE plan = $1 AND operation = $2")
+            .bind(plan)
+            .bind(operation.as_str())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    /// Returns false if the user doesn't exist.
+    pub(super) async fn update_user_plan(db: &Database, user_id: user::Id, plan: &str) -> bool {
+        sqlx::query("UPDATE users SET plan = $1 WHERE id = $2 RETURNING id")
+            .bind(plan)
+            .bind(user_id.0)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct PlanRow {
+        plan: String,
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct ScheduleRow {
+        operation: String,
+        flat_msats: i64,
+        rate_ppm: i64,
+    }
+
+    impl ScheduleRow {
+        /// None for operations this version doesn't know about.
+        fn into_entity(self) -> Option<FeeSchedule> {
+            Some(FeeSchedule {
+                operation: Operation::parse(&self.operation)?,
+                flat: btc::MilliSats(self.flat_msats),
+                rate_ppm: self.rate_ppm,
+            })
+        }
+    }
+}
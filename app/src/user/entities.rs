@@ -1,11 +1,38 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use chrono::{DateTime, Utc};
+use const_format::formatcp;
 use uuid::Uuid;
 
+use super::Error;
 use crate::btc;
 
 #[derive(Debug)]
 pub struct Email(pub String);
 
+impl Email {
+    const MAX_CHARS: usize = 254;
+
+    /// Normalizes and validates an email address. Emails are compared case-insensitively, so
+    /// they're always stored in lowercase.
+    pub fn parse(email: &str) -> Result<Self, Error> {
+        let email = email.trim().to_lowercase();
+        if email.chars().count() > Self::MAX_CHARS || email.chars().any(char::is_whitespace) {
+            return Err(Error::InvalidEmail);
+        }
+        match email.split_once('@') {
+            Some((local, domain))
+                if !local.is_empty() && !domain.is_empty() && !domain.contains('@') =>
+            {
+                Ok(Self(email))
+            }
+            _ => Err(Error::InvalidEmail),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Id(pub Uuid);
 
@@ -16,3 +43,76 @@ pub struct User {
     pub balance: btc::MilliSats,
     pub created: DateTime<Utc>,
 }
+
+impl User {
+    /// Creates a new user with an empty balance.
+    pub(crate) fn create(email: Email) -> Self {
+        Self {
+            id: Id(Uuid::new_v4()),
+            email,
+            balance: btc::MilliSats(0),
+            created: Utc::now(),
+        }
+    }
+}
+
+/// The password in the form given by the user, before it's hashed.
+pub struct Password(pub String);
+
+const MIN_PASSWORD_CHARS: usize = 8;
+// Hashing is expensive, so the length is limited to keep the login endpoint from being abused
+const MAX_PASSWORD_CHARS: usize = 128;
+
+impl Password {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let chars = self.0.chars().count();
+        if chars < MIN_PASSWORD_CHARS {
+            Err(Error::InvalidPassword(formatcp!(
+                "password must have at least {} characters",
+                MIN_PASSWORD_CHARS
+            )))
+        } else if chars > MAX_PASSWORD_CHARS {
+            Err(Error::InvalidPassword(formatcp!(
+                "password can have at most {} characters",
+                MAX_PASSWORD_CHARS
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A salted Argon2 hash of the password, in the PHC string format. Unlike [`crate::auth::TokenHash`],
+/// passwords are chosen by humans and have low entropy, so a slow hashing algorithm is needed.
+pub(crate) struct PasswordHash(String);
+
+impl PasswordHash {
+    /// Hashes the password with a random salt. This is slow, so avoid calling it from async code
+    /// directly.
+    pub(crate) fn generate(password: &Password) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.0.as_bytes(), &salt)
+            .unwrap();
+        Self(hash.to_string())
+    }
+
+    pub(crate) fn from_string(hash: String) -> Self {
+        Self(hash)
+    }
+
+    /// Checks the password against the hash. Hashes that can't be parsed, e.g. legacy plaintext
+    /// passwords, never match.
+    pub(crate) fn verify(&self, password: &Password) -> bool {
+        match argon2::PasswordHash::new(&self.0) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.0.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
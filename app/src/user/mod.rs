@@ -3,25 +3,188 @@ use thiserror::Error;
 
 mod entities;
 
-pub use entities::{Email, Id, User};
+pub(crate) use entities::PasswordHash;
+pub use entities::{Email, Id, Password, User};
 
 pub async fn get(grant: &auth::ReadGrant, db: &Database) -> Option<User> {
     queries::get(db, grant.user_id).await
 }
 
+/// Signs up a new user with an empty balance.
+pub async fn register(db: &Database, email: &str, password: Password) -> Result<User, Error> {
+    let email = Email::parse(email)?;
+    password.validate()?;
+    let user = User::create(email);
+    let password_hash = hash(password).await;
+    let mut data_tx = db.begin().await.unwrap();
+    queries::insert(&mut data_tx, &user, Some(&password_hash)).await?;
+    data_tx.commit().await.unwrap();
+    Ok(user)
+}
+
+/// Checks the user's email and password. Users created by an operator don't have a password, so
+/// they can't be authenticated this way.
+pub async fn authenticate(db: &Database, email: &str, password: Password) -> Result<User, Error> {
+    let email = Email::parse(email).map_err(|_| Error::InvalidCredentials)?;
+    match queries::get_with_password_hash(db, &email).await {
+        Some((user, Some(password_hash))) => {
+            let valid = tokio::task::spawn_blocking(move || password_hash.verify(&password))
+                .await
+                .unwrap();
+            if valid {
+                Ok(user)
+            } else {
+                Err(Error::InvalidCredentials)
+            }
+        }
+        _ => {
+            // Hash anyway so that the response time doesn't reveal which emails are registered
+            hash(password).await;
+            Err(Error::InvalidCredentials)
+        }
+    }
+}
+
+/// Checks the password of the user, e.g. before a change a token alone isn't enough for.
+pub(crate) async fn verify_password(
+    db: &Database,
+    id: Id,
+    password: Password,
+) -> Result<(), Error> {
+    match queries::get_password_hash(db, id).await {
+        Some(password_hash) => {
+            let valid = tokio::task::spawn_blocking(move || password_hash.verify(&password))
+                .await
+                .unwrap();
+            if valid {
+                Ok(())
+            } else {
+                Err(Error::InvalidCredentials)
+            }
+        }
+        None => Err(Error::InvalidCredentials),
+    }
+}
+
+/// Hashes the password on a blocking thread, since hashing is slow.
+pub(crate) async fn hash(password: Password) -> PasswordHash {
+    tokio::task::spawn_blocking(move || PasswordHash::generate(&password))
+        .await
+        .unwrap()
+}
+
+pub(crate) async fn create(db: &Database, email: Email) -> Result<User, Error> {
+    let user = User::create(email);
+    let mut data_tx = db.begin().await.unwrap();
+    queries::insert(&mut data_tx, &user, None).await?;
+    data_tx.commit().await.unwrap();
+    Ok(user)
+}
+
+pub(crate) async fn get_by_email(db: &Database, email: &Email) -> Option<User> {
+    queries::get_by_email(db, email).await
+}
+
+pub(crate) async fn list(db: &Database) -> Vec<User> {
+    queries::list(db).await
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("User being created already exists")]
     UserAlreadyExists,
+    #[error("Invalid email address")]
+    InvalidEmail,
+    #[error("Invalid password: {0}")]
+    InvalidPassword(&'static str),
+    #[error("Invalid email or password")]
+    InvalidCredentials,
 }
 
 mod queries {
-    use super::{Email, Id, User};
+    use super::{Email, Error, Id, PasswordHash, User};
     use crate::btc;
-    use crate::database::Database;
+    use crate::database::{self, Database};
     use chrono::{DateTime, Utc};
     use uuid::Uuid;
 
+    pub(super) async fn insert(
+        data_tx: &mut database::Transaction,
+        user: &User,
+        password_hash: Option<&PasswordHash>,
+    ) -> Result<(), Error> {
+        match sqlx::query(
+            "INSERT INTO users (id, email, password, balance_msats, created) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(user.id.0)
+        .bind(&user.email.0)
+        .bind(password_hash.map(|hash| hash.as_str()))
+        .bind(user.balance.0)
+        .bind(user.created)
+        .execute(data_tx)
+        .await
+        {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.to_string().to_lowercase().contains(
+                    "duplicate key value violates unique constraint \"users_email_key\"",
+                ) || e.to_string().to_lowercase().contains(
+                    "duplicate key value violates unique constraint \"user_email_lower\"",
+                ) =>
+            {
+                Err(Error::UserAlreadyExists)
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    pub(super) async fn get_by_email(db: &Database, email: &Email) -> Option<User> {
+        sqlx::query_as::<_, UserRow>(
+            "SELECT id, email, balance_msats, created FROM users WHERE email = $1",
+        )
+        .bind(&email.0)
+        .fetch_optional(db)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn get_with_password_hash(
+        db: &Database,
+        email: &Email,
+    ) -> Option<(User, Option<PasswordHash>)> {
+        sqlx::query_as::<_, UserWithPasswordRow>(
+            "SELECT id, email, password, balance_msats, created FROM users WHERE email = $1",
+        )
+        .bind(&email.0)
+        .fetch_optional(db)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn get_password_hash(db: &Database, id: Id) -> Option<PasswordHash> {
+        sqlx::query_as::<_, PasswordRow>("SELECT password FROM users WHERE id = $1")
+            .bind(id.0)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+            .and_then(|row| row.password)
+            .map(PasswordHash::from_string)
+    }
+
+    pub(super) async fn list(db: &Database) -> Vec<User> {
+        sqlx::query_as::<_, UserRow>(
+            "SELECT id, email, balance_msats, created FROM users ORDER BY created",
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
     pub(super) async fn get(db: &Database, id: Id) -> Option<User> {
         sqlx::query_as::<_, UserRow>(
             "SELECT id, email, balance_msats, created FROM users WHERE id = $1",
@@ -41,6 +204,35 @@ mod queries {
         created: DateTime<Utc>,
     }
 
+    #[derive(sqlx::FromRow)]
+    struct UserWithPasswordRow {
+        id: Uuid,
+        email: String,
+        password: Option<String>,
+        balance_msats: i64,
+        created: DateTime<Utc>,
+    }
+
+    #[derive(sqlx::FromRow)]
+    struct PasswordRow {
+        password: Option<String>,
+    }
+
+    impl UserWithPasswordRow {
+        fn into_entity(self) -> (User, Option<PasswordHash>) {
+            let user = UserRow {
+                id: self.id,
+                email: self.email,
+                balance_msats: self.balance_msats,
+                created: self.created,
+            };
+            (
+                user.into_entity(),
+                self.password.map(PasswordHash::from_string),
+            )
+        }
+    }
+
     impl UserRow {
         fn into_entity(self) -> User {
             User {
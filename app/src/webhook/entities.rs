@@ -0,0 +1,263 @@
+//! Webhooks notify users about state changes, so that they don't have to poll the list endpoints.
+//!
+//! Whenever an [`Event`] is published, a [`Delivery`] is written to the outbox for each of the
+//! user's webhooks, in the same database transaction as the state change. A background worker then
+//! POSTs the event to the webhook URL, signed with the webhook [`Secret`], retrying failed
+//! deliveries with an exponential backoff.
+
+use crate::{
+    event::{Event, EventId, EventKind},
+    hex::Hex,
+    user,
+};
+use chrono::{DateTime, Duration, Utc};
+use const_format::formatcp;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use std::{net::IpAddr, str::FromStr};
+use thiserror::Error;
+use url::{Host, Url};
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("webhook not found")]
+    NotFound,
+    #[error("invalid url: {0}")]
+    InvalidUrl(&'static str),
+    #[error("too many webhooks")]
+    TooManyWebhooks,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Id(pub Uuid);
+
+impl FromStr for Id {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::from_str(s).map(Self)
+    }
+}
+
+/// The key used to sign the events sent to a webhook, so that the user can check that the events
+/// really come from us. Unlike token secrets, it has to be stored as is, since we need it to sign
+/// the events.
+#[derive(Debug, Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    const NUM_BYTES: usize = 32;
+
+    fn generate() -> Self {
+        let bytes: Vec<u8> = (0..Self::NUM_BYTES)
+            .map(|_| rand::thread_rng().gen())
+            .collect();
+        Self(Hex::encode(&bytes).as_str().to_owned())
+    }
+
+    pub(crate) fn from_string(secret: String) -> Self {
+        Self(secret)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Signs the event sent at the given unix timestamp. The timestamp is part of the signature,
+    /// which allows the user to reject replayed events.
+    pub(crate) fn sign(&self, timestamp: i64, payload: &str) -> Hex {
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(self.0.as_bytes()).unwrap();
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        Hex::encode(&mac.finalize().into_bytes())
+    }
+}
+
+#[derive(Debug)]
+pub struct Webhook {
+    pub id: Id,
+    pub user_id: user::Id,
+    pub url: Url,
+    pub secret: Secret,
+    pub created: DateTime<Utc>,
+    pub disabled: Option<DateTime<Utc>>,
+}
+
+const MAX_URL_CHARS: usize = 2048;
+
+impl Webhook {
+    /// Creates a new webhook with a randomly generated secret.
+    pub(crate) fn create(user_id: user::Id, url: &str) -> Result<Self, Error> {
+        if url.chars().count() > MAX_URL_CHARS {
+            return Err(Error::InvalidUrl(formatcp!(
+                "url can be up to {} characters long",
+                MAX_URL_CHARS
+            )));
+        }
+        let url = Url::parse(url).map_err(|_| Error::InvalidUrl("url can't be parsed"))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(Error::InvalidUrl("url must use http or https"));
+        }
+        check_host(&url)?;
+        Ok(Self {
+            id: Id(Uuid::new_v4()),
+            user_id,
+            url,
+            secret: Secret::generate(),
+            created: Utc::now(),
+            disabled: None,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.disabled.is_none()
+    }
+
+    /// Disables the webhook, so that no more events are sent to it. Disabling an already disabled
+    /// webhook does nothing.
+    pub(crate) fn disable(&mut self) {
+        if self.is_enabled() {
+            self.disabled = Some(Utc::now());
+        }
+    }
+}
+
+/// Checks that the url has a host, and that the host isn't an address of our own network, see
+/// [`is_public`]. Hosts which are names are checked when they're resolved.
+pub(crate) fn check_host(url: &Url) -> Result<(), Error> {
+    let ip = match url.host() {
+        None => return Err(Error::InvalidUrl("url must have a host")),
+        Some(Host::Domain(_)) => return Ok(()),
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+    };
+    if !is_public(ip) {
+        return Err(Error::InvalidUrl("url must not point to a private address"));
+    }
+    Ok(())
+}
+
+/// False for the loopback, private, link-local and unspecified addresses, which webhooks must not
+/// be able to reach, since they would let users send requests into our own network.
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first_segment = ip.segments()[0];
+                let is_unique_local = first_segment & 0xfe00 == 0xfc00;
+                let is_link_local = first_segment & 0xffc0 == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || is_unique_local || is_link_local)
+            }
+        },
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryId(pub Uuid);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The event hasn't been delivered yet, but it will be retried.
+    Pending,
+    /// The webhook responded with a 2xx status.
+    Delivered,
+    /// All attempts failed, or the webhook was disabled. The event won't be sent again.
+    Failed,
+}
+
+/// Delivery of an [`Event`] to a [`Webhook`], together with the outcome of the last attempt.
+#[derive(Debug)]
+pub struct Delivery {
+    pub id: DeliveryId,
+    pub webhook_id: Id,
+    pub event_id: EventId,
+    pub event_kind: EventKind,
+    pub attempts: i32,
+    pub next_attempt: Option<DateTime<Utc>>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// HTTP status of the last response, if the webhook responded at all.
+    pub last_response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+}
+
+/// With the exponential backoff, the last attempt is made about 17 hours after the first one.
+const MAX_ATTEMPTS: i32 = 12;
+const FIRST_RETRY_SECONDS: i64 = 30;
+const MAX_ERROR_CHARS: usize = 500;
+
+impl Delivery {
+    /// Creates a delivery which will be attempted right away.
+    pub(crate) fn create(webhook: &Webhook, event: &Event) -> Self {
+        Self {
+            id: DeliveryId(Uuid::new_v4()),
+            webhook_id: webhook.id,
+            event_id: event.id,
+            event_kind: event.kind,
+            attempts: 0,
+            next_attempt: Some(event.created),
+            last_attempt: None,
+            last_response_status: None,
+            last_error: None,
+            delivered: None,
+            created: event.created,
+        }
+    }
+
+    pub fn status(&self) -> DeliveryStatus {
+        match (self.delivered, self.next_attempt) {
+            (Some(_), _) => DeliveryStatus::Delivered,
+            (None, Some(_)) => DeliveryStatus::Pending,
+            (None, None) => DeliveryStatus::Failed,
+        }
+    }
+
+    pub(crate) fn is_due(&self) -> bool {
+        matches!(self.next_attempt, Some(next_attempt) if next_attempt <= Utc::now())
+    }
+
+    pub(crate) fn succeed(&mut self, response_status: u16) {
+        let now = Utc::now();
+        self.attempts += 1;
+        self.last_attempt = Some(now);
+        self.last_response_status = Some(response_status.into());
+        self.last_error = None;
+        self.delivered = Some(now);
+        self.next_attempt = None;
+    }
+
+    /// Schedules the next attempt, doubling the delay after each failure. Gives up after
+    /// [`MAX_ATTEMPTS`].
+    pub(crate) fn fail(&mut self, response_status: Option<u16>, error: String) {
+        let now = Utc::now();
+        self.attempts += 1;
+        self.last_attempt = Some(now);
+        self.last_response_status = response_status.map(i32::from);
+        self.last_error = Some(error.chars().take(MAX_ERROR_CHARS).collect());
+        self.next_attempt = if self.attempts < MAX_ATTEMPTS {
+            Some(now + Duration::seconds(FIRST_RETRY_SECONDS << (self.attempts - 1)))
+        } else {
+            None
+        };
+    }
+
+    /// Marks the delivery as being attempted by pushing the next attempt past the end of this one,
+    /// so that other senders skip it. If the sender stops before recording the outcome, the
+    /// delivery is attempted again once the lease runs out.
+    pub(crate) fn start_attempt(&mut self, lease: Duration) {
+        self.next_attempt = Some(Utc::now() + lease);
+    }
+
+    /// Gives up on the delivery without attempting it, e.g. because the webhook was disabled.
+    pub(crate) fn cancel(&mut self, reason: &str) {
+        self.last_error = Some(reason.to_owned());
+        self.next_attempt = None;
+    }
+}
@@ -0,0 +1,499 @@
+use crate::{
+    auth,
+    database::{self, Database},
+    event::Event,
+    swallow_panic, worker, QueryRange,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use hyper::client::connect::dns::Name;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+mod entities;
+
+pub use entities::{Delivery, DeliveryId, DeliveryStatus, Error, Id, Secret, Webhook};
+
+const MAX_WEBHOOKS_PER_USER: i64 = 10;
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+/// How many deliveries are attempted in one run of the sender.
+const BATCH_SIZE: i64 = 100;
+/// How many deliveries of a batch are attempted at the same time.
+const MAX_CONCURRENT_DELIVERIES: usize = 10;
+/// How long other senders skip a delivery which is being attempted. Longer than the request
+/// timeout, so that the attempt has finished by then.
+const ATTEMPT_LEASE_SECONDS: i64 = 3 * REQUEST_TIMEOUT_SECONDS as i64;
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const EVENT_ID_HEADER: &str = "X-Webhook-Event-Id";
+
+/// Registers a new webhook. The returned webhook contains the secret used to sign the events.
+pub async fn create(grant: &auth::AdminGrant, db: &Database, url: &str) -> Result<Webhook, Error> {
+    let webhook = Webhook::create(grant.user_id, url)?;
+    let mut data_tx = db.begin().await.unwrap();
+    // Lock the user's webhooks, so that concurrent requests can't go over the limit
+    if queries::lock_enabled_for_user(&mut data_tx, grant.user_id)
+        .await
+        .len() as i64
+        >= MAX_WEBHOOKS_PER_USER
+    {
+        return Err(Error::TooManyWebhooks);
+    }
+    queries::upsert(&mut data_tx, &webhook).await;
+    data_tx.commit().await.unwrap();
+    Ok(webhook)
+}
+
+pub async fn get(grant: &auth::AdminGrant, db: &Database, id: Id) -> Option<Webhook> {
+    queries::get(db, id, grant.user_id).await
+}
+
+pub async fn list(grant: &auth::AdminGrant, db: &Database) -> Vec<Webhook> {
+    queries::list(db, grant.user_id).await
+}
+
+/// Disables the webhook. Pending deliveries to the webhook are dropped. Disabling an already
+/// disabled webhook does nothing.
+pub async fn disable(grant: &auth::AdminGrant, db: &Database, id: Id) -> Result<Webhook, Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut webhook = queries::lock(&mut data_tx, id, grant.user_id)
+        .await
+        .ok_or(Error::NotFound)?;
+    webhook.disable();
+    queries::upsert(&mut data_tx, &webhook).await;
+    data_tx.commit().await.unwrap();
+    Ok(webhook)
+}
+
+/// Lists the deliveries to the webhook, most recent first.
+pub async fn list_deliveries(
+    grant: &auth::AdminGrant,
+    db: &Database,
+    id: Id,
+    range: QueryRange,
+) -> Result<Vec<Delivery>, Error> {
+    queries::get(db, id, grant.user_id)
+        .await
+        .ok_or(Error::NotFound)?;
+    Ok(queries::list_deliveries(db, id, range).await)
+}
+
+/// Adds the event to the outbox, to be sent to all enabled webhooks of the user. This is called
+/// when the event is published.
+pub(crate) async fn enqueue(data_tx: &mut database::Transaction, event: &Event) {
+    for webhook in queries::list_enabled_for_user(data_tx, event.user_id).await {
+        queries::upsert_delivery(data_tx, &Delivery::create(&webhook, event)).await;
+    }
+}
+
+pub async fn start_worker(db: &Database) {
+    worker::start(WebhookSender {
+        db: db.clone(),
+        client: client(),
+    });
+}
+
+/// Builds the client to send the deliveries with, see [`send_due`].
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+        // A redirect or a proxy would take the request to a host we haven't checked
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .unwrap()
+}
+
+/// Attempts all deliveries which are due, several at a time. Each delivery is marked as being
+/// attempted before its request is sent, so that no database transaction or lock is held while
+/// waiting for the webhook.
+pub async fn send_due(db: &Database, client: &reqwest::Client) {
+    stream::iter(queries::list_due(db, BATCH_SIZE).await)
+        .for_each_concurrent(MAX_CONCURRENT_DELIVERIES, |id| {
+            swallow_panic(attempt(db, client, id))
+        })
+        .await;
+}
+
+async fn attempt(db: &Database, client: &reqwest::Client, id: DeliveryId) {
+    let mut data_tx = db.begin().await.unwrap();
+    // Skip deliveries which are being attempted by another sender
+    let mut delivery = match queries::lock_delivery(&mut data_tx, id).await {
+        Some(delivery) if delivery.is_due() => delivery,
+        _ => return,
+    };
+    let webhook = queries::get_by_id(&mut data_tx, delivery.webhook_id).await;
+    if !webhook.is_enabled() {
+        delivery.cancel("webhook disabled");
+        queries::upsert_delivery(&mut data_tx, &delivery).await;
+        data_tx.commit().await.unwrap();
+        return;
+    }
+    let payload = queries::get_payload(&mut data_tx, delivery.event_id).await;
+    delivery.start_attempt(chrono::Duration::seconds(ATTEMPT_LEASE_SECONDS));
+    queries::upsert_delivery(&mut data_tx, &delivery).await;
+    data_tx.commit().await.unwrap();
+
+    send(client, &webhook, &mut delivery, payload).await;
+    let mut data_tx = db.begin().await.unwrap();
+    queries::upsert_delivery(&mut data_tx, &delivery).await;
+    data_tx.commit().await.unwrap();
+}
+
+async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &mut Delivery,
+    payload: String,
+) {
+    log::info!(
+        "sending event {:?} to webhook {:?}, attempt {}",
+        delivery.event_id,
+        webhook.id,
+        delivery.attempts + 1
+    );
+    // Webhooks created before private addresses were refused may still point to one
+    if let Err(e) = entities::check_host(&webhook.url) {
+        delivery.fail(None, e.to_string());
+        return;
+    }
+    let timestamp = Utc::now().timestamp();
+    let signature = webhook.secret.sign(timestamp, &payload);
+    let result = client
+        .post(webhook.url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_ID_HEADER, delivery.event_id.0.to_string())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, format!("sha256={}", signature.as_str()))
+        .body(payload)
+        .send()
+        .await;
+    match result {
+        Ok(response) if response.status().is_success() => {
+            delivery.succeed(response.status().as_u16())
+        }
+        Ok(response) => delivery.fail(
+            Some(response.status().as_u16()),
+            format!("unexpected response status {}", response.status()),
+        ),
+        Err(e) => delivery.fail(e.status().map(|status| status.as_u16()), e.to_string()),
+    }
+}
+
+/// Resolves the hosts of webhooks, refusing the ones with addresses of our own network, see
+/// [`entities::is_public`]. The addresses are checked each time a request connects, so a host can't
+/// pass a check and then resolve to another address.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| !entities::is_public(addr.ip())) {
+                return Err(format!(
+                    "{} resolves to private address {}",
+                    name.as_str(),
+                    addr.ip()
+                )
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+struct WebhookSender {
+    db: Database,
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl worker::Worker for WebhookSender {
+    async fn run(&mut self) {
+        send_due(&self.db, &self.client).await;
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+mod queries {
+    use super::{Delivery, DeliveryId, Id, Secret, Webhook};
+    use crate::{
+        database::{self, Database},
+        event::{EventId, EventKind},
+        user, QueryRange,
+    };
+    use chrono::{DateTime, Utc};
+    use const_format::formatcp;
+    use url::Url;
+    use uuid::Uuid;
+
+    const COLUMNS: &str = "id, user_id, url, secret, created, disabled";
+    const DELIVERY_COLUMNS: &str = r#"webhook_deliveries.id, webhook_deliveries.webhook_id,
+        webhook_deliveries.event_id, events.kind AS event_kind, webhook_deliveries.attempts,
+        webhook_deliveries.next_attempt, webhook_deliveries.last_attempt,
+        webhook_deliveries.last_response_status, webhook_deliveries.last_error,
+        webhook_deliveries.delivered, webhook_deliveries.created"#;
+
+    pub(super) async fn upsert(data_tx: &mut database::Transaction, webhook: &Webhook) {
+        sqlx::query(formatcp!(
+            r#"INSERT INTO webhooks ({})
+                VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (id) DO UPDATE SET
+                user_id = $2, url = $3, secret = $4, created = $5, disabled = $6"#,
+            COLUMNS
+        ))
+        .bind(webhook.id.0)
+        .bind(webhook.user_id.0)
+        .bind(webhook.url.to_string())
+        .bind(webhook.secret.as_str())
+        .bind(webhook.created)
+        .bind(webhook.disabled)
+        .execute(data_tx)
+        .await
+        .unwrap();
+    }
+
+    pub(super) async fn get(db: &Database, id: Id, user_id: user::Id) -> Option<Webhook> {
+        sqlx::query_as::<_, WebhookRow>(formatcp!(
+            "SELECT {} FROM webhooks WHERE id = $1 AND user_id = $2",
+            COLUMNS
+        ))
+        .bind(id.0)
+        .bind(user_id.0)
+        .fetch_optional(db)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn get_by_id(data_tx: &mut database::Transaction, id: Id) -> Webhook {
+        sqlx::query_as::<_, WebhookRow>(formatcp!("SELECT {} FROM webhooks WHERE id = $1", COLUMNS))
+            .bind(id.0)
+            .fetch_one(data_tx)
+            .await
+            .unwrap()
+            .into_entity()
+    }
+
+    pub(super) async fn lock(
+        data_tx: &mut database::Transaction,
+        id: Id,
+        user_id: user::Id,
+    ) -> Option<Webhook> {
+        sqlx::query_as::<_, WebhookRow>(formatcp!(
+            "SELECT {} FROM webhooks WHERE id = $1 AND user_id = $2 FOR UPDATE",
+            COLUMNS
+        ))
+        .bind(id.0)
+        .bind(user_id.0)
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn list(db: &Database, user_id: user::Id) -> Vec<Webhook> {
+        sqlx::query_as::<_, WebhookRow>(formatcp!(
+            "SELECT {} FROM webhooks WHERE user_id = $1 ORDER BY created DESC",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn list_enabled_for_user(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> Vec<Webhook> {
+        sqlx::query_as::<_, WebhookRow>(formatcp!(
+            "SELECT {} FROM webhooks WHERE user_id = $1 AND disabled IS NULL",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn lock_enabled_for_user(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> Vec<Webhook> {
+        sqlx::query_as::<_, WebhookRow>(formatcp!(
+            "SELECT {} FROM webhooks WHERE user_id = $1 AND disabled IS NULL FOR UPDATE",
+            COLUMNS
+        ))
+        .bind(user_id.0)
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    pub(super) async fn get_payload(data_tx: &mut database::Transaction, id: EventId) -> String {
+        sqlx::query_as::<_, PayloadRow>("SELECT payload FROM events WHERE id = $1")
+            .bind(id.0)
+            .fetch_one(data_tx)
+            .await
+            .unwrap()
+            .payload
+    }
+
+    pub(super) async fn upsert_delivery(data_tx: &mut database::Transaction, delivery: &Delivery) {
+        sqlx::query(
+            r#"INSERT INTO webhook_deliveries (id, webhook_id, event_id, attempts, next_attempt, last_attempt, last_response_status, last_error, delivered, created)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT (id) DO UPDATE SET
+                webhook_id = $2, event_id = $3, attempts = $4, next_attempt = $5, last_attempt = $6, last_response_status = $7, last_error = $8, delivered = $9, created = $10"#,
+        )
+        .bind(delivery.id.0)
+        .bind(delivery.webhook_id.0)
+        .bind(delivery.event_id.0)
+        .bind(delivery.attempts)
+        .bind(delivery.next_attempt)
+        .bind(delivery.last_attempt)
+        .bind(delivery.last_response_status)
+        .bind(delivery.last_error.clone())
+        .bind(delivery.delivered)
+        .bind(delivery.created)
+        .execute(data_tx)
+        .await
+        .unwrap();
+    }
+
+    pub(super) async fn list_due(db: &Database, limit: i64) -> Vec<DeliveryId> {
+        sqlx::query_as::<_, IdRow>(
+            r#"SELECT id FROM webhook_deliveries WHERE next_attempt <= $1
+                ORDER BY next_attempt LIMIT $2"#,
+        )
+        .bind(Utc::now())
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| DeliveryId(row.id))
+        .collect()
+    }
+
+    /// Returns None if the delivery is locked by another transaction.
+    pub(super) async fn lock_delivery(
+        data_tx: &mut database::Transaction,
+        id: DeliveryId,
+    ) -> Option<Delivery> {
+        sqlx::query_as::<_, DeliveryRow>(formatcp!(
+            r#"SELECT {} FROM webhook_deliveries
+                JOIN events ON webhook_deliveries.event_id = events.id
+                WHERE webhook_deliveries.id = $1 FOR UPDATE OF webhook_deliveries SKIP LOCKED"#,
+            DELIVERY_COLUMNS
+        ))
+        .bind(id.0)
+        .fetch_optional(data_tx)
+        .await
+        .unwrap()
+        .map(|row| row.into_entity())
+    }
+
+    pub(super) async fn list_deliveries(
+        db: &Database,
+        webhook_id: Id,
+        range: QueryRange,
+    ) -> Vec<Delivery> {
+        sqlx::query_as::<_, DeliveryRow>(formatcp!(
+            r#"SELECT {} FROM webhook_deliveries
+                JOIN events ON webhook_deliveries.event_id = events.id
+                WHERE webhook_deliveries.webhook_id = $1
+                ORDER BY webhook_deliveries.created DESC LIMIT $2 OFFSET $3"#,
+            DELIVERY_COLUMNS
+        ))
+        .bind(webhook_id.0)
+        .bind(range.limit)
+        .bind(range.offset)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct IdRow {
+        id: Uuid,
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct PayloadRow {
+        payload: String,
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct WebhookRow {
+        id: Uuid,
+        user_id: Uuid,
+        url: String,
+        secret: String,
+        created: DateTime<Utc>,
+        disabled: Option<DateTime<Utc>>,
+    }
+
+    impl WebhookRow {
+        fn into_entity(self) -> Webhook {
+            Webhook {
+                id: Id(self.id),
+                user_id: user::Id(self.user_id),
+                url: Url::parse(&self.url).unwrap(),
+                secret: Secret::from_string(self.secret),
+                created: self.created,
+                disabled: self.disabled,
+            }
+        }
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct DeliveryRow {
+        id: Uuid,
+        webhook_id: Uuid,
+        event_id: Uuid,
+        event_kind: String,
+        attempts: i32,
+        next_attempt: Option<DateTime<Utc>>,
+        last_attempt: Option<DateTime<Utc>>,
+        last_response_status: Option<i32>,
+        last_error: Option<String>,
+        delivered: Option<DateTime<Utc>>,
+        created: DateTime<Utc>,
+    }
+
+    impl DeliveryRow {
+        fn into_entity(self) -> Delivery {
+            Delivery {
+                id: DeliveryId(self.id),
+                webhook_id: Id(self.webhook_id),
+                event_id: EventId(self.event_id),
+                event_kind: EventKind::parse(&self.event_kind),
+                attempts: self.attempts,
+                next_attempt: self.next_attempt,
+                last_attempt: self.last_attempt,
+                last_response_status: self.last_response_status,
+                last_error: self.last_error,
+                delivered: self.delivered,
+                created: self.created,
+            }
+        }
+    }
+}
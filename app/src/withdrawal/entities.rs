@@ -1,13 +1,25 @@
 //! Enables withdrawal of funds from our service into an onchain address. This is the primary way
 //! for users to get funds out of our service. When a user requests a withdrawal, a new
-//! [`Withdrawal`] is created. Then, the [`Withdrawal::send`] method is called, broadcasting the
-//! withdrawal transaction to the BTC network. Once that transaction is confirmed,
-//! [`Withdrawal::confirm`] is called.
+//! [`Withdrawal`] is created. Then, the withdrawal transaction is funded and signed, possibly
+//! together with other withdrawals in a batch, and [`Withdrawal::sign`] saves the signed
+//! transaction in the withdrawal. Only then the transaction is broadcast to the BTC network and
+//! [`Withdrawal::mark_sent`] is called. Since the saved transaction is always the same,
+//! broadcasting it again after a crash can't send the funds twice. Once that transaction is
+//! confirmed, [`Withdrawal::confirm`] is called. If the transaction gets stuck, its fee can be
+//! raised with [`Withdrawal::bump`], which links the withdrawal to the replacement transaction, if
+//! the transaction was replaced. Until the transaction is signed, the user can back out with
+//! [`Withdrawal::cancel`].
 
 use crate::{
-    auth,
+    allowlist, auth,
     balance::{self, Balance},
-    btc, concurrency, ln, user,
+    btc,
+    cash_limits::{self, CashLimits},
+    concurrency, idempotency,
+    ledger::EntryKind,
+    ln,
+    pricing::FeeSchedule,
+    user,
 };
 use chrono::{DateTime, Utc};
 use thiserror::Error;
@@ -15,17 +27,115 @@ use uuid::Uuid;
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("{0:?}")]
+    LimitsViolated(#[from] cash_limits::Error),
     #[error("insufficient balance")]
     InsufficientBalance(#[from] balance::InsufficientBalance),
     #[error("{0:?}")]
     ConcurrencyConflict(#[from] concurrency::ConflictError),
     #[error("amount not positive")]
     AmountNotPositive,
+    #[error("fee rate must be at least 1 sat/vbyte")]
+    InvalidFeeRate,
+    #[error("fee rate can be at most {0} sat/vbyte")]
+    FeeRateTooHigh(u64),
+    #[error("withdrawal not found")]
+    NotFound,
+    #[error("withdrawal is not waiting for confirmation")]
+    NotPending,
+    #[error("withdrawal has already been signed")]
+    AlreadySigned,
+    #[error("withdrawal has been sent together with withdrawals of other users")]
+    SharedTransaction,
+    #[error("{0}")]
+    AddressNotAllowed(#[from] allowlist::NotAllowed),
+    #[error("{0}")]
+    FeeBump(#[from] ln::FeeBumpError),
+    #[error("{0}")]
+    Idempotency(#[from] idempotency::Error),
 }
 
+impl idempotency::RequestError for Error {}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Id(pub Uuid);
 
+/// How fast the withdrawal transaction should confirm. Faster withdrawals pay higher fees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Confirms in the next block.
+    Fast,
+    /// Confirms within about an hour.
+    Normal,
+    /// Confirms within about a day.
+    Economy,
+    /// Pays a fixed fee rate in satoshis per virtual byte.
+    SatsPerVbyte(u64),
+}
+
+impl Priority {
+    /// The priorities with a fee rate estimated by the node.
+    pub const TIERS: [Priority; 3] = [Priority::Fast, Priority::Normal, Priority::Economy];
+
+    pub(crate) fn fee_rate(&self) -> ln::FeeRate {
+        match self {
+            Priority::Fast => ln::FeeRate::Target(1),
+            Priority::Normal => ln::FeeRate::Target(6),
+            Priority::Economy => ln::FeeRate::Target(144),
+            Priority::SatsPerVbyte(rate) => ln::FeeRate::SatsPerVbyte(*rate),
+        }
+    }
+
+    /// Checks that an explicit fee rate is at least 1 sat/vbyte, and at most the maximum we allow.
+    pub(crate) fn check(&self, max_sats_per_vbyte: u64) -> Result<(), Error> {
+        match *self {
+            Priority::SatsPerVbyte(0) => Err(Error::InvalidFeeRate),
+            Priority::SatsPerVbyte(rate) if rate > max_sats_per_vbyte => {
+                Err(Error::FeeRateTooHigh(max_sats_per_vbyte))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Fast => "fast",
+            Priority::Normal => "normal",
+            Priority::Economy => "economy",
+            Priority::SatsPerVbyte(_) => "sats_per_vbyte",
+        }
+    }
+
+    /// Parses a priority stored with [`Priority::as_str`], along with the fee rate of
+    /// [`Priority::SatsPerVbyte`].
+    pub(crate) fn parse(priority: &str, sats_per_vbyte: Option<u64>) -> Self {
+        match (priority, sats_per_vbyte) {
+            ("fast", _) => Priority::Fast,
+            ("normal", _) => Priority::Normal,
+            ("economy", _) => Priority::Economy,
+            ("sats_per_vbyte", Some(rate)) => Priority::SatsPerVbyte(rate),
+            _ => panic!("unknown priority {} {:?}", priority, sats_per_vbyte),
+        }
+    }
+}
+
+/// The fee a withdrawal would pay with a priority, see [`super::quote`].
+#[derive(Debug, Clone, Copy)]
+pub struct Quote {
+    pub priority: Priority,
+    pub fee: btc::Sats,
+    pub sats_per_vbyte: u64,
+}
+
+/// A transaction which paid a withdrawal before a fee bump replaced it, with the fees the
+/// withdrawal paid then. It's kept in case it confirms instead of the replacement.
+pub(crate) struct ReplacedTx {
+    pub tx_out: btc::TxOut,
+    pub raw_tx: btc::RawTx,
+    pub fee: btc::Sats,
+    pub tx_fee: btc::Sats,
+}
+
 /// Represents a withdrawal of user funds from our service into an onchain address.
 pub struct Withdrawal {
     pub id: Id,
@@ -34,22 +144,51 @@ pub struct Withdrawal {
     pub reservation_id: balance::ReservationId,
     pub address: btc::Address,
     pub fee: btc::Sats,
+    /// Our service fee, reserved together with the amount and the fee.
+    pub service_fee: btc::MilliSats,
     pub amount: btc::Sats,
+    pub priority: Priority,
+    /// The output paying the withdrawal, known once the transaction is signed.
     pub tx_out: Option<btc::TxOut>,
+    /// The signed transaction, kept until it's broadcast. Withdrawals sent before transactions
+    /// were saved don't have it.
+    pub raw_tx: Option<btc::RawTx>,
+    /// The fee of the whole transaction, which may pay other withdrawals too. Withdrawals signed
+    /// before it was saved don't have it.
+    pub tx_fee: Option<btc::Sats>,
     pub created: DateTime<Utc>,
+    /// Time the transaction was broadcast. A withdrawal whose transaction was replaced by a fee
+    /// bump is sent again.
+    pub sent: Option<DateTime<Utc>>,
+    /// Time the fee was last bumped, or a bump was last attempted.
+    pub bumped: Option<DateTime<Utc>>,
     pub confirmed: Option<DateTime<Utc>>,
+    /// Time the withdrawal was cancelled by the user. Only withdrawals which haven't been signed
+    /// can be cancelled.
+    pub cancelled: Option<DateTime<Utc>>,
+    /// Set if a chain reorganization removed the transaction after the withdrawal was confirmed.
+    /// The funds have already left the user's reserved balance, so the withdrawal can't be
+    /// reversed, and flagged withdrawals have to be sorted out by an operator.
+    pub flagged: Option<DateTime<Utc>>,
 }
 
 impl Withdrawal {
     /// Starts a new withdrawal. Reserves user funds. This method will estimate and save the
-    /// transaction fees, but it will not broadcast the transaction. For broadcasting, see the
-    /// [`send`] method.
+    /// transaction fees for the priority, and our service fee according to the schedule, but it
+    /// will not broadcast the transaction. For broadcasting, see [`super::send_unsent`].
+    /// `daily_total` is the amount the user has withdrawn in the last 24 hours. The priority must
+    /// have passed [`Priority::check`].
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn start(
         grant: &auth::SpendGrant,
         node: &mut ln::Node,
         balance: &mut Balance,
         address: btc::Address,
         amount: btc::Sats,
+        priority: Priority,
+        limits: &CashLimits,
+        daily_total: btc::MilliSats,
+        schedule: &FeeSchedule,
     ) -> Result<(Self, balance::Reservation), Error> {
         if grant.user_id != balance.user_id() {
             panic!(
@@ -62,62 +201,230 @@ impl Withdrawal {
         if amount <= btc::Sats(0) {
             return Err(Error::AmountNotPositive);
         }
-        let fee = node.estimate_fee(amount, &address).await;
-        // TODO Pricing (fees). We should probably have withdrawal fees.
-        // TODO There should be a minimum limit for withdrawals. This should probably be part of
-        // the pricing package.
-        let reservation = balance.reserve(amount.msats() + fee.msats())?;
+        limits.check(cash_limits::Amounts {
+            amount: amount.msats(),
+            daily_total,
+        })?;
+        let fee = node
+            .estimate_fee(amount, &address, priority.fee_rate())
+            .await
+            .fee;
+        let service_fee = schedule.fee(amount.msats());
+        let id = Id(Uuid::new_v4());
+        let reservation = balance.reserve(
+            amount.msats(),
+            fee.msats(),
+            service_fee,
+            EntryKind::Withdrawal,
+            id.0,
+        )?;
         Ok((
             Self {
-                id: Id(Uuid::new_v4()),
+                id,
                 token_id: grant.token_id,
                 reservation_id: reservation.id,
                 user_id: grant.user_id,
                 amount,
                 fee,
+                service_fee,
                 address,
+                priority,
                 tx_out: None,
+                raw_tx: None,
+                tx_fee: None,
                 created: Utc::now(),
+                sent: None,
+                bumped: None,
+                cancelled: None,
                 confirmed: None,
+                flagged: None,
             },
             reservation,
         ))
     }
 
-    pub fn is_sent(&self) -> bool {
+    pub fn is_signed(&self) -> bool {
         self.tx_out.is_some()
     }
 
+    pub fn is_sent(&self) -> bool {
+        self.sent.is_some()
+    }
+
     pub fn is_confirmed(&self) -> bool {
         self.confirmed.is_some()
     }
 
-    /// Broadcasts the withdrawal transaction to the BTC network.
-    pub(crate) async fn send(&mut self, node: &mut ln::Node) {
-        // TODO Currently, a lock is acquired before calling this method to avoid race conditions.
-        // Use PSBTs in the future.
-        if self.is_sent() {
-            panic!("withdrawal {:?} has already been sent", self.id);
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.is_some()
+    }
+
+    /// Cancels a withdrawal which hasn't been signed yet, and refunds the reserved funds. Once the
+    /// transaction is signed, it may be broadcast at any moment, so the withdrawal can't be
+    /// cancelled anymore.
+    pub(crate) fn cancel(
+        &mut self,
+        reservation: &mut balance::Reservation,
+        balance: &mut Balance,
+    ) -> Result<(), Error> {
+        if self.is_cancelled() {
+            panic!("withdrawal {:?} has already been cancelled", self.id);
         }
-        let tx_out = match node
-            .get_tx(&self.address, self.amount, &self.id.0.to_string())
-            .await
-        {
-            Some(tx_out) => tx_out,
-            None => {
-                node.send_onchain(&self.address, self.amount, &self.id.0.to_string())
-                    .await
-            }
+        if self.is_signed() {
+            return Err(Error::AlreadySigned);
+        }
+        if self.reservation_id != reservation.id {
+            panic!(
+                "reservation {:?} does not match {:?} for withdrawal {:?}",
+                reservation.id, self.reservation_id, self.id
+            );
+        }
+        reservation.refund(balance);
+        self.cancelled = Some(Utc::now());
+        Ok(())
+    }
+
+    /// Saves the signed withdrawal transaction without broadcasting it. Save the withdrawal before
+    /// broadcasting the transaction, so that the same transaction is broadcast if sending is
+    /// retried. The fee is the withdrawal's share of the transaction fee, see [`split_fee`]. If it's
+    /// lower than the fee reserved when the withdrawal was started, the difference is refunded. If
+    /// it's higher, e.g. because fee rates went up while the withdrawal waited for its batch, the
+    /// user is still charged only the reserved fee and we pay the rest, which is returned.
+    pub(crate) fn sign(
+        &mut self,
+        tx: &ln::SignedTx,
+        tx_out: btc::TxOut,
+        fee: btc::Sats,
+        reservation: &mut balance::Reservation,
+        balance: &mut Balance,
+    ) -> btc::Sats {
+        if self.is_signed() {
+            panic!("withdrawal {:?} has already been signed", self.id);
+        }
+        if self.is_cancelled() {
+            panic!("withdrawal {:?} has been cancelled", self.id);
+        }
+        if self.reservation_id != reservation.id {
+            panic!(
+                "reservation {:?} does not match {:?} for withdrawal {:?}",
+                reservation.id, self.reservation_id, self.id
+            );
+        }
+        let absorbed = btc::Sats((fee.0 - self.fee.0).max(0));
+        if fee < self.fee {
+            reservation.release(self.fee.msats() - fee.msats(), balance);
+            self.fee = fee;
+        }
+        self.raw_tx = Some(tx.raw.clone());
+        self.tx_fee = Some(tx.fee);
+        self.tx_out = Some(tx_out);
+        absorbed
+    }
+
+    /// True if the withdrawal is waiting for its saved transaction to confirm, so that its fee
+    /// can be bumped.
+    pub fn can_bump(&self) -> bool {
+        self.is_sent() && !self.is_confirmed() && self.raw_tx.is_some() && self.tx_fee.is_some()
+    }
+
+    /// Links the withdrawal to the transaction paying it after a fee bump to the priority, and
+    /// charges the user the part of the extra fee the user pays, which is zero if we pay all of it.
+    /// If the transaction was replaced, the withdrawal has to be sent again with the replacement,
+    /// and the replaced transaction is returned.
+    pub(crate) fn bump(
+        &mut self,
+        priority: Priority,
+        tx: &ln::SignedTx,
+        tx_out: btc::TxOut,
+        charge: btc::Sats,
+        reservation: &mut balance::Reservation,
+        balance: &mut Balance,
+    ) -> Result<Option<ReplacedTx>, balance::InsufficientBalance> {
+        if !self.can_bump() {
+            panic!("withdrawal {:?} can't be bumped", self.id);
+        }
+        if self.reservation_id != reservation.id {
+            panic!(
+                "reservation {:?} does not match {:?} for withdrawal {:?}",
+                reservation.id, self.reservation_id, self.id
+            );
+        }
+        let replaced = if tx_out.tx.id != self.tx_out.as_ref().unwrap().tx.id {
+            Some(ReplacedTx {
+                tx_out: self.tx_out.clone().unwrap(),
+                raw_tx: self.raw_tx.clone().unwrap(),
+                fee: self.fee,
+                tx_fee: self.tx_fee.unwrap(),
+            })
+        } else {
+            None
         };
+        if charge > btc::Sats(0) {
+            reservation.add_fee(charge.msats(), balance)?;
+            self.fee = btc::Sats(self.fee.0 + charge.0);
+        }
+        if replaced.is_some() {
+            self.raw_tx = Some(tx.raw.clone());
+            self.sent = None;
+        }
+        self.priority = priority;
         self.tx_out = Some(tx_out);
+        self.tx_fee = Some(tx.fee);
+        self.bumped = Some(Utc::now());
+        Ok(replaced)
+    }
+
+    /// Links the withdrawal back to a transaction which was replaced by a fee bump but confirmed
+    /// instead of the replacement. The replacement can't confirm anymore, so the fees go back to
+    /// what the replaced transaction paid, and what the user was charged for the bump is released.
+    pub(crate) fn revert_bump(
+        &mut self,
+        replaced: ReplacedTx,
+        reservation: &mut balance::Reservation,
+        balance: &mut Balance,
+    ) {
+        if self.is_confirmed() {
+            panic!("withdrawal {:?} has already been completed", self.id);
+        }
+        if replaced.tx_out.tx.id == self.tx_out.as_ref().unwrap().tx.id {
+            panic!(
+                "withdrawal {:?} is still paid by tx {:?}",
+                self.id, replaced.tx_out.tx.id
+            );
+        }
+        if self.reservation_id != reservation.id {
+            panic!(
+                "reservation {:?} does not match {:?} for withdrawal {:?}",
+                reservation.id, self.reservation_id, self.id
+            );
+        }
+        if replaced.fee < self.fee {
+            reservation.release(self.fee.msats() - replaced.fee.msats(), balance);
+        }
+        self.fee = replaced.fee;
+        self.tx_fee = Some(replaced.tx_fee);
+        self.raw_tx = Some(replaced.raw_tx);
+        self.tx_out = Some(replaced.tx_out);
+    }
+
+    /// Marks the withdrawal as sent once its signed transaction has been broadcast to the BTC
+    /// network.
+    pub(crate) fn mark_sent(&mut self) {
+        if self.is_sent() {
+            panic!("withdrawal {:?} has already been sent", self.id);
+        }
+        if self.raw_tx.is_none() {
+            panic!("withdrawal {:?} has not been signed", self.id);
+        }
+        self.sent = Some(Utc::now());
     }
 
     /// Marks the withdrawal as confirmed, and marks the user balance reservation as irrevocably
     /// debited. This method gets called when the withdrawal transaction is confirmed on the BTC
     /// network.
     pub(crate) fn confirm(&mut self, tx_out: &btc::TxOut, reservation: &mut balance::Reservation) {
-        if !self.is_sent() {
-            panic!("withdrawal {:?} has not been sent", self.id);
+        if !self.is_signed() {
+            panic!("withdrawal {:?} has not been signed", self.id);
         }
         if self.is_confirmed() {
             panic!("withdrawal {:?} has already been completed", self.id);
@@ -149,7 +456,73 @@ impl Withdrawal {
             );
         }
         self.tx_out = Some(tx_out.clone());
+        // The transaction may get confirmed before the withdrawal is marked as sent, if sending
+        // was interrupted after broadcasting
+        self.sent.get_or_insert_with(Utc::now);
         self.confirmed = Some(Utc::now());
         reservation.debit();
     }
+
+    /// Flags a confirmed withdrawal whose transaction was removed from the chain, see
+    /// [`Withdrawal::flagged`].
+    pub(crate) fn flag(&mut self) {
+        self.flagged = Some(Utc::now());
+    }
+}
+
+/// Splits the fee of a transaction paying several withdrawals among them, in proportion to the
+/// size of their outputs, so that each withdrawal pays for the block space it takes. The rest of
+/// the transaction, i.e. the inputs and the change, is shared the same way. The shares add up to
+/// the fee and are in the same order as the withdrawals.
+pub(crate) fn split_fee(fee: btc::Sats, withdrawals: &[Withdrawal]) -> Vec<btc::Sats> {
+    let sizes = output_sizes(withdrawals);
+    let total_size: i64 = sizes.iter().sum();
+    let mut shares: Vec<btc::Sats> = sizes
+        .iter()
+        .map(|size| btc::Sats(fee.0 * size / total_size))
+        .collect();
+    let mut remainder = fee.0 - shares.iter().map(|share| share.0).sum::<i64>();
+    for share in shares.iter_mut() {
+        if remainder == 0 {
+            break;
+        }
+        share.0 += 1;
+        remainder -= 1;
+    }
+    shares
+}
+
+/// The highest fee [`split_fee`] can split among the withdrawals without the shares of any user
+/// adding up to more than the user can afford. It may be a few sats lower than that.
+pub(crate) fn max_split_fee(
+    withdrawals: &[Withdrawal],
+    affordable: impl Fn(user::Id) -> btc::Sats,
+) -> btc::Sats {
+    let sizes = output_sizes(withdrawals);
+    let total_size: i128 = sizes.iter().sum::<i64>().into();
+    withdrawals
+        .iter()
+        .map(|withdrawal| {
+            let (size, count) = withdrawals
+                .iter()
+                .zip(&sizes)
+                .filter(|(other, _)| other.user_id == withdrawal.user_id)
+                .fold((0, 0), |(size, count), (_, other_size)| {
+                    (size + other_size, count + 1)
+                });
+            // Each share is rounded up by at most 1 sat
+            let affordable = (affordable(withdrawal.user_id).0 - count).max(0);
+            let max_fee = i128::from(affordable) * total_size / i128::from(size);
+            btc::Sats(i64::try_from(max_fee).unwrap_or(i64::MAX))
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn output_sizes(withdrawals: &[Withdrawal]) -> Vec<i64> {
+    // An output is its amount, the length of its script and the script
+    withdrawals
+        .iter()
+        .map(|withdrawal| 9 + withdrawal.address.script_pubkey().len() as i64)
+        .collect()
 }
@@ -1,80 +1,608 @@
 use crate::{
-    auth, balance, btc, chain, concurrency,
-    database::Database,
+    allowlist, auth,
+    balance::{self, Balance},
+    btc,
+    cash_limits::CashLimits,
+    chain, concurrency,
+    database::{self, Database},
+    event::{self, Event},
+    idempotency::{self, Fingerprint, Operation},
+    ledger::{self, Account, EntryKind, JournalEntry},
     ln::{self, Lightning},
-    swallow_panic, worker, QueryRange,
+    pricing, swallow_panic, user, worker, AmountRange, Page, Period,
 };
 use async_trait::async_trait;
-pub use entities::{Error, Id, Withdrawal};
+use chrono::Utc;
+pub use entities::{Error, Id, Priority, Quote, Withdrawal};
 use std::time::Duration;
 use tokio::sync::Mutex;
 
 mod entities;
 
+/// Starts a withdrawal. If a withdrawal has already been started with the same idempotency key,
+/// that withdrawal is returned instead. An explicit fee rate can be at most `max_sats_per_vbyte`.
+#[allow(clippy::too_many_arguments)]
 pub async fn start(
     grant: &auth::SpendGrant,
     db: &Database,
     node: ln::Node,
     address: &btc::Address,
     amount: btc::Sats,
+    priority: Priority,
+    max_sats_per_vbyte: u64,
+    limits: &CashLimits,
+    idempotency_key: Option<&idempotency::Key>,
 ) -> Result<Withdrawal, Error> {
-    let node = Mutex::new(node);
-    concurrency::retry_loop(|| async {
-        let mut data_tx = db.begin().await.unwrap();
-        let mut balance = balance::get(&mut data_tx, grant.user_id).await;
-        let mut node = node.lock().await;
-        let (withdrawal, reservation) =
-            Withdrawal::start(grant, &mut node, &mut balance, address.clone(), amount).await?;
-        balance::update(&mut data_tx, &balance).await?;
-        balance::upsert_reservation(&mut data_tx, &reservation).await;
-        queries::upsert(&mut data_tx, &withdrawal).await;
-        data_tx.commit().await.unwrap();
-        Ok::<_, Error>(withdrawal)
-    })
+    priority.check(max_sats_per_vbyte)?;
+    let fingerprint = Fingerprint::new(
+        Operation::Withdrawal,
+        &format!("{} {:?} {:?}", address, amount, priority),
+    );
+    idempotency::run(
+        db,
+        grant.user_id,
+        idempotency_key,
+        Operation::Withdrawal,
+        &fingerprint,
+        |id| queries::get(db, Id(id), grant.user_id),
+        || async {
+            let node = Mutex::new(node);
+            concurrency::retry_loop(|| async {
+                let mut data_tx = db.begin().await.unwrap();
+                allowlist::check(&mut data_tx, grant.user_id, address).await?;
+                // Locking the balance keeps concurrent withdrawals of the user from all passing
+                // the daily limit
+                let mut balance = balance::lock(&mut data_tx, grant.user_id).await;
+                let daily_total = queries::daily_total(&mut data_tx, grant.user_id).await;
+                let schedule = pricing::get_schedule(
+                    &mut data_tx,
+                    grant.user_id,
+                    pricing::Operation::Withdrawal,
+                )
+                .await;
+                let mut node = node.lock().await;
+                let (withdrawal, reservation) = Withdrawal::start(
+                    grant,
+                    &mut node,
+                    &mut balance,
+                    address.clone(),
+                    amount,
+                    priority,
+                    limits,
+                    daily_total,
+                    &schedule,
+                )
+                .await?;
+                balance::update(&mut data_tx, &balance).await?;
+                balance::upsert_reservation(&mut data_tx, &reservation).await?;
+                queries::upsert(&mut data_tx, &withdrawal).await;
+                if let Some(key) = idempotency_key {
+                    idempotency::record(&mut data_tx, withdrawal.user_id, key, withdrawal.id.0)
+                        .await;
+                }
+                data_tx.commit().await.unwrap();
+                Ok::<_, Error>(withdrawal)
+            })
+            .await
+        },
+    )
     .await
 }
 
+/// Estimates the fee of withdrawing the amount to the address with each of the
+/// [`Priority::TIERS`], fastest first.
+pub async fn quote(
+    node: &mut ln::Node,
+    address: &btc::Address,
+    amount: btc::Sats,
+) -> Result<Vec<Quote>, Error> {
+    if amount <= btc::Sats(0) {
+        return Err(Error::AmountNotPositive);
+    }
+    let mut quotes = Vec::new();
+    for priority in Priority::TIERS {
+        let estimate = node
+            .estimate_fee(amount, address, priority.fee_rate())
+            .await;
+        quotes.push(Quote {
+            priority,
+            fee: estimate.fee,
+            sats_per_vbyte: estimate.sats_per_vbyte,
+        });
+    }
+    Ok(quotes)
+}
+
+/// Raises the fee of a sent withdrawal which hasn't confirmed yet, so that it confirms with the
+/// priority. The user pays the extra fee, so the bump fails if the user can't afford it. If the
+/// withdrawal was sent together with other withdrawals of the user, the whole transaction is bumped
+/// and the user pays for all of it, while a transaction paying other users too can't be bumped on
+/// their behalf. An explicit fee rate can be at most `max_sats_per_vbyte`.
+pub async fn bump_fee(
+    grant: &auth::SpendGrant,
+    db: &Database,
+    mut node: ln::Node,
+    id: Id,
+    priority: Priority,
+    max_sats_per_vbyte: u64,
+) -> Result<Withdrawal, Error> {
+    let withdrawal = queries::get(db, id, grant.user_id)
+        .await
+        .ok_or(Error::NotFound)?;
+    priority.check(max_sats_per_vbyte)?;
+    if !withdrawal.can_bump() {
+        return Err(Error::NotPending);
+    }
+    let tx_id = withdrawal.tx_out.unwrap().tx.id;
+    bump(db, &mut node, &tx_id, priority, BumpCharge::Withdrawal(id)).await?;
+    Ok(queries::get(db, id, grant.user_id).await.unwrap())
+}
+
+/// Cancels a withdrawal which hasn't been signed yet, and refunds the reserved funds. Cancelling
+/// an already cancelled withdrawal has no effect.
+pub async fn cancel(grant: &auth::SpendGrant, db: &Database, id: Id) -> Result<Withdrawal, Error> {
+    queries::get(db, id, grant.user_id)
+        .await
+        .ok_or(Error::NotFound)?;
+    let mut data_tx = db.begin().await.unwrap();
+    // Signing locks the withdrawal too, so it can't be signed while it's being cancelled
+    let mut withdrawal = queries::lock(&mut data_tx, id).await;
+    if withdrawal.is_cancelled() {
+        return Ok(withdrawal);
+    }
+    let mut balance = balance::lock(&mut data_tx, withdrawal.user_id).await;
+    let mut reservation = balance::get_reservation(&mut data_tx, withdrawal.reservation_id).await;
+    withdrawal.cancel(&mut reservation, &mut balance)?;
+    balance::upsert_reservation(&mut data_tx, &reservation).await?;
+    balance::update(&mut data_tx, &balance).await?;
+    queries::upsert(&mut data_tx, &withdrawal).await;
+    data_tx.commit().await.unwrap();
+    log::info!("cancelled withdrawal {:?}", withdrawal.id);
+    Ok(withdrawal)
+}
+
 pub async fn get(grant: &auth::ReadGrant, db: &Database, id: Id) -> Option<Withdrawal> {
     queries::get(db, id, grant.user_id).await
 }
 
-pub async fn list(grant: &auth::ReadGrant, db: &Database, range: QueryRange) -> Vec<Withdrawal> {
-    queries::list(db, grant.user_id, range).await
+/// Selects the withdrawals to list. The default filter selects all withdrawals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Filter {
+    pub confirmed: Option<bool>,
+    pub period: Period,
+    pub amount: AmountRange<btc::Sats>,
 }
 
-pub async fn start_workers(start_height: u32, db: &Database, lightning: &Lightning) {
+/// Lists the withdrawals matching the filter, most recent first.
+pub async fn list(
+    grant: &auth::ReadGrant,
+    db: &Database,
+    filter: &Filter,
+    page: &Page<Id>,
+) -> Vec<Withdrawal> {
+    queries::list(db, grant.user_id, filter, page).await
+}
+
+/// Lists the withdrawals flagged after chain reorganizations, oldest first. See
+/// [`Withdrawal::flagged`].
+pub(crate) async fn list_flagged(db: &Database) -> Vec<Withdrawal> {
+    queries::list_flagged(db).await
+}
+
+/// When stuck withdrawals get their fee bumped automatically, and who pays for it.
+#[derive(Debug, Clone, Copy)]
+pub struct FeeBumpPolicy {
+    /// Withdrawals which stay unconfirmed for this long after they were sent or last bumped are
+    /// bumped to [`Priority::Fast`].
+    pub after: Duration,
+    /// Charge the users for the extra fee. Otherwise we pay it.
+    pub charge_users: bool,
+}
+
+/// Starts sending withdrawals and confirming them once their transactions are confirmed. See
+/// [`send_unsent`] for the batch window. With a fee bump policy, stuck withdrawals get their fee
+/// bumped, see [`bump_stuck`].
+pub async fn start_workers(
+    start_height: u32,
+    db: &Database,
+    lightning: &Lightning,
+    batch_window: Option<Duration>,
+    fee_bumps: Option<FeeBumpPolicy>,
+) {
     worker::start(WithdrawalSender {
         db: db.clone(),
         node: lightning.create_node().await,
+        batch_window,
     });
+    if let Some(policy) = fee_bumps {
+        worker::start(FeeBumper {
+            db: db.clone(),
+            node: lightning.create_node().await,
+            policy,
+        });
+    }
     chain::listen(start_height, db, lightning, Listener { db: db.clone() }).await;
 }
 
+/// Broadcasts all withdrawals which haven't been sent yet. Without a batch window, each withdrawal
+/// is sent in its own transaction. With a batch window, withdrawals wait until the oldest of them
+/// has waited for the window, then all the withdrawals with the same priority are sent in one
+/// transaction, which saves fees. Either way, each transaction is signed and saved first, and
+/// withdrawals which were signed before but not sent, e.g. because of a crash, broadcast the saved
+/// transaction again.
+pub async fn send_unsent(db: &Database, node: &mut ln::Node, batch_window: Option<Duration>) {
+    let unsigned: Vec<Withdrawal> = queries::list_unsent(db)
+        .await
+        .into_iter()
+        .filter(|withdrawal| !withdrawal.is_signed())
+        .collect();
+    match batch_window {
+        None => {
+            for withdrawal in &unsigned {
+                sign(db, node, &[withdrawal.id]).await;
+            }
+        }
+        Some(batch_window) => {
+            // Withdrawals with different priorities pay different fee rates, so they're batched
+            // separately
+            let mut groups: Vec<Vec<&Withdrawal>> = Vec::new();
+            for withdrawal in &unsigned {
+                match groups
+                    .iter_mut()
+                    .find(|group| group[0].priority == withdrawal.priority)
+                {
+                    Some(group) => group.push(withdrawal),
+                    None => groups.push(vec![withdrawal]),
+                }
+            }
+            let due = Utc::now() - chrono::Duration::from_std(batch_window).unwrap();
+            for group in groups {
+                if group.iter().any(|withdrawal| withdrawal.created <= due) {
+                    let ids: Vec<Id> = group.iter().map(|withdrawal| withdrawal.id).collect();
+                    sign(db, node, &ids).await;
+                }
+            }
+        }
+    }
+    let mut batches: Vec<Vec<Withdrawal>> = Vec::new();
+    for withdrawal in queries::list_unsent(db).await {
+        let tx_id = match withdrawal.tx_out.as_ref() {
+            Some(tx_out) => tx_out.tx.id,
+            None => continue,
+        };
+        match batches
+            .iter_mut()
+            .find(|batch| batch[0].tx_out.as_ref().unwrap().tx.id == tx_id)
+        {
+            Some(batch) => batch.push(withdrawal),
+            None => batches.push(vec![withdrawal]),
+        }
+    }
+    for batch in batches {
+        swallow_panic(send(db, node, batch)).await;
+    }
+}
+
+/// Signs one transaction paying all the withdrawals, and saves it in each of them. The withdrawals
+/// must have the same priority. Withdrawals signed or cancelled in the meantime are skipped, and so
+/// are withdrawals to an address which is already paid by the transaction. Those are left for the
+/// next transaction.
+async fn sign(db: &Database, node: &mut ln::Node, ids: &[Id]) {
+    swallow_panic(async {
+        let mut data_tx = db.begin().await.unwrap();
+        let mut withdrawals: Vec<Withdrawal> = Vec::new();
+        for id in ids {
+            let withdrawal = queries::lock(&mut data_tx, *id).await;
+            if withdrawal.is_signed() {
+                log::info!("withdrawal {:?} was signed concurrently", withdrawal.id);
+            } else if withdrawal.is_cancelled() {
+                log::info!("withdrawal {:?} was cancelled concurrently", withdrawal.id);
+            } else if !withdrawals
+                .iter()
+                .any(|batched| batched.address == withdrawal.address)
+            {
+                withdrawals.push(withdrawal);
+            }
+        }
+        if withdrawals.is_empty() {
+            return;
+        }
+        log::info!(
+            "signing withdrawals {:?}",
+            withdrawals
+                .iter()
+                .map(|withdrawal| withdrawal.id)
+                .collect::<Vec<_>>()
+        );
+        let outputs: Vec<(btc::Address, btc::Sats)> = withdrawals
+            .iter()
+            .map(|withdrawal| (withdrawal.address.clone(), withdrawal.amount))
+            .collect();
+        let signed_tx = node
+            .sign_onchain(&outputs, withdrawals[0].priority.fee_rate())
+            .await;
+        let fees = entities::split_fee(signed_tx.fee, &withdrawals);
+        // The transaction is signed, so the refunds of the fee differences can't be retried
+        let mut balances: Vec<Balance> = Vec::new();
+        for ((withdrawal, tx_out), fee) in withdrawals
+            .iter_mut()
+            .zip(signed_tx.tx_outs.clone())
+            .zip(fees)
+        {
+            let balance = match balances
+                .iter()
+                .position(|balance| balance.user_id() == withdrawal.user_id)
+            {
+                Some(i) => &mut balances[i],
+                None => {
+                    balances.push(balance::lock(&mut data_tx, withdrawal.user_id).await);
+                    balances.last_mut().unwrap()
+                }
+            };
+            let mut reservation =
+                balance::get_reservation(&mut data_tx, withdrawal.reservation_id).await;
+            let absorbed = withdrawal.sign(&signed_tx, tx_out, fee, &mut reservation, balance);
+            if absorbed > btc::Sats(0) {
+                log::warn!(
+                    "paying {:?} of fee share {:?} of withdrawal {:?} of user {:?}",
+                    absorbed,
+                    fee,
+                    withdrawal.id,
+                    withdrawal.user_id
+                );
+                record_absorbed_fee(&mut data_tx, withdrawal.id, absorbed).await;
+            }
+            balance::upsert_reservation(&mut data_tx, &reservation)
+                .await
+                .unwrap();
+            queries::upsert(&mut data_tx, withdrawal).await;
+        }
+        for balance in &balances {
+            balance::update(&mut data_tx, balance).await.unwrap();
+        }
+        data_tx.commit().await.unwrap();
+    })
+    .await;
+}
+
+/// Records the part of the on-chain fee of a withdrawal which we pay instead of the user. It comes
+/// out of our revenue.
+async fn record_absorbed_fee(data_tx: &mut database::Transaction, id: Id, absorbed: btc::Sats) {
+    let entry = JournalEntry::transfer(
+        id.0,
+        EntryKind::WithdrawalFee,
+        Account::Revenue,
+        Account::Onchain,
+        absorbed.msats(),
+    );
+    ledger::record(data_tx, &[entry]).await;
+}
+
+/// Broadcasts the transaction of withdrawals which were signed together, and marks them as sent.
+async fn send(db: &Database, node: &mut ln::Node, mut withdrawals: Vec<Withdrawal>) {
+    let tx_out = withdrawals[0].tx_out.clone().unwrap();
+    log::info!(
+        "sending withdrawals {:?} in tx {:?}",
+        withdrawals
+            .iter()
+            .map(|withdrawal| withdrawal.id)
+            .collect::<Vec<_>>(),
+        tx_out.tx.id
+    );
+    let label = match withdrawals.len() {
+        1 => withdrawals[0].id.0.to_string(),
+        len => format!("{} and {} more", withdrawals[0].id.0, len - 1),
+    };
+    node.publish_tx(withdrawals[0].raw_tx.as_ref().unwrap(), &label)
+        .await;
+    let mut data_tx = db.begin().await.unwrap();
+    for withdrawal in &mut withdrawals {
+        withdrawal.mark_sent();
+        queries::upsert(&mut data_tx, withdrawal).await;
+        event::publish(&mut data_tx, Event::withdrawal_sent(withdrawal)).await;
+    }
+    data_tx.commit().await.unwrap();
+}
+
+/// Bumps the fee of the withdrawals which have been waiting for confirmation for longer than the
+/// policy allows. Withdrawals sent together are bumped together, and if the users are charged,
+/// each pays a share of the extra fee like of the original fee, see [`entities::split_fee`].
+pub async fn bump_stuck(db: &Database, node: &mut ln::Node, policy: FeeBumpPolicy) {
+    let stuck_since = Utc::now() - chrono::Duration::from_std(policy.after).unwrap();
+    let mut tx_ids: Vec<btc::TxId> = Vec::new();
+    for withdrawal in queries::list_stuck(db, stuck_since).await {
+        let tx_id = withdrawal.tx_out.unwrap().tx.id;
+        if !tx_ids.contains(&tx_id) {
+            tx_ids.push(tx_id);
+        }
+    }
+    let charge = if policy.charge_users {
+        BumpCharge::Shared
+    } else {
+        BumpCharge::Absorbed
+    };
+    for tx_id in tx_ids {
+        swallow_panic(async {
+            if let Err(e) = bump(db, node, &tx_id, Priority::Fast, charge).await {
+                log::warn!("failed to bump the fee of tx {:?}: {}", tx_id, e);
+            }
+        })
+        .await;
+    }
+}
+
+/// Who pays the extra fee of a fee bump.
+#[derive(Debug, Clone, Copy)]
+enum BumpCharge {
+    /// The user of the withdrawal pays all of it.
+    Withdrawal(Id),
+    /// The users of the withdrawals in the transaction pay a share each.
+    Shared,
+    /// We pay all of it.
+    Absorbed,
+}
+
+/// Bumps the fee of the transaction paying withdrawals, and links the withdrawals to the
+/// transaction paying them afterwards. A replacement transaction is saved before it's sent, like
+/// a newly signed one. The balances of the users who pay are locked first, and the node bumps the
+/// fee only as far as they can afford, so that charging them can't fail once the bump is published.
+async fn bump(
+    db: &Database,
+    node: &mut ln::Node,
+    tx_id: &btc::TxId,
+    priority: Priority,
+    charge: BumpCharge,
+) -> Result<(), Error> {
+    let mut data_tx = db.begin().await.unwrap();
+    let mut withdrawals = queries::lock_by_tx_id(&mut data_tx, tx_id).await;
+    if withdrawals.is_empty() || !withdrawals.iter().all(Withdrawal::can_bump) {
+        return Err(Error::NotPending);
+    }
+    if let BumpCharge::Withdrawal(id) = charge {
+        let user_id = withdrawals
+            .iter()
+            .find(|withdrawal| withdrawal.id == id)
+            .ok_or(Error::NotPending)?
+            .user_id;
+        if withdrawals
+            .iter()
+            .any(|withdrawal| withdrawal.user_id != user_id)
+        {
+            return Err(Error::SharedTransaction);
+        }
+    }
+    let mut balances: Vec<Balance> = Vec::new();
+    let mut reservations = Vec::new();
+    for withdrawal in &withdrawals {
+        if !balances
+            .iter()
+            .any(|balance| balance.user_id() == withdrawal.user_id)
+        {
+            balances.push(balance::lock(&mut data_tx, withdrawal.user_id).await);
+        }
+        reservations.push(balance::get_reservation(&mut data_tx, withdrawal.reservation_id).await);
+    }
+    let affordable = |user_id: user::Id| {
+        balances
+            .iter()
+            .find(|balance| balance.user_id() == user_id)
+            .unwrap()
+            .amount()
+            .sats_floor()
+    };
+    let max_extra_fee = match charge {
+        BumpCharge::Withdrawal(_) => Some(affordable(withdrawals[0].user_id)),
+        BumpCharge::Shared => Some(entities::max_split_fee(&withdrawals, affordable)),
+        BumpCharge::Absorbed => None,
+    };
+    let tx = ln::SignedTx {
+        raw: withdrawals[0].raw_tx.clone().unwrap(),
+        tx_outs: withdrawals
+            .iter()
+            .map(|withdrawal| withdrawal.tx_out.clone().unwrap())
+            .collect(),
+        fee: withdrawals[0].tx_fee.unwrap(),
+    };
+    let fee_bump = match node.bump_fee(&tx, priority.fee_rate(), max_extra_fee).await {
+        Ok(fee_bump) => fee_bump,
+        Err(e) => {
+            // Wait before trying again
+            for withdrawal in &mut withdrawals {
+                withdrawal.bumped = Some(Utc::now());
+                queries::upsert(&mut data_tx, withdrawal).await;
+            }
+            data_tx.commit().await.unwrap();
+            return Err(match e {
+                ln::FeeBumpError::AboveMaxFee => {
+                    Error::InsufficientBalance(balance::InsufficientBalance)
+                }
+                e => e.into(),
+            });
+        }
+    };
+    log::info!(
+        "bumped the fee of tx {:?} by {:?}, now paid by tx {:?}",
+        tx_id,
+        fee_bump.extra_fee,
+        fee_bump.tx.tx_outs[0].tx.id
+    );
+    let shares = match charge {
+        BumpCharge::Withdrawal(id) => withdrawals
+            .iter()
+            .map(|withdrawal| {
+                if withdrawal.id == id {
+                    fee_bump.extra_fee
+                } else {
+                    btc::Sats(0)
+                }
+            })
+            .collect(),
+        BumpCharge::Shared => entities::split_fee(fee_bump.extra_fee, &withdrawals),
+        BumpCharge::Absorbed => vec![btc::Sats(0); withdrawals.len()],
+    };
+    // The bump may be published already, so nothing below can fail: the balances are locked and
+    // cover the shares, and the withdrawals are locked, so their reservations can't change
+    for (((withdrawal, tx_out), share), mut reservation) in withdrawals
+        .iter_mut()
+        .zip(fee_bump.tx.tx_outs.clone())
+        .zip(shares)
+        .zip(reservations)
+    {
+        let balance = balances
+            .iter_mut()
+            .find(|balance| balance.user_id() == withdrawal.user_id)
+            .unwrap();
+        let replaced = withdrawal
+            .bump(
+                priority,
+                &fee_bump.tx,
+                tx_out,
+                share,
+                &mut reservation,
+                balance,
+            )
+            .unwrap();
+        if let Some(replaced) = replaced {
+            queries::insert_replaced_tx(&mut data_tx, withdrawal.id, &replaced).await;
+        }
+        balance::upsert_reservation(&mut data_tx, &reservation)
+            .await
+            .unwrap();
+        queries::upsert(&mut data_tx, withdrawal).await;
+    }
+    if let BumpCharge::Absorbed = charge {
+        for (withdrawal, absorbed) in withdrawals
+            .iter()
+            .zip(entities::split_fee(fee_bump.extra_fee, &withdrawals))
+        {
+            record_absorbed_fee(&mut data_tx, withdrawal.id, absorbed).await;
+        }
+    }
+    for balance in &balances {
+        balance::update(&mut data_tx, balance).await.unwrap();
+    }
+    data_tx.commit().await.unwrap();
+    if !withdrawals[0].is_sent() {
+        send(db, node, withdrawals).await;
+    }
+    Ok(())
+}
+
+/// Goes through the chain from the start height, confirming any withdrawals that were missed.
+pub async fn rescan(start_height: u32, db: &Database, lightning: &Lightning) {
+    chain::scan(start_height, db, lightning, Listener { db: db.clone() }).await;
+}
+
 struct WithdrawalSender {
     db: Database,
     node: ln::Node,
+    batch_window: Option<Duration>,
 }
 
 #[async_trait]
 impl worker::Worker for WithdrawalSender {
     async fn run(&mut self) {
-        let unsent_withdrawals = queries::list_unsent(&self.db).await;
-        for mut withdrawal in unsent_withdrawals {
-            swallow_panic(async {
-                log::info!(
-                    "sending withdrawal {:?} with amount {:?}",
-                    withdrawal.id,
-                    withdrawal.amount
-                );
-                let mut data_tx = self.db.begin().await.unwrap();
-                // TODO Use PSBTs instead of this
-                queries::lock(&mut data_tx, withdrawal.id).await;
-                withdrawal.send(&mut self.node).await;
-                queries::upsert(&mut data_tx, &withdrawal).await;
-                data_tx.commit().await.unwrap();
-            })
-            .await;
-        }
+        send_unsent(&self.db, &mut self.node, self.batch_window).await;
     }
 
     fn timeout() -> Duration {
@@ -82,12 +610,31 @@ impl worker::Worker for WithdrawalSender {
     }
 }
 
+struct FeeBumper {
+    db: Database,
+    node: ln::Node,
+    policy: FeeBumpPolicy,
+}
+
+#[async_trait]
+impl worker::Worker for FeeBumper {
+    async fn run(&mut self) {
+        bump_stuck(&self.db, &mut self.node, self.policy).await;
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
 struct Listener {
     db: Database,
 }
 
 #[async_trait]
 impl chain::TxListener for Listener {
+    const NAME: &'static str = "withdrawal";
+
     async fn process(&mut self, tx_out: &btc::TxOut) {
         log::info!("processing transaction as withdrawal: {:?}", tx_out);
         if !tx_out.tx.is_confirmed() {
@@ -99,33 +646,79 @@ impl chain::TxListener for Listener {
                 log::info!("confirming withdrawal {:?}", withdrawal.id);
                 let mut data_tx = self.db.begin().await.unwrap();
                 let mut reservation =
-                    balance::get_reservation(&self.db, withdrawal.reservation_id).await;
+                    balance::get_reservation(&mut data_tx, withdrawal.reservation_id).await;
+                if withdrawal.tx_out.as_ref().unwrap().tx.id != tx_out.tx.id {
+                    log::warn!(
+                        "withdrawal {:?} is confirmed by tx {:?}, which was replaced by tx {:?}",
+                        withdrawal.id,
+                        tx_out.tx.id,
+                        withdrawal.tx_out.as_ref().unwrap().tx.id
+                    );
+                    let replaced = queries::get_replaced_tx(&mut data_tx, tx_out).await;
+                    let mut balance = balance::lock(&mut data_tx, withdrawal.user_id).await;
+                    withdrawal.revert_bump(replaced, &mut reservation, &mut balance);
+                    balance::update(&mut data_tx, &balance).await.unwrap();
+                }
                 withdrawal.confirm(tx_out, &mut reservation);
                 queries::upsert(&mut data_tx, &withdrawal).await;
-                balance::upsert_reservation(&mut data_tx, &reservation).await;
+                balance::upsert_reservation(&mut data_tx, &reservation)
+                    .await
+                    .unwrap();
+                event::publish(&mut data_tx, Event::withdrawal_confirmed(&withdrawal)).await;
                 data_tx.commit().await.unwrap();
             }
-            Some(withdrawal) => log::info!(
-                "withdrawal {:?} already confirmed by txout {:?}",
-                withdrawal.id,
-                tx_out
-            ),
+            Some(mut withdrawal) => {
+                log::info!(
+                    "withdrawal {:?} already confirmed by txout {:?}",
+                    withdrawal.id,
+                    tx_out
+                );
+                // The transaction may have moved to another block after a reorganization
+                if withdrawal.tx_out.as_ref().unwrap().tx.block_height != tx_out.tx.block_height {
+                    withdrawal.tx_out = Some(tx_out.clone());
+                    let mut data_tx = self.db.begin().await.unwrap();
+                    queries::upsert(&mut data_tx, &withdrawal).await;
+                    data_tx.commit().await.unwrap();
+                }
+            }
             None => log::info!("no withdrawals confirmed by txout {:?}", tx_out),
         }
     }
+
+    async fn reorg(&mut self, tx_out: &btc::TxOut) {
+        let mut withdrawal =
+            match queries::get_by_tx_out(&self.db, &tx_out.tx.id, tx_out.v_out).await {
+                Some(withdrawal) => withdrawal,
+                None => return,
+            };
+        if withdrawal.is_confirmed() {
+            log::error!(
+                "confirmed withdrawal {:?} of user {:?} is not in the chain anymore, flagging it",
+                withdrawal.id,
+                withdrawal.user_id
+            );
+            withdrawal.flag();
+        }
+        withdrawal.tx_out = Some(tx_out.clone());
+        let mut data_tx = self.db.begin().await.unwrap();
+        queries::upsert(&mut data_tx, &withdrawal).await;
+        data_tx.commit().await.unwrap();
+    }
 }
 
 mod queries {
-    use super::{Id, Withdrawal};
+    use super::{entities::ReplacedTx, Filter, Id, Priority, Withdrawal};
     use crate::{
         auth, balance, btc,
-        database::{self, Database},
-        user, QueryRange,
+        database::{self, Database, SumRow},
+        user, Page,
     };
-    use chrono::{DateTime, Utc};
+    use chrono::{DateTime, Duration, Utc};
     use std::str::FromStr;
     use uuid::Uuid;
 
+    /// Gets the withdrawal paid by the tx_out, or by a transaction the tx_out's transaction was
+    /// replaced with.
     pub(super) async fn get_by_tx_out(
         db: &Database,
         tx_id: &btc::TxId,
@@ -139,15 +732,26 @@ mod queries {
                 withdrawals.reservation_id,
                 withdrawals.address,
                 withdrawals.fee_sats,
+                withdrawals.service_fee_msats,
                 withdrawals.amount_sats,
                 withdrawals.tx_id,
                 withdrawals.v_out,
+                withdrawals.priority,
+                withdrawals.sats_per_vbyte,
+                withdrawals.raw_tx,
+                withdrawals.tx_fee_sats,
                 withdrawals.created,
+                withdrawals.sent,
+                withdrawals.bumped,
+                withdrawals.cancelled,
                 withdrawals.confirmed,
+                withdrawals.flagged,
                 tx_outs.block_height
             FROM withdrawals
             JOIN tx_outs ON withdrawals.tx_id = tx_outs.tx_id AND withdrawals.v_out = tx_outs.v_out
-            WHERE withdrawals.tx_id = $1 AND withdrawals.v_out = $2"#,
+            WHERE (withdrawals.tx_id = $1 AND withdrawals.v_out = $2) OR withdrawals.id IN (
+                SELECT withdrawal_id FROM replaced_withdrawal_tx_outs WHERE tx_id = $1 AND v_out = $2
+            )"#,
         )
         .bind(tx_id.to_string())
         .bind(v_out)
@@ -157,10 +761,49 @@ mod queries {
         .map(|row| row.into_entity())
     }
 
+    /// Remembers the transaction which paid the withdrawal before it was replaced, in case it
+    /// confirms after all.
+    pub(super) async fn insert_replaced_tx(
+        data_tx: &mut database::Transaction,
+        id: Id,
+        replaced: &ReplacedTx,
+    ) {
+        sqlx::query(
+            r#"INSERT INTO replaced_withdrawal_tx_outs (tx_id, v_out, withdrawal_id, raw_tx, fee_sats, tx_fee_sats)
+                VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (tx_id, v_out) DO NOTHING"#,
+        )
+        .bind(replaced.tx_out.tx.id.to_string())
+        .bind(replaced.tx_out.v_out)
+        .bind(id.0)
+        .bind(&replaced.raw_tx.0)
+        .bind(replaced.fee.0)
+        .bind(replaced.tx_fee.0)
+        .execute(data_tx)
+        .await
+        .unwrap();
+    }
+
+    /// Gets the replaced transaction the tx_out belongs to.
+    pub(super) async fn get_replaced_tx(
+        data_tx: &mut database::Transaction,
+        tx_out: &btc::TxOut,
+    ) -> ReplacedTx {
+        sqlx::query_as::<_, ReplacedTxRow>(
+            r#"SELECT raw_tx, fee_sats, tx_fee_sats FROM replaced_withdrawal_tx_outs
+                WHERE tx_id = $1 AND v_out = $2"#,
+        )
+        .bind(tx_out.tx.id.to_string())
+        .bind(tx_out.v_out)
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .into_entity(tx_out.clone())
+    }
+
     pub(super) async fn list_unsent(db: &Database) -> Vec<Withdrawal> {
         sqlx::query_as::<_, WithdrawalRow>(
-            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, amount_sats, tx_id, v_out, created, confirmed, NULL AS block_height
-                FROM withdrawals WHERE tx_id IS NULL"#,
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, created, raw_tx, tx_fee_sats, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
+                FROM withdrawals WHERE sent IS NULL AND confirmed IS NULL AND cancelled IS NULL"#,
         )
         .fetch_all(db)
         .await
@@ -170,12 +813,55 @@ mod queries {
         .collect()
     }
 
-    pub(super) async fn lock(data_tx: &mut database::Transaction, id: Id) {
-        sqlx::query("SELECT id FROM withdrawals WHERE id = $1 FOR UPDATE")
-            .bind(id.0)
-            .fetch_one(data_tx)
-            .await
-            .unwrap();
+    /// Gets the withdrawal and locks it until the end of the transaction.
+    pub(super) async fn lock(data_tx: &mut database::Transaction, id: Id) -> Withdrawal {
+        sqlx::query_as::<_, WithdrawalRow>(
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, raw_tx, tx_fee_sats, created, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
+                FROM withdrawals WHERE id = $1 FOR UPDATE"#,
+        )
+        .bind(id.0)
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .into_entity()
+    }
+
+    /// Gets the withdrawals paid by the transaction, and locks them until the end of the
+    /// transaction.
+    pub(super) async fn lock_by_tx_id(
+        data_tx: &mut database::Transaction,
+        tx_id: &btc::TxId,
+    ) -> Vec<Withdrawal> {
+        sqlx::query_as::<_, WithdrawalRow>(
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, raw_tx, tx_fee_sats, created, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
+                FROM withdrawals WHERE tx_id = $1 ORDER BY v_out FOR UPDATE"#,
+        )
+        .bind(tx_id.to_string())
+        .fetch_all(data_tx)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    /// Lists the sent withdrawals which haven't confirmed, and haven't been sent or bumped since
+    /// the given time.
+    pub(super) async fn list_stuck(db: &Database, since: DateTime<Utc>) -> Vec<Withdrawal> {
+        sqlx::query_as::<_, WithdrawalRow>(
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, raw_tx, tx_fee_sats, created, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
+                FROM withdrawals
+                WHERE sent IS NOT NULL AND confirmed IS NULL AND raw_tx IS NOT NULL AND tx_fee_sats IS NOT NULL
+                AND COALESCE(bumped, sent) <= $1
+                ORDER BY sent"#,
+        )
+        .bind(since)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
     }
 
     pub(super) async fn upsert(data_tx: &mut database::Transaction, withdrawal: &Withdrawal) {
@@ -195,9 +881,10 @@ mod queries {
             .unwrap();
         }
         sqlx::query(
-            r#"INSERT INTO withdrawals (id, user_id, token_id, reservation_id, address, fee_sats, amount_sats, tx_id, v_out, created, confirmed)
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) ON CONFLICT (id) DO UPDATE SET
-                user_id = $2, token_id = $3, reservation_id = $4, address = $5, fee_sats = $6, amount_sats = $7, tx_id = $8, v_out = $9, created = $10, confirmed = $11"#,
+            r#"INSERT INTO withdrawals (id, user_id, token_id, reservation_id, address, fee_sats, amount_sats, tx_id, v_out, created, confirmed, flagged, raw_tx, sent, priority, sats_per_vbyte, tx_fee_sats, bumped, cancelled, service_fee_msats)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20) ON CONFLICT (id) DO UPDATE SET
+                user_id = $2, token_id = $3, reservation_id = $4, address = $5, fee_sats = $6, amount_sats = $7, tx_id = $8, v_out = $9, created = $10, confirmed = $11,
+                flagged = $12, raw_tx = $13, sent = $14, priority = $15, sats_per_vbyte = $16, tx_fee_sats = $17, bumped = $18, cancelled = $19, service_fee_msats = $20"#,
         )
         .bind(withdrawal.id.0)
         .bind(withdrawal.user_id.0)
@@ -210,6 +897,18 @@ mod queries {
         .bind(withdrawal.tx_out.as_ref().map(|tx_out| tx_out.v_out))
         .bind(withdrawal.created)
         .bind(withdrawal.confirmed)
+        .bind(withdrawal.flagged)
+        .bind(withdrawal.raw_tx.as_ref().map(|raw_tx| raw_tx.0.clone()))
+        .bind(withdrawal.sent)
+        .bind(withdrawal.priority.as_str())
+        .bind(match withdrawal.priority {
+            Priority::SatsPerVbyte(rate) => Some(i64::try_from(rate).unwrap()),
+            _ => None,
+        })
+        .bind(withdrawal.tx_fee.map(|fee| fee.0))
+        .bind(withdrawal.bumped)
+        .bind(withdrawal.cancelled)
+        .bind(withdrawal.service_fee.0)
         .execute(&mut *data_tx)
         .await
         .unwrap();
@@ -217,7 +916,7 @@ mod queries {
 
     pub(super) async fn get(db: &Database, id: Id, user_id: user::Id) -> Option<Withdrawal> {
         sqlx::query_as::<_, WithdrawalRow>(
-            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, amount_sats, tx_id, v_out, created, confirmed, NULL AS block_height
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, created, raw_tx, tx_fee_sats, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
                 FROM withdrawals WHERE id = $1 AND user_id = $2"#,
         )
         .bind(id.0)
@@ -231,15 +930,28 @@ mod queries {
     pub(super) async fn list(
         db: &Database,
         user_id: user::Id,
-        range: QueryRange,
+        filter: &Filter,
+        page: &Page<Id>,
     ) -> Vec<Withdrawal> {
         sqlx::query_as::<_, WithdrawalRow>(
-            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, amount_sats, tx_id, v_out, created, confirmed, NULL AS block_height
-                FROM withdrawals WHERE user_id = $1 ORDER BY created DESC LIMIT $2 OFFSET $3"#,
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, created, raw_tx, tx_fee_sats, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
+                FROM withdrawals WHERE user_id = $1
+                AND ($2::BOOLEAN IS NULL OR (confirmed IS NOT NULL) = $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR created >= $3) AND ($4::TIMESTAMPTZ IS NULL OR created < $4)
+                AND ($5::BIGINT IS NULL OR amount_sats >= $5) AND ($6::BIGINT IS NULL OR amount_sats <= $6)
+                AND ($7::TIMESTAMPTZ IS NULL OR (created, id) < ($7, $8))
+                ORDER BY created DESC, id DESC LIMIT $9 OFFSET $10"#,
         )
         .bind(user_id.0)
-        .bind(range.limit)
-        .bind(range.offset)
+        .bind(filter.confirmed)
+        .bind(filter.period.from)
+        .bind(filter.period.to)
+        .bind(filter.amount.min.map(|amount| amount.0))
+        .bind(filter.amount.max.map(|amount| amount.0))
+        .bind(page.after.map(|cursor| cursor.created))
+        .bind(page.after.map(|cursor| cursor.id.0))
+        .bind(page.range.limit)
+        .bind(page.range.offset)
         .fetch_all(db)
         .await
         .unwrap()
@@ -248,6 +960,57 @@ mod queries {
         .collect()
     }
 
+    /// Sums the withdrawals of the user in the last 24 hours, except the refunded ones.
+    pub(super) async fn daily_total(
+        data_tx: &mut database::Transaction,
+        user_id: user::Id,
+    ) -> btc::MilliSats {
+        sqlx::query_as::<_, SumRow<Option<i64>>>(
+            r#"SELECT SUM(withdrawals.amount_sats)::BIGINT AS sum FROM withdrawals
+                JOIN balance_reservations ON withdrawals.reservation_id = balance_reservations.id
+                WHERE withdrawals.user_id = $1 AND withdrawals.created > $2 AND balance_reservations.status <> 2"#,
+        )
+        .bind(user_id.0)
+        .bind(Utc::now() - Duration::days(1))
+        .fetch_one(data_tx)
+        .await
+        .unwrap()
+        .sum
+        .map(|sum| btc::Sats(sum).msats())
+        .unwrap_or_default()
+    }
+
+    pub(super) async fn list_flagged(db: &Database) -> Vec<Withdrawal> {
+        sqlx::query_as::<_, WithdrawalRow>(
+            r#"SELECT id, user_id, token_id, reservation_id, address, fee_sats, service_fee_msats, amount_sats, tx_id, v_out, priority, sats_per_vbyte, created, raw_tx, tx_fee_sats, sent, bumped, cancelled, confirmed, flagged, NULL AS block_height
+                FROM withdrawals WHERE flagged IS NOT NULL ORDER BY flagged"#,
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.into_entity())
+        .collect()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct ReplacedTxRow {
+        raw_tx: Vec<u8>,
+        fee_sats: i64,
+        tx_fee_sats: i64,
+    }
+
+    impl ReplacedTxRow {
+        fn into_entity(self, tx_out: btc::TxOut) -> ReplacedTx {
+            ReplacedTx {
+                tx_out,
+                raw_tx: btc::RawTx(self.raw_tx),
+                fee: btc::Sats(self.fee_sats),
+                tx_fee: btc::Sats(self.tx_fee_sats),
+            }
+        }
+    }
+
     #[derive(sqlx::FromRow, Debug)]
     struct WithdrawalRow {
         id: Uuid,
@@ -256,12 +1019,21 @@ mod queries {
         reservation_id: Uuid,
         address: String,
         fee_sats: i64,
+        service_fee_msats: i64,
         amount_sats: i64,
         tx_id: Option<String>,
         v_out: Option<i32>,
         block_height: Option<i32>,
+        priority: String,
+        sats_per_vbyte: Option<i64>,
+        raw_tx: Option<Vec<u8>>,
+        tx_fee_sats: Option<i64>,
         created: DateTime<Utc>,
+        sent: Option<DateTime<Utc>>,
+        bumped: Option<DateTime<Utc>>,
+        cancelled: Option<DateTime<Utc>>,
         confirmed: Option<DateTime<Utc>>,
+        flagged: Option<DateTime<Utc>>,
     }
 
     impl WithdrawalRow {
@@ -273,12 +1045,19 @@ mod queries {
                 reservation_id: balance::ReservationId(self.reservation_id),
                 address: btc::Address::from_str(&self.address).unwrap(),
                 fee: btc::Sats(self.fee_sats),
+                service_fee: btc::MilliSats(self.service_fee_msats),
                 amount: btc::Sats(self.amount_sats),
+                priority: Priority::parse(
+                    &self.priority,
+                    self.sats_per_vbyte.map(|rate| rate.try_into().unwrap()),
+                ),
                 tx_out: match (self.tx_id, self.v_out) {
                     (Some(tx_id), Some(v_out)) => Some(btc::TxOut {
                         tx: btc::Tx {
                             id: btc::TxId::from_str(&tx_id).unwrap(),
                             block_height: self.block_height.map(|x| x.try_into().unwrap()),
+                            block_hash: None,
+                            inputs: Vec::new(),
                         },
                         address: btc::Address::from_str(&self.address).unwrap(),
                         v_out: v_out.try_into().unwrap(),
@@ -286,8 +1065,14 @@ mod queries {
                     }),
                     _ => None,
                 },
+                raw_tx: self.raw_tx.map(btc::RawTx),
+                tx_fee: self.tx_fee_sats.map(btc::Sats),
                 created: self.created,
+                sent: self.sent,
+                bumped: self.bumped,
+                cancelled: self.cancelled,
                 confirmed: self.confirmed,
+                flagged: self.flagged,
             }
         }
     }
@@ -0,0 +1,72 @@
+mod common;
+
+use app::{admin, btc, cash_limits, deposit};
+use common::{sat_limits, Env, TOKEN};
+
+async fn receive(env: &Env, amount: i64) {
+    let grant = env.receive_grant(TOKEN).await;
+    let address = deposit::create_address(&grant, &env.db, env.node().await).await;
+    env.network
+        .receive_onchain(&address.address, btc::Sats(amount));
+    env.network.mine_blocks(1);
+    deposit::rescan(
+        0,
+        &env.db,
+        &env.lightning,
+        deposit::ConfirmationPolicy::Fixed(1),
+        sat_limits(1000, 1_000_000, 1_500_000),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn credits_deposit_within_limits() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    receive(&env, 500_000).await;
+    assert_eq!(
+        env.balance(TOKEN).await,
+        initial + btc::Sats(500_000).msats()
+    );
+    assert!(admin::list_held_deposits(&env.db).await.is_empty());
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn holds_deposit_above_maximum_until_released() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    receive(&env, 2_000_000).await;
+    assert_eq!(env.balance(TOKEN).await, initial);
+    let held = admin::list_held_deposits(&env.db).await;
+    assert_eq!(held.len(), 1);
+    assert_eq!(held[0].held, Some(cash_limits::Error::AmountTooHigh));
+    assert!(!held[0].is_confirmed());
+
+    let released = admin::release_deposit(&env.db, held[0].id).await.unwrap();
+    assert!(released.is_confirmed());
+    assert_eq!(released.held, None);
+    assert_eq!(
+        env.balance(TOKEN).await,
+        initial + btc::Sats(2_000_000).msats()
+    );
+    assert!(admin::list_held_deposits(&env.db).await.is_empty());
+    assert!(admin::release_deposit(&env.db, held[0].id).await.is_none());
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn holds_deposit_above_daily_limit() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    receive(&env, 1_000_000).await;
+    receive(&env, 800_000).await;
+    assert_eq!(
+        env.balance(TOKEN).await,
+        initial + btc::Sats(1_000_000).msats()
+    );
+    let held = admin::list_held_deposits(&env.db).await;
+    assert_eq!(held.len(), 1);
+    assert_eq!(held[0].held, Some(cash_limits::Error::DailyLimitExceeded));
+    env.finish().await;
+}
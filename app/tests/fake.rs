@@ -0,0 +1,195 @@
+use app::{
+    btc,
+    ln::{
+        fake::{FakeNetwork, FAKE_TX_VSIZE},
+        FeeBumpError, FeeRate, InvoiceStatus, Lightning, Node, PaymentError, PaymentStatus,
+        TransactionsQuery,
+    },
+    seconds::Seconds,
+};
+use futures::StreamExt;
+
+async fn node(network: &FakeNetwork) -> Node {
+    Lightning::fake(network.clone()).create_node().await
+}
+
+async fn tx_outs(node: &mut Node) -> Vec<btc::TxOut> {
+    let height = node.get_block_height().await;
+    node.get_tx_outs(TransactionsQuery {
+        start_height: 0,
+        num_blocks: height + 1,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn received_transaction_confirms_in_next_block() {
+    let network = FakeNetwork::new();
+    let mut node = node(&network).await;
+    let address = node.generate_address().await;
+    assert_ne!(node.generate_address().await, address);
+
+    let received = network.receive_onchain(&address, btc::Sats(50_000));
+    let unconfirmed = tx_outs(&mut node).await;
+    assert_eq!(unconfirmed.len(), 1);
+    assert_eq!(unconfirmed[0].tx.id, received.tx.id);
+    assert_eq!(unconfirmed[0].amount, btc::Sats(50_000));
+    assert_eq!(unconfirmed[0].tx.block_height, None);
+
+    network.mine_blocks(3);
+    assert_eq!(node.get_block_height().await, 3);
+    let confirmed = tx_outs(&mut node).await;
+    assert_eq!(confirmed[0].tx.block_height, Some(1));
+    let later = node
+        .get_tx_outs(TransactionsQuery {
+            start_height: 2,
+            num_blocks: 2,
+        })
+        .await;
+    assert!(later.is_empty());
+}
+
+#[tokio::test]
+async fn reorg_returns_transactions_to_mempool_or_drops_them() {
+    let network = FakeNetwork::new();
+    let mut node = node(&network).await;
+    let address = node.generate_address().await;
+    let kept = network.receive_onchain(&address, btc::Sats(10_000));
+    let dropped = network.receive_onchain(&address, btc::Sats(20_000));
+    network.mine_blocks(2);
+
+    network.reorg(2, &[dropped.tx.id]);
+    assert_eq!(node.get_block_height().await, 2);
+    let tx_outs_after_reorg = tx_outs(&mut node).await;
+    assert_eq!(tx_outs_after_reorg.len(), 1);
+    assert_eq!(tx_outs_after_reorg[0].tx.id, kept.tx.id);
+    assert_eq!(tx_outs_after_reorg[0].tx.block_height, None);
+
+    network.mine_blocks(1);
+    assert_eq!(tx_outs(&mut node).await[0].tx.block_height, Some(3));
+}
+
+#[tokio::test]
+async fn replacement_takes_the_place_of_signed_transaction() {
+    let network = FakeNetwork::new();
+    let mut node = node(&network).await;
+    let address = node.generate_address().await;
+    network.set_onchain_fee(btc::Sats(600));
+
+    let signed = node
+        .sign_onchain(&[(address.clone(), btc::Sats(30_000))], FeeRate::Target(6))
+        .await;
+    assert_eq!(signed.fee, btc::Sats(100));
+    assert!(tx_outs(&mut node).await.is_empty());
+    node.publish_tx(&signed.raw, "withdrawal").await;
+    node.publish_tx(&signed.raw, "withdrawal").await;
+    assert_eq!(tx_outs(&mut node).await.len(), 1);
+
+    assert!(matches!(
+        node.bump_fee(&signed, FeeRate::SatsPerVbyte(0), None).await,
+        Err(FeeBumpError::FeeTooLow)
+    ));
+    assert!(matches!(
+        node.bump_fee(&signed, FeeRate::Target(1), Some(btc::Sats(499)))
+            .await,
+        Err(FeeBumpError::AboveMaxFee)
+    ));
+    let bump = node
+        .bump_fee(
+            &signed,
+            FeeRate::SatsPerVbyte(10),
+            Some(btc::Sats(10 * FAKE_TX_VSIZE)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(bump.tx.fee, btc::Sats(10 * FAKE_TX_VSIZE));
+    assert_eq!(bump.extra_fee, btc::Sats(10 * FAKE_TX_VSIZE - 100));
+    assert_ne!(bump.tx.tx_outs[0].tx.id, signed.tx_outs[0].tx.id);
+    assert_eq!(bump.tx.tx_outs[0].amount, btc::Sats(30_000));
+
+    node.publish_tx(&bump.tx.raw, "withdrawal").await;
+    let replaced = tx_outs(&mut node).await;
+    assert_eq!(replaced.len(), 1);
+    assert_eq!(replaced[0].tx.id, bump.tx.tx_outs[0].tx.id);
+    network.mine_blocks(1);
+    assert_eq!(tx_outs(&mut node).await[0].tx.block_height, Some(1));
+}
+
+#[tokio::test]
+async fn paying_own_invoice_settles_it() {
+    let network = FakeNetwork::new();
+    let mut node = node(&network).await;
+    network.set_routing_fee(btc::MilliSats(1_000));
+    let invoice = node
+        .create_invoice(btc::MilliSats(5_000_000), None, Seconds(3600))
+        .await;
+    assert!(matches!(
+        node.get_invoice_status(&invoice).await,
+        InvoiceStatus::Pending
+    ));
+
+    node.pay_invoice(&invoice, None, btc::MilliSats(1_000))
+        .await
+        .unwrap();
+    assert!(matches!(
+        node.get_payment_status(&invoice).await,
+        PaymentStatus::Succeeded { fee } if fee == btc::MilliSats(1_000)
+    ));
+    assert!(matches!(
+        node.get_invoice_status(&invoice).await,
+        InvoiceStatus::Settled(settlement) if settlement.amount == btc::MilliSats(5_000_000)
+    ));
+    let settlement = node.stream_settled_invoices(0).await.next().await.unwrap();
+    assert_eq!(settlement.raw, invoice);
+    assert_eq!(settlement.settle_index, 1);
+    assert!(matches!(
+        node.pay_invoice(&invoice, None, btc::MilliSats(1_000))
+            .await,
+        Err(PaymentError::InvoiceAlreadyPaid)
+    ));
+    assert_eq!(network.payments().len(), 1);
+}
+
+#[tokio::test]
+async fn payments_fail_as_simulated() {
+    let network = FakeNetwork::new();
+    let mut node = node(&network).await;
+    let invoice = node
+        .create_invoice(btc::MilliSats(5_000_000), None, Seconds(3600))
+        .await;
+
+    network.set_routing_fee(btc::MilliSats(2_000));
+    assert!(matches!(
+        node.pay_invoice(&invoice, None, btc::MilliSats(1_000))
+            .await,
+        Err(PaymentError::NoRouteFound)
+    ));
+    network.fail_next_payment(PaymentError::TimedOut);
+    assert!(matches!(
+        node.pay_invoice(&invoice, None, btc::MilliSats(2_000))
+            .await,
+        Err(PaymentError::TimedOut)
+    ));
+    assert!(matches!(
+        node.get_payment_status(&invoice).await,
+        PaymentStatus::Failed(PaymentError::TimedOut)
+    ));
+
+    network.fail_next_payment(PaymentError::Unknown);
+    assert!(matches!(
+        node.pay_invoice(&invoice, None, btc::MilliSats(2_000))
+            .await,
+        Err(PaymentError::Unknown)
+    ));
+    assert!(matches!(
+        node.get_payment_status(&invoice).await,
+        PaymentStatus::InFlight
+    ));
+    network.resolve_payment(&invoice, Ok(()));
+    assert!(matches!(
+        node.get_payment_status(&invoice).await,
+        PaymentStatus::Succeeded { fee } if fee == btc::MilliSats(2_000)
+    ));
+    assert_eq!(network.payments().len(), 1);
+    assert_eq!(network.payments()[0].amount, btc::MilliSats(5_000_000));
+}
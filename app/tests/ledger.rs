@@ -0,0 +1,93 @@
+mod common;
+
+use app::{
+    admin, btc,
+    ledger::{self, Account, Entity, EntryKind},
+    user,
+    withdrawal::{self, Priority},
+    Period, QueryRange,
+};
+use common::{sat_limits, Env, TOKEN};
+
+#[tokio::test]
+async fn check_finds_balance_which_doesnt_match_ledger() {
+    let env = Env::new().await;
+    assert!(admin::check_ledger(&env.db).await.is_consistent());
+    let balance = env.balance(TOKEN).await;
+    let user_id = user::get(&env.read_grant(TOKEN).await, &env.db)
+        .await
+        .unwrap()
+        .id;
+
+    sqlx::query("UPDATE users SET balance_msats = balance_msats + 1 WHERE id = $1")
+        .bind(user_id.0)
+        .execute(&env.db)
+        .await
+        .unwrap();
+    let check = admin::check_ledger(&env.db).await;
+    assert!(!check.is_consistent());
+    assert!(check.unbalanced_journals.is_empty());
+    assert_eq!(check.balance_mismatches.len(), 1);
+    let mismatch = &check.balance_mismatches[0];
+    assert_eq!(mismatch.user_id, user_id);
+    assert!(matches!(mismatch.account, Account::User(id) if id == user_id));
+    assert_eq!(mismatch.cached, balance + btc::MilliSats(1));
+    assert_eq!(mismatch.ledger, balance);
+
+    sqlx::query("UPDATE users SET balance_msats = balance_msats - 1 WHERE id = $1")
+        .bind(user_id.0)
+        .execute(&env.db)
+        .await
+        .unwrap();
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn statement_lists_raised_withdrawal_fee_as_withdrawal() {
+    let env = Env::new().await;
+    let grant = env.spend_grant(TOKEN).await;
+    let started = withdrawal::start(
+        &grant,
+        &env.db,
+        env.node().await,
+        &"bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"
+            .parse()
+            .unwrap(),
+        btc::Sats(100_000),
+        Priority::Economy,
+        1000,
+        &sat_limits(1000, 1_000_000, 10_000_000),
+        None,
+    )
+    .await
+    .unwrap();
+    withdrawal::send_unsent(&env.db, &mut env.node().await, None).await;
+    env.network.set_onchain_fee(btc::Sats(1000));
+    let bumped = withdrawal::bump_fee(
+        &grant,
+        &env.db,
+        env.node().await,
+        started.id,
+        Priority::Fast,
+        1000,
+    )
+    .await
+    .unwrap();
+
+    let transactions = ledger::list_transactions(
+        &env.read_grant(TOKEN).await,
+        &env.db,
+        Period::default(),
+        QueryRange {
+            limit: 10,
+            offset: 0,
+        },
+    )
+    .await;
+    let raised = &transactions[0];
+    assert_eq!(raised.kind, EntryKind::Withdrawal);
+    assert_eq!(raised.entity, Some(Entity::Withdrawal(started.id)));
+    assert_eq!(raised.amount, btc::MilliSats(0));
+    assert_eq!(raised.fee, btc::Sats(bumped.fee.0 - started.fee.0).msats());
+    env.finish().await;
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use app::{
+    btc, idempotency,
+    ln::{self, fake::FakeNetwork, Lightning},
+    payment::{self, Error, Payment},
+    seconds::Seconds,
+};
+use common::{sat_limits, Env, TOKEN};
+
+/// Creates an invoice of a node outside of our service.
+async fn external_invoice(msats: i64) -> ln::RawInvoice {
+    Lightning::fake(FakeNetwork::new())
+        .create_node()
+        .await
+        .create_invoice(btc::MilliSats(msats), None, Seconds(3600))
+        .await
+}
+
+async fn send(
+    env: &Env,
+    invoice: &ln::RawInvoice,
+    key: &idempotency::Key,
+) -> Result<Payment, Error> {
+    payment::send(
+        &env.spend_grant(TOKEN).await,
+        &env.db,
+        env.node().await,
+        invoice.clone(),
+        None,
+        &sat_limits(1, 1_000_000, 10_000_000),
+        Some(key),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn replays_payment_with_the_same_idempotency_key() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    let invoice = external_invoice(10_000_000).await;
+    let key = idempotency::Key::new("payment-1".to_owned()).unwrap();
+
+    let paid = send(&env, &invoice, &key).await.unwrap();
+    let replayed = send(&env, &invoice, &key).await.unwrap();
+    assert_eq!(replayed.id, paid.id);
+    assert_eq!(env.network.payments().len(), 1);
+    let debited = initial - env.balance(TOKEN).await;
+    assert!(debited >= btc::MilliSats(10_000_000));
+
+    let other = external_invoice(20_000_000).await;
+    assert!(matches!(
+        send(&env, &other, &key).await,
+        Err(Error::Idempotency(idempotency::Error::KeyReused))
+    ));
+    assert_eq!(env.balance(TOKEN).await, initial - debited);
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn retries_failed_payment_with_the_same_idempotency_key() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    let invoice = external_invoice(10_000_000).await;
+    let key = idempotency::Key::new("payment-1".to_owned()).unwrap();
+
+    env.network
+        .fail_next_payment(ln::PaymentError::NoRouteFound);
+    assert!(matches!(
+        send(&env, &invoice, &key).await,
+        Err(Error::PaymentError(ln::PaymentError::NoRouteFound))
+    ));
+    assert_eq!(env.balance(TOKEN).await, initial);
+
+    send(&env, &invoice, &key).await.unwrap();
+    assert_eq!(env.network.payments().len(), 1);
+    assert!(env.balance(TOKEN).await < initial);
+    env.finish().await;
+}
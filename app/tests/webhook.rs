@@ -0,0 +1,82 @@
+mod common;
+
+use app::{
+    btc, invoice,
+    seconds::Seconds,
+    webhook::{self, DeliveryStatus, Error},
+    QueryRange,
+};
+use common::{sat_limits, Env, TOKEN};
+
+#[tokio::test]
+async fn refuses_urls_of_private_addresses() {
+    let env = Env::new().await;
+    let grant = env.admin_grant(TOKEN).await;
+    for url in [
+        "http://127.0.0.1/hook",
+        "http://10.1.2.3/hook",
+        "http://192.168.0.1/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://0.0.0.0/hook",
+        "http://[::1]/hook",
+        "http://[fe80::1]/hook",
+        "http://[fd00::1]/hook",
+        "http://[::ffff:127.0.0.1]/hook",
+    ] {
+        assert!(
+            matches!(
+                webhook::create(&grant, &env.db, url).await,
+                Err(Error::InvalidUrl(_))
+            ),
+            "{}",
+            url
+        );
+    }
+    webhook::create(&grant, &env.db, "https://93.184.216.34/hook")
+        .await
+        .unwrap();
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn does_not_send_to_hosts_resolving_to_private_addresses() {
+    let env = Env::new().await;
+    let grant = env.admin_grant(TOKEN).await;
+    let hook = webhook::create(&grant, &env.db, "http://localhost:1/hook")
+        .await
+        .unwrap();
+    let created = invoice::create(
+        &env.receive_grant(TOKEN).await,
+        &env.db,
+        &mut env.node().await,
+        btc::MilliSats(1_000_000),
+        None,
+        Seconds(3600),
+        &sat_limits(1, 1_000_000, 10_000_000),
+        None,
+    )
+    .await
+    .unwrap();
+    // Settling the invoice publishes an event for the webhook
+    env.network.settle_invoice(&created.raw);
+    invoice::reconcile(&env.db, &mut env.node().await).await;
+
+    webhook::send_due(&env.db, &webhook::client()).await;
+    let deliveries = webhook::list_deliveries(
+        &grant,
+        &env.db,
+        hook.id,
+        QueryRange {
+            limit: 10,
+            offset: 0,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].status(), DeliveryStatus::Pending);
+    assert_eq!(deliveries[0].attempts, 1);
+    let error = deliveries[0].last_error.as_ref().unwrap();
+    assert!(error.contains("private address"), "{}", error);
+    env.finish().await;
+}
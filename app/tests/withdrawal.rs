@@ -0,0 +1,214 @@
+mod common;
+
+use app::{
+    admin, btc,
+    withdrawal::{self, Error, Priority, Withdrawal},
+};
+use common::{sat_limits, Env, OTHER_TOKEN, TOKEN};
+use std::time::Duration;
+
+const MAX_SATS_PER_VBYTE: u64 = 1000;
+
+fn address(index: usize) -> btc::Address {
+    [
+        "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+        "bcrt1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qzf4jry",
+    ][index]
+        .parse()
+        .unwrap()
+}
+
+async fn start(env: &Env, token: &str, index: usize, amount: i64) -> Withdrawal {
+    withdrawal::start(
+        &env.spend_grant(token).await,
+        &env.db,
+        env.node().await,
+        &address(index),
+        btc::Sats(amount),
+        Priority::Economy,
+        MAX_SATS_PER_VBYTE,
+        &sat_limits(1000, 1_000_000, 10_000_000),
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+async fn get(env: &Env, token: &str, withdrawal: &Withdrawal) -> Withdrawal {
+    withdrawal::get(&env.read_grant(token).await, &env.db, withdrawal.id)
+        .await
+        .unwrap()
+}
+
+async fn bump(env: &Env, withdrawal: &Withdrawal, priority: Priority) -> Result<Withdrawal, Error> {
+    withdrawal::bump_fee(
+        &env.spend_grant(TOKEN).await,
+        &env.db,
+        env.node().await,
+        withdrawal.id,
+        priority,
+        MAX_SATS_PER_VBYTE,
+    )
+    .await
+}
+
+fn tx_id(withdrawal: &Withdrawal) -> btc::TxId {
+    withdrawal.tx_out.as_ref().unwrap().tx.id
+}
+
+fn reserved(withdrawal: &Withdrawal) -> btc::MilliSats {
+    btc::Sats(withdrawal.amount.0 + withdrawal.fee.0).msats() + withdrawal.service_fee
+}
+
+#[tokio::test]
+async fn sends_and_confirms_withdrawal() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    let started = start(&env, TOKEN, 0, 100_000).await;
+    assert_eq!(env.balance(TOKEN).await, initial - reserved(&started));
+
+    withdrawal::send_unsent(&env.db, &mut env.node().await, None).await;
+    let sent = get(&env, TOKEN, &started).await;
+    assert!(sent.sent.is_some());
+    assert_eq!(sent.tx_out.as_ref().unwrap().amount, btc::Sats(100_000));
+
+    env.network.mine_blocks(1);
+    withdrawal::rescan(0, &env.db, &env.lightning).await;
+    let confirmed = get(&env, TOKEN, &started).await;
+    assert!(confirmed.confirmed.is_some());
+    assert_eq!(env.balance(TOKEN).await, initial - reserved(&confirmed));
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn cancel_refunds_unsigned_withdrawal() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    let started = start(&env, TOKEN, 0, 100_000).await;
+    let grant = env.spend_grant(TOKEN).await;
+
+    let cancelled = withdrawal::cancel(&grant, &env.db, started.id)
+        .await
+        .unwrap();
+    assert!(cancelled.cancelled.is_some());
+    assert_eq!(env.balance(TOKEN).await, initial);
+    withdrawal::cancel(&grant, &env.db, started.id)
+        .await
+        .unwrap();
+    assert_eq!(env.balance(TOKEN).await, initial);
+
+    let signed = start(&env, TOKEN, 0, 100_000).await;
+    withdrawal::send_unsent(&env.db, &mut env.node().await, None).await;
+    assert!(matches!(
+        withdrawal::cancel(&grant, &env.db, signed.id).await,
+        Err(Error::AlreadySigned)
+    ));
+    assert_eq!(env.balance(TOKEN).await, initial - reserved(&signed));
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn batch_splits_fee_among_withdrawals() {
+    let env = Env::new().await;
+    let first = start(&env, TOKEN, 0, 100_000).await;
+    let second = start(&env, OTHER_TOKEN, 1, 200_000).await;
+    withdrawal::send_unsent(&env.db, &mut env.node().await, Some(Duration::from_secs(0))).await;
+    let first = get(&env, TOKEN, &first).await;
+    let second = get(&env, OTHER_TOKEN, &second).await;
+    assert_eq!(tx_id(&first), tx_id(&second));
+    assert_eq!(first.tx_fee, second.tx_fee);
+    assert_eq!(Some(btc::Sats(first.fee.0 + second.fee.0)), first.tx_fee);
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn fee_above_reserved_one_comes_out_of_revenue() {
+    let env = Env::new().await;
+    let started = start(&env, TOKEN, 0, 100_000).await;
+    let revenue = admin::get_revenue(&env.db).await;
+
+    env.network
+        .set_onchain_fee(btc::Sats(started.fee.0 * 6 + 500));
+    withdrawal::send_unsent(&env.db, &mut env.node().await, None).await;
+    let signed = get(&env, TOKEN, &started).await;
+    assert_eq!(signed.fee, started.fee);
+    let absorbed = btc::Sats(signed.tx_fee.unwrap().0 - signed.fee.0);
+    assert!(absorbed > btc::Sats(0));
+    assert_eq!(
+        admin::get_revenue(&env.db).await,
+        revenue - absorbed.msats()
+    );
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn bump_charges_extra_fee_and_original_transaction_still_confirms() {
+    let env = Env::new().await;
+    let initial = env.balance(TOKEN).await;
+    let started = start(&env, TOKEN, 0, 100_000).await;
+    withdrawal::send_unsent(&env.db, &mut env.node().await, None).await;
+    let original = get(&env, TOKEN, &started).await;
+
+    env.network.set_onchain_fee(btc::Sats(1000));
+    assert!(matches!(
+        bump(
+            &env,
+            &original,
+            Priority::SatsPerVbyte(MAX_SATS_PER_VBYTE + 1)
+        )
+        .await,
+        Err(Error::FeeRateTooHigh(MAX_SATS_PER_VBYTE))
+    ));
+    let bumped = bump(&env, &original, Priority::Fast).await.unwrap();
+    assert_eq!(bumped.fee, btc::Sats(1000));
+    assert_ne!(tx_id(&bumped), tx_id(&original));
+    assert_eq!(env.balance(TOKEN).await, initial - reserved(&bumped));
+
+    // The replaced transaction confirms instead of the replacement
+    env.node()
+        .await
+        .publish_tx(original.raw_tx.as_ref().unwrap(), "withdrawal")
+        .await;
+    env.network.mine_blocks(1);
+    withdrawal::rescan(0, &env.db, &env.lightning).await;
+    let confirmed = get(&env, TOKEN, &started).await;
+    assert!(confirmed.confirmed.is_some());
+    assert_eq!(tx_id(&confirmed), tx_id(&original));
+    assert_eq!(confirmed.fee, original.fee);
+    assert_eq!(confirmed.tx_fee, original.tx_fee);
+    assert_eq!(confirmed.raw_tx, original.raw_tx);
+    assert_eq!(env.balance(TOKEN).await, initial - reserved(&original));
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn bump_is_refused_when_user_cannot_afford_it() {
+    let env = Env::new().await;
+    let started = start(&env, TOKEN, 0, 100_000).await;
+    withdrawal::send_unsent(&env.db, &mut env.node().await, None).await;
+    let balance = env.balance(TOKEN).await;
+
+    env.network
+        .set_onchain_fee(btc::Sats(balance.sats_floor().0 + 1000));
+    assert!(matches!(
+        bump(&env, &started, Priority::Fast).await,
+        Err(Error::InsufficientBalance(_))
+    ));
+    assert_eq!(env.balance(TOKEN).await, balance);
+    env.finish().await;
+}
+
+#[tokio::test]
+async fn bump_is_refused_for_transaction_shared_with_other_users() {
+    let env = Env::new().await;
+    let started = start(&env, TOKEN, 0, 100_000).await;
+    start(&env, OTHER_TOKEN, 1, 100_000).await;
+    withdrawal::send_unsent(&env.db, &mut env.node().await, Some(Duration::from_secs(0))).await;
+
+    env.network.set_onchain_fee(btc::Sats(1000));
+    assert!(matches!(
+        bump(&env, &started, Priority::Fast).await,
+        Err(Error::SharedTransaction)
+    ));
+    env.finish().await;
+}
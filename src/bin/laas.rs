@@ -1,3 +1,554 @@
 //! Cli tool to manage LaaS
 
-fn main() {}
+use anyhow::{anyhow, bail};
+use app::admin;
+use app::allowlist;
+use app::auth::{self, Permissions, TokenId};
+use app::btc;
+use app::database::{run_migrations, Database};
+use app::deposit;
+use app::ln::{self, Lightning};
+use app::pricing;
+use app::user::{self, Email, User};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use url::Url;
+
+/// Manages LaaS. The configuration is read the same way as for the server, i.e. from Rocket.toml
+/// and ROCKET_ environment variables.
+#[derive(Debug, Parser)]
+#[clap(name = "laas")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the database migrations.
+    Migrate,
+    /// Manage users.
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Manage API tokens.
+    #[clap(subcommand)]
+    Token(TokenCommand),
+    /// List pending balance reservations.
+    Reservations {
+        /// Only list reservations of this user.
+        #[clap(long)]
+        email: Option<String>,
+    },
+    /// Run a reconciliation job once.
+    #[clap(subcommand)]
+    Reconcile(ReconcileCommand),
+    /// Inspect the ledger.
+    #[clap(subcommand)]
+    Ledger(LedgerCommand),
+    /// Manage withdrawal allowlists, for users who can't confirm changes with a password.
+    #[clap(subcommand)]
+    Allowlist(AllowlistCommand),
+    /// Manage the service fees charged to users.
+    #[clap(subcommand)]
+    Pricing(PricingCommand),
+    /// Manage deposits held because they're outside the deposit limits.
+    #[clap(subcommand)]
+    Held(HeldCommand),
+    /// List deposits and withdrawals whose transactions were removed from the chain by a
+    /// reorganization after they were credited or confirmed.
+    Flagged,
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Create a new user with an empty balance.
+    Create { email: String },
+    /// Show user details, including the balance.
+    Show { email: String },
+    /// List all users and their balances.
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum TokenCommand {
+    /// Issue a new token. The token is printed only once.
+    Create {
+        email: String,
+        name: String,
+        /// Allow the token to spend funds.
+        #[clap(long)]
+        spend: bool,
+        /// Allow the token to receive funds.
+        #[clap(long)]
+        receive: bool,
+        /// Allow the token to read data.
+        #[clap(long)]
+        read: bool,
+        /// Allow the token to manage the user's tokens.
+        #[clap(long)]
+        admin: bool,
+    },
+    /// List the tokens of a user.
+    List { email: String },
+    /// Disable a token.
+    Disable { id: TokenId },
+}
+
+#[derive(Debug, Subcommand)]
+enum AllowlistCommand {
+    /// List the allowed withdrawal addresses of a user.
+    List { email: String },
+    /// Confirm an allowed address. Make sure the user added it before confirming.
+    Confirm { id: allowlist::Id },
+    /// Turn the allowlist of a user off right away.
+    Disable { email: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum PricingCommand {
+    /// List the plans in use and the fee schedules which apply to their users.
+    List,
+    /// Set the fee schedule of an operation (payment, invoice, withdrawal or deposit) on a plan.
+    Set {
+        plan: String,
+        operation: String,
+        /// Flat fee in millisatoshis.
+        #[clap(long, default_value = "0")]
+        flat_msats: i64,
+        /// Proportional fee in parts per million of the amount.
+        #[clap(long, default_value = "0")]
+        rate_ppm: i64,
+    },
+    /// Remove the fee schedule of an operation from a plan, so that the default plan applies.
+    Remove { plan: String, operation: String },
+    /// Move a user to another plan.
+    Assign { email: String, plan: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum HeldCommand {
+    /// List the held deposits.
+    List,
+    /// Credit a held deposit to its user regardless of the deposit limits.
+    Release { id: deposit::Id },
+}
+
+#[derive(Debug, Subcommand)]
+enum ReconcileCommand {
+    /// Settle invoices that were paid while the invoice listener wasn't running.
+    Invoices,
+    /// Send queued payments and finalize payments whose outcome was unknown.
+    Payments,
+    /// Broadcast withdrawals that haven't been sent yet.
+    Withdrawals,
+    /// Go through the chain and process missed deposits and withdrawals.
+    Chain {
+        /// Block height to start from. Defaults to the first block in the LND config.
+        #[clap(long)]
+        start_height: Option<u32>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum LedgerCommand {
+    /// Verify that the user balances and reservations match the ledger entries.
+    Check,
+    /// Show the service fees earned so far.
+    Revenue,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    database_url: Url,
+    lnd: LndConfig,
+    limits: LimitsConfig,
+    deposits: DepositsConfig,
+}
+
+#[derive(Debug, Deserialize)]
+struct LndConfig {
+    url: Url,
+    macaroon_path: String,
+    cert_path: String,
+    first_block: u32,
+}
+
+impl LndConfig {
+    async fn into_lightning(self) -> Lightning {
+        Lightning::new(ln::Config {
+            endpoint: self.url,
+            macaroon_path: self.macaroon_path,
+            cert_path: self.cert_path,
+        })
+        .await
+    }
+}
+
+/// Only the deposit limits, the other limits are enforced by the server.
+#[derive(Debug, Deserialize)]
+struct LimitsConfig {
+    deposit_min_sats: i64,
+    deposit_max_sats: i64,
+    deposit_daily_sats: i64,
+}
+
+impl LimitsConfig {
+    fn into_deposit_limits(self) -> app::CashLimits {
+        app::CashLimits {
+            min: btc::Sats(self.deposit_min_sats).msats(),
+            max: btc::Sats(self.deposit_max_sats).msats(),
+            daily: btc::Sats(self.deposit_daily_sats).msats(),
+        }
+    }
+}
+
+/// Deposits need `required_confirmations`, unless they're below the amount of a confirmation
+/// tier.
+#[derive(Debug, Deserialize)]
+struct DepositsConfig {
+    required_confirmations: u32,
+    #[serde(default)]
+    confirmation_tiers: Vec<ConfirmationTierConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmationTierConfig {
+    below_sats: i64,
+    confirmations: u32,
+}
+
+impl DepositsConfig {
+    fn into_confirmation_policy(self) -> deposit::ConfirmationPolicy {
+        if self.confirmation_tiers.is_empty() {
+            return deposit::ConfirmationPolicy::Fixed(self.required_confirmations);
+        }
+        deposit::ConfirmationPolicy::Tiered {
+            tiers: self
+                .confirmation_tiers
+                .into_iter()
+                .map(|tier| deposit::ConfirmationTier {
+                    below: btc::Sats(tier.below_sats),
+                    confirmations: tier.confirmations,
+                })
+                .collect(),
+            above: self.required_confirmations,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let config: Config = rocket::Config::figment().extract()?;
+    let db = Database::connect(config.database_url.as_str()).await?;
+
+    match cli.command {
+        Command::Migrate => {
+            run_migrations(&db).await;
+            println!("migrations done");
+        }
+        Command::User(UserCommand::Create { email }) => {
+            let email = Email::parse(&email).map_err(|e| anyhow!("{}", e))?;
+            match admin::create_user(&db, email).await {
+                Ok(user) => print_user(&user),
+                Err(user::Error::UserAlreadyExists) => bail!("user already exists"),
+                Err(e) => bail!("can't create user: {}", e),
+            }
+        }
+        Command::User(UserCommand::Show { email }) => print_user(&get_user(&db, email).await?),
+        Command::User(UserCommand::List) => {
+            for user in admin::list_users(&db).await {
+                print_user(&user);
+            }
+        }
+        Command::Token(TokenCommand::Create {
+            email,
+            name,
+            spend,
+            receive,
+            read,
+            admin,
+        }) => {
+            let user = get_user(&db, email).await?;
+            let permissions = Permissions {
+                can_spend: spend,
+                can_receive: receive,
+                can_read: read,
+                can_admin: admin,
+            };
+            let (token, secret) = admin::create_token(&db, user.id, name, permissions)
+                .await
+                .map_err(|e| anyhow!("can't create token: {}", e))?;
+            print_token(&token);
+            println!("token (shown only once): {}", secret.as_str());
+        }
+        Command::Token(TokenCommand::List { email }) => {
+            let user = get_user(&db, email).await?;
+            for token in admin::list_tokens(&db, user.id).await {
+                print_token(&token);
+            }
+        }
+        Command::Token(TokenCommand::Disable { id }) => match admin::disable_token(&db, id).await {
+            Ok(token) => print_token(&token),
+            Err(auth::Error::NotFound) => bail!("token {} not found", id.0),
+            Err(e) => bail!("can't disable token: {}", e),
+        },
+        Command::Allowlist(AllowlistCommand::List { email }) => {
+            let user = get_user(&db, email).await?;
+            for allowed in admin::list_allowed_addresses(&db, user.id).await {
+                print_allowed_address(&allowed);
+            }
+        }
+        Command::Allowlist(AllowlistCommand::Confirm { id }) => {
+            match admin::confirm_allowed_address(&db, id).await {
+                Ok(allowed) => print_allowed_address(&allowed),
+                Err(allowlist::Error::NotFound) => bail!("allowed address {} not found", id.0),
+                Err(e) => bail!("can't confirm allowed address: {}", e),
+            }
+        }
+        Command::Allowlist(AllowlistCommand::Disable { email }) => {
+            let user = get_user(&db, email).await?;
+            admin::disable_allowlist(&db, user.id).await;
+            println!("allowlist of {} disabled", user.email.0);
+        }
+        Command::Pricing(PricingCommand::List) => {
+            for plan in admin::list_plans(&db).await {
+                for schedule in &plan.schedules {
+                    print_fee_schedule(&plan.name, schedule);
+                }
+            }
+        }
+        Command::Pricing(PricingCommand::Set {
+            plan,
+            operation,
+            flat_msats,
+            rate_ppm,
+        }) => {
+            let operation = parse_operation(&operation)?;
+            match admin::set_fee_schedule(
+                &db,
+                &plan,
+                operation,
+                btc::MilliSats(flat_msats),
+                rate_ppm,
+            )
+            .await
+            {
+                Ok(schedule) => print_fee_schedule(&plan, &schedule),
+                Err(e) => bail!("can't set fee schedule: {}", e),
+            }
+        }
+        Command::Pricing(PricingCommand::Remove { plan, operation }) => {
+            let operation = parse_operation(&operation)?;
+            admin::remove_fee_schedule(&db, &plan, operation).await;
+            println!(
+                "{} fee schedule removed from plan {}",
+                operation.as_str(),
+                plan
+            );
+        }
+        Command::Pricing(PricingCommand::Assign { email, plan }) => {
+            let user = get_user(&db, email).await?;
+            admin::set_user_plan(&db, user.id, &plan)
+                .await
+                .map_err(|e| anyhow!("can't assign plan: {}", e))?;
+            println!("{} is on plan {}", user.email.0, plan);
+        }
+        Command::Reservations { email } => {
+            let user_id = match email {
+                Some(email) => Some(get_user(&db, email).await?.id),
+                None => None,
+            };
+            for reservation in admin::list_pending_reservations(&db, user_id).await {
+                println!(
+                    "{}\tuser {}\t{} msats\tcreated {}",
+                    reservation.id.0,
+                    reservation.user_id.0,
+                    reservation.amount.0,
+                    reservation.created
+                );
+            }
+        }
+        Command::Reconcile(command) => {
+            let first_block = config.lnd.first_block;
+            let lightning = config.lnd.into_lightning().await;
+            match command {
+                ReconcileCommand::Invoices => {
+                    app::invoice::reconcile(&db, &mut lightning.create_node().await).await
+                }
+                ReconcileCommand::Payments => {
+                    let mut node = lightning.create_node().await;
+                    app::payment::send_queued(&db, &mut node).await;
+                    app::payment::reconcile(&db, &mut node).await;
+                }
+                ReconcileCommand::Withdrawals => {
+                    let mut node = lightning.create_node().await;
+                    app::withdrawal::send_unsent(&db, &mut node, None).await;
+                }
+                ReconcileCommand::Chain { start_height } => {
+                    let start_height = start_height.unwrap_or(first_block);
+                    let policy = config.deposits.into_confirmation_policy();
+                    let limits = config.limits.into_deposit_limits();
+                    app::deposit::rescan(start_height, &db, &lightning, policy, limits).await;
+                    app::withdrawal::rescan(start_height, &db, &lightning).await;
+                }
+            }
+            println!("reconciliation done");
+        }
+        Command::Ledger(LedgerCommand::Check) => {
+            let check = admin::check_ledger(&db).await;
+            for mismatch in &check.balance_mismatches {
+                println!(
+                    "user {}	{} account	cached {} msats	ledger {} msats",
+                    mismatch.user_id.0,
+                    mismatch.account.name(),
+                    mismatch.cached.0,
+                    mismatch.ledger.0
+                );
+            }
+            for journal_id in &check.unbalanced_journals {
+                println!("journal {}	does not add up to zero", journal_id.0);
+            }
+            if !check.is_consistent() {
+                bail!("the ledger is inconsistent");
+            }
+            println!("the ledger is consistent");
+        }
+        Command::Ledger(LedgerCommand::Revenue) => {
+            let revenue = admin::get_revenue(&db).await;
+            println!(
+                "revenue {} msats ({} sats)",
+                revenue.0,
+                revenue.sats_floor().0
+            );
+        }
+        Command::Held(HeldCommand::List) => {
+            for deposit in admin::list_held_deposits(&db).await {
+                println!(
+                    "deposit {}\tuser {}\t{} sats\ttx {}:{}\theld {}",
+                    deposit.id.0,
+                    deposit.user_id.0,
+                    deposit.tx_out.amount.0,
+                    deposit.tx_out.tx.id,
+                    deposit.tx_out.v_out,
+                    deposit.held.unwrap().as_str()
+                );
+            }
+        }
+        Command::Held(HeldCommand::Release { id }) => match admin::release_deposit(&db, id).await {
+            Some(deposit) => println!(
+                "released deposit {}\tuser {}\t{} sats",
+                deposit.id.0, deposit.user_id.0, deposit.tx_out.amount.0
+            ),
+            None => bail!("held deposit not found"),
+        },
+        Command::Flagged => {
+            for deposit in admin::list_flagged_deposits(&db).await {
+                println!(
+                    "deposit {}\tuser {}\t{} sats\ttx {}:{}\tflagged {}",
+                    deposit.id.0,
+                    deposit.user_id.0,
+                    deposit.tx_out.amount.0,
+                    deposit.tx_out.tx.id,
+                    deposit.tx_out.v_out,
+                    deposit.flagged.unwrap()
+                );
+            }
+            for withdrawal in admin::list_flagged_withdrawals(&db).await {
+                let tx_out = withdrawal.tx_out.as_ref().unwrap();
+                println!(
+                    "withdrawal {}\tuser {}\t{} sats\ttx {}:{}\tflagged {}",
+                    withdrawal.id.0,
+                    withdrawal.user_id.0,
+                    withdrawal.amount.0,
+                    tx_out.tx.id,
+                    tx_out.v_out,
+                    withdrawal.flagged.unwrap()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn get_user(db: &Database, email: String) -> anyhow::Result<User> {
+    let email = Email::parse(&email).map_err(|e| anyhow!("{}", e))?;
+    admin::get_user(db, &email)
+        .await
+        .ok_or_else(|| anyhow!("user {} not found", email.0))
+}
+
+fn print_user(user: &User) {
+    println!(
+        "{}\t{}\tbalance {} msats ({} sats)\tcreated {}",
+        user.id.0,
+        user.email.0,
+        user.balance.0,
+        user.balance.sats_floor().0,
+        user.created
+    );
+}
+
+fn parse_operation(operation: &str) -> anyhow::Result<pricing::Operation> {
+    pricing::Operation::parse(operation).ok_or_else(|| {
+        anyhow!(
+            "unknown operation {}, use payment, invoice, withdrawal or deposit",
+            operation
+        )
+    })
+}
+
+fn print_fee_schedule(plan: &str, schedule: &pricing::FeeSchedule) {
+    println!(
+        "{}	{}	flat {} msats	rate {} ppm",
+        plan,
+        schedule.operation.as_str(),
+        schedule.flat.0,
+        schedule.rate_ppm
+    );
+}
+
+fn print_allowed_address(allowed: &allowlist::AllowedAddress) {
+    println!(
+        "{}\t{}\t{}\tcreated {}\t{}",
+        allowed.id.0,
+        allowed.address,
+        allowed.label.as_deref().unwrap_or("-"),
+        allowed.created,
+        match (allowed.removed, allowed.confirmed) {
+            (Some(removed), _) => format!("removed {}", removed),
+            (None, Some(_)) if allowed.is_usable() => "usable".to_owned(),
+            (None, Some(_)) => format!("usable after {}", allowed.usable_after),
+            (None, None) => "unconfirmed".to_owned(),
+        }
+    );
+}
+
+fn print_token(token: &auth::Token) {
+    let permissions = [
+        (token.permissions.can_spend, "spend"),
+        (token.permissions.can_receive, "receive"),
+        (token.permissions.can_read, "read"),
+        (token.permissions.can_admin, "admin"),
+    ]
+    .iter()
+    .filter(|(allowed, _)| *allowed)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>()
+    .join(",");
+    println!(
+        "{}\t{}\t[{}]\tcreated {}\t{}",
+        token.id.0,
+        token.name,
+        permissions,
+        token.created,
+        match (token.disabled, token.expires) {
+            (Some(disabled), _) => format!("disabled {}", disabled),
+            (None, Some(expires)) if token.is_expired() => format!("expired {}", expires),
+            (None, Some(expires)) => format!("expires {}", expires),
+            (None, None) => "enabled".to_owned(),
+        }
+    );
+}
@@ -1,8 +1,8 @@
 use std::time::Duration;
 
-use app::btc;
 use app::database::{run_migrations, seed_development_data, Database};
 use app::ln::{self, Lightning};
+use app::{btc, deposit, withdrawal};
 use rocket::{launch, Build, Rocket};
 use serde::Deserialize;
 use url::Url;
@@ -13,6 +13,8 @@ struct Config {
     lnd: LndConfig,
     limits: LimitsConfig,
     rate_limit: RateLimitConfig,
+    deposits: DepositsConfig,
+    withdrawals: WithdrawalsConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,9 +33,24 @@ struct LimitsConfig {
     invoice_min_sats: i64,
     invoice_max_sats: i64,
     invoice_daily_sats: i64,
+    withdrawal_min_sats: i64,
+    withdrawal_max_sats: i64,
+    withdrawal_daily_sats: i64,
+    deposit_min_sats: i64,
+    deposit_max_sats: i64,
+    deposit_daily_sats: i64,
 }
 
 impl LimitsConfig {
+    /// Deposits outside these limits are held instead of credited.
+    pub fn deposit_limits(&self) -> app::CashLimits {
+        app::CashLimits {
+            min: btc::Sats(self.deposit_min_sats).msats(),
+            max: btc::Sats(self.deposit_max_sats).msats(),
+            daily: btc::Sats(self.deposit_daily_sats).msats(),
+        }
+    }
+
     pub fn into_api_limits(self) -> api::CashLimits {
         api::CashLimits {
             payment_limits: app::CashLimits {
@@ -46,10 +63,72 @@ impl LimitsConfig {
                 max: btc::Sats(self.invoice_max_sats).msats(),
                 daily: btc::Sats(self.invoice_daily_sats).msats(),
             },
+            withdrawal_limits: app::CashLimits {
+                min: btc::Sats(self.withdrawal_min_sats).msats(),
+                max: btc::Sats(self.withdrawal_max_sats).msats(),
+                daily: btc::Sats(self.withdrawal_daily_sats).msats(),
+            },
         }
     }
 }
 
+/// Deposits need `required_confirmations`, unless they're below the amount of a confirmation
+/// tier.
+#[derive(Debug, Deserialize)]
+struct DepositsConfig {
+    required_confirmations: u32,
+    #[serde(default)]
+    confirmation_tiers: Vec<ConfirmationTierConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmationTierConfig {
+    below_sats: i64,
+    confirmations: u32,
+}
+
+impl DepositsConfig {
+    fn into_confirmation_policy(self) -> deposit::ConfirmationPolicy {
+        if self.confirmation_tiers.is_empty() {
+            return deposit::ConfirmationPolicy::Fixed(self.required_confirmations);
+        }
+        deposit::ConfirmationPolicy::Tiered {
+            tiers: self
+                .confirmation_tiers
+                .into_iter()
+                .map(|tier| deposit::ConfirmationTier {
+                    below: btc::Sats(tier.below_sats),
+                    confirmations: tier.confirmations,
+                })
+                .collect(),
+            above: self.required_confirmations,
+        }
+    }
+}
+
+/// Without a `batch_window`, each withdrawal is sent in its own transaction right away. Without
+/// `bump_fees_after`, the fees of stuck withdrawals are only bumped when users ask for it. Newly
+/// allowed withdrawal addresses can be used only after `allowlist_cooling_off`. Users can set fee
+/// rates up to `max_sats_per_vbyte`.
+#[derive(Debug, Deserialize)]
+struct WithdrawalsConfig {
+    batch_window: Option<Duration>,
+    bump_fees_after: Option<Duration>,
+    #[serde(default)]
+    charge_fee_bumps: bool,
+    allowlist_cooling_off: Duration,
+    max_sats_per_vbyte: u64,
+}
+
+impl WithdrawalsConfig {
+    fn fee_bump_policy(&self) -> Option<withdrawal::FeeBumpPolicy> {
+        self.bump_fees_after.map(|after| withdrawal::FeeBumpPolicy {
+            after,
+            charge_users: self.charge_fee_bumps,
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct RateLimitConfig {
     limit: usize,
@@ -80,7 +159,6 @@ async fn start_server() -> Rocket<Build> {
         endpoint: config.lnd.url,
         macaroon_path: config.lnd.macaroon_path,
         cert_path: config.lnd.cert_path,
-        first_block: config.lnd.first_block,
     })
     .await;
 
@@ -88,9 +166,25 @@ async fn start_server() -> Rocket<Build> {
     #[cfg(debug_assertions)]
     seed_development_data(&db).await;
 
-    app::withdrawal::start_workers(config.lnd.first_block, &db, &lightning).await;
-    app::deposit::start_worker(config.lnd.first_block, &db, &lightning).await;
+    app::withdrawal::start_workers(
+        config.lnd.first_block,
+        &db,
+        &lightning,
+        config.withdrawals.batch_window,
+        config.withdrawals.fee_bump_policy(),
+    )
+    .await;
+    app::deposit::start_worker(
+        config.lnd.first_block,
+        &db,
+        &lightning,
+        config.deposits.into_confirmation_policy(),
+        config.limits.deposit_limits(),
+    )
+    .await;
     app::invoice::start_worker(db.clone(), &lightning).await;
+    app::payment::start_worker(&db, &lightning).await;
+    app::webhook::start_worker(&db).await;
 
     api::register(
         rocket,
@@ -98,5 +192,7 @@ async fn start_server() -> Rocket<Build> {
         lightning,
         config.limits.into_api_limits(),
         config.rate_limit.into_rate_limit(),
+        config.withdrawals.allowlist_cooling_off,
+        config.withdrawals.max_sats_per_vbyte,
     )
 }